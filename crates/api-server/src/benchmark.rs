@@ -0,0 +1,206 @@
+//! Latency Benchmark Module
+//!
+//! Backs `GET /v1/admin/benchmark`. Runs one small streaming request and
+//! times each stage separately, so slow requests can be pinned on auth,
+//! project discovery, or the model itself:
+//!
+//! 1. Account selection, including an access-token refresh if one is due
+//! 2. `loadCodeAssist` project discovery
+//! 3. Time to the first streamed token (discovery is skipped here, the
+//!    project found in step 2 is forced)
+//! 4. The rest of the generation
+
+use anyhow::{anyhow, Context, Result};
+use browser_automator::{AntigravityClient, AntigravityModel, GenerationParams, Message, StreamChunk};
+use futures_util::stream::{BoxStream, StreamExt};
+use oauth::accounts::Account;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::routes::build_client;
+use crate::state::AppState;
+
+/// Prompt sent by the benchmark; short so generation time stays small
+const BENCHMARK_PROMPT: &str = "Reply with the single word: pong";
+
+/// Stages of one benchmarked request
+pub(crate) trait BenchmarkBackend {
+    /// Selects an account, refreshing its access token if needed
+    async fn acquire_account(&mut self) -> Result<()>;
+
+    /// Discovers the project the request will be billed to
+    async fn discover_project(&mut self) -> Result<()>;
+
+    /// Starts the streaming request
+    async fn open_stream(&mut self) -> Result<BoxStream<'_, Result<StreamChunk>>>;
+}
+
+/// Per-stage timings of one request
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub model: String,
+    pub token_refresh: Duration,
+    pub project_discovery: Duration,
+    pub time_to_first_token: Duration,
+    /// First token to end of stream
+    pub generation: Duration,
+    pub total: Duration,
+    pub output_chars: usize,
+}
+
+impl BenchmarkReport {
+    pub fn to_json(&self) -> Value {
+        let ms = |d: Duration| d.as_millis() as u64;
+        serde_json::json!({
+            "model": self.model,
+            "token_refresh_ms": ms(self.token_refresh),
+            "project_discovery_ms": ms(self.project_discovery),
+            "time_to_first_token_ms": ms(self.time_to_first_token),
+            "generation_ms": ms(self.generation),
+            "total_ms": ms(self.total),
+            "output_chars": self.output_chars
+        })
+    }
+}
+
+/// Runs one request through the backend, timing each stage
+pub(crate) async fn run_benchmark(backend: &mut impl BenchmarkBackend, model: &str) -> Result<BenchmarkReport> {
+    let start = Instant::now();
+
+    backend.acquire_account().await.context("Token refresh failed")?;
+    let token_refresh = start.elapsed();
+
+    backend.discover_project().await.context("Project discovery failed")?;
+    let project_discovery = start.elapsed() - token_refresh;
+
+    let stream_start = Instant::now();
+    let mut stream = backend.open_stream().await.context("Request failed")?;
+    let mut first_token = None;
+    let mut output_chars = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.context("Stream failed")?;
+        if chunk.done {
+            break;
+        }
+        if !chunk.delta.is_empty() && first_token.is_none() {
+            first_token = Some(stream_start.elapsed());
+        }
+        output_chars += chunk.delta.chars().count();
+    }
+    let stream_time = stream_start.elapsed();
+    let time_to_first_token = first_token.ok_or_else(|| anyhow!("The model returned no output"))?;
+
+    Ok(BenchmarkReport {
+        model: model.to_string(),
+        token_refresh,
+        project_discovery,
+        time_to_first_token,
+        generation: stream_time - time_to_first_token,
+        total: start.elapsed(),
+        output_chars,
+    })
+}
+
+/// Benchmarks the real upstream with the server's accounts and config
+pub(crate) struct LiveBackend<'a> {
+    state: &'a AppState,
+    model: AntigravityModel,
+    account: Option<Account>,
+    project_id: Option<String>,
+    // Owned here because the stream borrows both
+    client: Option<AntigravityClient>,
+    params: GenerationParams,
+}
+
+impl<'a> LiveBackend<'a> {
+    pub(crate) fn new(state: &'a AppState, model: AntigravityModel) -> Self {
+        Self { state, model, account: None, project_id: None, client: None, params: GenerationParams::default() }
+    }
+
+    fn access_token(&self) -> Result<String> {
+        self.account.as_ref().map(|a| a.access_token.clone()).ok_or_else(|| anyhow!("No account selected"))
+    }
+}
+
+impl BenchmarkBackend for LiveBackend<'_> {
+    async fn acquire_account(&mut self) -> Result<()> {
+        let account = self.state.account_manager.get_available_account().await
+            .ok_or_else(|| anyhow!("No available account (none configured, or all rate limited)"))?;
+        self.account = Some(account);
+        Ok(())
+    }
+
+    async fn discover_project(&mut self) -> Result<()> {
+        let client = build_client(&self.state.config, &self.state.fingerprint, &self.state.context_cache, &self.state.response_cache, &self.state.endpoint_stats, self.access_token()?)?;
+        client.fetch_provisioned_project_id().await;
+        self.project_id = Some(client.project_id().await);
+        Ok(())
+    }
+
+    async fn open_stream(&mut self) -> Result<BoxStream<'_, Result<StreamChunk>>> {
+        // Force the discovered project so the stream doesn't discover it again
+        let mut config = (*self.state.config).clone();
+        config.project_id = self.project_id.clone();
+        let client = self.client.insert(build_client(&config, &self.state.fingerprint, &self.state.context_cache, &self.state.response_cache, &self.state.endpoint_stats, self.access_token()?)?);
+
+        let stream = client
+            .chat_completion_stream(self.model, vec![Message::user(BENCHMARK_PROMPT)], None, None, &self.params)
+            .await?;
+        Ok(stream.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sleeps a fixed time per stage
+    struct MockBackend;
+
+    impl BenchmarkBackend for MockBackend {
+        async fn acquire_account(&mut self) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(())
+        }
+
+        async fn discover_project(&mut self) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok(())
+        }
+
+        async fn open_stream(&mut self) -> Result<BoxStream<'_, Result<StreamChunk>>> {
+            let chunks = vec![
+                (40, StreamChunk { delta: "po".into(), ..Default::default() }),
+                (20, StreamChunk { delta: "ng".into(), ..Default::default() }),
+                (0, StreamChunk { done: true, ..Default::default() }),
+            ];
+            Ok(futures_util::stream::iter(chunks)
+                .then(|(delay, chunk)| async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Ok(chunk)
+                })
+                .boxed())
+        }
+    }
+
+    // The clock only moves when the mock sleeps, so stage times are exact
+    #[tokio::test(start_paused = true)]
+    async fn test_breakdown_sums_to_total() {
+        let report = run_benchmark(&mut MockBackend, "antigravity-gemini-3-flash").await.unwrap();
+
+        assert_eq!(report.token_refresh, Duration::from_millis(20));
+        assert_eq!(report.project_discovery, Duration::from_millis(30));
+        assert_eq!(report.time_to_first_token, Duration::from_millis(40));
+        assert_eq!(report.generation, Duration::from_millis(20));
+        assert_eq!(report.output_chars, 4);
+
+        let stages = report.token_refresh + report.project_discovery + report.time_to_first_token + report.generation;
+        assert_eq!(stages, report.total);
+
+        let json = report.to_json();
+        for field in ["token_refresh_ms", "project_discovery_ms", "time_to_first_token_ms", "generation_ms", "total_ms"] {
+            assert!(json[field].as_u64().unwrap() > 0, "{} not populated", field);
+        }
+    }
+}
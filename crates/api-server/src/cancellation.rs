@@ -0,0 +1,101 @@
+//! Stream Cancellation Module
+//!
+//! Lets a client abort a running generation without dropping its connection.
+//! Streaming handlers register under the id the client sees in the stream
+//! (`msg_...`, `chatcmpl-...` or `cmpl-...`), and
+//! `POST /v1/admin/cancel/{request_id}` signals that stream to stop. The
+//! stream then ends, which drops the upstream connection with it.
+
+use futures_util::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// In-flight streams that can be cancelled, keyed by request id
+#[derive(Default)]
+pub struct CancelRegistry {
+    streams: Mutex<HashMap<String, watch::Sender<bool>>>,
+}
+
+impl CancelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a stream; it stays cancellable until the token is dropped
+    pub fn register(self: &Arc<Self>, request_id: &str) -> CancelToken {
+        let (sender, receiver) = watch::channel(false);
+        self.streams.lock().unwrap().insert(request_id.to_string(), sender);
+        CancelToken {
+            registry: Arc::clone(self),
+            request_id: request_id.to_string(),
+            receiver,
+        }
+    }
+
+    /// Signals a stream to stop. Returns false if no such stream is running.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.streams.lock().unwrap().get(request_id) {
+            Some(sender) => {
+                sender.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A registered stream's handle on its cancellation signal
+pub struct CancelToken {
+    registry: Arc<CancelRegistry>,
+    request_id: String,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancelToken {
+    /// Resolves once the stream has been cancelled
+    pub async fn cancelled(&mut self) {
+        if self.receiver.wait_for(|cancelled| *cancelled).await.is_err() {
+            // The registry holds the sender until this token drops
+            std::future::pending::<()>().await;
+        }
+        tracing::info!("Request {} cancelled by client", self.request_id);
+    }
+}
+
+impl Drop for CancelToken {
+    fn drop(&mut self) {
+        self.registry.streams.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/// Ends `stream` as soon as `token` is cancelled
+pub fn until_cancelled<S: Stream>(stream: S, mut token: CancelToken) -> impl Stream<Item = S::Item> {
+    stream.take_until(async move { token.cancelled().await })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_stops_in_flight_stream() {
+        let registry = Arc::new(CancelRegistry::new());
+        let ticks = futures_util::stream::unfold(0, |n| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some((n, n + 1))
+        });
+        let mut stream = Box::pin(until_cancelled(ticks, registry.register("msg_1")));
+
+        assert_eq!(stream.next().await, Some(0));
+        assert_eq!(stream.next().await, Some(1));
+
+        assert!(registry.cancel("msg_1"));
+        assert_eq!(stream.next().await, None);
+
+        // Once the stream is gone the id is no longer known
+        drop(stream);
+        assert!(!registry.cancel("msg_1"));
+    }
+}
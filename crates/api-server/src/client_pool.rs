@@ -0,0 +1,162 @@
+//! Antigravity Client Pool
+//!
+//! Keeps `AntigravityClient`s per account and header style, so requests
+//! reuse their HTTP connections instead of paying for a new client and TLS
+//! handshake every time.
+//!
+//! Requests whose settings differ (a project or timeout header, or a config
+//! change) get clients of their own, up to `CLIENTS_PER_ACCOUNT` per account
+//! and header style with the least recently used one dropped, so mixed
+//! traffic doesn't keep replacing a shared client. A client is rebuilt when
+//! the account's access token changes (after a refresh).
+
+use browser_automator::{AntigravityClient, HeaderStyle};
+use common::config::{ClientHeaders, Config, ModelDefaults, SessionIdPolicy};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Most differently configured clients kept per account and header style
+const CLIENTS_PER_ACCOUNT: usize = 4;
+
+/// The config values `build_client` applies to a client
+#[derive(Debug, PartialEq)]
+struct ClientSettings {
+    project_id: Option<String>,
+    request_timeout_secs: u64,
+    client_headers: ClientHeaders,
+    gemini_pro_default_tier: String,
+    strict_anthropic_passthrough: bool,
+    defaults: ModelDefaults,
+    session_id_policy: SessionIdPolicy,
+    clean_responses: bool,
+    permission_retry_delay_ms: Option<u64>,
+    context_cache: bool,
+    response_cache: bool,
+}
+
+impl From<&Config> for ClientSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            project_id: config.project_id.clone(),
+            request_timeout_secs: config.request_timeout_secs,
+            client_headers: config.client_headers.clone(),
+            gemini_pro_default_tier: config.gemini_pro_default_tier.clone(),
+            strict_anthropic_passthrough: config.strict_anthropic_passthrough,
+            defaults: config.defaults.clone(),
+            session_id_policy: config.session_id_policy,
+            clean_responses: config.clean_responses,
+            permission_retry_delay_ms: config.permission_retry_delay_ms,
+            context_cache: config.context_cache.enabled,
+            response_cache: config.response_cache.enabled,
+        }
+    }
+}
+
+/// A client together with what it was built from
+struct PooledClient {
+    access_token: String,
+    settings: ClientSettings,
+    client: Arc<AntigravityClient>,
+}
+
+/// Antigravity clients shared across requests, by account email and header
+/// style, least recently used first
+#[derive(Default)]
+pub struct ClientPool {
+    clients: Mutex<HashMap<(String, HeaderStyle), Vec<PooledClient>>>,
+}
+
+impl ClientPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the pooled client for an account and `config`'s settings,
+    /// calling `build` when there is none yet or it has an old token
+    pub async fn get_or_build<F, Fut>(
+        &self,
+        email: &str,
+        style: HeaderStyle,
+        access_token: &str,
+        config: &Config,
+        build: F,
+    ) -> anyhow::Result<Arc<AntigravityClient>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<AntigravityClient>>,
+    {
+        let key = (email.to_string(), style);
+        let settings = ClientSettings::from(config);
+        {
+            let mut clients = self.clients.lock().unwrap();
+            let pooled = clients.entry(key.clone()).or_default();
+            if let Some(position) = pooled.iter().position(|p| p.settings == settings && p.access_token == access_token) {
+                let hit = pooled.remove(position);
+                let client = hit.client.clone();
+                pooled.push(hit);
+                return Ok(client);
+            }
+        }
+
+        let client = Arc::new(build().await?);
+        let mut clients = self.clients.lock().unwrap();
+        let pooled = clients.entry(key).or_default();
+        pooled.retain(|p| p.settings != settings);
+        if pooled.len() >= CLIENTS_PER_ACCOUNT {
+            pooled.remove(0);
+        }
+        pooled.push(PooledClient {
+            access_token: access_token.to_string(),
+            settings,
+            client: client.clone(),
+        });
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_same_account_reuses_client() {
+        let pool = ClientPool::new();
+        let config = Config::default();
+        let get = |email: &'static str, style, token: &'static str, config: Config| {
+            let pool = &pool;
+            async move {
+                pool.get_or_build(email, style, token, &config, || async move {
+                    AntigravityClient::new(token.to_string(), Some("project".into()), None)
+                }).await.unwrap()
+            }
+        };
+
+        // Two requests in a row for the same account share one client
+        let first = get("a@example.com", HeaderStyle::Antigravity, "token-1", config.clone()).await;
+        let second = get("a@example.com", HeaderStyle::Antigravity, "token-1", config.clone()).await;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Another account or header style gets its own
+        assert!(!Arc::ptr_eq(&first, &get("b@example.com", HeaderStyle::Antigravity, "token-1", config.clone()).await));
+        assert!(!Arc::ptr_eq(&first, &get("a@example.com", HeaderStyle::GeminiCli, "token-1", config.clone()).await));
+
+        // A refreshed token replaces the entry
+        let refreshed = get("a@example.com", HeaderStyle::Antigravity, "token-2", config.clone()).await;
+        assert!(!Arc::ptr_eq(&first, &refreshed));
+
+        // Other settings get a client of their own, and alternating between
+        // them keeps both
+        let overridden = Config { request_timeout_secs: 30, ..config.clone() };
+        let reconfigured = get("a@example.com", HeaderStyle::Antigravity, "token-2", overridden.clone()).await;
+        assert!(!Arc::ptr_eq(&refreshed, &reconfigured));
+        assert!(Arc::ptr_eq(&refreshed, &get("a@example.com", HeaderStyle::Antigravity, "token-2", config.clone()).await));
+        assert!(Arc::ptr_eq(&reconfigured, &get("a@example.com", HeaderStyle::Antigravity, "token-2", overridden.clone()).await));
+
+        // Past the limit, the least recently used settings are dropped
+        for timeout in 1..CLIENTS_PER_ACCOUNT as u64 {
+            get("a@example.com", HeaderStyle::Antigravity, "token-2", Config { request_timeout_secs: timeout, ..config.clone() }).await;
+        }
+        assert!(!Arc::ptr_eq(&refreshed, &get("a@example.com", HeaderStyle::Antigravity, "token-2", config).await));
+    }
+}
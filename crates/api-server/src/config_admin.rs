@@ -0,0 +1,148 @@
+//! Runtime Config Module
+//!
+//! Backs `GET`/`PATCH /v1/admin/config`. Patches are JSON merge patches
+//! (RFC 7396): objects merge key by key, other values replace, and `null`
+//! clears a field. Account credentials are write-only: they are redacted
+//! on read, and a redacted placeholder sent back leaves the stored value
+//! alone. Both endpoints answer 403 unless an `api_key` is configured and
+//! sent with the request.
+//!
+//! Most fields apply to the next request. The ones in `RESTART_ONLY` are read
+//! once at startup (listener, account manager, caches), so a patch to them
+//! is saved but only takes effect after a restart; the response lists them.
+
+use anyhow::{Context, Result};
+use common::config::Config;
+use serde_json::Value;
+
+/// Placeholder shown instead of secret values
+pub const REDACTED: &str = "[redacted]";
+
+/// Config fields (JSON pointers) only read at startup
+const RESTART_ONLY: &[&str] = &[
+    "/server",
+    "/allow_lan",
+    "/token_warming",
+    "/throttle",
+    "/account_cooldown_ms",
+    "/max_concurrent_per_account",
+    "/endpoint_probe_interval",
+    "/context_cache/min_tokens",
+    "/context_cache/ttl_secs",
+    "/response_cache/capacity",
+    "/response_cache/replay_streams",
+];
+
+/// The config as JSON, with account credentials and the API key redacted
+pub fn redacted_config(config: &Config) -> Result<Value> {
+    let mut value = serde_json::to_value(config)?;
+    if value["api_key"].is_string() {
+        value["api_key"] = Value::String(REDACTED.to_string());
+    }
+    if let Some(accounts) = value["accounts"].as_object_mut() {
+        for account in accounts.values_mut() {
+            if let Some(credentials) = account["credentials"].as_object_mut() {
+                for secret in credentials.values_mut() {
+                    *secret = Value::String(REDACTED.to_string());
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Applies a merge patch to `config`, returning the updated config
+///
+/// Fails if the result is no longer a valid config (unknown enum values,
+/// wrong types), leaving the caller's config untouched.
+pub fn apply_patch(config: &Config, patch: &Value) -> Result<Config> {
+    anyhow::ensure!(patch.is_object(), "Config patch must be a JSON object");
+    let mut value = serde_json::to_value(config)?;
+    merge_patch(&mut value, patch);
+    serde_json::from_value(value).context("Invalid config after patch")
+}
+
+/// Fields changed between `before` and `after` that need a restart to apply
+pub fn restart_required(before: &Config, after: &Config) -> Result<Vec<String>> {
+    let (before, after) = (serde_json::to_value(before)?, serde_json::to_value(after)?);
+    Ok(RESTART_ONLY.iter()
+        .filter(|pointer| before.pointer(pointer) != after.pointer(pointer))
+        .map(|pointer| pointer[1..].replace('/', "."))
+        .collect())
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Some(patch) = patch.as_object() else {
+        // A redacted secret sent back unchanged keeps the real value
+        if patch.as_str() != Some(REDACTED) {
+            *target = patch.clone();
+        }
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            // Optional fields deserialize a missing key as None
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_patch_then_read_reflects_change() {
+        let config = Config::default();
+        let patched = apply_patch(&config, &json!({
+            "default_model": "antigravity-gemini-3-pro",
+            "enable_spoofing": false,
+            "defaults": { "claude": { "thinking_budget": 4096 } }
+        })).unwrap();
+
+        let read = redacted_config(&patched).unwrap();
+        assert_eq!(read["default_model"], "antigravity-gemini-3-pro");
+        assert_eq!(read["enable_spoofing"], false);
+        assert_eq!(read["defaults"]["claude"]["thinking_budget"], 4096);
+        // Untouched fields keep their values
+        assert_eq!(read["server"]["port"], 8080);
+
+        let cleared = apply_patch(&patched, &json!({ "default_model": null })).unwrap();
+        assert!(cleared.default_model.is_none());
+        assert!(apply_patch(&config, &json!({ "session_id_policy": "sometimes" })).is_err());
+    }
+
+    #[test]
+    fn test_credentials_are_write_only() {
+        let patch = json!({ "accounts": { "work": { "provider": "google", "credentials": { "api_key": "sk-secret" } } } });
+        let config = apply_patch(&Config::default(), &patch).unwrap();
+
+        let read = redacted_config(&config).unwrap();
+        assert_eq!(read["accounts"]["work"]["credentials"]["api_key"], REDACTED);
+
+        // Writing back what was read doesn't clobber the secret
+        let round_trip = apply_patch(&config, &read).unwrap();
+        assert_eq!(round_trip.accounts["work"].credentials["api_key"], "sk-secret");
+    }
+
+    #[test]
+    fn test_restart_only_fields_reported() {
+        let config = Config::default();
+        let patched = apply_patch(&config, &json!({
+            "default_model": "antigravity-gemini-3-pro",
+            "server": { "port": 9090 },
+            "throttle": { "threshold": 10 },
+            "response_cache": { "enabled": true, "capacity": 16 }
+        })).unwrap();
+
+        assert_eq!(restart_required(&config, &patched).unwrap(), vec!["server", "throttle", "response_cache.capacity"]);
+        assert!(restart_required(&patched, &patched).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! Config Validation Module
+//!
+//! Backs the `check-config` command. serde only rejects malformed JSON and
+//! wrong types, so values that deserialize fine but can't work at runtime
+//! (unknown model names, unparseable URLs, missing paths, out-of-range
+//! sampling values) are caught here before the server starts:
+//!
+//! 1. Syntax and type errors are reported at serde's line
+//! 2. Each field is then checked against what the server accepts
+//! 3. Problems are reported with the config-file line of the offending key
+
+use axum::http::Uri;
+use browser_automator::AntigravityModel;
+use common::config::{Config, FamilyDefaults};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::routes::known_anthropic_model;
+
+/// How serious a config problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The server would fail or misbehave
+    Error,
+    /// Allowed, but probably not intended
+    Warning,
+}
+
+/// A single problem found in the config
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    /// Dotted path of the offending field (e.g. `server.port`)
+    pub path: String,
+    /// 1-based line in the config file, when it could be located
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Result of validating a config file
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl ConfigReport {
+    pub fn error_count(&self) -> usize {
+        self.issues.iter().filter(|i| i.severity == Severity::Error).count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.issues.iter().filter(|i| i.severity == Severity::Warning).count()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.error_count() == 0
+    }
+}
+
+/// Validates the text of a config file
+pub fn check_config_text(text: &str) -> ConfigReport {
+    let mut checker = Checker { text, report: ConfigReport::default() };
+
+    let raw: Value = match serde_json::from_str(text) {
+        Ok(raw) => raw,
+        Err(e) => {
+            checker.issue_at(Severity::Error, "", Some(e.line()), format!("Invalid JSON: {}", e));
+            return checker.report;
+        }
+    };
+    let config: Config = match serde_json::from_value(raw) {
+        Ok(config) => config,
+        Err(e) => {
+            // from_value has no positions; re-run on the text for serde's line
+            let line = serde_json::from_str::<Config>(text).err().map(|e| e.line());
+            checker.issue_at(Severity::Error, "", line, format!("Invalid config: {}", e));
+            return checker.report;
+        }
+    };
+
+    checker.check(&config);
+    checker.report
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    report: ConfigReport,
+}
+
+impl Checker<'_> {
+    fn check(&mut self, config: &Config) {
+        self.check_server(config);
+        self.check_project_id(config);
+
+        for (name, provider) in &config.providers {
+            if let Err(message) = check_url(&provider.base_url) {
+                self.issue(Severity::Error, &format!("providers.{}.base_url", name), message);
+            }
+        }
+
+        if !matches!(config.gemini_pro_default_tier.as_str(), "low" | "high") {
+            self.issue(
+                Severity::Error,
+                "gemini_pro_default_tier",
+                format!("Must be \"low\" or \"high\", got \"{}\"", config.gemini_pro_default_tier),
+            );
+        }
+        if config.first_token_timeout_ms == Some(0) {
+            self.issue(Severity::Error, "first_token_timeout_ms", "Must be greater than 0".to_string());
+        }
+        if config.request_timeout_secs == 0 {
+            self.issue(Severity::Error, "request_timeout_secs", "Must be greater than 0".to_string());
+        }
+        if config.context_warning_percent > 100 {
+            self.issue(
+                Severity::Warning,
+                "context_warning_percent",
+                "Above 100 the context usage warning is never sent".to_string(),
+            );
+        }
+        if config.thinking_thresholds.medium > config.thinking_thresholds.high {
+            self.issue(
+                Severity::Warning,
+                "thinking_thresholds",
+                "medium is above high, so no budget maps to \"medium\"".to_string(),
+            );
+        }
+        if config.enable_preemptive_spoof && !config.enable_spoofing {
+            self.issue(
+                Severity::Warning,
+                "enable_preemptive_spoof",
+                "Has no effect while enable_spoofing is false".to_string(),
+            );
+        }
+
+        self.check_family_defaults("defaults.claude", &config.defaults.claude);
+        self.check_family_defaults("defaults.gemini", &config.defaults.gemini);
+        self.check_models(config);
+    }
+
+    fn check_server(&mut self, config: &Config) {
+        let server = &config.server;
+        if server.port == 0 {
+            self.issue(Severity::Error, "server.port", "Port must be between 1 and 65535".to_string());
+        } else if server.port < 1024 {
+            self.issue(Severity::Warning, "server.port", format!("Port {} usually requires root", server.port));
+        }
+
+        if server.host.parse::<IpAddr>().is_err() {
+            self.issue(
+                Severity::Error,
+                "server.host",
+                format!("\"{}\" is not an IP address (e.g. 127.0.0.1)", server.host),
+            );
+        }
+
+        if let Some(path) = server.browser_profile_path.as_ref().filter(|p| !Path::new(p).exists()) {
+            self.issue(Severity::Error, "server.browser_profile_path", format!("{} does not exist", path));
+        }
+    }
+
+    fn check_project_id(&mut self, config: &Config) {
+        let Some(project_id) = &config.project_id else {
+            return;
+        };
+        // Comma-separated lists rotate between projects
+        for id in project_id.split(',').map(str::trim) {
+            if id.is_empty() {
+                self.issue(Severity::Error, "project_id", "Contains an empty project ID".to_string());
+            } else if !is_valid_project_id(id) {
+                self.issue(
+                    Severity::Warning,
+                    "project_id",
+                    format!("\"{}\" doesn't look like a GCP project ID", id),
+                );
+            }
+        }
+    }
+
+    fn check_family_defaults(&mut self, path: &str, defaults: &FamilyDefaults) {
+        if let Some(t) = defaults.temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+            self.issue(Severity::Error, &format!("{}.temperature", path), format!("{} is outside 0.0-2.0", t));
+        }
+        if let Some(p) = defaults.top_p.filter(|p| !(*p > 0.0 && *p <= 1.0)) {
+            self.issue(Severity::Error, &format!("{}.top_p", path), format!("{} is outside (0.0, 1.0]", p));
+        }
+        let known_level = |l: &&String| matches!(l.as_str(), "minimal" | "low" | "medium" | "high");
+        if let Some(level) = defaults.thinking_level.as_ref().filter(|l| !known_level(l)) {
+            self.issue(
+                Severity::Error,
+                &format!("{}.thinking_level", path),
+                format!("Unknown thinking level \"{}\"", level),
+            );
+        }
+    }
+
+    /// Checks each field naming models resolves the way the server resolves it
+    fn check_models(&mut self, config: &Config) {
+        for id in config.enabled_models.iter().flatten() {
+            self.check_model_id("enabled_models", id);
+        }
+        for id in &config.downgrade_chain {
+            self.check_model_id("downgrade_chain", id);
+        }
+
+        // Looked up by exact model id; an empty target disables the fallback
+        for (key, target) in sorted(&config.spoof_map) {
+            let path = format!("spoof_map.{}", key);
+            match key.parse::<AntigravityModel>() {
+                Ok(model) if model.api_id() != key => self.issue(
+                    Severity::Error,
+                    &path,
+                    format!("Never matches; use the model id \"{}\"", model.api_id()),
+                ),
+                Ok(_) => {}
+                Err(e) => self.issue(Severity::Error, &path, e.to_string()),
+            }
+            if !target.is_empty() {
+                self.check_model_id(&path, target);
+            }
+        }
+
+        // Keys are Anthropic ids, which needn't be known
+        for (key, target) in sorted(&config.anthropic_model_map) {
+            self.check_model_id(&format!("anthropic_model_map.{}", key), target);
+        }
+
+        // Serves Anthropic and OpenAI requests alike
+        if let Some(id) = config.default_model.as_ref().filter(|id| known_anthropic_model(config, id).is_none()) {
+            self.issue(Severity::Error, "default_model", format!("Unknown model: {}", id));
+        }
+    }
+
+    fn check_model_id(&mut self, path: &str, id: &str) {
+        if let Err(e) = id.parse::<AntigravityModel>() {
+            self.issue(Severity::Error, path, e.to_string());
+        }
+    }
+
+    fn issue(&mut self, severity: Severity, path: &str, message: String) {
+        let line = line_of(self.text, path);
+        self.issue_at(severity, path, line, message);
+    }
+
+    fn issue_at(&mut self, severity: Severity, path: &str, line: Option<usize>, message: String) {
+        self.report.issues.push(ConfigIssue { severity, path: path.to_string(), line, message });
+    }
+}
+
+/// Map entries in key order, so issues come out the same every run
+fn sorted(map: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort();
+    entries
+}
+
+/// Checks a provider URL is an absolute http(s) URL
+fn check_url(url: &str) -> Result<(), String> {
+    let uri: Uri = url.parse().map_err(|e| format!("\"{}\" is not a valid URL: {}", url, e))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.authority().is_none() {
+        return Err(format!("\"{}\" must be an absolute http(s) URL", url));
+    }
+    Ok(())
+}
+
+/// GCP project IDs: 6-30 chars, lowercase letters, digits and hyphens
+fn is_valid_project_id(id: &str) -> bool {
+    (6..=30).contains(&id.len())
+        && id.starts_with(|c: char| c.is_ascii_lowercase())
+        && !id.ends_with('-')
+        && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Finds the 1-based line of a dotted path's key, following each segment in turn
+fn line_of(text: &str, path: &str) -> Option<usize> {
+    if path.is_empty() {
+        return None;
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    let mut line = 0;
+    for segment in path.split('.') {
+        let needle = format!("\"{}\"", segment);
+        line += lines[line..].iter().position(|l| l.contains(&needle))?;
+    }
+    Some(line + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_model_alias_is_an_error() {
+        let text = r#"{
+  "project_id": "my-project-123",
+  "accounts": {},
+  "providers": {},
+  "server": { "host": "127.0.0.1", "port": 8080, "browser_profile_path": null },
+  "downgrade_chain": ["claude-opus-4-5-thinking", "gemini-9-ultra"]
+}"#;
+
+        let report = check_config_text(text);
+        assert!(!report.passed());
+        assert_eq!(report.error_count(), 1);
+        let issue = &report.issues[0];
+        assert_eq!(issue.path, "downgrade_chain");
+        assert_eq!(issue.line, Some(6));
+        assert!(issue.message.contains("gemini-9-ultra"));
+    }
+
+    #[test]
+    fn test_model_fields_checked_like_runtime() {
+        let text = r#"{
+  "accounts": {},
+  "providers": {},
+  "server": { "host": "127.0.0.1", "port": 8080, "browser_profile_path": null },
+  "default_model": "claude-3-opus-20240229",
+  "enabled_models": ["gemini-3-pro", "gemini-3-flash"],
+  "spoof_map": { "gemini-3-pro": "", "Gemini 3 Flash": "gemini-3-pro", "claude-sonnet-4-5": "claude-sonnet-5" },
+  "anthropic_model_map": { "claude-3-opus-20240229": "claude-opus-9" },
+  "some_model_notes": "not a model"
+}"#;
+
+        let report = check_config_text(text);
+        let issues: Vec<(&str, Option<usize>)> = report.issues.iter().map(|i| (i.path.as_str(), i.line)).collect();
+        assert_eq!(issues, [
+            ("spoof_map.Gemini 3 Flash", Some(7)),
+            ("spoof_map.claude-sonnet-4-5", Some(7)),
+            ("anthropic_model_map.claude-3-opus-20240229", Some(8)),
+        ]);
+
+        // Anthropic ids are fine as the default; unknown names aren't
+        let text = text.replace("\"claude-3-opus-20240229\": \"claude-opus-9\"", "");
+        assert!(check_config_text(&text).issues.iter().all(|i| i.path != "default_model"));
+        let text = text.replace("claude-3-opus-20240229", "claude-9");
+        assert!(check_config_text(&text).issues.iter().any(|i| i.path == "default_model"));
+    }
+
+    #[test]
+    fn test_field_checks() {
+        let text = r#"{
+  "accounts": {},
+  "providers": { "local": { "base_url": "not a url", "api_type": "OpenAI" } },
+  "server": { "host": "localhost", "port": 0, "browser_profile_path": "/definitely/missing" },
+  "gemini_pro_default_tier": "ultra",
+  "defaults": { "gemini": { "temperature": 3.5 } }
+}"#;
+
+        let report = check_config_text(text);
+        let paths: Vec<&str> = report.issues.iter().map(|i| i.path.as_str()).collect();
+        for expected in [
+            "providers.local.base_url",
+            "server.port",
+            "server.host",
+            "server.browser_profile_path",
+            "gemini_pro_default_tier",
+            "defaults.gemini.temperature",
+        ] {
+            assert!(paths.contains(&expected), "missing {} in {:?}", expected, paths);
+        }
+
+        assert!(check_config_text(&serde_json::to_string_pretty(&Config::default()).unwrap()).passed());
+        assert_eq!(check_config_text("{ \"server\": ").issues[0].severity, Severity::Error);
+    }
+}
@@ -3,10 +3,17 @@
 //! This crate provides the HTTP server for the AetherBridge platform,
 //! exposing OpenAI-compatible API endpoints.
 
+pub mod benchmark;
+pub mod cancellation;
+pub mod client_pool;
+pub mod config_admin;
+pub mod config_check;
+pub mod metrics;
 pub mod routes;
 pub mod server;
 pub mod session_recovery;
 pub mod state;
+pub mod tool_repair;
 
-pub use server::{create_router, start_server, run_server_blocking, ServerHandle};
+pub use server::{create_router, serve_with_signals, start_server, run_server_blocking, ServerHandle};
 pub use state::AppState;
@@ -1,7 +1,10 @@
+use api_server::config_check::{self, Severity};
 use api_server::state::AppState;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use common::config::Config;
 use common::platform;
+use oauth::accounts::AccountStatus;
+use oauth::{AccountManager, LoginMode, OAuthFlow};
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tracing::Level;
@@ -29,6 +32,10 @@ struct Args {
     #[arg(short, long, env = "AETHER_BROWSER_PROFILE", global = true)]
     browser_profile: Option<String>,
 
+    /// Re-run browser profile detection even if a profile is saved in the config
+    #[arg(long, global = true)]
+    redetect: bool,
+
     /// AI provider to use
     #[arg(short = 'P', long, env = "AETHER_PROVIDER", default_value = "google", global = true)]
     provider: String,
@@ -36,6 +43,27 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     verbose: bool,
+
+    /// Don't print the startup banner
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, env = "AETHER_LOG_FORMAT", default_value_t = LogFormat::Pretty, global = true)]
+    log_format: LogFormat,
+
+    /// Allow binding a non-loopback host without an api_key
+    #[arg(long, global = true)]
+    allow_lan: bool,
+}
+
+/// How log lines are written
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -43,9 +71,22 @@ enum Commands {
     /// Start the bridge server (default if no command specified)
     Serve,
     /// Show detected configuration and browser profiles
-    Status,
+    Status {
+        /// Also load the accounts, check their tokens and send a tiny test
+        /// request (uses the network)
+        #[arg(long)]
+        check: bool,
+    },
     /// Print help for integrating with other tools
     Setup,
+    /// Validate the config file and report problems
+    CheckConfig,
+    /// Add a Google account via OAuth
+    Login {
+        /// Print the authorization URL instead of opening a browser (SSH/headless hosts)
+        #[arg(long)]
+        no_browser: bool,
+    },
 }
 
 #[tokio::main]
@@ -54,31 +95,47 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize logging based on verbosity
     let log_level = if args.verbose { Level::DEBUG } else { Level::INFO };
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+    let logger = tracing_subscriber::fmt().with_max_level(log_level);
+    match args.log_format {
+        LogFormat::Pretty => logger.init(),
+        LogFormat::Json => logger.json().init(),
+    }
 
     match args.command.clone().unwrap_or(Commands::Serve) {
         Commands::Serve => run_server(args).await,
-        Commands::Status => show_status(args),
+        Commands::Status { check } => show_status(args, check).await,
         Commands::Setup => show_setup(),
+        Commands::CheckConfig => check_config(),
+        Commands::Login { no_browser } => run_login(no_browser).await,
     }
 }
 
 async fn run_server(args: Args) -> anyhow::Result<()> {
-    let mut config = Config::default();
+    // Auto-detect the browser profile on first run (saved for later starts)
+    let loaded = match &args.browser_profile {
+        Some(_) => Config::load(),
+        None => Config::load_with_browser_profile(&Config::get_config_path(), args.redetect, || {
+            tracing::info!("Auto-detecting browser profile...");
+            let detected = platform::detect_browser_profile();
+            if let Some(path) = &detected {
+                tracing::info!("Detected browser profile: {}", path.display());
+            }
+            detected
+        }),
+    };
+    let mut config = loaded.unwrap_or_else(|e| {
+        tracing::warn!("Failed to load config, using defaults: {}", e);
+        Config::default()
+    });
 
     // Override config with CLI args
     config.server.port = args.port;
     config.server.host = args.host.clone();
-
-    // Auto-detect browser profile if not specified
-    config.server.browser_profile_path = args.browser_profile.or_else(|| {
-        tracing::info!("Auto-detecting browser profile...");
-        platform::detect_browser_profile().map(|p| {
-            let path_str = p.to_string_lossy().to_string();
-            tracing::info!("Detected browser profile: {}", path_str);
-            path_str
-        })
-    });
+    config.allow_lan |= args.allow_lan;
+    api_server::server::check_bind_exposure(&args.host, &config)?;
+    if let Some(path) = args.browser_profile {
+        config.server.browser_profile_path = Some(path);
+    }
 
     if config.server.browser_profile_path.is_none() {
         tracing::warn!(
@@ -92,34 +149,81 @@ async fn run_server(args: Args) -> anyhow::Result<()> {
 
     let addr: SocketAddr = format!("{}:{}", args.host, args.port).parse()?;
 
+    if !args.quiet {
+        print_banner(addr, &args.provider);
+    }
+
+    tracing::info!("Starting server on {}", addr);
+
+    let listener = TcpListener::bind(addr).await?;
+    api_server::serve_with_signals(listener, state).await
+}
+
+fn print_banner(addr: SocketAddr, provider: &str) {
     println!();
     println!("╔════════════════════════════════════════════════════════════╗");
     println!("║             AetherBridge v{}                      ║", env!("CARGO_PKG_VERSION"));
     println!("╠════════════════════════════════════════════════════════════╣");
     println!("║  Server:    http://{}                        ║", addr);
-    println!("║  Provider:  {:<46} ║", args.provider);
+    println!("║  Provider:  {:<46} ║", provider);
     println!("║  OS:        {:<46} ║", platform::get_os_name());
     println!("╚════════════════════════════════════════════════════════════╝");
     println!();
     println!("Endpoints:");
     println!("  POST /v1/chat/completions  (OpenAI compatible)");
+    println!("  POST /v1/completions       (OpenAI legacy completions)");
     println!("  POST /v1/messages          (Anthropic compatible)");
     println!();
     println!("Quick test:");
     println!("  curl http://{}/v1/chat/completions -d '{{\"model\":\"bridge\",\"messages\":[{{\"role\":\"user\",\"content\":\"Hello\"}}]}}'", addr);
     println!();
+}
 
-    tracing::info!("Starting server on {}", addr);
+async fn run_login(no_browser: bool) -> anyhow::Result<()> {
+    let flow = OAuthFlow::new();
+    let mode = if no_browser { LoginMode::Headless } else { LoginMode::Browser };
+    let auth_url = flow.present_authorization_url(mode, |url| open::that(url));
 
-    let app = api_server::create_router(state);
+    println!("Sign in with Google by opening this URL:");
+    println!();
+    println!("  {}", auth_url);
+    println!();
 
-    let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let code = if no_browser {
+        // The browser may be on another machine, so its redirect to localhost
+        // can't reach us; accept the pasted redirect URL as well.
+        println!("After approving, paste the URL your browser was redirected to (or just the code):");
+        let pasted = async {
+            use tokio::io::AsyncBufReadExt;
+            let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+            lines.next_line().await?.ok_or_else(|| anyhow::anyhow!("No authorization code entered"))
+        };
+        tokio::pin!(pasted);
+
+        tokio::select! {
+            callback = flow.wait_for_callback() => match callback {
+                Ok(code) => code,
+                Err(e) => {
+                    tracing::warn!("Callback server unavailable ({}), waiting for pasted code", e);
+                    flow.code_from_redirect(&pasted.await?)?
+                }
+            },
+            line = &mut pasted => flow.code_from_redirect(&line?)?,
+        }
+    } else {
+        println!("Waiting for authorization (5 minute timeout)...");
+        flow.wait_for_callback().await?
+    };
 
+    let token_pair = flow.exchange_code(&code).await?;
+    let manager = AccountManager::new().await?;
+    manager.add_account(token_pair.clone()).await?;
+
+    println!("Logged in as {}", token_pair.email);
     Ok(())
 }
 
-fn show_status(args: Args) -> anyhow::Result<()> {
+async fn show_status(args: Args, check: bool) -> anyhow::Result<()> {
     println!("AetherBridge Status");
     println!("═══════════════════");
     println!();
@@ -158,9 +262,110 @@ fn show_status(args: Args) -> anyhow::Result<()> {
         println!("Config File: {:?} ({})", config_path, status);
     }
 
+    if check {
+        println!();
+        check_accounts().await?;
+    }
+
     Ok(())
 }
 
+/// Loads the accounts (refreshing their tokens) and tries one small request
+/// through the first usable account
+async fn check_accounts() -> anyhow::Result<()> {
+    let config = Config::load().unwrap_or_default();
+    let manager = AccountManager::new().await?;
+    for line in account_summary(&manager.account_statuses().await) {
+        println!("{}", line);
+    }
+    println!();
+
+    let Some(account) = manager.get_available_account().await else {
+        println!("API Check: skipped (no usable account)");
+        return Ok(());
+    };
+    let client = browser_automator::AntigravityClient::new(account.access_token, config.project_id.clone(), None)?;
+    client.fetch_provisioned_project_id().await;
+    println!("Project: {}", client.project_id().await);
+
+    let messages = vec![browser_automator::Message::user("Reply with OK.")];
+    let params = browser_automator::GenerationParams::default();
+    match client.chat_completion(browser_automator::AntigravityModel::Gemini3Flash, messages, None, None, &params).await {
+        Ok(_) => println!("API Check: ✓ {} answered a test request", account.email),
+        Err(e) => println!("API Check: ✗ {}", e),
+    }
+    Ok(())
+}
+
+/// One line per account with its token and rate-limit state, after a count
+fn account_summary(statuses: &[AccountStatus]) -> Vec<String> {
+    if statuses.is_empty() {
+        return vec!["Accounts: none (run 'aether-bridge login' to add one)".to_string()];
+    }
+
+    let usable = statuses.iter().filter(|s| !s.disabled && !s.refresh_failed).count();
+    let mut lines = vec![format!("Accounts: {} ({} usable)", statuses.len(), usable)];
+    for status in statuses {
+        let (marker, mut notes) = if status.refresh_failed {
+            ("✗", vec!["token refresh failed, log in again".to_string()])
+        } else if status.disabled {
+            ("-", vec!["disabled".to_string()])
+        } else {
+            ("✓", vec!["token valid".to_string()])
+        };
+        let limits = [
+            ("Claude", status.claude_limited_until),
+            ("Gemini", status.gemini_limited_until),
+            ("Other models", status.unknown_limited_until),
+        ];
+        for (family, until) in limits {
+            if let Some(until) = until {
+                notes.push(format!("{} rate limited until {}", family, until.format("%H:%M:%S UTC")));
+            }
+        }
+        lines.push(format!("  {} {} - {}", marker, status.email, notes.join(", ")));
+    }
+    lines
+}
+
+fn check_config() -> anyhow::Result<()> {
+    let path = Config::get_config_path();
+    println!("Config File: {:?}", path);
+    println!();
+
+    if !path.exists() {
+        println!("No config file found; built-in defaults will be used.");
+        return Ok(());
+    }
+
+    let text = std::fs::read_to_string(&path)?;
+    let report = config_check::check_config_text(&text);
+
+    for issue in &report.issues {
+        let marker = match issue.severity {
+            Severity::Error => "✗ error",
+            Severity::Warning => "! warning",
+        };
+        let location = match issue.line {
+            Some(line) => format!("line {}", line),
+            None => "-".to_string(),
+        };
+        let field = if issue.path.is_empty() { String::new() } else { format!("{}: ", issue.path) };
+        println!("  {} ({}): {}{}", marker, location, field, issue.message);
+    }
+    if !report.issues.is_empty() {
+        println!();
+    }
+
+    if report.passed() {
+        println!("PASS ({} warning(s))", report.warning_count());
+        Ok(())
+    } else {
+        println!("FAIL ({} error(s), {} warning(s))", report.error_count(), report.warning_count());
+        anyhow::bail!("Config check failed")
+    }
+}
+
 fn show_setup() -> anyhow::Result<()> {
     println!("AetherBridge Setup Guide");
     println!("════════════════════════");
@@ -174,6 +379,9 @@ fn show_setup() -> anyhow::Result<()> {
     println!("   or with custom port:");
     println!("   $ aether-bridge --port 9090 serve");
     println!();
+    println!("   Add a Google account (use --no-browser over SSH):");
+    println!("   $ aether-bridge login");
+    println!();
     println!("3. CONFIGURE YOUR TOOLS");
     println!();
     println!("   Claude Code:");
@@ -193,6 +401,54 @@ fn show_setup() -> anyhow::Result<()> {
     println!("   AETHER_HOST            - Override bind address (127.0.0.1)");
     println!("   AETHER_BROWSER_PROFILE - Override browser profile path");
     println!("   AETHER_PROVIDER        - Set default provider (google)");
+    println!("   AETHER_LOG_FORMAT      - Log format: pretty or json (pretty)");
+    println!("   AETHER_ACCOUNTS_JSON   - Accounts to import at startup, as JSON");
+    println!("                            [{{\"email\": ..., \"refresh_token\": ...}}]");
+    println!("   AETHER_ACCOUNTS_FILE   - File with the same JSON (e.g. a mounted secret)");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_flag() {
+        let args = Args::try_parse_from(["aether-bridge"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Pretty);
+        assert!(!args.quiet);
+
+        let args = Args::try_parse_from(["aether-bridge", "--quiet", "--log-format", "json", "serve"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Json);
+        assert!(args.quiet);
+
+        assert!(Args::try_parse_from(["aether-bridge", "--log-format", "xml"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_account_summary_reports_token_state() {
+        let manager = AccountManager::with_store(oauth::MemoryStore::default()).await.unwrap();
+        assert_eq!(account_summary(&manager.account_statuses().await).len(), 1);
+
+        for email in ["live@example.com", "off@example.com"] {
+            manager.add_account(oauth::TokenPair {
+                access_token: "access".into(),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        manager.set_account_disabled("off@example.com", true).await.unwrap();
+        let until = chrono::Utc::now() + chrono::Duration::minutes(5);
+        manager.mark_rate_limited(0, oauth::accounts::ModelFamily::Claude, until).await;
+
+        let lines = account_summary(&manager.account_statuses().await);
+        assert_eq!(lines[0], "Accounts: 2 (1 usable)");
+        assert!(lines[1].starts_with("  ✓ live@example.com - token valid, Claude rate limited until"));
+        assert_eq!(lines[2], "  - off@example.com - disabled");
+
+        let args = Args::try_parse_from(["aether-bridge", "status", "--check"]).unwrap();
+        assert!(matches!(args.command, Some(Commands::Status { check: true })));
+    }
+}
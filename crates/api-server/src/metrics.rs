@@ -0,0 +1,168 @@
+//! Metrics Module
+//!
+//! In-process latency metrics, served by `GET /v1/admin/metrics`. Histograms
+//! keep a rolling window of recent observations, so percentiles follow the
+//! current behaviour of the upstream rather than the whole uptime. The usage
+//! ledger keeps the same window of completed requests, tagged with the
+//! client-supplied user for accounting.
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Observations kept per histogram
+const WINDOW: usize = 1024;
+
+/// Bucket upper bounds in milliseconds (the last bucket is unbounded)
+const BUCKET_BOUNDS_MS: &[u64] = &[100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000];
+
+/// Latency metrics shared by all requests
+#[derive(Default)]
+pub struct Metrics {
+    /// Request start to the first content delta of a streamed message
+    pub first_token_latency: LatencyHistogram,
+    /// Token usage of recent completed requests
+    pub usage: UsageLedger,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All metrics as JSON
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "first_token_latency": self.first_token_latency.to_json(),
+            "usage": self.usage.to_json()
+        })
+    }
+}
+
+/// Rolling histogram of the last `WINDOW` durations
+#[derive(Default)]
+pub struct LatencyHistogram {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyHistogram {
+    /// Records one observation, evicting the oldest once the window is full
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// Number of observations in the window
+    pub fn count(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    /// Bucket counts and percentiles (in milliseconds) over the window
+    pub fn to_json(&self) -> Value {
+        let mut ms: Vec<u64> = self.samples.lock().unwrap().iter().map(|d| d.as_millis() as u64).collect();
+        ms.sort_unstable();
+
+        let mut buckets: Vec<Value> = BUCKET_BOUNDS_MS.iter().map(|&le| serde_json::json!({
+            "le_ms": le,
+            "count": ms.iter().filter(|&&v| v <= le).count()
+        })).collect();
+        buckets.push(serde_json::json!({ "le_ms": "+Inf", "count": ms.len() }));
+
+        let percentile = |p: usize| (!ms.is_empty()).then(|| ms[(ms.len() - 1) * p / 100]);
+        serde_json::json!({
+            "count": ms.len(),
+            "buckets": buckets,
+            "p50_ms": percentile(50),
+            "p95_ms": percentile(95),
+            "p99_ms": percentile(99)
+        })
+    }
+}
+
+/// One completed request in the usage ledger
+#[derive(Debug, Clone)]
+pub struct UsageEntry {
+    pub at: DateTime<Utc>,
+    /// OpenAI `user` or Anthropic `metadata.user_id`, if the client sent one
+    pub user: Option<String>,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+/// Rolling record of the last `WINDOW` requests' token usage
+#[derive(Default)]
+pub struct UsageLedger {
+    entries: Mutex<VecDeque<UsageEntry>>,
+}
+
+impl UsageLedger {
+    /// Records one request, evicting the oldest once the window is full
+    pub fn record(&self, entry: UsageEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == WINDOW {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Entries in the window, oldest first
+    pub fn entries(&self) -> Vec<UsageEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Recent entries and per-user totals over the window
+    pub fn to_json(&self) -> Value {
+        let entries = self.entries();
+        let mut by_user = serde_json::Map::new();
+        for entry in &entries {
+            let totals = by_user
+                .entry(entry.user.clone().unwrap_or_else(|| "-".to_string()))
+                .or_insert_with(|| serde_json::json!({ "requests": 0, "prompt_tokens": 0, "completion_tokens": 0 }));
+            for (key, add) in [("requests", 1), ("prompt_tokens", entry.prompt_tokens), ("completion_tokens", entry.completion_tokens)] {
+                totals[key] = (totals[key].as_u64().unwrap_or(0) + add as u64).into();
+            }
+        }
+
+        serde_json::json!({
+            "by_user": by_user,
+            "recent": entries.iter().map(|e| serde_json::json!({
+                "at": e.at.to_rfc3339(),
+                "user": e.user,
+                "model": e.model,
+                "prompt_tokens": e.prompt_tokens,
+                "completion_tokens": e.completion_tokens
+            })).collect::<Vec<_>>()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_window_and_buckets() {
+        let histogram = LatencyHistogram::default();
+        for ms in [50, 300, 300, 4_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let json = histogram.to_json();
+        assert_eq!(json["count"], 4);
+        assert_eq!(json["buckets"][0], serde_json::json!({ "le_ms": 100, "count": 1 }));
+        assert_eq!(json["buckets"][2], serde_json::json!({ "le_ms": 500, "count": 3 }));
+        assert_eq!(json["p50_ms"], 300);
+
+        for _ in 0..WINDOW {
+            histogram.record(Duration::from_millis(10));
+        }
+        assert_eq!(histogram.count(), WINDOW);
+        assert_eq!(histogram.to_json()["p99_ms"], 10);
+    }
+}
@@ -1,16 +1,28 @@
 use axum::{
-    extract::{Json, State},
-    response::{Html, IntoResponse, Sse, sse::Event},
-    http::StatusCode,
+    extract::{Json, Path, Query, Request, State},
+    middleware::Next,
+    response::{Html, IntoResponse, Response, Sse, sse::Event},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
 };
-use serde_json::{Value, json};
-use browser_automator::{AntigravityClient, AntigravityModel, Message as AntigravityMessage};
+use serde_json::Value;
+use browser_automator::schema_sanitizer::sanitize_schema;
+use browser_automator::{AntigravityClient, AntigravityModel, ChatResponse, ContextCache, EndpointStats, Fingerprint, GenerationParams, HeaderStyle, Message as AntigravityMessage, ResponseCache, ThinkingBlock, TokenLogprob, ToolChoice};
 use futures_util::stream::Stream;
+use std::collections::HashMap;
 use std::convert::Infallible;
-
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::benchmark::{run_benchmark, LiveBackend};
+use crate::cancellation::until_cancelled;
+use crate::config_admin::{apply_patch, redacted_config, restart_required};
+use crate::metrics::{LatencyHistogram, Metrics, UsageEntry, UsageLedger};
 use crate::state::AppState;
 use crate::session_recovery::{recover_session, is_recoverable_error, format_recovery_summary};
-use oauth::accounts::ModelFamily;
+use crate::tool_repair::parse_tool_use_chunk;
+use oauth::accounts::{Account, AccountManager, AccountSlot, ModelFamily, SelectionFailure};
+use common::config::{ClientProfile, Config, ThinkingThresholds, Transformer};
 
 /// Health check / welcome page at root
 pub async fn health_check() -> Html<&'static str> {
@@ -45,8 +57,12 @@ pub async fn health_check() -> Html<&'static str> {
         <div class="endpoints">
             <h3>Endpoints</h3>
             <div class="endpoint"><span class="method">POST</span> <code>/v1/chat/completions</code> - OpenAI compatible</div>
+            <div class="endpoint"><span class="method">POST</span> <code>/v1/completions</code> - OpenAI legacy completions</div>
             <div class="endpoint"><span class="method">POST</span> <code>/v1/messages</code> - Anthropic compatible</div>
             <div class="endpoint"><span class="method">GET</span> <code>/v1/models</code> - List available models</div>
+            <div class="endpoint"><span class="method">GET</span> <code>/v1/accounts/status</code> - Account rate limit status</div>
+            <div class="endpoint"><span class="method">POST</span> <code>/v1/admin/accounts/{email}/disable</code> - Take an account out of rotation</div>
+            <div class="endpoint"><span class="method">POST</span> <code>/v1/admin/accounts/{email}/enable</code> - Put an account back into rotation</div>
             <div class="endpoint"><span class="method">GET</span> <code>/health</code> - Health check</div>
         </div>
     </div>
@@ -84,128 +100,462 @@ fn convert_anthropic_tools(payload: &Value) -> Option<Vec<Value>> {
     None
 }
 
-/// Recursively sanitizes JSON schema to remove fields forbidden by Antigravity API
-fn sanitize_schema(schema: &mut Value) {
-    if let Some(obj) = schema.as_object_mut() {
-        // 1. Remove forbidden top-level keys
-        let forbidden_keys = [
-            "$schema", "$id", "default", "title", "examples",
-            "minLength", "maxLength", "pattern", "format",
-            "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "multipleOf",
-            "minItems", "maxItems", "uniqueItems",
-            "minProperties", "maxProperties", "propertyNames",
-            "const", "contentMediaType", "contentEncoding",
-            "additionalProperties" // Often strict in Gemini
-        ];
+/// Mock organization endpoint - Claude CLI calls this on startup
+pub async fn get_organization() -> impl IntoResponse {
+    Json(serde_json::json!({
+        "id": "org_aetherbridge",
+        "name": "AetherBridge Local",
+        "created_at": "2024-01-01T00:00:00Z",
+        "updated_at": "2024-01-01T00:00:00Z"
+    }))
+}
+
+/// Account status endpoint - current rate limits and recent rate-limit events
+pub async fn accounts_status(State(state): State<AppState>) -> impl IntoResponse {
+    let accounts = state.account_manager.account_statuses().await;
+    let history = state.account_manager.rate_limit_history().await;
+
+    Json(serde_json::json!({
+        "accounts": accounts,
+        "rate_limit_history": history
+    }))
+}
 
-        for key in forbidden_keys {
-            obj.remove(key);
+/// Admin endpoint - takes an account out of rotation without removing it
+pub async fn disable_account(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+) -> axum::response::Response {
+    set_account_disabled(&state, &email, true).await
+}
+
+/// Admin endpoint - puts a disabled account back into rotation
+pub async fn enable_account(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+) -> axum::response::Response {
+    set_account_disabled(&state, &email, false).await
+}
+
+/// Admin endpoint - sets an account's selection priority (lower is tried first)
+///
+/// Body: `{"priority": <i32>}`
+pub async fn set_account_priority(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    Json(payload): Json<Value>,
+) -> axum::response::Response {
+    let Some(priority) = payload["priority"].as_i64().and_then(|p| i32::try_from(p).ok()) else {
+        return ApiError::new(StatusCode::BAD_REQUEST, serde_json::json!({
+            "error": {
+                "message": "Expected an integer `priority`",
+                "type": "invalid_request_error"
+            }
+        })).into_response();
+    };
+
+    match state.account_manager.set_account_priority(&email, priority).await {
+        Ok(true) => Json(serde_json::json!({
+            "email": email,
+            "priority": priority
+        })).into_response(),
+        Ok(false) => ApiError::new(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("No account found for {}", email),
+                "type": "not_found_error"
+            }
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to update account {}: {}", email, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                "error": {
+                    "message": format!("Failed to update account: {}", e),
+                    "type": "api_error"
+                }
+            })).into_response()
         }
+    }
+}
 
-        // 2. Recursively process known nested structures
-        if let Some(properties) = obj.get_mut("properties").and_then(|p| p.as_object_mut()) {
-            for (_, prop_schema) in properties.iter_mut() {
-                sanitize_schema(prop_schema);
+async fn set_account_disabled(state: &AppState, email: &str, disabled: bool) -> axum::response::Response {
+    match state.account_manager.set_account_disabled(email, disabled).await {
+        Ok(true) => Json(serde_json::json!({
+            "email": email,
+            "disabled": disabled
+        })).into_response(),
+        Ok(false) => ApiError::new(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("No account found for {}", email),
+                "type": "not_found_error"
             }
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to update account {}: {}", email, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                "error": {
+                    "message": format!("Failed to update account: {}", e),
+                    "type": "api_error"
+                }
+            })).into_response()
         }
+    }
+}
+
+/// Admin endpoint - dedicates an account to some model families
+///
+/// Body: `{"families": ["claude" | "gemini", ...]}`; an empty list lets the
+/// account serve every family again.
+pub async fn set_account_families(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    Json(payload): Json<Value>,
+) -> axum::response::Response {
+    let families: Option<Vec<ModelFamily>> = payload["families"].as_array().and_then(|names| {
+        names.iter().map(|n| n.as_str().and_then(ModelFamily::from_name)).collect()
+    });
+    let Some(families) = families else {
+        return ApiError::new(StatusCode::BAD_REQUEST, serde_json::json!({
+            "error": {
+                "message": "Expected `families` to be a list of \"claude\" or \"gemini\"",
+                "type": "invalid_request_error"
+            }
+        })).into_response();
+    };
 
-        if let Some(items) = obj.get_mut("items") {
-             sanitize_schema(items);
+    match state.account_manager.set_account_families(&email, families.clone()).await {
+        Ok(true) => Json(serde_json::json!({
+            "email": email,
+            "families": families
+        })).into_response(),
+        Ok(false) => ApiError::new(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("No account found for {}", email),
+                "type": "not_found_error"
+            }
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to update account {}: {}", email, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                "error": {
+                    "message": format!("Failed to update account: {}", e),
+                    "type": "api_error"
+                }
+            })).into_response()
         }
+    }
+}
+
+/// Times one small request stage by stage (token refresh, project
+/// discovery, time to first token, total)
+///
+/// `?model=` picks the model (default `antigravity-gemini-3-flash`).
+pub async fn benchmark(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> axum::response::Response {
+    let state = state.current();
+    let model_id = params.get("model").map(String::as_str).unwrap_or("antigravity-gemini-3-flash");
+    let model = match parse_openai_model(model_id) {
+        Ok(m) => m,
+        Err(response) => return response.into_response(),
+    };
 
-        // Handle array of schemas (allOf, anyOf, oneOf)
-        for key in ["allOf", "anyOf", "oneOf"] {
-            if let Some(arr) = obj.get_mut(key).and_then(|v| v.as_array_mut()) {
-                for sub_schema in arr {
-                    sanitize_schema(sub_schema);
+    match run_benchmark(&mut LiveBackend::new(&state, model), model_id).await {
+        Ok(report) => Json(report.to_json()).into_response(),
+        Err(e) => {
+            tracing::warn!("Benchmark failed: {:#}", e);
+            ApiError::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+                "error": {
+                    "message": format!("{:#}", e),
+                    "type": "api_error"
                 }
+            })).into_response()
+        }
+    }
+}
+
+/// Admin endpoint - stops an in-flight streaming response
+///
+/// `request_id` is the id clients see in the stream (`msg_...` for Anthropic
+/// messages, `chatcmpl-...`/`cmpl-...` for OpenAI).
+pub async fn cancel_request(
+    State(state): State<AppState>,
+    Path(request_id): Path<String>,
+) -> axum::response::Response {
+    if state.cancellations.cancel(&request_id) {
+        Json(serde_json::json!({
+            "id": request_id,
+            "cancelled": true
+        })).into_response()
+    } else {
+        ApiError::new(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("No in-flight stream with id {}", request_id),
+                "type": "not_found_error"
+            }
+        })).into_response()
+    }
+}
+
+/// Admin endpoint - latency metrics (time to first token)
+pub async fn get_metrics(State(state): State<AppState>) -> Json<Value> {
+    Json(state.metrics.to_json())
+}
+
+/// Admin endpoint - the running config, with secrets redacted
+pub async fn get_config(State(state): State<AppState>, headers: HeaderMap) -> axum::response::Response {
+    let config = state.current().config;
+    if let Some(refusal) = config_admin_refusal(&config, &headers) {
+        return refusal;
+    }
+    match redacted_config(&config) {
+        Ok(config) => Json(config).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+            "error": {
+                "message": format!("Failed to serialize config: {}", e),
+                "type": "api_error"
+            }
+        })).into_response(),
+    }
+}
+
+/// Admin endpoint - merges a JSON merge patch into the running config
+///
+/// The change applies to requests that start afterwards and is saved to
+/// `config.json`. Returns the updated `config`, redacted like `GET`, along
+/// with `persisted` (whether the save succeeded) and `restart_required`
+/// (changed fields that only apply after a restart).
+pub async fn patch_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(patch): Json<Value>,
+) -> axum::response::Response {
+    if let Some(refusal) = config_admin_refusal(&state.current().config, &headers) {
+        return refusal;
+    }
+    let (previous, updated) = {
+        let mut live = state.live_config.write().unwrap();
+        match apply_patch(&live, &patch) {
+            Ok(updated) => {
+                let previous = std::mem::replace(&mut *live, Arc::new(updated.clone()));
+                (previous, updated)
             }
+            Err(e) => {
+                return ApiError::new(StatusCode::BAD_REQUEST, serde_json::json!({
+                    "error": {
+                        "message": format!("{:#}", e),
+                        "type": "invalid_request_error"
+                    }
+                })).into_response();
+            }
+        }
+    };
+    tracing::info!("Config updated via admin endpoint");
+
+    let persisted = match updated.save() {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!("Failed to save config: {}", e);
+            false
         }
+    };
+    let restart = restart_required(&previous, &updated).unwrap_or_default();
+    if !restart.is_empty() {
+        tracing::warn!("Config fields only applied after a restart: {}", restart.join(", "));
+    }
+    match redacted_config(&updated) {
+        Ok(config) => Json(serde_json::json!({
+            "config": config,
+            "persisted": persisted,
+            "restart_required": restart,
+        })).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+            "error": {
+                "message": format!("Failed to serialize config: {}", e),
+                "type": "api_error"
+            }
+        })).into_response(),
     }
 }
 
-/// Mock organization endpoint - Claude CLI calls this on startup
-pub async fn get_organization() -> impl IntoResponse {
-    Json(serde_json::json!({
-        "id": "org_aetherbridge",
-        "name": "AetherBridge Local",
-        "created_at": "2024-01-01T00:00:00Z",
-        "updated_at": "2024-01-01T00:00:00Z"
-    }))
+/// 403 unless an API key is configured and the request carries it; the
+/// config can redirect traffic and holds secrets, so it is never left open
+fn config_admin_refusal(config: &Config, headers: &HeaderMap) -> Option<axum::response::Response> {
+    if config.api_key.as_deref().is_some_and(|key| !key.is_empty() && carries_api_key(headers, key)) {
+        return None;
+    }
+    Some(ApiError::new(StatusCode::FORBIDDEN, serde_json::json!({
+        "error": {
+            "message": "The config endpoints are only available with an api_key configured and sent with the request",
+            "type": "permission_error"
+        }
+    })).into_response())
 }
 
 /// List available models (OpenAI compatible)
-pub async fn list_models() -> impl IntoResponse {
+pub async fn list_models(State(state): State<AppState>) -> impl IntoResponse {
+    let state = state.current();
     Json(serde_json::json!({
         "object": "list",
-        "data": [
-            {
-                "id": "antigravity-gemini-3-pro",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "google",
-                "permission": [],
-                "root": "gemini-3-pro",
-                "parent": null
-            },
-            {
-                "id": "antigravity-gemini-3-flash",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "google",
-                "permission": [],
-                "root": "gemini-3-flash",
-                "parent": null
-            },
-            {
-                "id": "antigravity-claude-sonnet-4-5",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "anthropic",
-                "permission": [],
-                "root": "claude-sonnet-4.5",
-                "parent": null
-            },
-            {
-                "id": "antigravity-claude-sonnet-4-5-thinking",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "anthropic",
-                "permission": [],
-                "root": "claude-sonnet-4.5-thinking",
-                "parent": null
-            },
-            {
-                "id": "antigravity-claude-opus-4-5-thinking",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "anthropic",
-                "permission": [],
-                "root": "claude-opus-4.5-thinking",
-                "parent": null
-            },
-            {
-                "id": "google-bridge",
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": "aether-bridge",
-                "permission": [],
-                "root": "google-bridge",
-                "parent": null
-            }
-        ]
+        "data": enabled_model_catalog(&state.config)
     }))
 }
 
+/// Model metadata plus whether it can be served right now
+///
+/// `currently_available` is false when no accounts are configured or every
+/// account is rate limited for the model's family.
+pub async fn get_model(
+    State(state): State<AppState>,
+    Path(model_id): Path<String>,
+) -> axum::response::Response {
+    let state = state.current();
+    let model = if model_enabled(&state.config, &model_id) {
+        model_availability(&state.account_manager, &model_id).await
+    } else {
+        None
+    };
+    match model {
+        Some(model) => Json(model).into_response(),
+        None => ApiError::new(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("The model '{}' does not exist", model_id),
+                "type": "invalid_request_error",
+                "code": "model_not_found"
+            }
+        })).into_response(),
+    }
+}
+
+async fn model_availability(account_manager: &AccountManager, model_id: &str) -> Option<Value> {
+    let catalog = model_catalog();
+    let mut model = catalog.as_array()?.iter().find(|m| m["id"] == model_id)?.clone();
+    let available = account_manager.account_count().await > 0
+        && !account_manager.all_rate_limited_for_model(model_id).await;
+    model["currently_available"] = Value::Bool(available);
+    Some(model)
+}
+
+/// The catalog minus models left out of `enabled_models`
+fn enabled_model_catalog(config: &Config) -> Value {
+    let catalog = model_catalog();
+    let models = catalog.as_array().into_iter().flatten();
+    Value::Array(models.filter(|m| m["id"].as_str().is_some_and(|id| model_enabled(config, id))).cloned().collect())
+}
+
+/// Whether `enabled_models` allows a model id (all are allowed when unset)
+///
+/// Entries may use any exact name `AntigravityModel` parses, so
+/// `gemini-3-flash` also enables `antigravity-gemini-3-flash`.
+fn model_enabled(config: &Config, model_id: &str) -> bool {
+    match resolve_model(model_id) {
+        Some(model) => antigravity_model_enabled(config, model),
+        None => config.enabled_models.as_ref().is_none_or(|enabled| enabled.iter().any(|m| m == model_id)),
+    }
+}
+
+fn antigravity_model_enabled(config: &Config, model: AntigravityModel) -> bool {
+    config.enabled_models.as_ref()
+        .is_none_or(|enabled| enabled.iter().any(|m| m.parse() == Ok(model)))
+}
+
+/// Rejects a model this deployment doesn't expose
+fn model_disabled_error(model_id: &str, anthropic_format: bool) -> ApiError {
+    tracing::warn!("Rejecting request for disabled model {}", model_id);
+    let message = format!("The model '{}' is not enabled on this server", model_id);
+    let body = if anthropic_format {
+        serde_json::json!({
+            "type": "error",
+            "error": { "type": "not_found_error", "message": message }
+        })
+    } else {
+        serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "invalid_request_error",
+                "code": "model_not_found"
+            }
+        })
+    };
+    ApiError::new(StatusCode::NOT_FOUND, body)
+}
+
+/// Models advertised by `/v1/models` (a JSON array)
+fn model_catalog() -> Value {
+    serde_json::json!([
+        {
+            "id": "antigravity-gemini-3-pro",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "google",
+            "permission": [],
+            "root": "gemini-3-pro",
+            "parent": null
+        },
+        {
+            "id": "antigravity-gemini-3-flash",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "google",
+            "permission": [],
+            "root": "gemini-3-flash",
+            "parent": null
+        },
+        {
+            "id": "antigravity-claude-sonnet-4-5",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "anthropic",
+            "permission": [],
+            "root": "claude-sonnet-4.5",
+            "parent": null
+        },
+        {
+            "id": "antigravity-claude-sonnet-4-5-thinking",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "anthropic",
+            "permission": [],
+            "root": "claude-sonnet-4.5-thinking",
+            "parent": null
+        },
+        {
+            "id": "antigravity-claude-opus-4-5-thinking",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "anthropic",
+            "permission": [],
+            "root": "claude-opus-4.5-thinking",
+            "parent": null
+        },
+        {
+            "id": "google-bridge",
+            "object": "model",
+            "created": 1700000000,
+            "owned_by": "aether-bridge",
+            "permission": [],
+            "root": "google-bridge",
+            "parent": null
+        }
+    ])
+}
+
 pub async fn chat_completions(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
-    tracing::info!("Received chat completion request");
+    let state = request_state(&state, &headers);
+    let payload = with_default_model(&state.config, payload);
+    let payload = apply_transformers(&state.config.transformers, payload, false);
+    tracing::info!(user = request_user(&payload).unwrap_or("-"), "Received chat completion request");
 
     // Extract model from request
     let model_id = payload["model"].as_str().unwrap_or("antigravity-claude-sonnet-4-5");
     tracing::info!("Requested model: {}", model_id);
+    if !model_enabled(&state.config, model_id) {
+        return model_disabled_error(model_id, false).into_response();
+    }
 
     // Check if this is an Antigravity model request
     if model_id.starts_with("antigravity-") {
@@ -225,7 +575,7 @@ pub async fn chat_completions(
 
     let automator = state.automator.lock().await;
 
-    let response_text = if let Some(protocol) = &automator.protocol {
+    let response_text = if let Some(protocol) = automator.as_ref().and_then(|a| a.protocol.as_ref()) {
         match protocol.chat_completion(prompt).await {
             Ok(resp) => resp,
             Err(e) => {
@@ -258,76 +608,223 @@ pub async fn chat_completions(
     })).into_response()
 }
 
-/// Handles requests for Antigravity models via OAuth
-async fn handle_antigravity_request(
+/// Error response with an optional retry hint
+///
+/// When `retry_after` is set, the standard `Retry-After` header and OpenAI's
+/// `x-ratelimit-reset` header are added so clients can back off automatically.
+struct ApiError {
+    status: StatusCode,
+    body: Value,
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, body: Value) -> Self {
+        Self { status, body, retry_after: None }
+    }
+
+    fn rate_limited(wait_secs: u64, body: Value) -> Self {
+        Self { status: StatusCode::TOO_MANY_REQUESTS, body, retry_after: Some(wait_secs) }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let mut response = (self.status, Json(self.body)).into_response();
+        if let Some(secs) = self.retry_after {
+            let headers = response.headers_mut();
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            headers.insert("x-ratelimit-reset", HeaderValue::from_str(&format!("{}s", secs)).unwrap_or(HeaderValue::from(secs)));
+        }
+        response
+    }
+}
+
+/// Resolves a client-supplied model name: exact ids first, then by keyword
+fn resolve_model(model_id: &str) -> Option<AntigravityModel> {
+    model_id.parse().ok().or_else(|| {
+        let model = AntigravityModel::from_loose(model_id)?;
+        tracing::debug!("Model '{}' matched loosely as {}", model_id, model.api_id());
+        Some(model)
+    })
+}
+
+/// Parses an OpenAI-style model id, returning a ready 400 response if unknown
+fn parse_openai_model(model_id: &str) -> Result<AntigravityModel, ApiError> {
+    resolve_model(model_id).ok_or_else(|| {
+        tracing::warn!("Unknown Antigravity model: {}", model_id);
+        ApiError::new(StatusCode::BAD_REQUEST, serde_json::json!({
+            "error": {
+                "message": format!("Unknown model: {}", model_id),
+                "type": "invalid_request_error"
+            }
+        }))
+    })
+}
+
+/// Selects an available account (queuing while rate limited) and builds a client for it
+///
+/// Errors are returned as ready-to-send OpenAI-style responses.
+async fn acquire_openai_client(
     state: &AppState,
     payload: &Value,
     model_id: &str,
-) -> axum::response::Response {
-    // Parse the model
-    let model = match AntigravityModel::from_str(model_id) {
-        Some(m) => m,
-        None => {
-            tracing::warn!("Unknown Antigravity model: {}", model_id);
-            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
-                "error": {
-                    "message": format!("Unknown model: {}", model_id),
-                    "type": "invalid_request_error"
-                }
-            }))).into_response();
-        }
-    };
-
-    // Get an available account
-    // Get an available account with retry queueing
-    let account = loop {
-        match state.account_manager.get_available_account().await {
-            Some(acc) => break acc,
+) -> Result<(Account, Arc<AntigravityClient>, Option<AccountSlot>), ApiError> {
+    let affinity_key = conversation_key(&state.config, payload);
+
+    // Get an available account with retry queueing, in arrival order
+    let mut turn = None;
+    let (account, slot) = loop {
+        match state.account_manager.next_in_line(&mut turn, affinity_key.as_deref(), model_id).await {
+            Some(selected) => break selected,
             None => {
                 // Check wait time
-                if let Some(wait_time) = state.account_manager.get_min_wait_time_for_model(&model_id.to_string()).await {
+                if let Some(wait_time) = state.account_manager.get_min_wait_time_for_model(model_id).await {
                     let wait_secs = wait_time.as_secs();
-                    if wait_secs > 600 { // Cap wait time at 10 minutes (claude-code-router default timeout is 1h)
+                    if exceeds_max_wait(&state.config, model_id, wait_secs) {
                          tracing::warn!("All accounts rate limited. Wait time {}s too long.", wait_secs);
-                         return (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({
+                         return Err(ApiError::rate_limited(wait_secs, serde_json::json!({
                             "error": {
                                 "message": format!("All accounts rate limited. Retry after {} seconds", wait_secs),
                                 "type": "rate_limit_error"
                             }
-                        }))).into_response();
+                        })));
                     }
 
                     tracing::info!("All accounts rate limited. Queuing request for {} seconds...", wait_secs);
-                    tokio::time::sleep(wait_time + std::time::Duration::from_secs(1)).await;
+                    state.account_manager.wait_turn(&mut turn, model_id, wait_time + std::time::Duration::from_secs(1)).await;
                     continue;
                 }
 
-                tracing::error!("No OAuth accounts configured");
-                return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+                let failure = state.account_manager.selection_failure(model_id).await;
+                let (status, kind, message) = no_account_error(failure, "No Google accounts configured. Please run 'aether login' first.");
+                tracing::error!("No account available: {}", message);
+                return Err(ApiError::new(status, serde_json::json!({
                     "error": {
-                        "message": "No Google accounts configured. Please run 'aether login' first.",
-                        "type": "authentication_error"
+                        "message": message,
+                        "type": kind
                     }
-                }))).into_response();
+                })));
             }
         }
     };
+    // Served; let the next waiter in
+    drop(turn);
 
-    tracing::info!("Using account: {} for model {}", account.email, model);
+    tracing::info!("Using account: {} for model {}", account.email, model_id);
+    state.account_manager.pace(account.index).await;
 
     // Create the Antigravity client with user's project ID from config
-    let project_id = state.config.project_id.clone();
-    let client = match AntigravityClient::new(account.access_token.clone(), project_id, Some((*state.fingerprint).clone())) {
-        Ok(c) => c,
+    match pooled_client(state, &account, HeaderStyle::Antigravity).await {
+        Ok(client) => Ok((account, client, slot)),
         Err(e) => {
             tracing::error!("Failed to create Antigravity client: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
                 "error": {
                     "message": format!("Failed to initialize client: {}", e),
                     "type": "api_error"
                 }
-            }))).into_response();
+            })))
+        }
+    }
+}
+
+/// Maps an upstream error to an OpenAI-style error response, recording rate limits
+async fn openai_error_response(
+    state: &AppState,
+    account: &Account,
+    model: AntigravityModel,
+    e: anyhow::Error,
+) -> axum::response::Response {
+    openai_error_body(state, account, model, e).await.into_response()
+}
+
+/// OpenAI-style error for an upstream error, recording rate limits
+async fn openai_error_body(
+    state: &AppState,
+    account: &Account,
+    model: AntigravityModel,
+    e: anyhow::Error,
+) -> ApiError {
+    let error_str = e.to_string();
+
+    // Check for rate limiting or capacity errors
+    if error_str.starts_with("RATE_LIMITED:") || error_str.starts_with("CAPACITY_ERROR:") {
+        let seconds = rate_limit_seconds(&state.account_manager, account.index, &error_str).await;
+
+        // Use longer backoff for capacity errors
+        let is_capacity = error_str.starts_with("CAPACITY_ERROR:");
+        let effective_seconds = if is_capacity {
+            std::cmp::max(seconds, 45)
+        } else {
+            seconds
+        };
+
+        let until = chrono::Utc::now() + chrono::Duration::seconds(effective_seconds as i64);
+
+        state.account_manager.mark_rate_limited(account.index, ModelFamily::from_model_id(model.api_id()), until).await;
+
+        let error_type = if is_capacity { "capacity_error" } else { "rate_limit_error" };
+        tracing::warn!("Account {} {} for {} seconds", account.email, error_type, effective_seconds);
+
+        return ApiError::rate_limited(effective_seconds, serde_json::json!({
+            "error": {
+                "message": format!("Rate limited. Retry after {} seconds", effective_seconds),
+                "type": error_type
+            }
+        }));
+    }
+
+    tracing::error!("Antigravity API error: {}", e);
+    let (status, error_type) = upstream_error_kind(&error_str);
+    ApiError::new(status, serde_json::json!({
+        "error": {
+            "message": error_str,
+            "type": error_type
         }
+    }))
+}
+
+/// HTTP status and error type for an upstream error that isn't a rate limit
+///
+/// An HTML login page instead of JSON means the account's tokens are bad;
+/// a denied default project means the user has to configure their own.
+fn upstream_error_kind(error_str: &str) -> (StatusCode, &'static str) {
+    if error_str.starts_with("AUTH_ERROR:") {
+        (StatusCode::UNAUTHORIZED, "authentication_error")
+    } else if error_str.starts_with("NO_PROJECT_CONFIGURED:") {
+        (StatusCode::FORBIDDEN, "permission_error")
+    } else if error_str.starts_with("PROMPT_BLOCKED:") {
+        (StatusCode::BAD_REQUEST, "invalid_request_error")
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "api_error")
+    }
+}
+
+/// Handles requests for Antigravity models via OAuth
+async fn handle_antigravity_request(
+    state: &AppState,
+    payload: &Value,
+    model_id: &str,
+) -> axum::response::Response {
+    // Parse the model
+    let model = match parse_openai_model(model_id) {
+        Ok(m) => m,
+        Err(response) => return response.into_response(),
+    };
+
+    if let Some(error) = context_length_error(payload, model, false) {
+        return error.into_response();
+    }
+    let context_usage = context_usage_percent(&state.config, payload, model);
+    let generation = generation_params(payload);
+    if let Some(error) = streamed_candidates_error(payload, &generation) {
+        return error.into_response();
+    }
+
+    let (account, client, slot) = match acquire_openai_client(state, payload, model_id).await {
+        Ok(pair) => pair,
+        Err(response) => return response.into_response(),
     };
 
     // Convert messages
@@ -340,6 +837,7 @@ async fn handle_antigravity_request(
             Some(AntigravityMessage {
                 role: role.to_string(),
                 content: content.to_string(),
+                thinking: Vec::new(),
             })
         })
         .collect();
@@ -347,72 +845,298 @@ async fn handle_antigravity_request(
     // Extract valid tools
     let tools = convert_anthropic_tools(payload);
 
+    if payload["stream"].as_bool().unwrap_or(false) {
+        let state = state.clone();
+        let model_id = model_id.to_string();
+        let include_usage = payload["stream_options"]["include_usage"].as_bool().unwrap_or(false);
+        let completion_id = format!("chatcmpl-{}", uuid::Uuid::new_v4());
+        let created = chrono::Utc::now().timestamp();
+        let cancel = state.cancellations.register(&completion_id);
+        let stops = stop_sequences(payload);
+        let user = request_user(payload).map(str::to_string);
+
+        let stream = async_stream::stream! {
+            use futures_util::StreamExt;
+            // Hold the account's slot until the stream ends
+            let _slot = slot;
+
+            let output_stream = match client.chat_completion_stream(model, messages, None, tools, &generation).await {
+                Ok(s) => s,
+                Err(e) => {
+                    let error = openai_error_body(&state, &account, model, e).await;
+                    yield Ok::<Event, Infallible>(Event::default().data(error.body.to_string()));
+                    yield Ok(Event::default().data("[DONE]"));
+                    return;
+                }
+            };
+            state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(model.api_id())).await;
+
+            let output_stream = record_stream_usage(output_stream, state.metrics.clone(), user, model_id.clone());
+            let output_stream = stop_at_sequences(output_stream, stops);
+            let events = chat_completion_chunks(output_stream, completion_id, created, model_id, include_usage);
+            tokio::pin!(events);
+            while let Some(data) = events.next().await {
+                yield Ok(Event::default().data(data));
+            }
+        };
+
+        let mut response = Sse::new(until_cancelled(stream, cancel)).into_response();
+        insert_context_usage(&mut response, context_usage);
+        return response;
+    }
+
     // Make the API call
-    match client.chat_completion(model, messages, None, tools).await {
-        Ok(response) => {
+    match client.chat_completion(model, messages, None, tools, &generation).await {
+        Ok(mut response) => {
+            truncate_at_stop(&mut response.content, stop_sequences(payload));
             // Clear rate limit on success
-            state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(&model.api_id().to_string())).await;
+            state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(model.api_id())).await;
+            record_usage(&state.metrics.usage, payload, model_id, response.usage.as_ref());
 
-            let usage = response.usage.as_ref();
-            Json(serde_json::json!({
+            let mut http_response = Json(serde_json::json!({
                 "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
                 "object": "chat.completion",
                 "created": chrono::Utc::now().timestamp(),
                 "model": model_id,
-                "choices": [{
-                    "index": 0,
-                    "message": {
-                        "role": "assistant",
-                        "content": response.content
-                    },
-                    "finish_reason": response.finish_reason
-                }],
-                "usage": {
-                    "prompt_tokens": usage.map(|u| u.prompt_tokens).unwrap_or(0),
-                    "completion_tokens": usage.map(|u| u.completion_tokens).unwrap_or(0),
-                    "total_tokens": usage.map(|u| u.total_tokens).unwrap_or(0)
-                }
-            })).into_response()
+                "choices": chat_choices(&response),
+                "usage": openai_usage(response.usage.as_ref())
+            })).into_response();
+            insert_context_usage(&mut http_response, context_usage);
+            http_response
         }
-        Err(e) => {
-            let error_str = e.to_string();
+        Err(e) => openai_error_response(state, &account, model, e).await,
+    }
+}
 
-            // Check for rate limiting or capacity errors
-            if error_str.starts_with("RATE_LIMITED:") || error_str.starts_with("CAPACITY_ERROR:") {
-                let parts: Vec<&str> = error_str.splitn(3, ':').collect();
-                let seconds = parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(60);
-                
-                // Use longer backoff for capacity errors
-                let is_capacity = error_str.starts_with("CAPACITY_ERROR:");
-                let effective_seconds = if is_capacity {
-                    std::cmp::max(seconds, 45)
-                } else {
-                    seconds
-                };
-                
-                let until = chrono::Utc::now() + chrono::Duration::seconds(effective_seconds as i64);
+/// Legacy OpenAI completions endpoint (`prompt` string instead of `messages`)
+pub async fn completions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> axum::response::Response {
+    let state = request_state(&state, &headers);
+    let payload = with_default_model(&state.config, payload);
+    let payload = apply_transformers(&state.config.transformers, payload, false);
+    tracing::info!(user = request_user(&payload).unwrap_or("-"), "Received legacy completion request");
+
+    let model_id = payload["model"].as_str().unwrap_or("antigravity-gemini-3-flash").to_string();
+    let model = match parse_openai_model(&model_id) {
+        Ok(m) => m,
+        Err(response) => return response.into_response(),
+    };
+    if !antigravity_model_enabled(&state.config, model) {
+        return model_disabled_error(&model_id, false).into_response();
+    }
 
-                state.account_manager.mark_rate_limited(account.index, ModelFamily::from_model_id(&model.api_id().to_string()), until).await;
+    let messages = completion_prompt_messages(&payload);
+    if messages.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({
+            "error": {
+                "message": "'prompt' must be a non-empty string or array of strings",
+                "type": "invalid_request_error"
+            }
+        }))).into_response();
+    }
 
-                let error_type = if is_capacity { "capacity_error" } else { "rate_limit_error" };
-                tracing::warn!("Account {} {} for {} seconds", account.email, error_type, effective_seconds);
+    let (account, client, slot) = match acquire_openai_client(&state, &payload, &model_id).await {
+        Ok(pair) => pair,
+        Err(response) => return response.into_response(),
+    };
 
-                return (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({
-                    "error": {
-                        "message": format!("Rate limited. Retry after {} seconds", effective_seconds),
-                        "type": error_type
-                    }
-                }))).into_response();
+    let completion_id = format!("cmpl-{}", uuid::Uuid::new_v4());
+    let created = chrono::Utc::now().timestamp();
+    let generation = generation_params(&payload);
+
+    let is_streaming = payload.get("stream").and_then(|v| v.as_bool()).unwrap_or(false);
+    if !is_streaming {
+        return match client.chat_completion(model, messages, None, None, &generation).await {
+            Ok(response) => {
+                state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(model.api_id())).await;
+                record_usage(&state.metrics.usage, &payload, &model_id, response.usage.as_ref());
+                Json(text_completion_response(
+                    &completion_id,
+                    created,
+                    &model_id,
+                    &response.content,
+                    Some(&response.finish_reason),
+                    response.usage.as_ref(),
+                )).into_response()
             }
+            Err(e) => openai_error_response(&state, &account, model, e).await,
+        };
+    }
 
-            tracing::error!("Antigravity API error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
-                "error": {
-                    "message": error_str,
-                    "type": "api_error"
+    let cancel = state.cancellations.register(&completion_id);
+    let user = request_user(&payload).map(str::to_string);
+    let stream = async_stream::stream! {
+        use futures_util::StreamExt;
+        // Hold the account's slot until the stream ends
+        let _slot = slot;
+
+        let output_stream = match client.chat_completion_stream(model, messages, None, None, &generation).await {
+            Ok(s) => s,
+            Err(e) => {
+                let error = openai_error_body(&state, &account, model, e).await;
+                yield Ok::<Event, Infallible>(Event::default().data(error.body.to_string()));
+                yield Ok(Event::default().data("[DONE]"));
+                return;
+            }
+        };
+        state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(model.api_id())).await;
+        let output_stream = record_stream_usage(output_stream, state.metrics.clone(), user, model_id.clone());
+        tokio::pin!(output_stream);
+
+        while let Some(chunk_res) = output_stream.next().await {
+            match chunk_res {
+                Ok(chunk) => {
+                    if chunk.done { break; }
+                    // Legacy completions have no notion of thinking or tool calls
+                    if chunk.is_thinking || chunk.is_tool_use { continue; }
+
+                    let data = text_completion_response(&completion_id, created, &model_id, &chunk.delta, None, None);
+                    yield Ok(Event::default().data(data.to_string()));
                 }
-            }))).into_response()
+                Err(e) => {
+                    tracing::error!("Completion stream chunk error: {}", e);
+                    let error_str = e.to_string();
+                    let error_event = serde_json::json!({
+                        "error": { "message": &error_str, "type": upstream_error_kind(&error_str).1 }
+                    });
+                    yield Ok(Event::default().data(error_event.to_string()));
+                    yield Ok(Event::default().data("[DONE]"));
+                    return;
+                }
+            }
+        }
+
+        let final_chunk = text_completion_response(&completion_id, created, &model_id, "", Some("stop"), None);
+        yield Ok(Event::default().data(final_chunk.to_string()));
+        yield Ok(Event::default().data("[DONE]"));
+    };
+
+    Sse::new(until_cancelled(stream, cancel)).into_response()
+}
+
+/// Wraps a legacy `prompt` (string or array of strings) into a single user message
+fn completion_prompt_messages(payload: &Value) -> Vec<AntigravityMessage> {
+    let prompt = match payload.get("prompt") {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(parts)) => parts.iter()
+            .filter_map(|p| p.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    };
+
+    if prompt.is_empty() {
+        vec![]
+    } else {
+        vec![AntigravityMessage::user(prompt)]
+    }
+}
+
+/// Builds a legacy `text_completion` body (also used for each streamed chunk)
+fn text_completion_response(
+    id: &str,
+    created: i64,
+    model_id: &str,
+    text: &str,
+    finish_reason: Option<&str>,
+    usage: Option<&browser_automator::Usage>,
+) -> Value {
+    let mut body = serde_json::json!({
+        "id": id,
+        "object": "text_completion",
+        "created": created,
+        "model": model_id,
+        "choices": [{
+            "text": text,
+            "index": 0,
+            "logprobs": null,
+            "finish_reason": finish_reason
+        }]
+    });
+
+    if finish_reason.is_some() {
+        body["usage"] = openai_usage(usage);
+    }
+
+    body
+}
+
+/// OpenAI `usage` object (zeros when the upstream reported none)
+///
+/// Thinking tokens go in `completion_tokens_details.reasoning_tokens`, as
+/// OpenAI reports them for its reasoning models.
+fn openai_usage(usage: Option<&browser_automator::Usage>) -> Value {
+    let mut body = serde_json::json!({
+        "prompt_tokens": usage.map(|u| u.prompt_tokens).unwrap_or(0),
+        "completion_tokens": usage.map(|u| u.completion_tokens).unwrap_or(0),
+        "total_tokens": usage.map(|u| u.total_tokens).unwrap_or(0)
+    });
+    if let Some(u) = usage.filter(|u| u.thinking_tokens > 0) {
+        body["completion_tokens_details"] = serde_json::json!({ "reasoning_tokens": u.thinking_tokens });
+    }
+    body
+}
+
+/// Converts an upstream chunk stream into OpenAI `chat.completion.chunk` SSE payloads
+///
+/// Every chunk carries the same `id` and `created`, fixed when the request
+/// arrived. With `include_usage` (from `stream_options`), a final chunk with
+/// empty `choices` and the reported usage is sent before `[DONE]`.
+fn chat_completion_chunks<S>(upstream: S, id: String, created: i64, model_id: String, include_usage: bool) -> impl Stream<Item = String>
+where
+    S: Stream<Item = anyhow::Result<browser_automator::StreamChunk>>,
+{
+    async_stream::stream! {
+        use futures_util::StreamExt;
+
+        let chunk = |delta: Value, finish_reason: Option<&str>| serde_json::json!({
+            "id": &id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": &model_id,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+        }).to_string();
+
+        yield chunk(serde_json::json!({ "role": "assistant", "content": "" }), None);
+
+        let mut usage = None;
+        tokio::pin!(upstream);
+        while let Some(chunk_res) = upstream.next().await {
+            match chunk_res {
+                Ok(c) if c.done => {
+                    usage = c.usage;
+                    break;
+                }
+                // Thinking and tool calls aren't forwarded (matches the non-streaming response)
+                Ok(c) if c.is_thinking || c.is_tool_use => continue,
+                Ok(c) => yield chunk(serde_json::json!({ "content": c.delta }), None),
+                Err(e) => {
+                    tracing::error!("Chat completion stream chunk error: {}", e);
+                    let error_str = e.to_string();
+                    yield serde_json::json!({
+                        "error": { "message": &error_str, "type": upstream_error_kind(&error_str).1 }
+                    }).to_string();
+                    yield "[DONE]".to_string();
+                    return;
+                }
+            }
+        }
+
+        yield chunk(serde_json::json!({}), Some("stop"));
+        if include_usage {
+            yield serde_json::json!({
+                "id": &id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": &model_id,
+                "choices": [],
+                "usage": openai_usage(usage.as_ref())
+            }).to_string();
         }
+        yield "[DONE]".to_string();
     }
 }
 
@@ -420,9 +1144,13 @@ async fn handle_antigravity_request(
 /// This enables: ANTHROPIC_BASE_URL=http://127.0.0.1:8080 claude-code
 pub async fn messages(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
-    tracing::info!("Received Anthropic messages request");
+    let state = request_state(&state, &headers);
+    let payload = with_default_model(&state.config, payload);
+    let payload = apply_transformers(&state.config.transformers, payload, true);
+    tracing::info!(user = request_user(&payload).unwrap_or("-"), "Received Anthropic messages request");
     tracing::info!(">>> PAYLOAD: {:?}", payload); // DEBUG: PROOF OF LIFE
 
     // Check if streaming is requested
@@ -430,81 +1158,93 @@ pub async fn messages(
         .and_then(|v| v.as_bool())
         .unwrap_or(false);
 
+    // Reject over-context prompts before anything is sent upstream
+    let requested_model = payload["model"].as_str().unwrap_or("claude-3-5-sonnet-20241022");
+    if !antigravity_model_enabled(&state.config, map_anthropic_to_antigravity(&state.config, requested_model)) {
+        return model_disabled_error(requested_model, true).into_response();
+    }
+    if let Some(error) = context_length_error(&payload, map_anthropic_to_antigravity(&state.config, requested_model), true) {
+        return error.into_response();
+    }
+    let context_usage = context_usage_percent(&state.config, &payload, map_anthropic_to_antigravity(&state.config, requested_model));
+
     if is_streaming {
         tracing::info!("Streaming mode requested");
-        return messages_streaming(state, payload).await.into_response();
+        let mut response = messages_streaming(state, payload).await.into_response();
+        insert_context_usage(&mut response, context_usage);
+        return response;
     }
 
     // Extract model from request and map to Antigravity
-    let requested_model = payload["model"].as_str().unwrap_or("claude-3-5-sonnet-20241022");
     tracing::info!("Anthropic model requested: {}", requested_model);
 
     // Map Anthropic model IDs to Antigravity models
-    let mut model = map_anthropic_to_antigravity(requested_model);
+    let mut model = map_anthropic_to_antigravity(&state.config, requested_model);
     tracing::info!("Mapped to Antigravity model: {:?}", model);
 
     // Check for extended thinking via anthropic-beta header or thinking field
     let thinking_enabled = payload.get("thinking").is_some()
         || payload.get("extended_thinking").is_some();
 
-    // Get an available OAuth account
+    // Which fallback strategy ends up serving the request
+    let mut strategy = FallbackStrategy::Primary;
+
     // Get an available OAuth account with retry queuing
-    let account = loop {
-        match state.account_manager.get_available_account().await {
-            Some(acc) => break acc,
+    let affinity_key = conversation_key(&state.config, &payload);
+    let mut turn = None;
+    let (account, _slot) = loop {
+        match state.account_manager.next_in_line(&mut turn, affinity_key.as_deref(), model.api_id()).await {
+            Some(selected) => break selected,
             None => {
                 // Check for Pre-emptive Spoofing (Strategy 0)
-                tracing::info!("Primary model rate limited. Checking Strategy 0 fallback for {:?}", model);
-                if let Some(spoof_model) = get_spoof_model(model) {
-                     tracing::info!("Spoof model available: {:?}", spoof_model);
-                     if let Some(acc) = state.account_manager.get_available_account_ignoring_rate_limit().await {
-                         // Log the pre-emptive switch
-                         tracing::info!("Strategy 0: Ignoring rate limit and using account {} for spoof model {:?}", acc.email, spoof_model);
-                         // Swap model and proceed
-                         model = spoof_model;
-                         break acc;
-                     } else {
-                         tracing::warn!("Strategy 0 Failed: Could not find ANY account (even ignoring rate limits) to try spoofing.");
-                     }
-                } else {
-                    tracing::info!("No spoof model defined for {:?}, skipping Strategy 0.", model);
+                if let Some((acc, spoof_model)) = preemptive_spoof(&state.config, &state.account_manager, model).await {
+                    // Log the pre-emptive switch
+                    tracing::info!("Strategy 0: Ignoring rate limit and using account {} for spoof model {:?}", acc.email, spoof_model);
+                    // Swap model and proceed
+                    model = spoof_model;
+                    strategy = FallbackStrategy::SpoofSame;
+                    let slot = state.account_manager.acquire_slot(acc.index).await;
+                    break (acc, slot);
                 }
 
                 if let Some(wait_time) = state.account_manager.get_min_wait_time_for_model(&requested_model).await {
                     let wait_secs = wait_time.as_secs();
-                    if wait_secs > 600 {
+                    if exceeds_max_wait(&state.config, requested_model, wait_secs) {
                          tracing::warn!("All accounts rate limited. Wait time {}s too long.", wait_secs);
-                         return (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({
+                         return ApiError::rate_limited(wait_secs, serde_json::json!({
                             "type": "error",
                             "error": {
                                 "type": "rate_limit_error",
                                 "message": format!("Rate limited. Retry after {} seconds", wait_secs)
                             }
-                        }))).into_response();
+                        })).into_response();
                     }
 
                     tracing::info!("All accounts rate limited. Queuing Anthropic request for {} seconds...", wait_secs);
-                    tokio::time::sleep(wait_time + std::time::Duration::from_secs(1)).await;
+                    state.account_manager.wait_turn(&mut turn, model.api_id(), wait_time + std::time::Duration::from_secs(1)).await;
                     continue;
                 }
 
-                tracing::error!("No OAuth accounts configured");
-                return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+                let failure = state.account_manager.selection_failure(model.api_id()).await;
+                let (status, kind, message) = no_account_error(failure, "No Google accounts configured. Run AetherBridge TUI and press [L] to login.");
+                tracing::error!("No account available: {}", message);
+                return (status, Json(serde_json::json!({
                     "type": "error",
                     "error": {
-                        "type": "authentication_error",
-                        "message": "No Google accounts configured. Run AetherBridge TUI and press [L] to login."
+                        "type": kind,
+                        "message": message
                     }
                 }))).into_response();
             }
         }
     };
+    drop(turn);
 
     tracing::info!("Using account: {} for Anthropic request", account.email);
+    state.account_manager.pace(account.index).await;
 
     // Create Antigravity client with user's project ID from config
-    let project_id = state.config.project_id.clone();
-    let client = match AntigravityClient::new(account.access_token.clone(), project_id.clone(), Some((*state.fingerprint).clone())) {
+    let client = match pooled_client(&state, &account, HeaderStyle::Antigravity).await {
         Ok(c) => c,
         Err(e) => {
             tracing::error!("Failed to create Antigravity client: {}", e);
@@ -528,18 +1268,12 @@ pub async fn messages(
             .get("budget_tokens")
             .and_then(|v| v.as_u64())
             .map(|v| v as u32)
-            .or(Some(10000)); // Default budget
-
-        let level = if let Some(b) = budget {
-            if b < 5000 { "low" } else if b < 15000 { "medium" } else { "high" }
-        } else {
-            "low"
-        };
+            .unwrap_or(10000); // Default budget
 
         Some(browser_automator::ThinkingConfig {
-            budget: budget,
-            level: Some(level.to_string()),
-            include_thoughts: true,
+            budget: Some(budget),
+            level: Some(thinking_level_for_budget(&state.config.thinking_thresholds, budget).to_string()),
+            include_thoughts: include_thoughts(&state.config, &payload),
         })
     } else {
         None
@@ -548,9 +1282,10 @@ pub async fn messages(
     // Extract tools and convert to Gemini format
     // Extract tools from payload
     let tools = convert_anthropic_tools(&payload);
+    let generation = generation_params(&payload);
 
     // Make the API call with potential spoofing
-    let result = client.chat_completion(model, messages.clone(), thinking_config.clone(), tools.clone()).await;
+    let result = client.chat_completion(model, messages.clone(), thinking_config.clone(), tools.clone(), &generation).await;
 
     // Track if we used a fallback strategy (don't clear rate limit if we did)
     let mut used_fallback = false;
@@ -568,7 +1303,7 @@ pub async fn messages(
                  let recovered_messages = convert_anthropic_messages(&payload);
                  
                  // Retry the request with recovered messages
-                 match client.chat_completion(model, recovered_messages, thinking_config.clone(), tools.clone()).await {
+                 match client.chat_completion(model, recovered_messages, thinking_config.clone(), tools.clone(), &generation).await {
                      Ok(res) => {
                          tracing::info!("Session recovery retry succeeded!");
                          Ok(res)
@@ -587,8 +1322,7 @@ pub async fn messages(
                  used_fallback = true; // Mark that we're using fallback strategies
                  
                  // Parse retry duration from RATE_LIMITED:seconds:error or CAPACITY_ERROR:seconds:error
-                 let parts: Vec<&str> = error_str.splitn(3, ':').collect();
-                 let seconds = parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(60);
+                 let seconds = rate_limit_seconds(&state.account_manager, account.index, &error_str).await;
                  
                  // Use exponential backoff for capacity errors (base 45s with exponential increase)
                  let is_capacity = error_str.starts_with("CAPACITY_ERROR:");
@@ -609,12 +1343,13 @@ pub async fn messages(
                  let mut spoof_success = false;
                  let mut final_res = Err(e); // Default to original error
 
-                 if let Some(spoof_model) = get_spoof_model(model) {
+                 if let Some(spoof_model) = spoof_target(&state.config, model) {
                      tracing::info!("Strategy 1: Spoofing {:?} on same account...", spoof_model);
                      let spoof_config = adapt_config_for_spoof(&thinking_config, spoof_model);
-                     match client.chat_completion(spoof_model, messages.clone(), spoof_config.clone(), tools.clone()).await {
+                     match client.chat_completion(spoof_model, messages.clone(), spoof_config.clone(), tools.clone(), &generation).await {
                          Ok(res) => {
                              spoof_success = true;
+                             strategy = FallbackStrategy::SpoofSame;
                              final_res = Ok(res);
                          },
                          Err(e2) => {
@@ -631,22 +1366,8 @@ pub async fn messages(
                           tracing::info!("Strategy 1.5: Attempting dual quota fallback with Gemini CLI headers...");
                           
                           // Create a new client with Gemini CLI headers
-                          let cli_client = match AntigravityClient::new(
-                              account.access_token.clone(), 
-                              project_id.clone(), 
-                              Some((*state.fingerprint).clone())
-                          ) {
-                              Ok(mut c) => {
-                                  // Enable dual quota mode
-                                  c.set_quota_fallback(true).await;
-                                  // Switch to Gemini CLI headers
-                                  if let Err(e) = c.switch_to_gemini_cli_headers().await {
-                                      tracing::warn!("Failed to switch to Gemini CLI headers: {}", e);
-                                      None
-                                  } else {
-                                      Some(c)
-                                  }
-                              }
+                          let cli_client = match pooled_client(&state, &account, HeaderStyle::GeminiCli).await {
+                              Ok(c) => Some(c),
                               Err(e) => {
                                   tracing::warn!("Failed to create CLI client: {}", e);
                                   None
@@ -655,10 +1376,11 @@ pub async fn messages(
                           
                           if let Some(ref cli_c) = cli_client {
                               // Try the same model with Gemini CLI headers
-                              match cli_c.chat_completion(model, messages.clone(), thinking_config.clone(), tools.clone()).await {
+                              match cli_c.chat_completion(model, messages.clone(), thinking_config.clone(), tools.clone(), &generation).await {
                                   Ok(res) => {
                                       tracing::info!("Strategy 1.5 SUCCESS: Dual quota worked!");
                                       spoof_success = true;
+                                      strategy = FallbackStrategy::DualQuota;
                                       final_res = Ok(res);
                                   }
                                   Err(e2) => {
@@ -673,22 +1395,28 @@ pub async fn messages(
                   if !spoof_success {
                       // Strategy 2: Rotate Account (Absolute Fallback)
                       tracing::info!("Strategy 2: Rotating account...");
-                      if let Some(new_account) = state.account_manager.get_available_account().await {
+                      // Try Spoof immediately on new account
+                      let target_model = spoof_target(&state.config, model).unwrap_or(model);
+                      if let Some(new_account) = state.account_manager.get_available_account_for_model(target_model.api_id()).await {
                           tracing::info!("Switched to account: {}", new_account.email);
-                          if let Ok(new_client) = AntigravityClient::new(new_account.access_token.clone(), project_id.clone(), Some((*state.fingerprint).clone())) {
-
-                              // Try Spoof immediately on new account
-                              let target_model = if let Some(spoof) = get_spoof_model(model) { spoof } else { model };
+                          // This request already holds the slot if it's the same account
+                          let _new_slot = if new_account.index == account.index {
+                              None
+                          } else {
+                              state.account_manager.acquire_slot(new_account.index).await
+                          };
+                          if let Ok(new_client) = pooled_client(&state, &new_account, HeaderStyle::Antigravity).await {
                               let target_config = if target_model != model {
                                   adapt_config_for_spoof(&thinking_config, target_model)
                               } else {
                                   thinking_config.clone()
                               };
 
-                               match new_client.chat_completion(target_model, messages, target_config, tools.clone()).await {
+                               match new_client.chat_completion(target_model, messages, target_config, tools.clone(), &generation).await {
                                    Ok(res) => {
                                        // NOTE: Don't clear rate limit on original account
                                        // The primary model is still rate-limited, we just used a fallback
+                                       strategy = FallbackStrategy::Rotate;
                                        final_res = Ok(res);
                                    },
                                    Err(e3) => {
@@ -710,52 +1438,23 @@ pub async fn messages(
     };
 
     match api_result {
-        Ok(response) => {
+        Ok(mut response) => {
             // Only clear rate limit if the PRIMARY request succeeded (not fallback)
             if !used_fallback {
                 state.account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(&model.api_id().to_string())).await;
             }
 
-            // Build content blocks (Anthropic format)
-            let mut content_blocks = Vec::new();
-
-            // Add thinking block if present
-            if let Some(ref thinking) = response.thinking {
-                content_blocks.push(serde_json::json!({
-                    "type": "thinking",
-                    "thinking": thinking
-                }));
-            }
-
-            // Add main text content
-            content_blocks.push(serde_json::json!({
-                "type": "text",
-                "text": response.content
-            }));
-
-            let usage = response.usage.as_ref();
-
-            Json(serde_json::json!({
-                "id": format!("msg_{}", &uuid::Uuid::new_v4().to_string().replace("-", "")[..24]),
-                "type": "message",
-                "role": "assistant",
-                "content": content_blocks,
-                "model": requested_model,
-                "stop_reason": &response.finish_reason,
-                "stop_sequence": null,
-                "usage": {
-                    "input_tokens": usage.map(|u| u.prompt_tokens).unwrap_or(0),
-                    "output_tokens": usage.map(|u| u.completion_tokens).unwrap_or(0)
-                }
-            })).into_response()
+            tracing::info!("Request served by {} via {} strategy", response.model, strategy.as_str());
+            record_usage(&state.metrics.usage, &payload, requested_model, response.usage.as_ref());
+            let stop_sequence = truncate_at_stop(&mut response.content, stop_sequences(&payload));
+            anthropic_message_response(requested_model, &response, stop_sequence.as_deref(), strategy, context_usage)
         }
         Err(e) => {
             let error_str = e.to_string();
 
             // Handle rate limiting and capacity errors
             if error_str.starts_with("RATE_LIMITED:") || error_str.starts_with("CAPACITY_ERROR:") {
-                let parts: Vec<&str> = error_str.splitn(3, ':').collect();
-                let seconds = parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(60);
+                let seconds = rate_limit_seconds(&state.account_manager, account.index, &error_str).await;
                 
                 // Use longer backoff for capacity errors
                 let is_capacity = error_str.starts_with("CAPACITY_ERROR:");
@@ -771,20 +1470,21 @@ pub async fn messages(
                 let error_type = if is_capacity { "capacity_error" } else { "rate_limit_error" };
                 tracing::warn!("Account {} {} for {} seconds", account.email, error_type, effective_seconds);
 
-                return (StatusCode::TOO_MANY_REQUESTS, Json(serde_json::json!({
+                return ApiError::rate_limited(effective_seconds, serde_json::json!({
                     "type": "error",
                     "error": {
                         "type": error_type,
                         "message": format!("Rate limited. Retry after {} seconds", effective_seconds)
                     }
-                }))).into_response();
+                })).into_response();
             }
 
             tracing::error!("Antigravity API error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            let (status, error_type) = upstream_error_kind(&error_str);
+            (status, Json(serde_json::json!({
                 "type": "error",
                 "error": {
-                    "type": "api_error",
+                    "type": error_type,
                     "message": error_str
                 }
             }))).into_response()
@@ -792,8 +1492,241 @@ pub async fn messages(
     }
 }
 
+/// Builds a non-streaming Anthropic `message` response
+///
+/// The fallback strategy that served it goes in `metadata` and the
+/// `x-aether-strategy` header, as does the context usage when it is high.
+fn anthropic_message_response(
+    requested_model: &str,
+    response: &ChatResponse,
+    stop_sequence: Option<&str>,
+    strategy: FallbackStrategy,
+    context_usage: Option<u32>,
+) -> axum::response::Response {
+    // Build content blocks (Anthropic format)
+    let mut content_blocks = Vec::new();
+
+    // Add thinking block if present
+    if let Some(ref thinking) = response.thinking {
+        content_blocks.push(serde_json::json!({
+            "type": "thinking",
+            "thinking": thinking
+        }));
+    }
+
+    // Add main text content
+    content_blocks.push(serde_json::json!({
+        "type": "text",
+        "text": response.content
+    }));
+
+    let usage = response.usage.as_ref();
+
+    // `model` echoes the request; report the model that actually answered separately
+    let mut metadata = actual_model_metadata(&response.model);
+    metadata["aether_strategy"] = serde_json::json!(strategy.as_str());
+    if let Some(percent) = context_usage {
+        metadata["aether_context_usage"] = serde_json::json!(percent);
+    }
+
+    let mut http_response = Json(serde_json::json!({
+        "id": format!("msg_{}", &uuid::Uuid::new_v4().to_string().replace("-", "")[..24]),
+        "type": "message",
+        "role": "assistant",
+        "content": content_blocks,
+        "model": requested_model,
+        "stop_reason": if stop_sequence.is_some() { "stop_sequence" } else { response.finish_reason.as_str() },
+        "stop_sequence": stop_sequence,
+        "usage": anthropic_usage(
+            usage.map(|u| u.completion_tokens).unwrap_or(0),
+            usage.map(|u| u.thinking_tokens).unwrap_or(0),
+            usage.map(|u| u.prompt_tokens),
+        ),
+        "metadata": metadata
+    })).into_response();
+    http_response.headers_mut().insert(STRATEGY_HEADER, HeaderValue::from_static(strategy.as_str()));
+    insert_context_usage(&mut http_response, context_usage);
+    http_response
+}
+
+/// Stop sequences requested by the client: Anthropic `stop_sequences`, or
+/// OpenAI `stop` as a single string or an array
+fn stop_sequences(payload: &Value) -> Vec<String> {
+    let stops = payload.get("stop_sequences").or_else(|| payload.get("stop"));
+    let stops = match stops {
+        Some(Value::String(stop)) => vec![stop.clone()],
+        Some(Value::Array(stops)) => stops.iter().filter_map(|s| s.as_str().map(str::to_string)).collect(),
+        _ => Vec::new(),
+    };
+    stops.into_iter().filter(|s| !s.is_empty()).collect()
+}
+
+/// Cuts text off at the earliest of several stop sequences, holding back
+/// any tail that could still turn into one with the next chunk
+struct StopMatcher {
+    stops: Vec<String>,
+    held: String,
+    matched: Option<String>,
+}
+
+impl StopMatcher {
+    fn new(stops: Vec<String>) -> Self {
+        Self { stops, held: String::new(), matched: None }
+    }
+
+    /// Returns the text that is safe to emit. Once a stop sequence matches,
+    /// the text before it is returned and `matched` is set.
+    fn push(&mut self, text: &str) -> String {
+        if self.matched.is_some() {
+            return String::new();
+        }
+        self.held.push_str(text);
+
+        // Earliest occurrence wins; on a tie, the stop listed first
+        let first = self.stops.iter()
+            .filter_map(|stop| self.held.find(stop.as_str()).map(|at| (at, stop)))
+            .min_by_key(|(at, _)| *at);
+        if let Some((at, stop)) = first {
+            self.matched = Some(stop.clone());
+            let emit = self.held[..at].to_string();
+            self.held.clear();
+            return emit;
+        }
+
+        let keep_from = self.held.char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| self.stops.iter().any(|stop| stop.starts_with(&self.held[i..])))
+            .unwrap_or(self.held.len());
+        let emit = self.held[..keep_from].to_string();
+        self.held.drain(..keep_from);
+        emit
+    }
+
+    /// Releases whatever was held back
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// Ends a stream at the first stop sequence in its answer text, finishing
+/// with a done chunk that reports which one matched
+fn stop_at_sequences<S>(upstream: S, stops: Vec<String>) -> impl Stream<Item = anyhow::Result<browser_automator::StreamChunk>>
+where
+    S: Stream<Item = anyhow::Result<browser_automator::StreamChunk>>,
+{
+    use browser_automator::StreamChunk;
+
+    async_stream::stream! {
+        use futures_util::StreamExt;
+        tokio::pin!(upstream);
+
+        if stops.is_empty() {
+            while let Some(chunk) = upstream.next().await {
+                yield chunk;
+            }
+            return;
+        }
+
+        let mut matcher = StopMatcher::new(stops);
+        while let Some(chunk_res) = upstream.next().await {
+            let chunk = match chunk_res {
+                Ok(chunk) if !chunk.done && !chunk.is_thinking && !chunk.is_tool_use && chunk.candidate == 0 => chunk,
+                other => {
+                    // Release held text before anything that isn't answer text
+                    let held = matcher.flush();
+                    if !held.is_empty() {
+                        yield Ok(StreamChunk { delta: held, ..Default::default() });
+                    }
+                    yield other;
+                    continue;
+                }
+            };
+
+            let delta = matcher.push(&chunk.delta);
+            if !delta.is_empty() {
+                yield Ok(StreamChunk { delta, ..chunk });
+            }
+            if let Some(stop) = matcher.matched.take() {
+                yield Ok(StreamChunk { done: true, stop_sequence: Some(stop), ..Default::default() });
+                return;
+            }
+        }
+
+        let held = matcher.flush();
+        if !held.is_empty() {
+            yield Ok(StreamChunk { delta: held, ..Default::default() });
+        }
+    }
+}
+
+/// Truncates a complete response at its first stop sequence, returning the
+/// one that matched
+fn truncate_at_stop(content: &mut String, stops: Vec<String>) -> Option<String> {
+    let mut matcher = StopMatcher::new(stops);
+    let kept = matcher.push(content);
+    let stop = matcher.matched.take()?;
+    *content = kept;
+    Some(stop)
+}
+
 /// Maps Anthropic model IDs to Antigravity models
-fn map_anthropic_to_antigravity(model_id: &str) -> AntigravityModel {
+///
+/// Uses `known_anthropic_model`; guessing from the name is the last resort.
+fn map_anthropic_to_antigravity(config: &Config, model_id: &str) -> AntigravityModel {
+    known_anthropic_model(config, model_id).unwrap_or_else(|| {
+        tracing::debug!("Unknown Anthropic model {}, guessing the target from its name", model_id);
+        guess_antigravity_model(model_id)
+    })
+}
+
+/// The model serving an Anthropic model ID, without guessing
+///
+/// Looks the id up in `Config::anthropic_model_map`, then in
+/// `ANTHROPIC_MODEL_MAP` (ignoring a date or `-latest` suffix), then as an
+/// Antigravity id.
+pub(crate) fn known_anthropic_model(config: &Config, model_id: &str) -> Option<AntigravityModel> {
+    if let Some(model) = config.anthropic_model_map.get(model_id).and_then(|id| id.parse().ok()) {
+        return Some(model);
+    }
+    let base = anthropic_base_model_id(model_id);
+    if let Some((_, model)) = ANTHROPIC_MODEL_MAP.iter().find(|(id, _)| *id == base) {
+        return Some(*model);
+    }
+    model_id.parse().ok()
+}
+
+/// Known Anthropic model ids (without date suffix) and the model serving them
+const ANTHROPIC_MODEL_MAP: &[(&str, AntigravityModel)] = &[
+    ("claude-opus-4-5", AntigravityModel::ClaudeOpus45Thinking),
+    ("claude-opus-4-1", AntigravityModel::ClaudeOpus45Thinking),
+    ("claude-opus-4-0", AntigravityModel::ClaudeOpus45Thinking),
+    ("claude-opus-4", AntigravityModel::ClaudeOpus45Thinking),
+    ("claude-3-opus", AntigravityModel::ClaudeOpus45Thinking),
+    ("claude-sonnet-4-5", AntigravityModel::ClaudeSonnet45),
+    ("claude-sonnet-4-0", AntigravityModel::ClaudeSonnet45),
+    ("claude-sonnet-4", AntigravityModel::ClaudeSonnet45),
+    ("claude-3-7-sonnet", AntigravityModel::ClaudeSonnet45),
+    ("claude-3-5-sonnet", AntigravityModel::ClaudeSonnet45),
+    ("claude-3-sonnet", AntigravityModel::ClaudeSonnet45),
+    // Haiku → use Flash for speed
+    ("claude-haiku-4-5", AntigravityModel::Gemini3Flash),
+    ("claude-3-5-haiku", AntigravityModel::Gemini3Flash),
+    ("claude-3-haiku", AntigravityModel::Gemini3Flash),
+];
+
+/// Model id without a trailing `-YYYYMMDD` date or `-latest`
+fn anthropic_base_model_id(model_id: &str) -> &str {
+    if let Some(base) = model_id.strip_suffix("-latest") {
+        return base;
+    }
+    match model_id.rsplit_once('-') {
+        Some((base, date)) if date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => model_id,
+    }
+}
+
+/// Picks a model from the family named in an unknown model id
+fn guess_antigravity_model(model_id: &str) -> AntigravityModel {
     if model_id.contains("opus") {
         // Claude Opus models → Claude Opus 4.5 Thinking
         AntigravityModel::ClaudeOpus45Thinking
@@ -819,125 +1752,815 @@ fn map_anthropic_to_antigravity(model_id: &str) -> AntigravityModel {
     }
 }
 
-/// Returns the Gemini spoof model for a given Anthropic model
+/// Returns the default spoof model from the other family
+///
+/// Claude falls back to Gemini and Gemini to Claude, keeping thinking models
+/// paired with thinking models.
 fn get_spoof_model(model: AntigravityModel) -> Option<AntigravityModel> {
     match model {
         AntigravityModel::ClaudeOpus45Thinking => Some(AntigravityModel::Gemini3Pro),
         AntigravityModel::ClaudeSonnet45Thinking | AntigravityModel::ClaudeSonnet45 => Some(AntigravityModel::Gemini3Flash),
-        _ => None,
+        AntigravityModel::Gemini3Pro => Some(AntigravityModel::ClaudeSonnet45Thinking),
+        AntigravityModel::Gemini3Flash => Some(AntigravityModel::ClaudeSonnet45),
     }
 }
 
-/// Adapts thinking configuration when spoofing (e.g., mapping budget to level)
-fn adapt_config_for_spoof(
-    config: &Option<browser_automator::ThinkingConfig>,
-    target_model: AntigravityModel,
-) -> Option<browser_automator::ThinkingConfig> {
-    let mut new_config = config.clone();
+/// Returns the spoof model for `model`, with `Config::spoof_map` taking
+/// precedence over the defaults
+fn configured_spoof_model(config: &Config, model: AntigravityModel) -> Option<AntigravityModel> {
+    let target = match config.spoof_map.get(model.api_id()) {
+        Some(id) => id.parse().ok(),
+        None => get_spoof_model(model),
+    };
+    target.filter(|t| *t != model)
+}
 
-    if let Some(ref mut cfg) = new_config {
-        // If switching to Gemini (which uses level)
-        if !target_model.is_claude() {
-            // Flash doesn't support "high" or has different constraints. safely force medium.
-            if matches!(target_model, AntigravityModel::Gemini3Flash) {
-                 cfg.level = Some("medium".to_string());
-            } else if cfg.level.is_none() {
-                // Default to "high" for Pro if not specified
-                cfg.level = Some("high".to_string());
+/// Status, error type and message for a request no account could serve
+///
+/// `no_accounts` is the endpoint's own hint for a fresh install.
+fn no_account_error(failure: SelectionFailure, no_accounts: &str) -> (StatusCode, &'static str, String) {
+    match failure {
+        SelectionFailure::NoAccounts => (StatusCode::UNAUTHORIZED, "authentication_error", no_accounts.to_string()),
+        SelectionFailure::AllRefreshFailed => (
+            StatusCode::UNAUTHORIZED,
+            "authentication_error",
+            "Every Google account needs to log in again: their refresh tokens were revoked or have expired. \
+             Run 'aether login' (or press [L] in the TUI) for each account.".to_string(),
+        ),
+        SelectionFailure::AllDisabled => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "api_error",
+            "Every account that can serve this model is disabled. Re-enable one with \
+             POST /v1/admin/accounts/{email}/enable.".to_string(),
+        ),
+        SelectionFailure::Unavailable => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "api_error",
+            "No account is available for this model right now. Try again shortly.".to_string(),
+        ),
+    }
+}
+
+/// Gemini thinking level for an Anthropic thinking budget
+fn thinking_level_for_budget(thresholds: &ThinkingThresholds, budget: u32) -> &'static str {
+    if budget < thresholds.medium {
+        "low"
+    } else if budget < thresholds.high {
+        "medium"
+    } else {
+        "high"
+    }
+}
+
+/// The caller's end user: OpenAI `user` or Anthropic `metadata.user_id`
+fn request_user(payload: &Value) -> Option<&str> {
+    payload["user"].as_str().or_else(|| payload["metadata"]["user_id"].as_str())
+}
+
+/// Adds a completed request to the usage ledger, tagged with its user
+fn record_usage(ledger: &UsageLedger, payload: &Value, model_id: &str, usage: Option<&browser_automator::Usage>) {
+    ledger.record(UsageEntry {
+        at: chrono::Utc::now(),
+        user: request_user(payload).map(str::to_string),
+        model: model_id.to_string(),
+        prompt_tokens: usage.map(|u| u.prompt_tokens).unwrap_or(0),
+        completion_tokens: usage.map(|u| u.completion_tokens).unwrap_or(0),
+    });
+}
+
+/// Usage of a streamed response, added to the ledger when dropped: once
+/// the stream has finished, failed or been cancelled
+struct StreamUsage {
+    metrics: Arc<Metrics>,
+    user: Option<String>,
+    model: String,
+    /// Whether the upstream sent anything (a failed request isn't recorded)
+    answered: bool,
+    usage: Option<browser_automator::Usage>,
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        if !self.answered {
+            return;
+        }
+        self.metrics.usage.record(UsageEntry {
+            at: chrono::Utc::now(),
+            user: self.user.take(),
+            model: std::mem::take(&mut self.model),
+            prompt_tokens: self.usage.as_ref().map(|u| u.prompt_tokens).unwrap_or(0),
+            completion_tokens: self.usage.as_ref().map(|u| u.completion_tokens).unwrap_or(0),
+        });
+    }
+}
+
+/// Passes a response stream through, recording the last usage it reports
+/// like `record_usage` once the stream is done with
+fn record_stream_usage<S>(upstream: S, metrics: Arc<Metrics>, user: Option<String>, model: String) -> impl Stream<Item = anyhow::Result<browser_automator::StreamChunk>>
+where
+    S: Stream<Item = anyhow::Result<browser_automator::StreamChunk>>,
+{
+    async_stream::stream! {
+        use futures_util::StreamExt;
+        let mut recorded = StreamUsage { metrics, user, model, answered: false, usage: None };
+        tokio::pin!(upstream);
+        while let Some(chunk_res) = upstream.next().await {
+            if let Ok(chunk) = &chunk_res {
+                recorded.answered = true;
+                if chunk.usage.is_some() {
+                    recorded.usage = chunk.usage.clone();
+                }
             }
+            yield chunk_res;
         }
     }
+}
 
-    new_config
+/// Whether a rate-limit wait is too long to queue for this model's family
+fn exceeds_max_wait(config: &Config, model_id: &str, wait_secs: u64) -> bool {
+    let cap = match ModelFamily::from_model_id(model_id) {
+        ModelFamily::Claude => config.max_wait.claude,
+        ModelFamily::Gemini => config.max_wait.gemini,
+        // No cap of its own; use the stricter one
+        ModelFamily::Unknown => config.max_wait.claude.min(config.max_wait.gemini),
+    };
+    wait_secs > cap
 }
 
-/// Converts Anthropic message format to Antigravity format
-fn convert_anthropic_messages(payload: &Value) -> Vec<AntigravityMessage> {
-    let mut messages = Vec::new();
+/// Derives the key used to pin a conversation to one account
+///
+/// Uses `metadata.conversation_id`/`session_id` when the client sends one,
+/// otherwise a hash of the system prompt. `None` when affinity is disabled.
+fn conversation_key(config: &Config, payload: &Value) -> Option<String> {
+    if !config.conversation_affinity {
+        return None;
+    }
 
-    // Handle system prompt
-    let system_text = if let Some(system) = payload.get("system") {
-        if let Some(s) = system.as_str() {
-            s.to_string()
-        } else if let Some(arr) = system.as_array() {
-            // System can be array of content blocks
-            arr.iter()
-                .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
-                .collect::<Vec<_>>()
-                .join("\n")
-        } else {
-            String::new()
+    let metadata = &payload["metadata"];
+    let explicit = ["conversation_id", "session_id"].iter()
+        .find_map(|field| metadata[field].as_str().or_else(|| payload[field].as_str()))
+        .filter(|id| !id.is_empty());
+    if let Some(id) = explicit {
+        return Some(format!("conversation:{}", id));
+    }
+
+    // Anthropic puts the system prompt at the top level, OpenAI in the messages
+    let system = match payload.get("system") {
+        Some(system) => system,
+        None => payload["messages"].as_array()?
+            .iter()
+            .find(|m| m["role"] == "system")
+            .map(|m| &m["content"])?,
+    };
+
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    system.to_string().hash(&mut hasher);
+    Some(format!("system:{:016x}", hasher.finish()))
+}
+
+/// Creates an Antigravity client for an account, applying client options from config
+pub(crate) fn build_client(
+    config: &Config,
+    fingerprint: &Fingerprint,
+    context_cache: &Arc<ContextCache>,
+    response_cache: &Arc<ResponseCache>,
+    endpoint_stats: &Arc<EndpointStats>,
+    access_token: String,
+) -> anyhow::Result<AntigravityClient> {
+    let mut client = AntigravityClient::with_client_headers(
+        access_token,
+        config.project_id.clone(),
+        Some(fingerprint.clone()),
+        config.client_headers.clone(),
+    )?;
+    client.set_gemini_pro_default_tier(&config.gemini_pro_default_tier);
+    client.set_preserve_thinking_signatures(config.strict_anthropic_passthrough);
+    client.set_model_defaults(config.defaults.clone());
+    client.set_session_id_policy(config.session_id_policy);
+    client.set_clean_responses(config.clean_responses);
+    client.set_permission_retry_delay(config.permission_retry_delay_ms.map(std::time::Duration::from_millis));
+    client.set_request_timeout(std::time::Duration::from_secs(config.request_timeout_secs));
+    client.set_endpoint_stats(endpoint_stats.clone());
+    if config.context_cache.enabled {
+        client.set_context_cache(context_cache.clone());
+    }
+    if config.response_cache.enabled {
+        client.set_response_cache(response_cache.clone());
+    }
+    Ok(client)
+}
+
+/// The pooled client for an account, building it with `build_client` (and
+/// switching it to Gemini CLI headers for the dual quota) on first use
+async fn pooled_client(state: &AppState, account: &Account, style: HeaderStyle) -> anyhow::Result<Arc<AntigravityClient>> {
+    state.client_pool.get_or_build(&account.email, style, &account.access_token, &state.config, || async {
+        let mut client = build_client(&state.config, &state.fingerprint, &state.context_cache, &state.response_cache, &state.endpoint_stats, account.access_token.clone())?;
+        #[cfg(test)]
+        if let Some(url) = &state.upstream_endpoint {
+            client.set_endpoint(url.as_str());
         }
+        if style == HeaderStyle::GeminiCli {
+            client.set_quota_fallback(true).await;
+            client.switch_to_gemini_cli_headers().await?;
+        }
+        Ok(client)
+    }).await
+}
+
+/// Response header identifying a request, for profiles that expect one
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Adds `x-request-id` to responses when the client profile asks for it,
+/// echoing the client's own id if it sent one
+pub async fn client_profile_headers(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let request_id = if state.current().config.client_profile.request_id_header() {
+        request.headers().get(REQUEST_ID_HEADER).cloned()
+            .or_else(|| HeaderValue::try_from(format!("req_{}", uuid::Uuid::new_v4().simple())).ok())
     } else {
-        String::new()
+        None
     };
 
-    // Handle conversation messages
-    let mut conversation_messages: Vec<Value> = Vec::new();
-    if let Some(msgs) = payload.get("messages").and_then(|m| m.as_array()) {
-        conversation_messages = msgs.clone();
+    let mut response = next.run(request).await;
+    if let Some(request_id) = request_id {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
     }
+    response
+}
 
-    // Apply session recovery to fix corrupted conversation states
-    // This handles: tool_use without tool_result, thinking block order issues
-    let recovery_result = recover_session(&conversation_messages);
-    if recovery_result.was_recovered {
-        tracing::info!("{}", format_recovery_summary(&recovery_result));
-        conversation_messages = recovery_result.messages;
+/// Rejects requests without the configured `api_key`, sent as a bearer
+/// token or in `x-api-key` (OpenAI and Anthropic clients respectively)
+///
+/// The health endpoints stay open for monitoring.
+pub async fn require_api_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.current().config;
+    let Some(expected) = config.api_key.as_deref().filter(|key| !key.is_empty()) else {
+        return next.run(request).await;
+    };
+    if matches!(request.uri().path(), "/" | "/health") {
+        return next.run(request).await;
     }
 
-    // Add system message if present
-    if !system_text.is_empty() {
-        messages.push(AntigravityMessage {
-            role: "system".to_string(),
-            content: system_text,
-        });
+    if carries_api_key(request.headers(), expected) {
+        return next.run(request).await;
     }
 
-    // Convert recovered messages to Antigravity format
-    for msg in conversation_messages {
-        let role = msg.get("role").and_then(|r| r.as_str()).unwrap_or("user");
+    tracing::warn!("Rejected {} {} without a valid API key", request.method(), request.uri().path());
+    (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+        "type": "error",
+        "error": {
+            "type": "authentication_error",
+            "message": "Invalid or missing API key. Send it as 'Authorization: Bearer <key>' or 'x-api-key: <key>'."
+        }
+    }))).into_response()
+}
 
-        // Content can be string or array of content blocks
-        let content = if let Some(text) = msg.get("content").and_then(|c| c.as_str()) {
-            text.to_string()
-        } else if let Some(blocks) = msg.get("content").and_then(|c| c.as_array()) {
-            // Extract text from content blocks
-            blocks.iter()
-                .filter_map(|block| {
-                    if block.get("type").and_then(|t| t.as_str()) == Some("text") {
-                        block.get("text").and_then(|t| t.as_str())
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>()
-                .join("\n")
-        } else {
-            String::new()
-        };
+/// Whether the request sends `expected` as a Bearer token or `x-api-key`
+fn carries_api_key(headers: &HeaderMap, expected: &str) -> bool {
+    let bearer = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let api_key = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+    bearer == Some(expected) || api_key == Some(expected)
+}
 
-        if !content.is_empty() {
-            messages.push(AntigravityMessage {
-                role: role.to_string(),
-                content,
-            });
-        }
+/// Header routing a single request to a specific GCP project
+const PROJECT_ID_HEADER: &str = "x-goog-project-id";
+
+/// Takes the live config and applies per-request header overrides
+fn request_state(state: &AppState, headers: &HeaderMap) -> AppState {
+    let state = state.current();
+    match request_config(&state.config, headers) {
+        Some(config) => AppState { config, ..state },
+        None => state,
     }
+}
 
-    messages
+/// Fills in `Config::default_model` when the request names no model
+fn with_default_model(config: &Config, mut payload: Value) -> Value {
+    if let (Some(model), Some(body)) = (&config.default_model, payload.as_object_mut()) {
+        body.entry("model").or_insert_with(|| Value::String(model.clone()));
+    }
+    payload
 }
 
-/// Streaming version of /v1/messages endpoint
-/// Returns SSE events in Anthropic format: message_start, content_block_delta, message_stop
-async fn messages_streaming(
-    state: AppState,
-    payload: Value,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    // Generate message ID upfront
-    let message_id = format!("msg_{}", &uuid::Uuid::new_v4().to_string().replace("-", "")[..24]);
+/// Applies the configured transformers to a request body, in order
+///
+/// `anthropic` selects where system text goes: the top-level `system` field
+/// for Messages requests, a leading system message for OpenAI chat requests.
+fn apply_transformers(transformers: &[Transformer], mut payload: Value, anthropic: bool) -> Value {
+    fn tool_name(tool: &Value) -> Option<&str> {
+        tool["name"].as_str().or_else(|| tool["function"]["name"].as_str())
+    }
+
+    for transformer in transformers {
+        let Some(body) = payload.as_object_mut() else {
+            break;
+        };
+        match transformer {
+            Transformer::ForceTool { tool } => {
+                let Some(tools) = body.entry("tools").or_insert_with(|| Value::Array(Vec::new())).as_array_mut() else {
+                    continue;
+                };
+                if !tools.iter().any(|t| tool_name(t).is_some() && tool_name(t) == tool_name(tool)) {
+                    tools.push(tool.clone());
+                }
+            }
+            Transformer::StripToolByName { name } => {
+                if let Some(tools) = body.get_mut("tools").and_then(Value::as_array_mut) {
+                    tools.retain(|t| tool_name(t) != Some(name.as_str()));
+                }
+            }
+            Transformer::InjectSystem { text } if anthropic => {
+                let system = match body.remove("system") {
+                    Some(Value::String(existing)) if !existing.is_empty() => Value::String(format!("{}\n\n{}", text, existing)),
+                    Some(Value::Array(mut blocks)) => {
+                        blocks.insert(0, serde_json::json!({ "type": "text", "text": text }));
+                        Value::Array(blocks)
+                    }
+                    _ => Value::String(text.clone()),
+                };
+                body.insert("system".into(), system);
+            }
+            Transformer::InjectSystem { text } => {
+                if let Some(messages) = body.get_mut("messages").and_then(Value::as_array_mut) {
+                    messages.insert(0, serde_json::json!({ "role": "system", "content": text }));
+                }
+            }
+        }
+    }
+    payload
+}
+
+/// Header setting the upstream timeout for a single request
+const TIMEOUT_HEADER: &str = "x-aether-timeout-secs";
+
+/// Config for this request when `x-goog-project-id` or
+/// `x-aether-timeout-secs` is set
+///
+/// The header's project is forced for the request (no auto-discovery), so
+/// billing can be split across projects without editing the config. The
+/// timeout is capped at `max_request_timeout_secs`.
+fn request_config(config: &Config, headers: &HeaderMap) -> Option<Arc<Config>> {
+    let header = |name: &str| headers.get(name)?.to_str().ok().map(str::trim).filter(|v| !v.is_empty());
+    let project_id = header(PROJECT_ID_HEADER);
+    let timeout_secs = header(TIMEOUT_HEADER).and_then(|v| v.parse::<u64>().ok()).filter(|&secs| secs > 0);
+    if project_id.is_none() && timeout_secs.is_none() {
+        return None;
+    }
+
+    let mut config = config.clone();
+    if let Some(project_id) = project_id {
+        tracing::info!("Using project {} from {} header", project_id, PROJECT_ID_HEADER);
+        config.project_id = Some(project_id.to_string());
+    }
+    if let Some(secs) = timeout_secs {
+        config.request_timeout_secs = secs.min(config.max_request_timeout_secs);
+        tracing::debug!("Upstream timeout {}s from {} header", config.request_timeout_secs, TIMEOUT_HEADER);
+    }
+    Some(Arc::new(config))
+}
+
+/// Sampling parameters from an Anthropic or OpenAI request body
+fn generation_params(payload: &Value) -> GenerationParams {
+    GenerationParams {
+        temperature: payload["temperature"].as_f64().map(|t| t as f32),
+        top_p: payload["top_p"].as_f64().map(|p| p as f32),
+        tool_choice: ToolChoice::from_value(&payload["tool_choice"]),
+        candidate_count: payload["n"]
+            .as_u64()
+            .or_else(|| payload["candidateCount"].as_u64())
+            .and_then(|n| u32::try_from(n).ok()),
+        logprobs: payload["logprobs"].as_bool().unwrap_or(false),
+        top_logprobs: payload["top_logprobs"].as_u64().and_then(|n| u32::try_from(n).ok()),
+        // Gemini takes a 32-bit seed; larger ones are dropped rather than rejected
+        seed: payload["seed"].as_i64().and_then(|n| i32::try_from(n).ok()),
+        cache_system_prompt: payload["system"]
+            .as_array()
+            .is_some_and(|blocks| blocks.iter().any(|b| b.get("cache_control").is_some())),
+    }
+}
+
+/// Rejects `n > 1` on a streaming request, as OpenAI does
+fn streamed_candidates_error(payload: &Value, generation: &GenerationParams) -> Option<ApiError> {
+    let n = generation.candidate_count.filter(|n| *n > 1)?;
+    if !payload["stream"].as_bool().unwrap_or(false) {
+        return None;
+    }
+    Some(ApiError::new(StatusCode::BAD_REQUEST, serde_json::json!({
+        "error": {
+            "message": format!("n={} is not supported with stream=true", n),
+            "type": "invalid_request_error",
+            "param": "n"
+        }
+    })))
+}
+
+/// OpenAI `choices` for a response, one per returned candidate
+fn chat_choices(response: &ChatResponse) -> Value {
+    let contents = std::iter::once(&response.content).chain(&response.other_candidates);
+    Value::Array(contents.enumerate().map(|(index, content)| {
+        // Logprobs are only tracked for the first candidate
+        let logprobs = response.logprobs.as_deref().filter(|_| index == 0);
+        serde_json::json!({
+            "index": index,
+            "message": {
+                "role": "assistant",
+                "content": content
+            },
+            "logprobs": logprobs.map(openai_logprobs),
+            "finish_reason": response.finish_reason
+        })
+    }).collect())
+}
+
+/// OpenAI `logprobs` object for a choice
+fn openai_logprobs(tokens: &[TokenLogprob]) -> Value {
+    let entry = |token: &str, logprob: f64| serde_json::json!({
+        "token": token,
+        "logprob": logprob,
+        "bytes": token.as_bytes()
+    });
+    let content: Vec<Value> = tokens.iter().map(|t| {
+        let mut item = entry(&t.token, t.logprob);
+        item["top_logprobs"] = t.top.iter().map(|(token, logprob)| entry(token, *logprob)).collect();
+        item
+    }).collect();
+    serde_json::json!({ "content": content })
+}
+
+/// Whether thought summaries should be returned for this request
+///
+/// Anthropic `thinking.include_thoughts`, or a top-level `include_thoughts`
+/// for OpenAI-style clients, overrides `Config::include_thoughts`.
+fn include_thoughts(config: &Config, payload: &Value) -> bool {
+    payload["thinking"]["include_thoughts"].as_bool()
+        .or_else(|| payload["include_thoughts"].as_bool())
+        .unwrap_or(config.include_thoughts)
+}
+
+/// Waits for the first item of `primary`, switching to `fallback` if none arrives in `budget`
+///
+/// The primary stream is dropped (cancelling the upstream request) when the budget
+/// runs out. If the fallback can't be started, keeps waiting on the primary.
+/// Returns the stream to keep reading, its first item, and whether it fell back.
+async fn first_chunk_with_fallback<S, F, Fut>(
+    mut primary: Pin<Box<S>>,
+    budget: Option<std::time::Duration>,
+    fallback: F,
+) -> (Pin<Box<S>>, Option<S::Item>, bool)
+where
+    S: Stream,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<S>>,
+{
+    use futures_util::StreamExt;
+
+    let Some(budget) = budget else {
+        let first = primary.next().await;
+        return (primary, first, false);
+    };
+
+    match tokio::time::timeout(budget, primary.next()).await {
+        Ok(first) => (primary, first, false),
+        Err(_) => match fallback().await {
+            Ok(stream) => {
+                let mut stream = Box::pin(stream);
+                let first = stream.next().await;
+                (stream, first, true)
+            }
+            Err(e) => {
+                tracing::warn!("First-token fallback failed to start: {}. Waiting on primary model.", e);
+                let first = primary.next().await;
+                (primary, first, false)
+            }
+        },
+    }
+}
+
+/// Reads on from `first` (already read) until a chunk with content, an error
+/// or the end of the stream, returning the chunks read and whether the stream
+/// is exhausted
+async fn leading_chunks<S>(stream: &mut S, first: Option<anyhow::Result<browser_automator::StreamChunk>>) -> (Vec<anyhow::Result<browser_automator::StreamChunk>>, bool)
+where
+    S: Stream<Item = anyhow::Result<browser_automator::StreamChunk>> + Unpin,
+{
+    use futures_util::StreamExt;
+
+    let mut chunks = Vec::new();
+    let mut next = first;
+    loop {
+        let Some(item) = next else {
+            return (chunks, true);
+        };
+        let more = matches!(&item, Ok(chunk) if !chunk.has_content() && !chunk.done);
+        chunks.push(item);
+        if !more {
+            return (chunks, false);
+        }
+        next = stream.next().await;
+    }
+}
+
+/// Whether a stream ended before producing anything, going by its
+/// `leading_chunks` (empty text followed by the final chunk counts as nothing)
+fn is_empty_stream_start(leading: &[anyhow::Result<browser_automator::StreamChunk>]) -> bool {
+    match leading.last() {
+        None => true,
+        Some(Ok(chunk)) => !chunk.has_content(),
+        Some(Err(_)) => false,
+    }
+}
+
+/// Response header naming the fallback strategy that served a request
+const STRATEGY_HEADER: &str = "x-aether-strategy";
+
+/// Which step of the rate-limit fallback chain served a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FallbackStrategy {
+    /// The requested model on the selected account
+    Primary,
+    /// The downgrade or spoof model on the same account (Strategy 0 and 1)
+    SpoofSame,
+    /// The same model on the Gemini CLI quota pool (Strategy 1.5)
+    DualQuota,
+    /// Another account (Strategy 2)
+    Rotate,
+}
+
+impl FallbackStrategy {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::SpoofSame => "spoof-same",
+            Self::DualQuota => "dual-quota",
+            Self::Rotate => "rotate",
+        }
+    }
+}
+
+/// Metadata reporting which upstream model actually served a response
+///
+/// Kept out of `model` so clients that validate it against the request don't break
+/// when fallback/spoofing swapped the model.
+fn actual_model_metadata(actual_model: &str) -> Value {
+    serde_json::json!({ "aether_actual_model": actual_model })
+}
+
+/// Builds the final streaming `message_delta`, including the actually served model
+fn message_delta_event(stop_reason: &str, stop_sequence: Option<&str>, actual_model: &str, output_tokens: u32, thinking_tokens: u32) -> Value {
+    serde_json::json!({
+        "type": "message_delta",
+        "delta": { "stop_reason": stop_reason, "stop_sequence": stop_sequence },
+        "usage": anthropic_usage(output_tokens, thinking_tokens, None),
+        "metadata": actual_model_metadata(actual_model)
+    })
+}
+
+/// Emit a progress `message_delta` each time the running estimate grows by this many tokens
+const USAGE_DELTA_INTERVAL_TOKENS: u32 = 256;
+
+/// Mid-stream `message_delta` carrying only the running token estimates
+fn usage_progress_event(output_tokens: u32, thinking_tokens: u32) -> Value {
+    serde_json::json!({
+        "type": "message_delta",
+        "delta": { "stop_reason": null, "stop_sequence": null },
+        "usage": anthropic_usage(output_tokens, thinking_tokens, None)
+    })
+}
+
+/// Anthropic `usage` object
+///
+/// Thinking consumption goes in a non-standard `thinking_tokens` field, only
+/// present once the model has thought.
+fn anthropic_usage(output_tokens: u32, thinking_tokens: u32, input_tokens: Option<u32>) -> Value {
+    let mut usage = serde_json::json!({ "output_tokens": output_tokens });
+    if let Some(input) = input_tokens {
+        usage["input_tokens"] = serde_json::json!(input);
+    }
+    if thinking_tokens > 0 {
+        usage["thinking_tokens"] = serde_json::json!(thinking_tokens);
+    }
+    usage
+}
+
+/// Seconds to hold off an account after a rate-limit or capacity error
+///
+/// A 429 that came without a retry hint (`RATE_LIMITED::…`) waits until the
+/// account's learned daily quota reset, if one has been observed; anything
+/// else without a hint waits 60 seconds.
+async fn rate_limit_seconds(account_manager: &AccountManager, index: usize, error_str: &str) -> u64 {
+    if let Some(seconds) = error_str.split(':').nth(1).and_then(|s| s.parse::<u64>().ok()) {
+        return seconds;
+    }
+    if error_str.starts_with("RATE_LIMITED::") {
+        let now = chrono::Utc::now();
+        if let Some(reset) = account_manager.predicted_reset(index, now).await {
+            tracing::info!("No retry hint on 429; waiting for account {}'s learned quota reset at {}", index, reset);
+            return (reset - now).num_seconds().max(1) as u64;
+        }
+    }
+    60
+}
+
+/// Returns the next enabled same-family model after `model` in
+/// `Config::downgrade_chain`
+fn downgrade_target(config: &Config, model: AntigravityModel) -> Option<AntigravityModel> {
+    let chain: Vec<AntigravityModel> = config.downgrade_chain.iter().filter_map(|id| id.parse().ok()).collect();
+    let position = chain.iter().position(|m| *m == model)?;
+    chain[position + 1..].iter().copied()
+        .find(|m| m.is_claude() == model.is_claude() && antigravity_model_enabled(config, *m))
+}
+
+/// Returns the substitute for a rate-limited model: a configured same-family
+/// downgrade first, otherwise the spoof model if substitution is enabled
+fn spoof_target(config: &Config, model: AntigravityModel) -> Option<AntigravityModel> {
+    if let Some(downgrade) = downgrade_target(config, model) {
+        return Some(downgrade);
+    }
+    if !config.enable_spoofing {
+        return None;
+    }
+    configured_spoof_model(config, model)
+}
+
+/// Strategy 0: pre-emptively switches a rate-limited model to its spoof model
+///
+/// A configured same-family downgrade (`downgrade_chain`) is preferred over the
+/// cross-family spoof, but rate limits are tracked per family, so it is only
+/// taken on an account where that family isn't limited. The cross-family
+/// spoof picks an account ignoring rate limits, since the substitute has its
+/// own quota. Returns `None` when pre-emptive spoofing is disabled, there is
+/// no substitute, or no account is usable.
+async fn preemptive_spoof(
+    config: &Config,
+    account_manager: &AccountManager,
+    model: AntigravityModel,
+) -> Option<(Account, AntigravityModel)> {
+    if !config.preemptive_spoof_enabled() {
+        tracing::info!("Pre-emptive spoofing disabled, not substituting {:?}", model);
+        return None;
+    }
+
+    if let Some(downgrade) = downgrade_target(config, model) {
+        if let Some(acc) = account_manager.get_available_account_for_model(downgrade.api_id()).await {
+            tracing::info!("Primary model rate limited. Downgrading {:?} to {:?}", model, downgrade);
+            return Some((acc, downgrade));
+        }
+        tracing::info!("Downgrade {:?} is rate limited on every account too", downgrade);
+    }
+
+    tracing::info!("Primary model rate limited. Checking Strategy 0 fallback for {:?}", model);
+    let Some(spoof_model) = configured_spoof_model(config, model) else {
+        tracing::info!("No spoof model defined for {:?}, skipping Strategy 0.", model);
+        return None;
+    };
+
+    tracing::info!("Spoof model available: {:?}", spoof_model);
+    let family = ModelFamily::from_model_id(spoof_model.api_id());
+    match account_manager.get_available_account_ignoring_rate_limit(family).await {
+        Some(acc) => Some((acc, spoof_model)),
+        None => {
+            tracing::warn!("Strategy 0 Failed: Could not find ANY account (even ignoring rate limits) to try spoofing.");
+            None
+        }
+    }
+}
+
+/// Adapts thinking configuration when spoofing (e.g., mapping budget to level)
+fn adapt_config_for_spoof(
+    config: &Option<browser_automator::ThinkingConfig>,
+    target_model: AntigravityModel,
+) -> Option<browser_automator::ThinkingConfig> {
+    let mut new_config = config.clone();
+
+    if let Some(ref mut cfg) = new_config {
+        // If switching to Gemini (which uses level)
+        if !target_model.is_claude() {
+            // Flash doesn't support "high" or has different constraints. safely force medium.
+            if matches!(target_model, AntigravityModel::Gemini3Flash) {
+                 cfg.level = Some("medium".to_string());
+            } else if cfg.level.is_none() {
+                // Default to "high" for Pro if not specified
+                cfg.level = Some("high".to_string());
+            }
+        }
+    }
+
+    new_config
+}
+
+/// Converts Anthropic message format to Antigravity format
+fn convert_anthropic_messages(payload: &Value) -> Vec<AntigravityMessage> {
+    let mut messages = Vec::new();
+
+    // Handle system prompt
+    let system_text = if let Some(system) = payload.get("system") {
+        if let Some(s) = system.as_str() {
+            s.to_string()
+        } else if let Some(arr) = system.as_array() {
+            // System can be array of content blocks
+            arr.iter()
+                .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            String::new()
+        }
+    } else {
+        String::new()
+    };
+
+    // Handle conversation messages
+    let mut conversation_messages: Vec<Value> = Vec::new();
+    if let Some(msgs) = payload.get("messages").and_then(|m| m.as_array()) {
+        conversation_messages = msgs.clone();
+    }
+
+    // Apply session recovery to fix corrupted conversation states
+    // This handles: tool_use without tool_result, thinking block order issues
+    let recovery_result = recover_session(&conversation_messages);
+    if recovery_result.was_recovered {
+        tracing::info!("{}", format_recovery_summary(&recovery_result));
+        conversation_messages = recovery_result.messages;
+    }
+
+    // Add system message if present
+    if !system_text.is_empty() {
+        messages.push(AntigravityMessage::system(system_text));
+    }
+
+    // Convert recovered messages to Antigravity format
+    for msg in conversation_messages {
+        let role = msg.get("role").and_then(|r| r.as_str()).unwrap_or("user");
+
+        // Keep signed thinking blocks; the client decides whether to send them
+        // (only in strict passthrough mode, and only to Claude)
+        let thinking: Vec<ThinkingBlock> = if role == "assistant" {
+            msg.get("content")
+                .and_then(|c| c.as_array())
+                .map(|blocks| blocks.iter().filter_map(signed_thinking_block).collect())
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        // Content can be string or array of content blocks
+        let content = if let Some(text) = msg.get("content").and_then(|c| c.as_str()) {
+            text.to_string()
+        } else if let Some(blocks) = msg.get("content").and_then(|c| c.as_array()) {
+            // Extract text from content blocks
+            blocks.iter()
+                .filter_map(|block| {
+                    if block.get("type").and_then(|t| t.as_str()) == Some("text") {
+                        block.get("text").and_then(|t| t.as_str())
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            String::new()
+        };
+
+        if !content.is_empty() || !thinking.is_empty() {
+            messages.push(AntigravityMessage {
+                role: role.to_string(),
+                content,
+                thinking,
+            });
+        }
+    }
+
+    messages
+}
+
+/// Extracts a well-formed `thinking` content block (non-empty text and signature)
+fn signed_thinking_block(block: &Value) -> Option<ThinkingBlock> {
+    if block.get("type").and_then(|t| t.as_str()) != Some("thinking") {
+        return None;
+    }
+    let thinking = block.get("thinking").and_then(|t| t.as_str()).filter(|t| !t.is_empty())?;
+    let signature = block.get("signature").and_then(|s| s.as_str()).filter(|s| !s.is_empty())?;
+    Some(ThinkingBlock {
+        thinking: thinking.to_string(),
+        signature: signature.to_string(),
+    })
+}
+
+/// Streaming version of /v1/messages endpoint
+/// Returns SSE events in Anthropic format: message_start, content_block_delta, message_stop
+async fn messages_streaming(
+    state: AppState,
+    payload: Value,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Generate message ID upfront
+    let message_id = format!("msg_{}", &uuid::Uuid::new_v4().to_string().replace("-", "")[..24]);
     let requested_model = payload["model"].as_str().unwrap_or("claude-3-5-sonnet-20241022").to_string();
-    let model = map_anthropic_to_antigravity(&requested_model);
+    let model = map_anthropic_to_antigravity(&state.config, &requested_model);
+    let request_start = std::time::Instant::now();
+    let stops = stop_sequences(&payload);
 
     // Check for thinking mode
     let thinking_enabled = payload.get("thinking").is_some()
@@ -945,11 +2568,17 @@ async fn messages_streaming(
 
     // Clone state for async move
     let account_manager = state.account_manager.clone();
-    let project_id = state.config.project_id.clone();
-    let fingerprint = state.fingerprint.clone();
+    let config = state.config.clone();
+    let affinity_key = conversation_key(&config, &payload);
+    let generation = generation_params(&payload);
+    let cancel = state.cancellations.register(&message_id);
+    let metrics = state.metrics.clone();
+    let user = request_user(&payload).map(str::to_string);
 
     // Create the stream
     let stream = async_stream::stream! {
+        use futures_util::StreamExt;
+
         // 1. Emit message_start IMMEDIATELY to ack connection
         let message_start = serde_json::json!({
             "type": "message_start",
@@ -969,35 +2598,15 @@ async fn messages_streaming(
         });
         yield Ok(Event::default().event("message_start").data(message_start.to_string()));
 
-        // 2. Start a "System Log" block to report status (as text so it's visible)
-        let mut block_index = 0;
-        let status_block_index = block_index;
-        // Track whether status block is still open - critical for preventing malformed SSE
-        // Note: Compiler warns about unused assignments due to async stream macro limitations,
-        // but this variable IS read across yield boundaries in error handling paths
-        #[allow(unused_assignments)]
-        let mut status_block_open = true;
-
-        // Use a text block for status updates because 'thinking' blocks are often hidden/collapsed in UIs
-        let block_start = serde_json::json!({
-            "type": "content_block_start",
-            "index": status_block_index,
-            "content_block": {
-                "type": "text",
-                "text": ""
-            }
-        });
-        yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-
-        // Helper to send status text
-        let status_msg = "> **AetherBridge System Log**\n> Finding available account...\n";
-        let delta = serde_json::json!({
-             "type": "content_block_delta",
-             "index": status_block_index,
-             "delta": { "type": "text_delta", "text": status_msg }
-        });
-        yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
+        // 2. Start a "System Log" block to report status (as text so it's visible,
+        // 'thinking' blocks are often hidden/collapsed in UIs). Routine progress
+        // only shows with `show_system_log`; waits and fallbacks show unless the
+        // client profile turns the log off. All block indices go through
+        // `blocks` so starts and stops always pair up.
+        let mut blocks = BlockSequencer::for_profile(config.client_profile).with_progress_log(config.show_system_log);
+        for event in blocks.progress("> **AetherBridge System Log**\n> Finding available account...\n") {
+            yield Ok(sse_event(event));
+        }
 
         // 3. Get Account Loop with Status Updates
         let mut model = model; // Make mutable for spoofing
@@ -1005,42 +2614,33 @@ async fn messages_streaming(
         let mut used_fallback = false;
         // Track the original model for rate limit clearing
         let original_model = model;
-        let account = loop {
-             match account_manager.get_available_account().await {
-                Some(acc) => break acc,
+        let mut turn = None;
+        let (account, _slot) = loop {
+             match account_manager.next_in_line(&mut turn, affinity_key.as_deref(), model.api_id()).await {
+                Some(selected) => break selected,
                 None => {
                     // Check for Pre-emptive Spoofing (Strategy 0)
-                    tracing::info!("Primary model rate limited. Checking Strategy 0 fallback for {:?}", model);
-                    if let Some(spoof_model) = get_spoof_model(model) {
-                         tracing::info!("Spoof model available: {:?}", spoof_model);
-                          if let Some(acc) = account_manager.get_available_account_ignoring_rate_limit().await {
-                              // Log the pre-emptive switch with clear messaging about which model is rate limited
-                              tracing::info!("Strategy 0: {} is rate limited. Spoofing to {} on account {}", model.display_name(), spoof_model.display_name(), acc.email);
-                              let msg = format!("> ⚠️  {} is currently rate limited.\n> 🔄  Switching to {} (fallback model) on account {}...\n", model.display_name(), spoof_model.display_name(), acc.email);
-                              let delta = serde_json::json!({
-                                   "type": "content_block_delta",
-                                   "index": status_block_index,
-                                   "delta": { "type": "text_delta", "text": msg }
-                              });
-                              yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
-                              // Swap model and mark that we used a fallback
-                              model = spoof_model;
-                              used_fallback = true;
-                              break acc;
-                         } else {
-                             tracing::warn!("Strategy 0 Failed: Could not find ANY account (even ignoring rate limits) to try spoofing.");
-                         }
-                    } else {
-                        tracing::info!("No spoof model defined for {:?}, skipping Strategy 0.", model);
+                    if let Some((acc, spoof_model)) = preemptive_spoof(&config, &account_manager, model).await {
+                        // Log the pre-emptive switch with clear messaging about which model is rate limited
+                        tracing::info!("Strategy 0: {} is rate limited. Spoofing to {} on account {}", model.display_name(), spoof_model.display_name(), acc.email);
+                        let msg = format!("> ⚠️  {} is currently rate limited.\n> 🔄  Switching to {} (fallback model) on account {}...\n", model.display_name(), spoof_model.display_name(), acc.email);
+                        for event in blocks.status(&msg) {
+                            yield Ok(sse_event(event));
+                        }
+
+                        // Swap model and mark that we used a fallback
+                        model = spoof_model;
+                        used_fallback = true;
+                        let slot = account_manager.acquire_slot(acc.index).await;
+                        break (acc, slot);
                     }
 
                     if let Some(wait_time) = account_manager.get_min_wait_time_for_model(&requested_model).await {
                         let wait_secs = wait_time.as_secs();
-                        if wait_secs > 600 {
-                            // Close status block
-                            let block_stop = serde_json::json!({ "type": "content_block_stop", "index": status_block_index });
-                            yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
+                        if exceeds_max_wait(&config, &requested_model, wait_secs) {
+                            for event in blocks.close() {
+                                yield Ok(sse_event(event));
+                            }
 
                             // Report Error
                             let error_event = serde_json::json!({
@@ -1056,26 +2656,26 @@ async fn messages_streaming(
 
                         // Report waiting status
                         let msg = format!("> Rate limited. Queuing for {} seconds...\n", wait_secs);
-                        let delta = serde_json::json!({
-                             "type": "content_block_delta",
-                             "index": status_block_index,
-                             "delta": { "type": "text_delta", "text": msg }
-                        });
-                        yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
-                        tokio::time::sleep(wait_time + std::time::Duration::from_secs(1)).await;
+                        for event in blocks.status(&msg) {
+                            yield Ok(sse_event(event));
+                        }
+
+                        account_manager.wait_turn(&mut turn, model.api_id(), wait_time + std::time::Duration::from_secs(1)).await;
                         continue;
                     }
 
-                    // No accounts configured
-                     let block_stop = serde_json::json!({ "type": "content_block_stop", "index": status_block_index });
-                     yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
+                    // No accounts configured, or none usable
+                    for event in blocks.close() {
+                        yield Ok(sse_event(event));
+                    }
 
+                    let failure = account_manager.selection_failure(model.api_id()).await;
+                    let (_, kind, message) = no_account_error(failure, "No Google accounts configured. Run AetherBridge TUI and press [L] to login.");
                     let error_event = serde_json::json!({
                         "type": "error",
                         "error": {
-                            "type": "authentication_error",
-                            "message": "No Google accounts configured. Run AetherBridge TUI and press [L] to login."
+                            "type": kind,
+                            "message": message
                         }
                     });
                     yield Ok(Event::default().event("error").data(error_event.to_string()));
@@ -1083,25 +2683,24 @@ async fn messages_streaming(
                 }
             }
         };
+        drop(turn);
 
         tracing::info!("Streaming with account: {}", account.email);
+        account_manager.pace(account.index).await;
 
         // Report Processing
         let msg = format!("> Using account: {}. Generating response...\n\n", account.email);
-        let delta = serde_json::json!({
-                "type": "content_block_delta",
-                "index": status_block_index,
-                "delta": { "type": "text_delta", "text": msg }
-        });
-        yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
+        for event in blocks.progress(&msg) {
+            yield Ok(sse_event(event));
+        }
 
         // 4. Create Client
-        let client = match AntigravityClient::new(account.access_token.clone(), project_id.clone(), Some((*fingerprint).clone())) {
+        let client = match pooled_client(&state, &account, HeaderStyle::Antigravity).await {
             Ok(c) => c,
             Err(e) => {
-                let block_stop = serde_json::json!({ "type": "content_block_stop", "index": status_block_index });
-                yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
+                for event in blocks.close() {
+                    yield Ok(sse_event(event));
+                }
 
                 let error_event = serde_json::json!({
                     "type": "error",
@@ -1115,13 +2714,10 @@ async fn messages_streaming(
             }
         };
 
-        // Close our status block so the real answer starts clean (or continues?)
-        // Let's close it so the real answer is distinct.
-        let block_stop = serde_json::json!({ "type": "content_block_stop", "index": status_block_index });
-        yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-        // Mark that status block is now closed - subsequent status messages need a new block
-        status_block_open = false;
-        block_index += 1;
+        // Close our status block so the real answer is distinct
+        for event in blocks.close() {
+            yield Ok(sse_event(event));
+        }
 
         // 5. Convert Messages & Config
         let messages = convert_anthropic_messages(&payload);
@@ -1133,19 +2729,13 @@ async fn messages_streaming(
                  .get("budget_tokens")
                  .and_then(|v| v.as_u64())
                  .map(|v| v as u32)
-                 .or(Some(10000)); // Default budget
-
-             // FIXED: Map budget to level for Gemini fallbacks
-             let level = if let Some(b) = budget {
-                if b < 5000 { "low" } else if b < 15000 { "medium" } else { "high" }
-             } else {
-                "low"
-             };
+                 .unwrap_or(10000); // Default budget
 
+             // Map budget to level for Gemini fallbacks
              Some(browser_automator::ThinkingConfig {
-                 budget: budget,
-                 level: Some(level.to_string()),
-                 include_thoughts: true,
+                 budget: Some(budget),
+                 level: Some(thinking_level_for_budget(&config.thinking_thresholds, budget).to_string()),
+                 include_thoughts: include_thoughts(&config, &payload),
              })
         } else {
             None
@@ -1154,150 +2744,101 @@ async fn messages_streaming(
         // 6. Make API Streaming Request
         tracing::info!("Starting streaming request to Antigravity model: {:?}", model);
         let start_time = std::time::Instant::now();
-        let result = client.chat_completion_stream(model, messages.clone(), thinking_config.clone(), tools.clone()).await;
+        let result = client.chat_completion_stream(model, messages.clone(), thinking_config.clone(), tools.clone(), &generation).await;
 
         match result {
-            Ok(output_stream) => { // Removed mut here, pin! handles it
+            Ok(output_stream) => {
                  // Only clear rate limit if the PRIMARY request succeeded (not fallback)
                  // This prevents clearing the wrong model's rate limit when spoofing
                  if !used_fallback {
                      account_manager.clear_rate_limit(account.index, ModelFamily::from_model_id(&original_model.api_id().to_string())).await;
                  }
 
-                 use futures_util::StreamExt;
-                 // Pin the stream so we can call next()
-                 tokio::pin!(output_stream);
-
-                 // We will simply stream everything into a single text block to guarantee visibility.
-                 // System logs (index 0) are closed. We start index 1.
-                 let mut text_index = block_index;
+                 // Boxed so a fallback stream can replace it
+                 let output_stream = Box::pin(output_stream);
+
+                 // First-token budget: if the primary model is too slow to start, switch to the faster model
+                 let fallback_model = spoof_target(&config, model);
+                 let budget = config.first_token_timeout_ms
+                     .filter(|_| fallback_model.is_some())
+                     .map(std::time::Duration::from_millis);
+                 let (mut output_stream, first_chunk, fell_back) = first_chunk_with_fallback(output_stream, budget, || {
+                     let fallback_model = fallback_model.unwrap_or(model);
+                     let fallback_config = adapt_config_for_spoof(&thinking_config, fallback_model);
+                     client.chat_completion_stream(fallback_model, messages.clone(), fallback_config, tools.clone(), &generation)
+                 }).await;
+
+                 if let Some(fallback_model) = fallback_model.filter(|_| fell_back) {
+                     tracing::warn!("No first token from {} within {:?}. Fell back to {}", model.display_name(), budget, fallback_model.display_name());
+                     let msg = format!("> ⏱️  {} didn't respond within {} ms.\n> 🔄  Switched to {} (faster model).\n\n", model.display_name(), config.first_token_timeout_ms.unwrap_or_default(), fallback_model.display_name());
+                     for event in blocks.status(&msg) {
+                         yield Ok(sse_event(event));
+                     }
+                     model = fallback_model;
+                 }
 
-                 let block_start = serde_json::json!({
-                    "type": "content_block_start",
-                    "index": text_index,
-                    "content_block": { "type": "text", "text": "" }
-                 });
-                 yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-
-                  let mut inside_thought = false;
-                  let mut has_tool_use = false; // Track if we encountered tool_use for stop_reason
-
-                  while let Some(chunk_res) = output_stream.next().await {
-                     match chunk_res {
-                         Ok(chunk) => {
-                             if chunk.done { break; }
-
-                              if chunk.is_tool_use {
-                                  has_tool_use = true; // Mark that we have tool_use for stop_reason
-                                  
-                                  // Close current text block if open
-                                  let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                                  yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                                  // Increment block index for tool use
-                                  text_index += 1; // Actually tool_index, but we reuse the variable for sequential indexing
-
-                                 // Parse tool use JSON
-                                 if let Ok(mut tool_json) = serde_json::from_str::<Value>(&chunk.delta) {
-                                      // Extract input for delta
-                                      let input_obj = tool_json.get("input").cloned().unwrap_or(json!({}));
-                                      // Remove input from start block (or set to empty)
-                                      if let Some(obj) = tool_json.as_object_mut() {
-                                           obj.insert("input".to_string(), json!({}));
-                                      }
-
-                                      let block_start = serde_json::json!({
-                                          "type": "content_block_start",
-                                          "index": text_index,
-                                          "content_block": tool_json
-                                      });
-                                      yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-
-                                      // Emit input as delta
-                                      let input_str = serde_json::to_string(&input_obj).unwrap_or_default();
-                                      let delta = serde_json::json!({
-                                          "type": "content_block_delta",
-                                          "index": text_index,
-                                          "delta": { "type": "input_json_delta", "partial_json": input_str }
-                                      });
-                                      yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
-                                      // Evaluate block stop immediately as tools are atomic in this stream logic
-                                      let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                                      yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                                      // Prepare for next text block
-                                      text_index += 1;
-                                      let block_start = serde_json::json!({
-                                          "type": "content_block_start",
-                                          "index": text_index,
-                                          "content_block": { "type": "text", "text": "" }
-                                      });
-                                      yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-                                 }
-                             } else {
-                                 // Normal text/thinking processing
-                                 let mut text_to_emit = chunk.delta;
-
-                                 // Optional: Visual indication of thinking vs answer
-                                 if chunk.is_thinking {
-                                     if !inside_thought {
-                                         // Start of a thought sequence
-                                         text_to_emit = format!("\n> *Thinking: {}*", text_to_emit);
-                                         inside_thought = true;
-                                     } else {
-                                         // Continue thought - maybe italicize?
-                                         // Markdown within a stream is tricky, usually we just dump text.
-                                         // Let's just dump it. formatting every chunk is risky.
-                                     }
-                                 } else {
-                                     if inside_thought {
-                                         // End of thought sequence
-                                         text_to_emit = format!("\n\n{}", text_to_emit);
-                                         inside_thought = false;
-                                     }
-                                 }
+                 let (leading, exhausted) = leading_chunks(&mut output_stream, first_chunk).await;
 
-                                 let delta = serde_json::json!({
-                                    "type": "content_block_delta",
-                                    "index": text_index,
-                                    "delta": { "type": "text_delta", "text": text_to_emit }
-                                 });
-                                 yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
+                 // A stream that ends before any content gets one try on the fallback model
+                 let (output_stream, leading, exhausted) = match fallback_model.filter(|_| !fell_back && is_empty_stream_start(&leading)) {
+                     Some(retry_model) => {
+                         tracing::warn!("{} returned an empty response. Retrying with {}", model.display_name(), retry_model.display_name());
+                         let msg = format!("> ⚠️  {} returned an empty response.\n> 🔄  Retrying with {}...\n\n", model.display_name(), retry_model.display_name());
+                         for event in blocks.status(&msg) {
+                             yield Ok(sse_event(event));
+                         }
+                         let retry_config = adapt_config_for_spoof(&thinking_config, retry_model);
+                         match client.chat_completion_stream(retry_model, messages.clone(), retry_config, tools.clone(), &generation).await {
+                             Ok(stream) => {
+                                 let mut stream = Box::pin(stream);
+                                 let first = stream.next().await;
+                                 let (leading, exhausted) = leading_chunks(&mut stream, first).await;
+                                 model = retry_model;
+                                 (stream, leading, exhausted)
                              }
-                         },
-                         Err(e) => {
-                             let err_msg = e.to_string();
-                             tracing::error!("Stream chunk error: {}", err_msg);
-                             let error_event = serde_json::json!({
-                                "type": "error",
-                                "error": { "type": "api_error", "message": err_msg }
-                            });
-                            yield Ok(Event::default().event("error").data(error_event.to_string()));
-                            return;
+                             Err(e) => {
+                                 tracing::warn!("Empty-response retry failed to start: {}", e);
+                                 (output_stream, leading, exhausted)
+                             }
+                         }
+                     }
+                     None => (output_stream, leading, exhausted),
+                 };
+
+                 // Still nothing: tell the client instead of ending on a blank answer
+                 if is_empty_stream_start(&leading) {
+                     let message = format!("{} returned an empty response", model.display_name());
+                     tracing::warn!("{}", message);
+                     if config.empty_response_error {
+                         for event in blocks.close() {
+                             yield Ok(sse_event(event));
                          }
+                         yield Ok(sse_event(("error", serde_json::json!({
+                             "type": "error",
+                             "error": { "type": "api_error", "message": message }
+                         }))));
+                         return;
                      }
+                     for event in blocks.text(&format!("> ⚠️  {}.\n", message)) {
+                         yield Ok(sse_event(event));
+                     }
+                 }
+
+                 // Put the already-read chunks back in front
+                 let rest = (!exhausted).then_some(output_stream);
+                 let upstream = futures_util::stream::iter(leading)
+                     .chain(futures_util::stream::iter(rest).flatten());
+
+                 let upstream = record_stream_usage(upstream, metrics.clone(), user, requested_model.clone());
+                 let upstream = stop_at_sequences(upstream, stops.clone());
+                 let events = anthropic_content_events(upstream, blocks, model.api_id().to_string(), config.preserve_partial_on_error);
+                 let events = record_first_token(events, request_start, &metrics.first_token_latency);
+                 tokio::pin!(events);
+                 while let Some(event) = events.next().await {
+                     yield Ok(sse_event(event));
                  }
 
-                 let elapsed = start_time.elapsed();
-                 tracing::info!("Stream finished in {:.2?}", elapsed);
-
-                 // Close text block
-                 let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                 yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                  // Message Delta and Stop
-                  // Use correct stop_reason: "tool_use" if tools were called, "end_turn" otherwise
-                  let stop_reason = if has_tool_use { "tool_use" } else { "end_turn" };
-                  let message_delta = serde_json::json!({
-                     "type": "message_delta",
-                     "delta": { "stop_reason": stop_reason, "stop_sequence": null },
-                     "usage": { "output_tokens": 0 }
-                  });
-                  yield Ok(Event::default().event("message_delta").data(message_delta.to_string()));
-
-                 let message_stop = serde_json::json!({ "type": "message_stop" });
-                 yield Ok(Event::default().event("message_stop").data(message_stop.to_string()));
+                 tracing::info!("Stream finished in {:.2?}", start_time.elapsed());
             }
             Err(e) => {
                 let error_str = e.to_string();
@@ -1305,9 +2846,8 @@ async fn messages_streaming(
 
                 // Rate Limit & Capacity Error Handling
                 if error_str.starts_with("RATE_LIMITED:") || error_str.starts_with("CAPACITY_ERROR:") {
-                     let parts: Vec<&str> = error_str.splitn(3, ':').collect();
-                     let seconds = parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(60);
-                     
+                     let seconds = rate_limit_seconds(&account_manager, account.index, &error_str).await;
+
                      // Use longer backoff for capacity errors
                      let is_capacity = error_str.starts_with("CAPACITY_ERROR:");
                      let effective_seconds = if is_capacity {
@@ -1315,218 +2855,471 @@ async fn messages_streaming(
                      } else {
                          seconds
                      };
-                     
+
                      let until = chrono::Utc::now() + chrono::Duration::seconds(effective_seconds as i64);
                      account_manager.mark_rate_limited(account.index, ModelFamily::from_model_id(&model.api_id().to_string()), until).await;
 
-                       // Strategy 1: Spoofing Fallback
-                       if let Some(spoof_model) = get_spoof_model(model) {
-                           // Mark that we used a fallback strategy
-                           used_fallback = true;
-                           
-                           // Determine which block index to use for fallback status messages
-                           // If original status block is closed, we need to open a new one
-                           let fallback_status_index = if status_block_open {
-                               // Use the original status block
-                               status_block_index
-                           } else {
-                               // Open a new status block since the original is closed
-                               block_index += 1;
-                               let block_start = serde_json::json!({
-                                   "type": "content_block_start",
-                                   "index": block_index,
-                                   "content_block": { "type": "text", "text": "" }
-                               });
-                               yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-                               block_index // Use the new block index
-                           };
-                           
-                           let msg = format!("\n> ⚠️  Rate limit hit while using {}.\n> 🔄  Fallback Strategy 1: Switching to {} on same account...\n", model.display_name(), spoof_model.display_name());
-                           let delta = serde_json::json!({
-                                "type": "content_block_delta",
-                                "index": fallback_status_index,
-                                "delta": { "type": "text_delta", "text": msg }
-                           });
-                           yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
-                          // Adapt config and retry
-                          let spoof_config = adapt_config_for_spoof(&thinking_config, spoof_model);
-                           match client.chat_completion_stream(spoof_model, messages.clone(), spoof_config.clone(), tools.clone()).await {
-                               Ok(spoof_stream) => {
-                                   // SUCCESS: Reuse the stream handling logic
-                                   // We need to duplicate the stream handling loop here or refactor.
-                                   // For now, duplication is safer to avoid complex borrow checker issues with recursion/closures in async gen blocks.
-
-                                   // NOTE: Don't clear rate limit - primary model is still rate-limited
-                                   // We successfully used a fallback, but the account should stay marked
-                                   // so next request knows to use Strategy 0 (pre-emptive spoofing)
-                                   use futures_util::StreamExt;
-                                  let output_stream = spoof_stream; // Move ownership
-                                  tokio::pin!(output_stream);
-
-                                    // Close the status block we used for fallback messages
-                                    let block_stop = serde_json::json!({ "type": "content_block_stop", "index": fallback_status_index });
-                                    yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                                 // Start text block
-                                 let mut text_index = block_index + 1; // Increment for new block
-                                 let block_start = serde_json::json!({
-                                    "type": "content_block_start",
-                                    "index": text_index,
-                                    "content_block": { "type": "text", "text": "" }
-                                 });
-                                 yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-
-                                  let mut inside_thought = false;
-                                  let mut has_tool_use = false; // Track if we encountered tool_use for stop_reason
-                                  
-                                  while let Some(chunk_res) = output_stream.next().await {
-                                      match chunk_res {
-                                          Ok(chunk) => {
-                                              if chunk.done { break; }
-
-                                              if chunk.is_tool_use {
-                                                   has_tool_use = true; // Mark that we have tool_use for stop_reason
-                                                   
-                                                   // Close current text block if open
-                                                   let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                                                   yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                                                   // Increment block index for tool use
-                                                   text_index += 1;
-
-                                                  // Parse tool use JSON
-                                                  if let Ok(mut tool_json) = serde_json::from_str::<Value>(&chunk.delta) {
-                                                       // Extract input for delta
-                                                       let input_obj = tool_json.get("input").cloned().unwrap_or(json!({}));
-                                                       // Remove input from start block (or set to empty)
-                                                       if let Some(obj) = tool_json.as_object_mut() {
-                                                            obj.insert("input".to_string(), json!({}));
-                                                       }
-
-                                                       let block_start = serde_json::json!({
-                                                           "type": "content_block_start",
-                                                           "index": text_index,
-                                                           "content_block": tool_json
-                                                       });
-                                                       yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-
-                                                       // Emit input as delta
-                                                       let input_str = serde_json::to_string(&input_obj).unwrap_or_default();
-                                                       let delta = serde_json::json!({
-                                                           "type": "content_block_delta",
-                                                           "index": text_index,
-                                                           "delta": { "type": "input_json_delta", "partial_json": input_str }
-                                                       });
-                                                       yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-
-                                                       // Evaluate block stop immediately as tools are atomic in this stream logic
-                                                       let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                                                       yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-
-                                                       // Prepare for next text block
-                                                       text_index += 1;
-                                                       let block_start = serde_json::json!({
-                                                           "type": "content_block_start",
-                                                           "index": text_index,
-                                                           "content_block": { "type": "text", "text": "" }
-                                                       });
-                                                       yield Ok(Event::default().event("content_block_start").data(block_start.to_string()));
-                                                  }
-                                             } else {
-                                                 let mut text_to_emit = chunk.delta;
-                                                 if chunk.is_thinking {
-                                                     if !inside_thought {
-                                                         text_to_emit = format!("\n> *Thinking: {}*", text_to_emit);
-                                                         inside_thought = true;
-                                                     }
-                                                 } else {
-                                                     if inside_thought {
-                                                         text_to_emit = format!("\n\n{}", text_to_emit);
-                                                         inside_thought = false;
-                                                     }
-                                                 }
-                                                 let delta = serde_json::json!({
-                                                    "type": "content_block_delta",
-                                                    "index": text_index,
-                                                    "delta": { "type": "text_delta", "text": text_to_emit }
-                                                 });
-                                                 yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-                                             }
-                                         },
-                                         Err(e) => {
-                                             let err_msg = e.to_string();
-                                             tracing::error!("Spoof Stream chunk error: {}", err_msg);
-                                              let error_event = serde_json::json!({
-                                                "type": "error",
-                                                "error": { "type": "api_error", "message": err_msg }
-                                            });
-                                            yield Ok(Event::default().event("error").data(error_event.to_string()));
-                                            return;
-                                         }
-                                     }
+                     // Strategy 1: Spoofing Fallback
+                     if let Some(spoof_model) = spoof_target(&config, model) {
+                         // The status block is closed by now, so this opens a fresh one
+                         let msg = format!("\n> ⚠️  Rate limit hit while using {}.\n> 🔄  Fallback Strategy 1: Switching to {} on same account...\n", model.display_name(), spoof_model.display_name());
+                         for event in blocks.status(&msg) {
+                             yield Ok(sse_event(event));
+                         }
+
+                         // Adapt config and retry
+                         let spoof_config = adapt_config_for_spoof(&thinking_config, spoof_model);
+                         match client.chat_completion_stream(spoof_model, messages.clone(), spoof_config, tools.clone(), &generation).await {
+                             Ok(spoof_stream) => {
+                                 // NOTE: Don't clear rate limit - primary model is still rate-limited
+                                 // We successfully used a fallback, but the account should stay marked
+                                 // so next request knows to use Strategy 0 (pre-emptive spoofing)
+                                 for event in blocks.close() {
+                                     yield Ok(sse_event(event));
                                  }
-                                  // Stream finished successfully
-                                  let block_stop = serde_json::json!({ "type": "content_block_stop", "index": text_index });
-                                  yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-                                  // Use correct stop_reason: "tool_use" if tools were called, "end_turn" otherwise
-                                  let stop_reason = if has_tool_use { "tool_use" } else { "end_turn" };
-                                  let message_delta = serde_json::json!({
-                                     "type": "message_delta",
-                                     "delta": { "stop_reason": stop_reason, "stop_sequence": null },
-                                     "usage": { "output_tokens": 0 }
-                                  });
-                                  yield Ok(Event::default().event("message_delta").data(message_delta.to_string()));
-                                  let message_stop = serde_json::json!({ "type": "message_stop" });
-                                  yield Ok(Event::default().event("message_stop").data(message_stop.to_string()));
-                                  return; // Done
-                              },
-                              Err(e2) => {
-                                  tracing::error!("Spoofing attempt failed: {}", e2);
-                                  // Send error message to our active status block
-                                  let msg = format!("> Spoofing failed: {}\n", e2);
-                                  let delta = serde_json::json!({
-                                       "type": "content_block_delta",
-                                       "index": fallback_status_index,
-                                       "delta": { "type": "text_delta", "text": msg }
-                                  });
-                                  yield Ok(Event::default().event("content_block_delta").data(delta.to_string()));
-                                  
-                                  // Close the fallback status block before falling through
-                                  let block_stop = serde_json::json!({ "type": "content_block_stop", "index": fallback_status_index });
-                                  yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
-                                  
-                                  // Fall through to original error report
-                              }
-                          }
-                      }
-                 } else {
-                     // Only close original status block if we DIDN'T attempt fallback (and it's still open)
-                     if status_block_open {
-                         let block_stop = serde_json::json!({ "type": "content_block_stop", "index": status_block_index });
-                         yield Ok(Event::default().event("content_block_stop").data(block_stop.to_string()));
+
+                                 let spoof_stream = record_stream_usage(spoof_stream, metrics.clone(), user, requested_model.clone());
+                                 let spoof_stream = stop_at_sequences(spoof_stream, stops.clone());
+                                 let events = anthropic_content_events(spoof_stream, blocks, spoof_model.api_id().to_string(), config.preserve_partial_on_error);
+                                 let events = record_first_token(events, request_start, &metrics.first_token_latency);
+                                 tokio::pin!(events);
+                                 while let Some(event) = events.next().await {
+                                     yield Ok(sse_event(event));
+                                 }
+                                 return; // Done
+                             }
+                             Err(e2) => {
+                                 tracing::error!("Spoofing attempt failed: {}", e2);
+                                 let msg = format!("> Spoofing failed: {}\n", e2);
+                                 for event in blocks.status(&msg) {
+                                     yield Ok(sse_event(event));
+                                 }
+                                 // Fall through to original error report
+                             }
+                         }
                      }
-                 }
+                }
+
+                for event in blocks.close() {
+                    yield Ok(sse_event(event));
+                }
 
                 // Emit original error
-                 let error_event = serde_json::json!({
+                let error_event = serde_json::json!({
                     "type": "error",
-                    "error": { "type": "api_error", "message": error_str }
+                    "error": { "type": upstream_error_kind(&error_str).1, "message": error_str }
                 });
                 yield Ok(Event::default().event("error").data(error_event.to_string()));
             }
         };
     };
 
-    Sse::new(stream)
+    Sse::new(until_cancelled(stream, cancel))
+}
+
+/// An Anthropic SSE event: the event name and its JSON data
+type AnthropicEvent = (&'static str, Value);
+
+fn sse_event((name, data): AnthropicEvent) -> Event {
+    Event::default().event(name).data(data.to_string())
+}
+
+/// Tracks whether streamed text is inside a ``` code fence
+///
+/// Fences can be split across chunks, so a trailing run of backticks is
+/// carried over to the next chunk.
+#[derive(Default)]
+struct FenceTracker {
+    open: bool,
+    backticks: usize,
+}
+
+impl FenceTracker {
+    fn push(&mut self, text: &str) {
+        for c in text.chars() {
+            self.push_char(c);
+        }
+    }
+
+    fn push_char(&mut self, c: char) {
+        if c == '`' {
+            self.backticks += 1;
+            return;
+        }
+        if self.backticks >= 3 {
+            self.open = !self.open;
+        }
+        self.backticks = 0;
+    }
+
+    /// Pushes thinking text, quoting each new line outside a fence so a whole
+    /// thinking run stays in one blockquote (no blank line until the answer)
+    fn push_quoted(&mut self, text: &str) -> String {
+        let mut quoted = String::with_capacity(text.len());
+        for c in text.chars() {
+            self.push_char(c);
+            quoted.push(c);
+            if c == '\n' && !self.is_open() {
+                quoted.push_str("> ");
+            }
+        }
+        quoted
+    }
+
+    /// Whether a fence is open at the end of the text seen so far
+    fn is_open(&self) -> bool {
+        self.open != (self.backticks >= 3)
+    }
+}
+
+/// Assigns content-block indices for an Anthropic stream
+///
+/// Blocks are numbered sequentially with at most one open at a time, so every
+/// `content_block_start` gets a `content_block_stop` at the same index before
+/// the next block starts. Text and thinking blocks are opened lazily on the
+/// first delta.
+struct BlockSequencer {
+    next_index: usize,
+    /// Index and type ("text" or "thinking") of the open block
+    open: Option<(usize, &'static str)>,
+    /// Whether status text is emitted at all
+    system_log: bool,
+    /// Whether routine progress (account selection) is emitted too
+    progress_log: bool,
+    /// Whether thinking gets its own blocks instead of inline text
+    thinking_blocks: bool,
+}
+
+impl BlockSequencer {
+    fn for_profile(profile: ClientProfile) -> Self {
+        Self {
+            next_index: 0,
+            open: None,
+            system_log: profile.system_log(),
+            progress_log: true,
+            thinking_blocks: profile.thinking_blocks(),
+        }
+    }
+
+    /// Sets whether routine progress is shown, or only waits and fallbacks
+    fn with_progress_log(mut self, progress_log: bool) -> Self {
+        self.progress_log = progress_log;
+        self
+    }
+
+    /// Appends proxy status text about a wait or fallback (the system log),
+    /// unless the profile hides it
+    fn status(&mut self, text: &str) -> Vec<AnthropicEvent> {
+        if !self.system_log {
+            return Vec::new();
+        }
+        self.text(text)
+    }
+
+    /// Appends routine progress to the system log, if it is shown
+    fn progress(&mut self, text: &str) -> Vec<AnthropicEvent> {
+        if !self.progress_log {
+            return Vec::new();
+        }
+        self.status(text)
+    }
+
+    /// Appends text, opening a text block if none is open
+    fn text(&mut self, text: &str) -> Vec<AnthropicEvent> {
+        self.delta("text", text)
+    }
+
+    /// Appends thinking, opening a thinking block if none is open
+    fn thinking(&mut self, thinking: &str) -> Vec<AnthropicEvent> {
+        self.delta("thinking", thinking)
+    }
+
+    fn delta(&mut self, kind: &'static str, text: &str) -> Vec<AnthropicEvent> {
+        let mut events = Vec::new();
+        if text.is_empty() {
+            return events;
+        }
+
+        let index = match self.open {
+            Some((index, open_kind)) if open_kind == kind => index,
+            _ => {
+                events.extend(self.close());
+                let index = self.next_index;
+                self.next_index += 1;
+                self.open = Some((index, kind));
+                let mut block = serde_json::json!({ "type": kind });
+                block[kind] = Value::from("");
+                events.push(("content_block_start", serde_json::json!({
+                    "type": "content_block_start",
+                    "index": index,
+                    "content_block": block
+                })));
+                index
+            }
+        };
+
+        let mut delta = serde_json::json!({ "type": format!("{}_delta", kind) });
+        delta[kind] = Value::from(text);
+        events.push(("content_block_delta", serde_json::json!({
+            "type": "content_block_delta",
+            "index": index,
+            "delta": delta
+        })));
+        events
+    }
+
+    /// Emits a complete tool_use block, closing any open block first
+    fn tool_use(&mut self, block: Value, input: &Value) -> Vec<AnthropicEvent> {
+        let mut events = self.close();
+        let index = self.next_index;
+        self.next_index += 1;
+
+        let input_str = serde_json::to_string(input).unwrap_or_default();
+        events.push(("content_block_start", serde_json::json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": block
+        })));
+        for partial in json_fragments(&input_str, TOOL_INPUT_CHUNK_CHARS) {
+            events.push(("content_block_delta", serde_json::json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": { "type": "input_json_delta", "partial_json": partial }
+            })));
+        }
+        events.push(("content_block_stop", serde_json::json!({ "type": "content_block_stop", "index": index })));
+        events
+    }
+
+    /// Closes the open text or thinking block, if any
+    fn close(&mut self) -> Vec<AnthropicEvent> {
+        match self.open.take() {
+            Some((index, _)) => vec![("content_block_stop", serde_json::json!({ "type": "content_block_stop", "index": index }))],
+            None => vec![],
+        }
+    }
+}
+
+/// Characters of tool input per `input_json_delta`, so large arguments arrive
+/// incrementally the way Anthropic streams them
+const TOOL_INPUT_CHUNK_CHARS: usize = 512;
+
+/// Splits serialized tool input into fragments of at most `max_chars`
+/// characters. Always yields at least one fragment, even for empty input.
+fn json_fragments(json: &str, max_chars: usize) -> Vec<&str> {
+    let mut fragments = Vec::new();
+    let step = max_chars.max(1);
+    let mut start = 0;
+    for (split, _) in json.char_indices().skip(step).step_by(step) {
+        fragments.push(&json[start..split]);
+        start = split;
+    }
+    fragments.push(&json[start..]);
+    fragments
+}
+
+/// Passes `events` through, recording the time from `started` to the first
+/// content delta (status text never goes through here)
+fn record_first_token<'a, S>(events: S, started: std::time::Instant, histogram: &'a LatencyHistogram) -> impl Stream<Item = AnthropicEvent> + 'a
+where
+    S: Stream<Item = AnthropicEvent> + 'a,
+{
+    use futures_util::StreamExt;
+
+    let mut recorded = false;
+    events.inspect(move |(name, _)| {
+        if !recorded && *name == "content_block_delta" {
+            recorded = true;
+            histogram.record(started.elapsed());
+        }
+    })
+}
+
+/// Turns upstream chunks into Anthropic content-block events
+///
+/// Ends with `message_delta` + `message_stop`, or with an `error` event if the
+/// upstream fails mid-stream. With `preserve_partial`, a failure after some
+/// content instead ends the message normally with `stop_reason: "error"` and
+/// the error as a trailing note, so clients keep what they received. Open
+/// blocks are closed either way.
+fn anthropic_content_events<S>(upstream: S, mut blocks: BlockSequencer, actual_model: String, preserve_partial: bool) -> impl Stream<Item = AnthropicEvent>
+where
+    S: Stream<Item = anyhow::Result<browser_automator::StreamChunk>>,
+{
+    async_stream::stream! {
+        use futures_util::StreamExt;
+        tokio::pin!(upstream);
+
+        let mut inside_thought = false;
+        // Thinking/answer markers are only added outside code fences, so they can't split one
+        let mut fence = FenceTracker::default();
+        let mut has_tool_use = false; // Track if we encountered tool_use for stop_reason
+        let mut tool_truncated = false; // Track cut-off tool calls so clients don't dispatch them blindly
+
+        // Running output estimate (chars / 4, like count_tokens) until usageMetadata arrives
+        let mut emitted_chars = 0;
+        let mut thinking_chars = 0;
+        let mut reported_tokens = 0;
+        let mut reported_usage = None;
+        let mut stop_sequence = None;
+
+        while let Some(chunk_res) = upstream.next().await {
+            if let Ok(chunk) = &chunk_res {
+                emitted_chars += chunk.delta.chars().count();
+                if chunk.is_thinking {
+                    thinking_chars += chunk.delta.chars().count();
+                }
+                let estimate = emitted_chars.div_ceil(4) as u32;
+                if estimate >= reported_tokens + USAGE_DELTA_INTERVAL_TOKENS {
+                    reported_tokens = estimate;
+                    yield ("message_delta", usage_progress_event(estimate, thinking_chars.div_ceil(4) as u32));
+                }
+            }
+
+            match chunk_res {
+                Ok(chunk) if chunk.done => {
+                    reported_usage = chunk.usage;
+                    stop_sequence = chunk.stop_sequence;
+                    break;
+                }
+                Ok(chunk) if chunk.is_tool_use => {
+                    has_tool_use = true;
+
+                    // Validate tool use JSON, repairing it if the stream cut off mid-call
+                    let tool = parse_tool_use_chunk(&chunk.delta);
+                    if tool.as_ref().is_none_or(|t| t.truncated) {
+                        tool_truncated = true;
+                    }
+                    match tool {
+                        Some(tool) => {
+                            for event in blocks.tool_use(tool.block, &tool.input) {
+                                yield event;
+                            }
+                        }
+                        None => tracing::warn!("Dropping truncated tool call: {}", chunk.delta),
+                    }
+                }
+                Ok(chunk) if blocks.thinking_blocks => {
+                    let events = if chunk.is_thinking { blocks.thinking(&chunk.delta) } else { blocks.text(&chunk.delta) };
+                    for event in events {
+                        yield event;
+                    }
+                }
+                Ok(chunk) => {
+                    // Visual indication of thinking vs answer. Every thinking run
+                    // opens with a marker and continues in the same quote; every
+                    // switch back to the answer starts a new paragraph.
+                    let text_to_emit = if chunk.is_thinking {
+                        let opening = !inside_thought && !fence.is_open();
+                        inside_thought = true;
+                        let quoted = fence.push_quoted(&chunk.delta);
+                        if opening { format!("\n> *Thinking: {}*", quoted) } else { quoted }
+                    } else {
+                        let closing = inside_thought && !fence.is_open();
+                        inside_thought = false;
+                        fence.push(&chunk.delta);
+                        if closing { format!("\n\n{}", chunk.delta) } else { chunk.delta }
+                    };
+
+                    for event in blocks.text(&text_to_emit) {
+                        yield event;
+                    }
+                }
+                Err(e) => {
+                    let err_msg = e.to_string();
+                    tracing::error!("Stream chunk error: {}", err_msg);
+                    if preserve_partial && (emitted_chars > 0 || has_tool_use) {
+                        let note = format!("\n\n> ⚠️  Response interrupted: {}\n", err_msg);
+                        for event in blocks.text(&note).into_iter().chain(blocks.close()) {
+                            yield event;
+                        }
+                        let (output_tokens, thinking_tokens) = (emitted_chars.div_ceil(4) as u32, thinking_chars.div_ceil(4) as u32);
+                        yield ("message_delta", message_delta_event("error", None, &actual_model, output_tokens, thinking_tokens));
+                        yield ("message_stop", serde_json::json!({ "type": "message_stop" }));
+                        return;
+                    }
+                    for event in blocks.close() {
+                        yield event;
+                    }
+                    yield ("error", serde_json::json!({
+                        "type": "error",
+                        "error": { "type": upstream_error_kind(&err_msg).1, "message": err_msg }
+                    }));
+                    return;
+                }
+            }
+        }
+
+        for event in blocks.close() {
+            yield event;
+        }
+
+        // Use correct stop_reason: "max_tokens" if a tool call was cut off,
+        // "tool_use" if tools were called, "stop_sequence" if one matched,
+        // "end_turn" otherwise
+        let stop_reason = if tool_truncated {
+            "max_tokens"
+        } else if has_tool_use {
+            "tool_use"
+        } else if stop_sequence.is_some() {
+            "stop_sequence"
+        } else {
+            "end_turn"
+        };
+        let (output_tokens, thinking_tokens) = match reported_usage {
+            Some(usage) => (usage.completion_tokens, usage.thinking_tokens),
+            None => (emitted_chars.div_ceil(4) as u32, thinking_chars.div_ceil(4) as u32),
+        };
+        yield ("message_delta", message_delta_event(stop_reason, stop_sequence.as_deref(), &actual_model, output_tokens, thinking_tokens));
+        yield ("message_stop", serde_json::json!({ "type": "message_stop" }));
+    }
 }
 
 /// Token counting endpoint
-/// Returns approximated token count (characters / 4)
+/// Returns approximated token count (characters / 4, plus a flat cost per image)
 pub async fn count_tokens(
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "input_tokens": estimate_input_tokens(&payload)
+    }))
+}
+
+/// Flat cost of one image block
+///
+/// Anthropic bills images at roughly `width * height / 750` tokens and
+/// downscales anything over ~1.15 megapixels, so ~1600 is the cost of a
+/// full-size image. Sizes aren't decoded here; every image is assumed full-size.
+const IMAGE_TOKEN_ESTIMATE: u32 = 1600;
+
+/// Whether a content block is an image (Anthropic `image` or OpenAI `image_url`)
+fn is_image_block(block: &Value) -> bool {
+    matches!(block.get("type").and_then(|t| t.as_str()), Some("image" | "image_url"))
+}
+
+/// Response header warning that a conversation is nearing the context window
+const CONTEXT_USAGE_HEADER: &str = "x-aether-context-usage";
+
+/// Estimated share of the context window used by the prompt, in percent
+///
+/// Only reported once it reaches `Config::context_warning_percent`, so
+/// clients know it is time to compact.
+fn context_usage_percent(config: &Config, payload: &Value, model: AntigravityModel) -> Option<u32> {
+    let percent = (estimate_input_tokens(payload) as u64 * 100 / model.context_window().max(1) as u64) as u32;
+    (percent >= config.context_warning_percent).then_some(percent)
+}
+
+/// Adds the context-usage warning header, if there is one to report
+fn insert_context_usage(response: &mut axum::response::Response, context_usage: Option<u32>) {
+    if let Some(percent) = context_usage {
+        response.headers_mut().insert(CONTEXT_USAGE_HEADER, HeaderValue::from(percent));
+    }
+}
+
+/// Estimates the prompt size of an Anthropic or OpenAI request
+fn estimate_input_tokens(payload: &Value) -> u32 {
     let mut total_chars = 0;
+    let mut image_count = 0;
 
     // Count system prompt
     if let Some(system) = payload.get("system") {
@@ -1551,6 +3344,8 @@ pub async fn count_tokens(
                     for block in blocks {
                         if let Some(text) = block.get("text").and_then(|t| t.as_str()) {
                             total_chars += text.len();
+                        } else if is_image_block(block) {
+                            image_count += 1;
                         }
                     }
                 }
@@ -1558,10 +3353,1144 @@ pub async fn count_tokens(
         }
     }
 
+    // Tool definitions are sent to the model as their JSON schemas
+    if let Some(tools) = payload.get("tools").and_then(|t| t.as_array()) {
+        total_chars += tools.iter().map(|tool| tool.to_string().len()).sum::<usize>();
+    }
+
     // Rough approximation: 1 token ~= 4 characters
-    let token_count = (total_chars as f64 / 4.0).ceil() as u32;
+    (total_chars as f64 / 4.0).ceil() as u32 + image_count * IMAGE_TOKEN_ESTIMATE
+}
 
-    Json(serde_json::json!({
-        "input_tokens": token_count
-    }))
+/// Output budget assumed when the request doesn't set one (matches `maxOutputTokens`)
+const DEFAULT_EXPECTED_OUTPUT_TOKENS: u32 = 8192;
+
+/// Rejects a request whose prompt can't fit the model's context window
+///
+/// Runs before any account is selected, so an over-limit request fails fast
+/// instead of waiting on an upstream error. Returns `None` if it fits.
+fn context_length_error(payload: &Value, model: AntigravityModel, anthropic_format: bool) -> Option<ApiError> {
+    let expected_output = ["max_tokens", "max_completion_tokens"].iter()
+        .find_map(|field| payload[field].as_u64())
+        .map(|n| n.min(u32::MAX as u64) as u32)
+        .unwrap_or(DEFAULT_EXPECTED_OUTPUT_TOKENS);
+    let limit = model.context_window().saturating_sub(expected_output);
+    let input_tokens = estimate_input_tokens(payload);
+    if input_tokens <= limit {
+        return None;
+    }
+
+    tracing::warn!("Rejecting request: ~{} input tokens exceeds {} limit of {}", input_tokens, model.api_id(), limit);
+    let message = format!(
+        "This model's maximum context length is {} tokens. The prompt is ~{} tokens, leaving no room for {} output tokens.",
+        model.context_window(), input_tokens, expected_output
+    );
+    let body = if anthropic_format {
+        serde_json::json!({
+            "type": "error",
+            "error": {
+                "type": "invalid_request_error",
+                "code": "context_length_exceeded",
+                "message": message
+            }
+        })
+    } else {
+        serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "invalid_request_error",
+                "code": "context_length_exceeded"
+            }
+        })
+    };
+    Some(ApiError::new(StatusCode::BAD_REQUEST, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// An account manager whose only account is rate limited for Claude
+    async fn claude_limited_manager() -> AccountManager {
+        let manager = AccountManager::empty();
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "test@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(0, ModelFamily::Claude, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_preemptive_spoof_respects_config() {
+        let manager = claude_limited_manager().await;
+        assert!(manager.get_available_account().await.is_none());
+
+        // Enabled by default: Claude is swapped for Gemini
+        let config = Config::default();
+        let (_, spoofed) = preemptive_spoof(&config, &manager, AntigravityModel::ClaudeSonnet45).await.unwrap();
+        assert!(spoofed.is_gemini());
+
+        // Disabled: no substitution, so the handler falls through to queue/429
+        let config = Config { enable_spoofing: false, ..Config::default() };
+        assert!(preemptive_spoof(&config, &manager, AntigravityModel::ClaudeSonnet45).await.is_none());
+        assert!(spoof_target(&config, AntigravityModel::ClaudeSonnet45).is_none());
+
+        let config = Config { enable_preemptive_spoof: false, ..Config::default() };
+        assert!(preemptive_spoof(&config, &manager, AntigravityModel::ClaudeSonnet45).await.is_none());
+        // Reactive spoofing (Strategy 1) is still allowed
+        assert_eq!(spoof_target(&config, AntigravityModel::ClaudeSonnet45), Some(AntigravityModel::Gemini3Flash));
+    }
+
+    #[tokio::test]
+    async fn test_spoof_fallback_skips_accounts_not_serving_target() {
+        let manager = claude_limited_manager().await;
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "gemini@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(1, ModelFamily::Claude, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+
+        // A Claude-only account never receives the Gemini substitute
+        manager.set_account_families("test@example.com", vec![ModelFamily::Claude]).await.unwrap();
+        for _ in 0..3 {
+            let (account, spoofed) = preemptive_spoof(&Config::default(), &manager, AntigravityModel::ClaudeSonnet45).await.unwrap();
+            assert!(spoofed.is_gemini());
+            assert_eq!(account.email, "gemini@example.com");
+        }
+
+        // With no account serving Gemini there is nothing to fall back to
+        manager.set_account_families("gemini@example.com", vec![ModelFamily::Claude]).await.unwrap();
+        assert!(preemptive_spoof(&Config::default(), &manager, AntigravityModel::ClaudeSonnet45).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_downgrade_chain_tried_before_gemini() {
+        let manager = claude_limited_manager().await;
+        let config = Config {
+            downgrade_chain: vec!["claude-opus-4-5-thinking".into(), "claude-sonnet-4-5-thinking".into()],
+            ..Config::default()
+        };
+
+        // Claude is limited on every account, so Sonnet would be too: Gemini it is
+        let (_, served) = preemptive_spoof(&config, &manager, AntigravityModel::ClaudeOpus45Thinking).await.unwrap();
+        assert_eq!(served, AntigravityModel::Gemini3Pro);
+        assert_eq!(spoof_target(&config, AntigravityModel::ClaudeOpus45Thinking), Some(AntigravityModel::ClaudeSonnet45Thinking));
+
+        // An account with Claude quota left takes the step down to Sonnet
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "fresh@example.com".into(),
+        }).await.unwrap();
+        let (account, served) = preemptive_spoof(&config, &manager, AntigravityModel::ClaudeOpus45Thinking).await.unwrap();
+        assert_eq!(served, AntigravityModel::ClaudeSonnet45Thinking);
+        assert_eq!(account.email, "fresh@example.com");
+
+        // The end of the chain falls through to Gemini
+        let (_, served) = preemptive_spoof(&config, &manager, AntigravityModel::ClaudeSonnet45Thinking).await.unwrap();
+        assert!(served.is_gemini());
+
+        // Nothing is substituted up front with pre-emptive spoofing off
+        let off = Config { enable_preemptive_spoof: false, ..config.clone() };
+        assert!(preemptive_spoof(&off, &manager, AntigravityModel::ClaudeOpus45Thinking).await.is_none());
+
+        // A downgrade target that isn't enabled is skipped
+        let config = Config { enabled_models: Some(vec!["claude-opus-4-5-thinking".into(), "gemini-3-pro".into()]), ..config };
+        let (_, served) = preemptive_spoof(&config, &manager, AntigravityModel::ClaudeOpus45Thinking).await.unwrap();
+        assert_eq!(served, AntigravityModel::Gemini3Pro);
+
+        // Without a chain, Opus goes straight to Gemini as before
+        let (_, served) = preemptive_spoof(&Config::default(), &manager, AntigravityModel::ClaudeOpus45Thinking).await.unwrap();
+        assert_eq!(served, AntigravityModel::Gemini3Pro);
+    }
+
+    #[test]
+    fn test_anthropic_model_ids_mapped_by_table() {
+        let config = Config::default();
+        let cases = [
+            ("claude-opus-4-5-20251101", AntigravityModel::ClaudeOpus45Thinking),
+            ("claude-3-opus-20240229", AntigravityModel::ClaudeOpus45Thinking),
+            ("claude-sonnet-4-5-20250929", AntigravityModel::ClaudeSonnet45),
+            ("claude-3-5-sonnet-20241022", AntigravityModel::ClaudeSonnet45),
+            ("claude-3-7-sonnet-latest", AntigravityModel::ClaudeSonnet45),
+            ("claude-3-5-haiku-20241022", AntigravityModel::Gemini3Flash),
+            ("claude-haiku-4-5", AntigravityModel::Gemini3Flash),
+            // Antigravity ids pass through, unknown names are still guessed
+            ("claude-sonnet-4-5-thinking", AntigravityModel::ClaudeSonnet45Thinking),
+            ("gemini-3-pro-high", AntigravityModel::Gemini3Pro),
+            ("claude-opus-5-preview", AntigravityModel::ClaudeOpus45Thinking),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(map_anthropic_to_antigravity(&config, id), expected, "{}", id);
+        }
+
+        // The config table wins over the built-in one
+        let config = Config {
+            anthropic_model_map: [("claude-3-opus-20240229".to_string(), "claude-sonnet-4-5".to_string())].into(),
+            ..Config::default()
+        };
+        assert_eq!(map_anthropic_to_antigravity(&config, "claude-3-opus-20240229"), AntigravityModel::ClaudeSonnet45);
+        assert_eq!(map_anthropic_to_antigravity(&config, "claude-3-opus-latest"), AntigravityModel::ClaudeOpus45Thinking);
+    }
+
+    #[test]
+    fn test_every_model_has_cross_family_spoof() {
+        let config = Config::default();
+        let expected = [
+            (AntigravityModel::ClaudeOpus45Thinking, AntigravityModel::Gemini3Pro),
+            (AntigravityModel::ClaudeSonnet45Thinking, AntigravityModel::Gemini3Flash),
+            (AntigravityModel::ClaudeSonnet45, AntigravityModel::Gemini3Flash),
+            (AntigravityModel::Gemini3Pro, AntigravityModel::ClaudeSonnet45Thinking),
+            (AntigravityModel::Gemini3Flash, AntigravityModel::ClaudeSonnet45),
+        ];
+        assert_eq!(expected.len(), AntigravityModel::all().len());
+        for (model, target) in expected {
+            assert_eq!(spoof_target(&config, model), Some(target), "{:?}", model);
+            assert_ne!(model.is_claude(), target.is_claude());
+        }
+
+        // The map overrides a default or switches the fallback off
+        let mut config = Config::default();
+        config.spoof_map.insert("gemini-3-pro".into(), "claude-opus-4-5-thinking".into());
+        config.spoof_map.insert("gemini-3-flash".into(), String::new());
+        assert_eq!(spoof_target(&config, AntigravityModel::Gemini3Pro), Some(AntigravityModel::ClaudeOpus45Thinking));
+        assert_eq!(spoof_target(&config, AntigravityModel::Gemini3Flash), None);
+        assert_eq!(spoof_target(&config, AntigravityModel::ClaudeSonnet45), Some(AntigravityModel::Gemini3Flash));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_gemini_falls_back_to_claude() {
+        let manager = AccountManager::empty();
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "test@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(0, ModelFamily::Gemini, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+        assert!(manager.get_available_account_for_model("gemini-3-pro").await.is_none());
+
+        let (_, served) = preemptive_spoof(&Config::default(), &manager, AntigravityModel::Gemini3Pro).await.unwrap();
+        assert_eq!(served, AntigravityModel::ClaudeSonnet45Thinking);
+    }
+
+    #[test]
+    fn test_dead_accounts_ask_for_relogin() {
+        let (status, kind, message) = no_account_error(SelectionFailure::AllRefreshFailed, "No Google accounts configured.");
+        assert_eq!((status, kind), (StatusCode::UNAUTHORIZED, "authentication_error"));
+        assert!(message.contains("log in again"));
+        assert!(!message.contains("No Google accounts configured"));
+
+        let (_, _, message) = no_account_error(SelectionFailure::NoAccounts, "No Google accounts configured.");
+        assert_eq!(message, "No Google accounts configured.");
+    }
+
+    #[test]
+    fn test_max_wait_is_per_family() {
+        let mut config = Config::default();
+        config.max_wait.claude = 900;
+        config.max_wait.gemini = 0;
+
+        // Gemini is over its cap: reject with 429 instead of queuing
+        assert!(exceeds_max_wait(&config, AntigravityModel::Gemini3Flash.api_id(), 120));
+        // The same wait on Claude is under its cap and queues
+        assert!(!exceeds_max_wait(&config, AntigravityModel::ClaudeSonnet45.api_id(), 120));
+        assert!(exceeds_max_wait(&config, AntigravityModel::ClaudeSonnet45.api_id(), 901));
+
+        // Defaults keep the old 10 minute cap for both
+        let config = Config::default();
+        assert!(!exceeds_max_wait(&config, "gemini-3-pro", 600));
+        assert!(exceeds_max_wait(&config, "claude-sonnet-4-5", 601));
+    }
+
+    #[test]
+    fn test_thinking_level_thresholds() {
+        let thresholds = ThinkingThresholds::default();
+        assert_eq!(thinking_level_for_budget(&thresholds, 4999), "low");
+        assert_eq!(thinking_level_for_budget(&thresholds, 5000), "medium");
+        assert_eq!(thinking_level_for_budget(&thresholds, 14999), "medium");
+        assert_eq!(thinking_level_for_budget(&thresholds, 15000), "high");
+
+        let tuned = ThinkingThresholds { medium: 1000, high: 2000 };
+        assert_eq!(thinking_level_for_budget(&tuned, 1500), "medium");
+        assert_eq!(thinking_level_for_budget(&tuned, 5000), "high");
+    }
+
+    #[tokio::test]
+    async fn test_actual_model_reported_after_spoof() {
+        let manager = claude_limited_manager().await;
+        let (_, served) = preemptive_spoof(&Config::default(), &manager, AntigravityModel::ClaudeOpus45Thinking).await.unwrap();
+
+        let delta = message_delta_event("end_turn", None, served.api_id(), 0, 0);
+        assert_eq!(delta["type"], "message_delta");
+        assert_eq!(delta["metadata"]["aether_actual_model"], AntigravityModel::Gemini3Pro.api_id());
+        assert_eq!(delta["delta"]["stop_reason"], "end_turn");
+    }
+
+    #[test]
+    fn test_signed_thinking_blocks_kept_from_history() {
+        let payload = json!({
+            "messages": [
+                { "role": "user", "content": "What is 2 + 2?" },
+                { "role": "assistant", "content": [
+                    { "type": "thinking", "thinking": "2 + 2 = 4", "signature": "sig_abc123" },
+                    { "type": "thinking", "thinking": "unsigned", "signature": "" },
+                    { "type": "text", "text": "4" }
+                ]},
+                { "role": "user", "content": "Thanks" }
+            ]
+        });
+
+        let messages = convert_anthropic_messages(&payload);
+        assert_eq!(messages[1].content, "4");
+        assert_eq!(messages[1].thinking, vec![ThinkingBlock {
+            thinking: "2 + 2 = 4".into(),
+            signature: "sig_abc123".into(),
+        }]);
+        assert!(messages[0].thinking.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_first_token_falls_back() {
+        use futures_util::StreamExt;
+        use std::time::Duration;
+
+        type TestStream = Pin<Box<dyn Stream<Item = &'static str> + Send>>;
+        let slow: TestStream = Box::pin(futures_util::stream::once(async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "slow primary"
+        }));
+
+        let started = std::time::Instant::now();
+        let (mut stream, first, fell_back) = first_chunk_with_fallback(Box::pin(slow), Some(Duration::from_millis(50)), || async {
+            Ok::<TestStream, anyhow::Error>(Box::pin(futures_util::stream::iter(["fast", "fallback"])))
+        }).await;
+
+        assert!(fell_back);
+        assert_eq!(first, Some("fast"));
+        assert_eq!(stream.next().await, Some("fallback"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // A prompt primary is kept
+        let quick: TestStream = Box::pin(futures_util::stream::iter(["primary"]));
+        let (_, first, fell_back) = first_chunk_with_fallback(Box::pin(quick), Some(Duration::from_millis(50)), || async {
+            Ok::<TestStream, anyhow::Error>(Box::pin(futures_util::stream::iter(["fallback"])))
+        }).await;
+        assert!(!fell_back);
+        assert_eq!(first, Some("primary"));
+    }
+
+    #[test]
+    fn test_completion_prompt_wrapped_as_user_message() {
+        let payload = json!({ "model": "antigravity-gemini-3-flash", "prompt": "Say hello" });
+        let messages = completion_prompt_messages(&payload);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "Say hello");
+
+        assert!(completion_prompt_messages(&json!({ "prompt": "" })).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_response_sets_retry_after() {
+        let wait_secs = 42;
+        let response = ApiError::rate_limited(wait_secs, json!({
+            "error": {
+                "message": format!("Rate limited. Retry after {} seconds", wait_secs),
+                "type": "rate_limit_error"
+            }
+        })).into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert_eq!(retry_after, wait_secs);
+        assert_eq!(response.headers()["x-ratelimit-reset"], "42s");
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains(&format!("Retry after {} seconds", retry_after)));
+
+        let error = ApiError::new(StatusCode::BAD_REQUEST, json!({})).into_response();
+        assert!(error.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[test]
+    fn test_text_completion_response_shape() {
+        let body = text_completion_response("cmpl-1", 1700000000, "antigravity-gemini-3-flash", "Hello!", Some("stop"), None);
+        assert_eq!(body["object"], "text_completion");
+        assert_eq!(body["choices"][0]["text"], "Hello!");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert!(body.get("usage").is_some());
+
+        // Streamed chunks carry the delta text and no usage
+        let chunk = text_completion_response("cmpl-1", 1700000000, "antigravity-gemini-3-flash", "Hel", None, None);
+        assert_eq!(chunk["choices"][0]["text"], "Hel");
+        assert!(chunk["choices"][0]["finish_reason"].is_null());
+        assert!(chunk.get("usage").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_same_conversation_prefers_same_account() {
+        let manager = AccountManager::empty();
+        for email in ["a@example.com", "b@example.com"] {
+            manager.add_account(oauth::TokenPair {
+                access_token: "access".into(),
+                refresh_token: "refresh".into(),
+                expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+
+        let config = Config { conversation_affinity: true, ..Config::default() };
+        let first = json!({ "metadata": { "conversation_id": "c-1" }, "messages": [{ "role": "user", "content": "hi" }] });
+        let second = json!({ "metadata": { "conversation_id": "c-1" }, "messages": [{ "role": "user", "content": "more" }] });
+
+        let key = conversation_key(&config, &first);
+        assert_eq!(key, conversation_key(&config, &second));
+        let a = manager.get_available_account_for_conversation(key.as_deref(), "claude-sonnet-4-5").await.unwrap();
+        let b = manager.get_available_account_for_conversation(conversation_key(&config, &second).as_deref(), "claude-sonnet-4-5").await.unwrap();
+        assert_eq!(a.email, b.email);
+
+        // Falls back to the system prompt, and is off unless configured
+        let system = json!({ "system": "You are a helpful agent", "messages": [] });
+        assert!(conversation_key(&config, &system).unwrap().starts_with("system:"));
+        assert!(conversation_key(&Config::default(), &first).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_prompt_rejected_before_upstream() {
+        let model = AntigravityModel::ClaudeSonnet45;
+        let huge = "x".repeat(model.context_window() as usize * 4);
+        let payload = json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 4096,
+            "messages": [{ "role": "user", "content": huge }]
+        });
+
+        let response = context_length_error(&payload, model, true).unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "context_length_exceeded");
+
+        // The same prompt fits Gemini's larger window; small prompts always pass
+        assert!(context_length_error(&payload, AntigravityModel::Gemini3Flash, false).is_none());
+        let small = json!({ "messages": [{ "role": "user", "content": "hi" }] });
+        assert!(context_length_error(&small, model, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_large_conversation_reports_context_usage() {
+        let model = AntigravityModel::ClaudeSonnet45;
+        let config = Config::default();
+        // ~90% of the window (4 chars per token)
+        let large = "x".repeat(model.context_window() as usize * 4 * 9 / 10);
+        let payload = json!({ "messages": [{ "role": "user", "content": large }] });
+
+        let percent = context_usage_percent(&config, &payload, model).unwrap();
+        assert!((85..=95).contains(&percent), "{}", percent);
+        assert!(context_usage_percent(&config, &json!({ "messages": [{ "role": "user", "content": "Hi" }] }), model).is_none());
+
+        let response = ChatResponse {
+            content: "Hi".into(),
+            thinking: None,
+            model: model.api_id().to_string(),
+            finish_reason: "end_turn".into(),
+            usage: None,
+            other_candidates: Vec::new(),
+            logprobs: None,
+        };
+        let http_response = anthropic_message_response("claude-sonnet-4-5", &response, None, FallbackStrategy::Primary, Some(percent));
+        assert_eq!(http_response.headers()[CONTEXT_USAGE_HEADER], percent.to_string().as_str());
+        let bytes = axum::body::to_bytes(http_response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["metadata"]["aether_context_usage"], percent);
+    }
+
+    #[tokio::test]
+    async fn test_stream_options_include_usage() {
+        use browser_automator::{StreamChunk, Usage};
+        use futures_util::StreamExt;
+
+        let upstream = || futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Hello".into(), ..Default::default() }),
+            Ok(StreamChunk {
+                done: true,
+                usage: Some(Usage { prompt_tokens: 12, completion_tokens: 3, total_tokens: 15, ..Default::default() }),
+                ..Default::default()
+            }),
+        ]);
+        let parse = |events: Vec<String>| -> Vec<Value> {
+            assert_eq!(events.last().unwrap(), "[DONE]");
+            events[..events.len() - 1].iter().map(|e| serde_json::from_str(e).unwrap()).collect()
+        };
+
+        let with_usage = parse(chat_completion_chunks(upstream(), "chatcmpl-test".into(), 1700000000, "antigravity-gemini-3-flash".into(), true).collect().await);
+        let last = with_usage.last().unwrap();
+        assert_eq!(last["choices"], json!([]));
+        assert_eq!(last["usage"]["prompt_tokens"], 12);
+        assert_eq!(last["usage"]["total_tokens"], 15);
+        assert!(with_usage.iter().any(|c| c["choices"][0]["delta"]["content"] == "Hello"));
+
+        let without = parse(chat_completion_chunks(upstream(), "chatcmpl-test".into(), 1700000000, "antigravity-gemini-3-flash".into(), false).collect().await);
+        assert!(without.iter().all(|c| c.get("usage").is_none()));
+        assert_eq!(without.last().unwrap()["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn test_stream_chunks_share_id_and_created() {
+        use browser_automator::{StreamChunk, Usage};
+        use futures_util::StreamExt;
+
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "One".into(), ..Default::default() }),
+            Ok(StreamChunk { delta: "Two".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, usage: Some(Usage::default()), ..Default::default() }),
+        ]);
+        let events: Vec<String> = chat_completion_chunks(upstream, "chatcmpl-stable".into(), 1700000000, "m".into(), true).collect().await;
+        let chunks: Vec<Value> = events.iter().filter(|e| *e != "[DONE]").map(|e| serde_json::from_str(e).unwrap()).collect();
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|c| c["id"] == "chatcmpl-stable" && c["created"] == 1700000000));
+    }
+
+    #[test]
+    fn test_ref_schema_sanitized_like_client() {
+        let schema = json!({
+            "type": "object",
+            "$defs": { "Mode": { "type": "string" } },
+            "definitions": { "Legacy": { "type": "string" } },
+            "properties": {
+                "mode": { "$ref": "#/$defs/Mode" },
+                "kind": { "type": "string", "const": "fixed" },
+                "tags": { "type": "array", "items": { "$ref": "#/definitions/Legacy" } },
+                "either": { "anyOf": [{ "$ref": "#/$defs/Mode" }, { "type": "integer", "minimum": 0 }] }
+            },
+            "additionalProperties": false
+        });
+        let payload = json!({
+            "tools": [{ "name": "set_mode", "description": "Set the mode", "input_schema": schema.clone() }]
+        });
+
+        let converted = convert_anthropic_tools(&payload).unwrap();
+        let route_params = &converted[0]["parameters"];
+
+        let mut client_params = schema;
+        sanitize_schema(&mut client_params);
+
+        assert_eq!(route_params, &client_params);
+        let text = route_params.to_string();
+        assert!(!text.contains("$ref") && !text.contains("$defs") && !text.contains("definitions"));
+        assert_eq!(route_params["properties"]["kind"]["enum"], json!(["fixed"]));
+    }
+
+    #[test]
+    fn test_strip_tool_transformer_runs_before_conversion() {
+        let config: Config = serde_json::from_value(json!({
+            "project_id": null, "accounts": {}, "providers": {},
+            "server": { "host": "127.0.0.1", "port": 8080, "browser_profile_path": null },
+            "transformers": [
+                { "type": "strip_tool_by_name", "name": "web_search" },
+                { "type": "inject_system", "text": "Be brief." }
+            ]
+        }))
+        .unwrap();
+        let payload = json!({
+            "system": "You are helpful.",
+            "tools": [
+                { "name": "web_search", "input_schema": { "type": "object" } },
+                { "name": "read_file", "input_schema": { "type": "object" } }
+            ]
+        });
+
+        let payload = apply_transformers(&config.transformers, payload, true);
+        let converted = convert_anthropic_tools(&payload).unwrap();
+        let names: Vec<&str> = converted.iter().filter_map(|t| t["name"].as_str()).collect();
+        assert_eq!(names, ["read_file"]);
+        assert_eq!(payload["system"], "Be brief.\n\nYou are helpful.");
+
+        // force_tool adds a missing tool once
+        let force = [Transformer::ForceTool { tool: json!({ "name": "read_file" }) }];
+        let payload = apply_transformers(&force, json!({ "tools": [] }), true);
+        let payload = apply_transformers(&force, payload, true);
+        assert_eq!(payload["tools"].as_array().unwrap().len(), 1);
+    }
+
+    /// Asserts blocks are sequential, non-overlapping and each start has a matching stop
+    fn assert_well_nested(events: &[AnthropicEvent]) {
+        let mut open: Option<u64> = None;
+        let mut next = 0;
+        for (name, data) in events {
+            let index = data["index"].as_u64();
+            match *name {
+                "content_block_start" => {
+                    assert!(open.is_none(), "block {:?} started while {:?} is open", index, open);
+                    assert_eq!(index, Some(next), "blocks must be numbered sequentially");
+                    open = index;
+                    next += 1;
+                }
+                "content_block_delta" => assert_eq!(index, open, "delta outside its block"),
+                "content_block_stop" => {
+                    assert_eq!(index, open, "stop without a matching start");
+                    open = None;
+                }
+                _ => {}
+            }
+        }
+        assert!(open.is_none(), "block {:?} never closed", open);
+    }
+
+    #[test]
+    fn test_json_fragments_split_on_char_boundaries() {
+        assert_eq!(json_fragments("", 3), [""]);
+        assert_eq!(json_fragments("abc", 3), ["abc"]);
+        assert_eq!(json_fragments("{\"é\":\"ñü\"}", 3), ["{\"é", "\":\"", "ñü\"", "}"]);
+    }
+
+    #[tokio::test]
+    async fn test_tool_use_first_yields_well_nested_blocks() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        // Status block opened and closed before the content starts
+        let mut blocks = BlockSequencer::for_profile(ClientProfile::default());
+        let mut events = blocks.text("> **AetherBridge System Log**\n");
+        events.extend(blocks.close());
+
+        let tool = json!({ "type": "tool_use", "id": "toolu_1", "name": "read_file", "input": { "path": "a.rs" } });
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: tool.to_string(), is_tool_use: true, ..Default::default() }),
+            Ok(StreamChunk { delta: "Done.".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+        events.extend(anthropic_content_events(upstream, blocks, "claude-sonnet-4-5".into(), false).collect::<Vec<_>>().await);
+
+        assert_well_nested(&events);
+        let starts: Vec<&Value> = events.iter().filter(|(n, _)| *n == "content_block_start").map(|(_, d)| d).collect();
+        assert_eq!(starts.len(), 3);
+        assert_eq!(starts[1]["content_block"]["type"], "tool_use");
+        assert_eq!(starts[1]["index"], 1);
+        assert_eq!(events.last().unwrap().0, "message_stop");
+
+        // A mid-stream error still closes the open block
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Partial".into(), ..Default::default() }),
+            Err(anyhow::anyhow!("connection reset")),
+        ]);
+        let events: Vec<_> = anthropic_content_events(upstream, BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false).collect().await;
+        assert_well_nested(&events);
+        assert_eq!(events.last().unwrap().0, "error");
+    }
+
+    #[test]
+    fn test_large_tool_input_streamed_in_fragments() {
+        let content: String = (0..3000).map(|i| if i % 7 == 0 { 'é' } else { 'x' }).collect();
+        let input = json!({ "path": "big.rs", "content": content });
+        let block = json!({ "type": "tool_use", "id": "toolu_1", "name": "write_file", "input": {} });
+
+        let mut blocks = BlockSequencer::for_profile(ClientProfile::default());
+        let events = blocks.tool_use(block, &input);
+        assert_well_nested(&events);
+
+        let partials: Vec<&str> = events
+            .iter()
+            .filter(|(n, _)| *n == "content_block_delta")
+            .map(|(_, d)| {
+                assert_eq!(d["delta"]["type"], "input_json_delta");
+                d["delta"]["partial_json"].as_str().unwrap()
+            })
+            .collect();
+        assert!(partials.len() > 1);
+        assert!(partials.iter().all(|p| p.chars().count() <= TOOL_INPUT_CHUNK_CHARS));
+        let reassembled: Value = serde_json::from_str(&partials.concat()).unwrap();
+        assert_eq!(reassembled, input);
+
+        // Small inputs still arrive as one delta
+        let events = blocks.tool_use(json!({ "type": "tool_use" }), &json!({}));
+        assert_eq!(events.iter().filter(|(n, _)| *n == "content_block_delta").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_system_log_profile_suppresses_status_block() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let mut blocks = BlockSequencer::for_profile(ClientProfile::NoSystemLog);
+        let mut events = blocks.status("> **AetherBridge System Log**\n> Finding available account...\n");
+        events.extend(blocks.close());
+        assert!(events.is_empty());
+
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Hello".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+        events.extend(anthropic_content_events(upstream, blocks, "m".into(), false).collect::<Vec<_>>().await);
+
+        // The answer is the first and only block
+        assert_well_nested(&events);
+        let starts: Vec<&Value> = events.iter().filter(|(n, _)| *n == "content_block_start").map(|(_, d)| d).collect();
+        assert_eq!(starts.len(), 1);
+        assert_eq!(starts[0]["index"], 0);
+        assert!(!events.iter().any(|(_, d)| d.to_string().contains("System Log")));
+    }
+
+    #[tokio::test]
+    async fn test_clean_success_streams_content_first_by_default() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        // The status calls made on the way to a successful request
+        let config = Config::default();
+        let mut blocks = BlockSequencer::for_profile(config.client_profile).with_progress_log(config.show_system_log);
+        let mut events = blocks.progress("> **AetherBridge System Log**\n> Finding available account...\n");
+        events.extend(blocks.progress("> Using account: a@example.com. Generating response...\n\n"));
+        events.extend(blocks.close());
+
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Hello".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+        events.extend(anthropic_content_events(upstream, blocks, "m".into(), false).collect::<Vec<_>>().await);
+
+        let first_delta = events.iter().find(|(n, _)| *n == "content_block_delta").unwrap();
+        assert_eq!(first_delta.1["index"], 0);
+        assert_eq!(first_delta.1["delta"]["text"], "Hello");
+
+        // Waits and fallbacks are still reported
+        let mut blocks = BlockSequencer::for_profile(config.client_profile).with_progress_log(false);
+        assert!(!blocks.status("> Rate limited. Queuing for 5 seconds...\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_claude_code_profile_streams_thinking_blocks() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let upstream = futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Let me see".into(), is_thinking: true, ..Default::default() }),
+            Ok(StreamChunk { delta: "Answer".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+        let blocks = BlockSequencer::for_profile(ClientProfile::ClaudeCode);
+        let events: Vec<_> = anthropic_content_events(upstream, blocks, "m".into(), false).collect().await;
+
+        assert_well_nested(&events);
+        let deltas: Vec<&Value> = events.iter().filter(|(n, _)| *n == "content_block_delta").map(|(_, d)| &d["delta"]).collect();
+        assert_eq!(deltas[0], &json!({ "type": "thinking_delta", "thinking": "Let me see" }));
+        assert_eq!(deltas[1], &json!({ "type": "text_delta", "text": "Answer" }));
+    }
+
+    #[tokio::test]
+    async fn test_mid_stream_error_keeps_partial_content() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let chunks = || futures_util::stream::iter(vec![
+            Ok(StreamChunk { delta: "Here is most of the answer".into(), ..Default::default() }),
+            Err(anyhow::anyhow!("connection reset")),
+        ]);
+
+        let events: Vec<_> = anthropic_content_events(chunks(), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), true)
+            .collect().await;
+        assert_well_nested(&events);
+        assert!(events.iter().all(|(name, _)| *name != "error"));
+        let text: String = events.iter().filter_map(|(_, d)| d["delta"]["text"].as_str()).collect();
+        assert!(text.starts_with("Here is most of the answer"));
+        assert!(text.contains("connection reset"));
+        let delta = events.iter().find(|(name, _)| *name == "message_delta").unwrap();
+        assert_eq!(delta.1["delta"]["stop_reason"], "error");
+        assert_eq!(events.last().unwrap().0, "message_stop");
+
+        // Without the flag, or before any content, the error is reported as such
+        let events: Vec<_> = anthropic_content_events(chunks(), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false)
+            .collect().await;
+        assert_eq!(events.last().unwrap().0, "error");
+        let upstream = futures_util::stream::iter(vec![Err(anyhow::anyhow!("connection reset"))]);
+        let events: Vec<_> = anthropic_content_events(upstream, BlockSequencer::for_profile(ClientProfile::default()), "m".into(), true)
+            .collect().await;
+        assert_eq!(events.last().unwrap().0, "error");
+    }
+
+    #[test]
+    fn test_include_thoughts_per_request() {
+        let config = Config::default();
+        assert!(include_thoughts(&config, &json!({ "thinking": { "type": "enabled" } })));
+        assert!(!include_thoughts(&config, &json!({ "thinking": { "type": "enabled", "include_thoughts": false } })));
+        assert!(!include_thoughts(&config, &json!({ "include_thoughts": false })));
+
+        let quiet = Config { include_thoughts: false, ..Config::default() };
+        assert!(!include_thoughts(&quiet, &json!({})));
+        assert!(include_thoughts(&quiet, &json!({ "thinking": { "include_thoughts": true } })));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_model_reported_unavailable() {
+        let manager = claude_limited_manager().await;
+
+        let claude = model_availability(&manager, "antigravity-claude-sonnet-4-5").await.unwrap();
+        assert_eq!(claude["currently_available"], false);
+        assert_eq!(claude["owned_by"], "anthropic");
+
+        let gemini = model_availability(&manager, "antigravity-gemini-3-flash").await.unwrap();
+        assert_eq!(gemini["currently_available"], true);
+
+        assert!(model_availability(&manager, "gpt-4").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_project_header_overrides_config() {
+        let config = Config { project_id: Some("default-project".into()), ..Config::default() };
+        let fingerprint = Fingerprint::generate();
+
+        let mut headers = HeaderMap::new();
+        assert!(request_config(&config, &headers).is_none());
+
+        headers.insert(PROJECT_ID_HEADER, HeaderValue::from_static("billing-project"));
+        let overridden = request_config(&config, &headers).unwrap();
+        let cache = Arc::new(ContextCache::new(4096, std::time::Duration::from_secs(3600)));
+        let responses = Arc::new(ResponseCache::new(0, false));
+        let stats = AntigravityClient::new_endpoint_stats(0);
+        let client = build_client(&overridden, &fingerprint, &cache, &responses, &stats, "token".into()).unwrap();
+        assert_eq!(client.project_id().await, "billing-project");
+    }
+
+    #[test]
+    fn test_timeout_header_capped_at_maximum() {
+        let config = Config { max_request_timeout_secs: 600, ..Config::default() };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("5"));
+        assert_eq!(request_config(&config, &headers).unwrap().request_timeout_secs, 5);
+
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("86400"));
+        assert_eq!(request_config(&config, &headers).unwrap().request_timeout_secs, 600);
+
+        // Unparseable or zero values are ignored
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("soon"));
+        assert!(request_config(&config, &headers).is_none());
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("0"));
+        assert!(request_config(&config, &headers).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_running_output_tokens() {
+        use browser_automator::{StreamChunk, Usage};
+        use futures_util::StreamExt;
+
+        // 10 chunks of 400 chars, roughly 100 tokens each
+        let mut chunks: Vec<anyhow::Result<StreamChunk>> = (0..10)
+            .map(|_| Ok(StreamChunk { delta: "x".repeat(400), ..Default::default() }))
+            .collect();
+        chunks.push(Ok(StreamChunk {
+            done: true,
+            usage: Some(Usage { prompt_tokens: 10, completion_tokens: 987, total_tokens: 997, ..Default::default() }),
+            ..Default::default()
+        }));
+
+        let events: Vec<_> = anthropic_content_events(futures_util::stream::iter(chunks), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false)
+            .collect()
+            .await;
+        let usages: Vec<(bool, u64)> = events.iter()
+            .filter(|(name, _)| *name == "message_delta")
+            .map(|(_, d)| (d["delta"]["stop_reason"].is_null(), d["usage"]["output_tokens"].as_u64().unwrap()))
+            .collect();
+
+        let (progress, last) = usages.split_at(usages.len() - 1);
+        assert!(progress.len() >= 2);
+        assert!(progress.iter().all(|(intermediate, _)| *intermediate));
+        assert!(progress.windows(2).all(|w| w[0].1 < w[1].1), "{:?}", progress);
+        // The final delta uses the authoritative usageMetadata count
+        assert_eq!(last[0], (false, 987));
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_thinking_runs_framed_separately() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let chunk = |delta: &str, is_thinking: bool| Ok(StreamChunk { delta: delta.into(), is_thinking, ..Default::default() });
+        let chunks = || futures_util::stream::iter(vec![
+            chunk("Plan the first step.", true),
+            chunk("\n\nStill planning.", true),
+            chunk("Step one done.", false),
+            chunk("Now the second step.", true),
+            chunk("All done.", false),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+
+        // Native blocks: thinking, text, thinking, text
+        let events: Vec<_> = anthropic_content_events(chunks(), BlockSequencer::for_profile(ClientProfile::ClaudeCode), "m".into(), false).collect().await;
+        assert_well_nested(&events);
+        let blocks: Vec<(String, String)> = events.iter()
+            .filter(|(n, _)| *n == "content_block_start")
+            .map(|(_, start)| {
+                let index = &start["index"];
+                let kind = start["content_block"]["type"].as_str().unwrap().to_string();
+                let content: String = events.iter()
+                    .filter(|(n, d)| *n == "content_block_delta" && d["index"] == *index)
+                    .filter_map(|(_, d)| d["delta"][kind.as_str()].as_str())
+                    .collect();
+                (kind, content)
+            })
+            .collect();
+        assert_eq!(blocks, [
+            ("thinking".to_string(), "Plan the first step.\n\nStill planning.".to_string()),
+            ("text".to_string(), "Step one done.".to_string()),
+            ("thinking".to_string(), "Now the second step.".to_string()),
+            ("text".to_string(), "All done.".to_string()),
+        ]);
+
+        // Inline: each thinking run is one quote, each answer run its own paragraph
+        let events: Vec<_> = anthropic_content_events(chunks(), BlockSequencer::for_profile(ClientProfile::NoSystemLog), "m".into(), false).collect().await;
+        let streamed: String = events.iter().filter_map(|(_, e)| e["delta"]["text"].as_str()).collect();
+        assert_eq!(
+            streamed,
+            "\n> *Thinking: Plan the first step.*\n> \n> Still planning.\n\nStep one done.\n> *Thinking: Now the second step.*\n\nAll done."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_thinking_boundary_does_not_split_code_fence() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let text = |delta: &str, is_thinking: bool| Ok(StreamChunk { delta: delta.into(), is_thinking, ..Default::default() });
+        let chunks: Vec<anyhow::Result<StreamChunk>> = vec![
+            text("Let me check.", true),
+            // The fence opens in the thought (with its backticks split) and closes in the answer
+            text("Draft:\n``", true),
+            text("`rust\nfn main() {", true),
+            text("}\n```\n", false),
+            text("Done.", false),
+        ];
+
+        let events: Vec<_> = anthropic_content_events(futures_util::stream::iter(chunks), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false)
+            .collect()
+            .await;
+        let streamed: String = events.iter()
+            .filter_map(|(_, e)| e["delta"]["text"].as_str())
+            .collect();
+
+        let fenced = streamed.split("```").nth(1).unwrap();
+        assert_eq!(fenced, "rust\nfn main() {}\n");
+        assert!(streamed.starts_with("\n> *Thinking: Let me check.*"));
+
+        let mut fence = FenceTracker::default();
+        fence.push("a ``");
+        assert!(!fence.is_open());
+        fence.push("`sh\nls\n``");
+        assert!(fence.is_open());
+        fence.push("`");
+        assert!(!fence.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_first_token_latency_recorded() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        let metrics = crate::metrics::Metrics::new();
+        let started = std::time::Instant::now();
+        let chunks: Vec<anyhow::Result<StreamChunk>> = vec![
+            Ok(StreamChunk { delta: "Hello".into(), ..Default::default() }),
+            Ok(StreamChunk { delta: " world".into(), ..Default::default() }),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ];
+        let upstream = futures_util::stream::iter(chunks).then(|chunk| async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            chunk
+        });
+
+        let events = anthropic_content_events(upstream, BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false);
+        let events: Vec<_> = record_first_token(events, started, &metrics.first_token_latency).collect().await;
+        assert!(events.iter().filter(|(name, _)| *name == "content_block_delta").count() >= 2);
+
+        // One observation per stream, taken at the first delta
+        assert_eq!(metrics.first_token_latency.count(), 1);
+        let p50 = metrics.to_json()["first_token_latency"]["p50_ms"].as_u64().unwrap();
+        assert!(p50 >= 20, "{}", p50);
+    }
+
+    #[tokio::test]
+    async fn test_thinking_tokens_reported_in_usage() {
+        use browser_automator::{StreamChunk, Usage};
+        use futures_util::StreamExt;
+
+        let usage = Usage { prompt_tokens: 10, completion_tokens: 40, total_tokens: 1074, thinking_tokens: 1024 };
+        let mut chunks: Vec<anyhow::Result<StreamChunk>> = (0..4)
+            .map(|_| Ok(StreamChunk { delta: "t".repeat(400), is_thinking: true, ..Default::default() }))
+            .collect();
+        chunks.push(Ok(StreamChunk { delta: "answer".into(), ..Default::default() }));
+        chunks.push(Ok(StreamChunk { done: true, usage: Some(usage.clone()), ..Default::default() }));
+
+        let events: Vec<_> = anthropic_content_events(futures_util::stream::iter(chunks), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false)
+            .collect()
+            .await;
+        let deltas: Vec<&Value> = events.iter().filter(|(name, _)| *name == "message_delta").map(|(_, d)| d).collect();
+
+        // Progress deltas carry the running thinking estimate
+        assert!(deltas[0]["delta"]["stop_reason"].is_null());
+        assert_eq!(deltas[0]["usage"]["thinking_tokens"], 300);
+        // The final delta carries thoughtsTokenCount
+        let last = deltas.last().unwrap();
+        assert_eq!(last["usage"]["thinking_tokens"], 1024);
+        assert_eq!(last["usage"]["output_tokens"], 40);
+
+        // Non-streaming OpenAI usage reports them as reasoning tokens
+        assert_eq!(openai_usage(Some(&usage))["completion_tokens_details"]["reasoning_tokens"], 1024);
+        assert!(openai_usage(None).get("completion_tokens_details").is_none());
+    }
+
+    #[test]
+    fn test_count_tokens_includes_tools_and_images() {
+        let plain = json!({ "messages": [{ "role": "user", "content": "What's the weather?" }] });
+        let base = estimate_input_tokens(&plain);
+
+        let mut with_tool = plain.clone();
+        with_tool["tools"] = json!([{
+            "name": "get_weather",
+            "description": "Get the current weather for a city",
+            "input_schema": { "type": "object", "properties": { "city": { "type": "string" } }, "required": ["city"] }
+        }]);
+        assert!(estimate_input_tokens(&with_tool) > base);
+
+        let with_image = json!({ "messages": [{ "role": "user", "content": [
+            { "type": "text", "text": "What's the weather?" },
+            { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo=" } }
+        ] }] });
+        assert_eq!(estimate_input_tokens(&with_image), base + IMAGE_TOKEN_ESTIMATE);
+    }
+
+    #[test]
+    fn test_disabled_model_hidden_and_rejected() {
+        let ids = |catalog: Value| -> Vec<String> {
+            catalog.as_array().unwrap().iter().map(|m| m["id"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(ids(enabled_model_catalog(&Config::default())), ids(model_catalog()));
+
+        let config = Config { enabled_models: Some(vec!["gemini-3-flash".into()]), ..Config::default() };
+        assert_eq!(ids(enabled_model_catalog(&config)), ["antigravity-gemini-3-flash"]);
+
+        assert!(model_enabled(&config, "antigravity-gemini-3-flash"));
+        assert!(!model_enabled(&config, "antigravity-claude-opus-4-5-thinking"));
+        assert!(!model_enabled(&config, "google-bridge"));
+        assert!(!antigravity_model_enabled(&config, map_anthropic_to_antigravity(&config, "claude-opus-4-5")));
+
+        let error = model_disabled_error("antigravity-claude-opus-4-5-thinking", false);
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(error.body["error"]["code"], "model_not_found");
+        assert_eq!(model_disabled_error("claude-opus-4-5", true).body["error"]["type"], "not_found_error");
+    }
+
+    #[test]
+    fn test_logprobs_in_openai_shape() {
+        let generation = generation_params(&json!({ "logprobs": true, "top_logprobs": 3 }));
+        assert_eq!(generation_params(&json!({ "seed": 42 })).seed, Some(42));
+        assert!(generation.logprobs);
+        assert_eq!(generation.top_logprobs, Some(3));
+
+        let response = ChatResponse {
+            content: "Hi".into(),
+            thinking: None,
+            model: "gemini-3-flash".into(),
+            finish_reason: "stop".into(),
+            usage: None,
+            other_candidates: Vec::new(),
+            logprobs: Some(vec![TokenLogprob { token: "Hi".into(), logprob: -0.25, top: vec![("Hi".into(), -0.25)] }]),
+        };
+        let logprobs = &chat_choices(&response)[0]["logprobs"]["content"][0];
+        assert_eq!(logprobs["token"], "Hi");
+        assert_eq!(logprobs["logprob"], -0.25);
+        assert_eq!(logprobs["bytes"], json!([72, 105]));
+        assert_eq!(logprobs["top_logprobs"][0]["token"], "Hi");
+    }
+
+    #[test]
+    fn test_n_maps_to_candidates_and_choices() {
+        let payload = json!({ "model": "antigravity-gemini-3-flash", "n": 2, "messages": [] });
+        let generation = generation_params(&payload);
+        assert_eq!(generation.candidate_count, Some(2));
+        assert!(streamed_candidates_error(&payload, &generation).is_none());
+
+        let response = ChatResponse {
+            content: "Hi".into(),
+            thinking: None,
+            model: "gemini-3-flash".into(),
+            finish_reason: "stop".into(),
+            usage: None,
+            other_candidates: vec!["Hey".into()],
+            logprobs: None,
+        };
+        let choices = chat_choices(&response);
+        assert_eq!(choices.as_array().unwrap().len(), 2);
+        assert_eq!(choices[1]["index"], 1);
+        assert_eq!(choices[1]["message"]["content"], "Hey");
+
+        assert!(choices[0]["logprobs"].is_null());
+
+        let streaming = json!({ "n": 2, "stream": true });
+        let error = streamed_candidates_error(&streaming, &generation_params(&streaming)).unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.body["error"]["param"], "n");
+    }
+
+    #[test]
+    fn test_request_user_tagged_in_usage_ledger() {
+        let metrics = crate::metrics::Metrics::new();
+        let usage = browser_automator::Usage { prompt_tokens: 12, completion_tokens: 30, total_tokens: 42, ..Default::default() };
+
+        let openai = json!({ "model": "antigravity-gemini-3-flash", "user": "alice", "messages": [] });
+        record_usage(&metrics.usage, &openai, "antigravity-gemini-3-flash", Some(&usage));
+        let anthropic = json!({ "model": "claude-sonnet-4-5", "metadata": { "user_id": "bob" }, "messages": [] });
+        record_usage(&metrics.usage, &anthropic, "claude-sonnet-4-5", None);
+
+        let entries = metrics.usage.entries();
+        assert_eq!(entries[0].user.as_deref(), Some("alice"));
+        assert_eq!(entries[0].completion_tokens, 30);
+        assert_eq!(entries[1].user.as_deref(), Some("bob"));
+
+        let json = metrics.to_json();
+        assert_eq!(json["usage"]["by_user"]["alice"]["prompt_tokens"], 12);
+        assert_eq!(json["usage"]["by_user"]["bob"]["requests"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_first_matching_stop_sequence_reported() {
+        use browser_automator::StreamChunk;
+        use futures_util::StreamExt;
+
+        // OpenAI `stop` may be a single string or an array
+        assert_eq!(stop_sequences(&serde_json::json!({ "stop": "END" })), ["END"]);
+        assert_eq!(stop_sequences(&serde_json::json!({ "stop": ["END", ""] })), ["END"]);
+
+        // "##" is listed second but occurs first, split across two chunks
+        let stops = stop_sequences(&serde_json::json!({ "stop_sequences": ["END", "##"] }));
+        let chunk = |delta: &str| Ok(StreamChunk { delta: delta.into(), ..Default::default() });
+        let upstream = futures_util::stream::iter(vec![
+            chunk("Answer: 42 #"),
+            chunk("# notes END"),
+            chunk(" more"),
+            Ok(StreamChunk { done: true, ..Default::default() }),
+        ]);
+
+        let events: Vec<_> = anthropic_content_events(stop_at_sequences(upstream, stops.clone()), BlockSequencer::for_profile(ClientProfile::default()), "m".into(), false)
+            .collect().await;
+        let streamed: String = events.iter().filter_map(|(_, e)| e["delta"]["text"].as_str()).collect();
+        assert_eq!(streamed, "Answer: 42 ");
+        let (_, delta) = events.iter().rfind(|(n, _)| *n == "message_delta").unwrap();
+        assert_eq!(delta["delta"]["stop_reason"], "stop_sequence");
+        assert_eq!(delta["delta"]["stop_sequence"], "##");
+
+        // Complete responses are cut the same way
+        let mut content = "Answer: 42 ## notes END".to_string();
+        assert_eq!(truncate_at_stop(&mut content, stops).as_deref(), Some("##"));
+        assert_eq!(content, "Answer: 42 ");
+    }
 }
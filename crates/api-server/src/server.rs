@@ -3,9 +3,11 @@
 //! This module exposes the server logic for use by both the CLI binary
 //! and the TUI application.
 
-use axum::{routing::{get, post}, Router};
+use axum::{middleware, routing::{get, post}, Router};
 use common::config::Config;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::oneshot;
 use tower_http::trace::TraceLayer;
@@ -21,19 +23,172 @@ pub fn create_router(state: AppState) -> Router {
         .route("/health", get(routes::health))
         // OpenAI compatible endpoints
         .route("/v1/chat/completions", post(routes::chat_completions))
+        .route("/v1/completions", post(routes::completions))
         .route("/v1/models", get(routes::list_models))
+        .route("/v1/models/{id}", get(routes::get_model))
         // Anthropic compatible endpoints
         .route("/v1/messages", post(routes::messages))
         .route("/v1/messages/count_tokens", post(routes::count_tokens))
+        // Account diagnostics
+        .route("/v1/accounts/status", get(routes::accounts_status))
+        .route("/v1/admin/accounts/{email}/disable", post(routes::disable_account))
+        .route("/v1/admin/accounts/{email}/enable", post(routes::enable_account))
+        .route("/v1/admin/accounts/{email}/priority", post(routes::set_account_priority))
+        .route("/v1/admin/accounts/{email}/families", post(routes::set_account_families))
+        .route("/v1/admin/benchmark", get(routes::benchmark))
+        .route("/v1/admin/cancel/{request_id}", post(routes::cancel_request))
+        .route("/v1/admin/config", get(routes::get_config).patch(routes::patch_config))
+        .route("/v1/admin/metrics", get(routes::get_metrics))
         // Organization endpoint (required by Claude CLI)
         .route("/v1/organizations/me", get(routes::get_organization))
+        .layer(middleware::from_fn_with_state(state.clone(), routes::require_api_key))
+        .layer(middleware::from_fn_with_state(state.clone(), routes::client_profile_headers))
         .layer(TraceLayer::new_for_http())
         .with_state(state)
 }
 
+/// Whether `host` only accepts connections from this machine
+fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.trim_matches(['[', ']']).parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Checks a bind address before the server starts
+///
+/// Anyone who can reach a non-loopback address can spend the linked Google
+/// accounts' quota, so binding one without an `api_key` is refused unless
+/// `allow_lan` is set, and then only warned about.
+pub fn check_bind_exposure(host: &str, config: &Config) -> anyhow::Result<()> {
+    let has_api_key = config.api_key.as_deref().is_some_and(|key| !key.is_empty());
+    if is_loopback_host(host) || has_api_key {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        config.allow_lan,
+        "Refusing to bind {} without an api_key: anyone on the network could use your Google quota. \
+         Set api_key in the config, bind 127.0.0.1, or pass --allow-lan to accept the risk.",
+        host
+    );
+    tracing::warn!("!!! Binding {} with no api_key: anyone who can reach this port can use your Google quota !!!", host);
+    Ok(())
+}
+
+/// How long in-flight requests (including open streams) get to finish after
+/// a shutdown signal before their connections are dropped
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a server run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// Every in-flight request finished
+    Drained,
+    /// The drain timeout elapsed with requests still running
+    TimedOut,
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Serves `app` until `shutdown` resolves, then stops accepting connections
+/// and waits up to `drain_timeout` for in-flight requests
+pub async fn serve_until(
+    listener: TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    drain_timeout: Duration,
+) -> anyhow::Result<ShutdownOutcome> {
+    let (draining_tx, draining_rx) = oneshot::channel::<()>();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown.await;
+        tracing::info!("Shutting down; draining in-flight requests (up to {}s)", drain_timeout.as_secs());
+        let _ = draining_tx.send(());
+    });
+
+    let drain_deadline = async {
+        match draining_rx.await {
+            Ok(()) => tokio::time::sleep(drain_timeout).await,
+            // The server stopped on its own
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = server.into_future() => {
+            result?;
+            Ok(ShutdownOutcome::Drained)
+        }
+        _ = drain_deadline => {
+            tracing::warn!("Drain timeout elapsed; dropping remaining connections");
+            Ok(ShutdownOutcome::TimedOut)
+        }
+    }
+}
+
+/// Serves until `shutdown` resolves, then drains and saves account state
+async fn serve_and_persist(
+    listener: TcpListener,
+    state: AppState,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let account_manager = state.account_manager.clone();
+    let warming = &state.config.token_warming;
+    let warmer = warming.enabled.then(|| {
+        let manager = account_manager.clone();
+        let interval = Duration::from_secs(warming.interval_secs.max(1));
+        let stagger = Duration::from_millis(warming.stagger_ms);
+        tokio::spawn(async move { manager.run_token_warmer(interval, stagger).await })
+    });
+    let app = create_router(state);
+
+    let outcome = serve_until(listener, app, shutdown, SHUTDOWN_DRAIN_TIMEOUT).await;
+    if let Some(warmer) = warmer {
+        warmer.abort();
+    }
+
+    // Rotated refresh tokens only live in memory until persisted
+    if let Err(e) = account_manager.persist().await {
+        tracing::error!("Failed to save account state on shutdown: {}", e);
+    }
+    tracing::info!("Server stopped");
+    outcome.map(|_| ())
+}
+
+/// Serves until Ctrl-C/SIGTERM, shutting down gracefully (for CLI usage)
+pub async fn serve_with_signals(listener: TcpListener, state: AppState) -> anyhow::Result<()> {
+    serve_and_persist(listener, state, shutdown_signal()).await
+}
+
 /// Server handle that can be used to shut down the server
 pub struct ServerHandle {
     shutdown_tx: oneshot::Sender<()>,
+    task: tokio::task::JoinHandle<()>,
 }
 
 impl ServerHandle {
@@ -41,6 +196,12 @@ impl ServerHandle {
     pub fn shutdown(self) {
         let _ = self.shutdown_tx.send(());
     }
+
+    /// Whether the server task is still alive (false once it has exited,
+    /// panicked or been aborted)
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
 }
 
 /// Start the server in a background task, returning a handle for shutdown
@@ -49,44 +210,561 @@ pub async fn start_server(
     host: &str,
     port: u16,
 ) -> anyhow::Result<ServerHandle> {
+    check_bind_exposure(host, &config)?;
     let automator = browser_automator::Automator::new(&config)?;
     let state = AppState::with_oauth(config, automator).await?;
 
     let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
     let listener = TcpListener::bind(addr).await?;
 
-    let app = create_router(state);
-
     let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
     // Spawn the server in a background task
-    tokio::spawn(async move {
-        axum::serve(listener, app)
-            .with_graceful_shutdown(async {
-                let _ = shutdown_rx.await;
-                tracing::info!("Received shutdown signal");
-            })
-            .await
-            .ok();
+    let task = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = shutdown_rx.await;
+            tracing::info!("Received shutdown signal");
+        };
+        if let Err(e) = serve_and_persist(listener, state, shutdown).await {
+            tracing::error!("Server error: {}", e);
+        }
     });
 
     tracing::info!("Server started on {}", addr);
 
-    Ok(ServerHandle { shutdown_tx })
+    Ok(ServerHandle { shutdown_tx, task })
 }
 
 /// Start the server and block until it shuts down (for CLI usage)
 pub async fn run_server_blocking(config: Config, host: &str, port: u16) -> anyhow::Result<()> {
+    check_bind_exposure(host, &config)?;
     let automator = browser_automator::Automator::new(&config)?;
     let state = AppState::with_oauth(config, automator).await?;
 
     let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
     let listener = TcpListener::bind(addr).await?;
 
-    let app = create_router(state);
-
     tracing::info!("Server running on {}", addr);
-    axum::serve(listener, app).await?;
+    serve_with_signals(listener, state).await
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    async fn local_listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn test_aborted_server_task_is_not_running() {
+        let (shutdown_tx, _shutdown_rx) = oneshot::channel::<()>();
+        let handle = ServerHandle { shutdown_tx, task: tokio::spawn(std::future::pending()) };
+        assert!(handle.is_running());
+
+        handle.task.abort();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.is_running() {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_signal_stops_server() {
+        let (listener, _) = local_listener().await;
+        let (fire, fired) = oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = fired.await;
+        };
+
+        let server = tokio::spawn(serve_until(listener, Router::new(), shutdown, Duration::from_secs(5)));
+        fire.send(()).unwrap();
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert_eq!(outcome, ShutdownOutcome::Drained);
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_bounds_hanging_request() {
+        let (listener, addr) = local_listener().await;
+        let app = Router::new().route("/hang", get(std::future::pending::<&'static str>));
+        let (fire, fired) = oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = fired.await;
+        };
+        let server = tokio::spawn(serve_until(listener, app, shutdown, Duration::from_millis(100)));
+
+        // Start a request that never completes
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET /hang HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        fire.send(()).unwrap();
+        let outcome = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert_eq!(outcome, ShutdownOutcome::TimedOut);
+    }
+    /// Stands in for Antigravity: records each request body and answers
+    /// with one SSE event
+    async fn mock_upstream(event: serde_json::Value) -> (String, Arc<Mutex<Vec<(String, serde_json::Value)>>>) {
+        let (listener, addr) = local_listener().await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().fallback(move |uri: axum::http::Uri, body: axum::body::Bytes| {
+            let recorded = recorded.clone();
+            let event = event.clone();
+            async move {
+                let body = serde_json::from_slice(&body).unwrap_or_default();
+                recorded.lock().unwrap().push((uri.to_string(), body));
+                ([(axum::http::header::CONTENT_TYPE, "text/event-stream")], format!("data: {}\n\n", event))
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), seen)
+    }
+
+    /// Router backed by one ready account and the given upstream
+    async fn test_router(upstream: String) -> Router {
+        test_router_with(upstream, Config::default()).await
+    }
+
+    /// Like `test_router`, starting from `config`
+    async fn test_router_with(upstream: String, config: Config) -> Router {
+        let manager = oauth::AccountManager::empty();
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "dev@example.com".into(),
+        }).await.unwrap();
+
+        let config = Config {
+            project_id: Some("test-project".into()),
+            ..config
+        };
+        create_router(AppState::headless(config, manager, Some(upstream)))
+    }
+
+    #[tokio::test]
+    async fn test_lan_bind_needs_api_key_or_allow_lan() {
+        let open = Config::default();
+        assert!(check_bind_exposure("127.0.0.1", &open).is_ok());
+        assert!(check_bind_exposure("localhost", &open).is_ok());
+        assert!(check_bind_exposure("::1", &open).is_ok());
+
+        let refused = check_bind_exposure("0.0.0.0", &open).unwrap_err();
+        assert!(refused.to_string().contains("--allow-lan"));
+        assert!(check_bind_exposure("192.168.1.20", &open).is_err());
+
+        // Allowed with a warning, or protected by a key
+        assert!(check_bind_exposure("0.0.0.0", &Config { allow_lan: true, ..Config::default() }).is_ok());
+        let keyed = Config { api_key: Some("sk-local".into()), ..Config::default() };
+        assert!(check_bind_exposure("0.0.0.0", &keyed).is_ok());
+        assert!(check_bind_exposure("0.0.0.0", &Config { api_key: Some(String::new()), ..Config::default() }).is_err());
+
+        // With a key set, requests must carry it
+        let app = create_router(AppState::headless(keyed, oauth::AccountManager::empty(), None));
+        let get = |path: &str, key: Option<(&'static str, &'static str)>| {
+            let mut request = axum::http::Request::get(path);
+            if let Some((name, value)) = key {
+                request = request.header(name, value);
+            }
+            tower::ServiceExt::oneshot(app.clone(), request.body(axum::body::Body::empty()).unwrap())
+        };
+        assert_eq!(get("/v1/models", None).await.unwrap().status(), axum::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/v1/models", Some(("authorization", "Bearer wrong"))).await.unwrap().status(), axum::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/v1/models", Some(("authorization", "Bearer sk-local"))).await.unwrap().status(), axum::http::StatusCode::OK);
+        assert_eq!(get("/v1/models", Some(("x-api-key", "sk-local"))).await.unwrap().status(), axum::http::StatusCode::OK);
+        assert_eq!(get("/health", None).await.unwrap().status(), axum::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_config_admin_needs_api_key() {
+        let patch = |app: Router, key: Option<&'static str>| {
+            let mut request = axum::http::Request::patch("/v1/admin/config")
+                .header("content-type", "application/json");
+            if let Some(key) = key {
+                request = request.header("x-api-key", key);
+            }
+            let body = axum::body::Body::from(r#"{"project_id":"elsewhere"}"#);
+            tower::ServiceExt::oneshot(app, request.body(body).unwrap())
+        };
+
+        // Without a configured key the config endpoints stay closed
+        let open = create_router(AppState::headless(Config::default(), oauth::AccountManager::empty(), None));
+        assert_eq!(patch(open.clone(), None).await.unwrap().status(), axum::http::StatusCode::FORBIDDEN);
+        let get = axum::http::Request::get("/v1/admin/config").body(axum::body::Body::empty()).unwrap();
+        assert_eq!(tower::ServiceExt::oneshot(open, get).await.unwrap().status(), axum::http::StatusCode::FORBIDDEN);
+
+        // With one, the request has to carry it
+        let keyed = Config { api_key: Some("sk-local".into()), ..Config::default() };
+        let app = create_router(AppState::headless(keyed, oauth::AccountManager::empty(), None));
+        assert_eq!(patch(app.clone(), None).await.unwrap().status(), axum::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(patch(app.clone(), Some("wrong")).await.unwrap().status(), axum::http::StatusCode::UNAUTHORIZED);
+        let get = axum::http::Request::get("/v1/admin/config").header("x-api-key", "sk-local")
+            .body(axum::body::Body::empty()).unwrap();
+        assert_eq!(tower::ServiceExt::oneshot(app, get).await.unwrap().status(), axum::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_messages_happy_path() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": {
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": "The config lives in src/config.rs." }] },
+                    "finishReason": "STOP"
+                }],
+                "usageMetadata": { "promptTokenCount": 1200, "candidatesTokenCount": 9, "totalTokenCount": 1209 }
+            }
+        })).await;
+        let app = test_router(upstream).await;
+
+        // Shaped like a Claude Code turn: cached system prompt, tools, one user message
+        let payload = serde_json::json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 32000,
+            "system": [
+                { "type": "text", "text": "You are Claude Code, a CLI for software engineering." },
+                { "type": "text", "text": "Use the tools to inspect the repository.", "cache_control": { "type": "ephemeral" } }
+            ],
+            "tools": [{
+                "name": "Read",
+                "description": "Reads a file from the local filesystem.",
+                "input_schema": {
+                    "$schema": "http://json-schema.org/draft-07/schema#",
+                    "type": "object",
+                    "properties": { "file_path": { "type": "string", "description": "Absolute path" } },
+                    "required": ["file_path"],
+                    "additionalProperties": false
+                }
+            }],
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Where is the config loaded?" }] }],
+            "metadata": { "user_id": "user_abc_session_123" }
+        });
+        let request = axum::http::Request::post("/v1/messages")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(payload.to_string()))
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        assert_eq!(response.headers()["x-aether-strategy"], "primary");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["type"], "message");
+        assert_eq!(body["role"], "assistant");
+        assert_eq!(body["model"], "claude-sonnet-4-5-20250929");
+        assert_eq!(body["content"], serde_json::json!([{ "type": "text", "text": "The config lives in src/config.rs." }]));
+        assert_eq!(body["usage"]["input_tokens"], 1200);
+        assert_eq!(body["usage"]["output_tokens"], 9);
+
+        // One upstream call, carrying the system prompt and the converted tool
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        let (uri, sent) = &seen[0];
+        assert_eq!(uri, "/v1internal:streamGenerateContent?alt=sse");
+        assert_eq!(sent["project"], "test-project");
+        assert_eq!(sent["model"], "claude-sonnet-4-5");
+        assert!(sent["request"]["systemInstruction"]["parts"][0]["text"].as_str().unwrap().contains("Use the tools"));
+        assert_eq!(sent["request"]["contents"][0]["parts"][0]["text"], "Where is the config loaded?");
+        let declaration = &sent["request"]["tools"][0]["function_declarations"][0];
+        assert_eq!(declaration["name"], "Read");
+        assert_eq!(declaration["parameters"]["required"], serde_json::json!(["file_path"]));
+        assert!(declaration["parameters"].get("$schema").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_project_header_sent_upstream() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }] }
+        })).await;
+        let app = test_router(upstream).await;
+
+        for project in [Some("billing-project"), None] {
+            let payload = serde_json::json!({
+                "model": "antigravity-gemini-3-flash",
+                "messages": [{ "role": "user", "content": "Hi" }]
+            });
+            let mut request = axum::http::Request::post("/v1/chat/completions")
+                .header("content-type", "application/json");
+            if let Some(project) = project {
+                request = request.header("x-goog-project-id", project);
+            }
+            let request = request.body(axum::body::Body::from(payload.to_string())).unwrap();
+            let response = tower::ServiceExt::oneshot(app.clone(), request).await.unwrap();
+            assert_eq!(response.status(), axum::http::StatusCode::OK);
+        }
+
+        let projects: Vec<serde_json::Value> = seen.lock().unwrap().iter()
+            .filter(|(uri, _)| uri.contains("streamGenerateContent"))
+            .map(|(_, body)| body["project"].clone())
+            .collect();
+        assert_eq!(projects, [serde_json::json!("billing-project"), serde_json::json!("test-project")]);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_deterministic_request_served_from_cache() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": {
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": "4" }] },
+                    "finishReason": "STOP"
+                }]
+            }
+        })).await;
+        let mut config = Config::default();
+        config.response_cache.enabled = true;
+        let app = test_router_with(upstream, config).await;
+
+        let send = |temperature: f64| {
+            let payload = serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "max_tokens": 100,
+                "temperature": temperature,
+                "messages": [{ "role": "user", "content": "What is 2 + 2?" }]
+            });
+            let request = axum::http::Request::post("/v1/messages")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+                assert_eq!(response.status(), axum::http::StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let first = send(0.0).await;
+        let second = send(0.0).await;
+        assert_eq!(first["content"], second["content"]);
+        assert_eq!(second["content"][0]["text"], "4");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // Sampled requests always go upstream
+        send(0.7).await;
+        send(0.7).await;
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_usage_recorded() {
+        let (upstream, _) = mock_upstream(serde_json::json!({
+            "response": {
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": "Done." }] },
+                    "finishReason": "STOP"
+                }],
+                "usageMetadata": { "promptTokenCount": 40, "candidatesTokenCount": 2, "totalTokenCount": 42 }
+            }
+        })).await;
+        let app = test_router(upstream).await;
+        let send = |path: &'static str, payload: serde_json::Value| {
+            let request = axum::http::Request::post(path)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(payload.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+                assert_eq!(response.status(), axum::http::StatusCode::OK);
+                axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            }
+        };
+
+        send("/v1/messages", serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 100,
+            "stream": true,
+            "metadata": { "user_id": "messages-user" },
+            "messages": [{ "role": "user", "content": "Hi" }]
+        })).await;
+        send("/v1/chat/completions", serde_json::json!({
+            "model": "antigravity-gemini-3-flash",
+            "stream": true,
+            "user": "chat-user",
+            "messages": [{ "role": "user", "content": "Hi" }]
+        })).await;
+        send("/v1/completions", serde_json::json!({
+            "model": "antigravity-gemini-3-flash",
+            "stream": true,
+            "user": "completions-user",
+            "prompt": "Hi"
+        })).await;
+
+        let request = axum::http::Request::get("/v1/admin/metrics").body(axum::body::Body::empty()).unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let metrics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        for user in ["messages-user", "chat-user", "completions-user"] {
+            assert_eq!(metrics["usage"]["by_user"][user], serde_json::json!({
+                "requests": 1, "prompt_tokens": 40, "completion_tokens": 2
+            }), "{}", user);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_stream_retried_then_reported() {
+        // Blank text before the end counts as nothing too
+        let empty = serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "" }] }, "finishReason": "STOP" }] }
+        });
+        let stream_body = |config: Config| {
+            let empty = empty.clone();
+            async move {
+                let (upstream, seen) = mock_upstream(empty).await;
+                let app = test_router_with(upstream, config).await;
+                let payload = serde_json::json!({
+                    "model": "claude-sonnet-4-5",
+                    "max_tokens": 100,
+                    "stream": true,
+                    "messages": [{ "role": "user", "content": "Hello?" }]
+                });
+                let request = axum::http::Request::post("/v1/messages")
+                    .header("content-type", "application/json")
+                    .body(axum::body::Body::from(payload.to_string()))
+                    .unwrap();
+                let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let calls = seen.lock().unwrap().len();
+                (String::from_utf8(body.to_vec()).unwrap(), calls)
+            }
+        };
+
+        // Retried once on the fallback model, then explained in the answer
+        let (body, calls) = stream_body(Config::default()).await;
+        assert_eq!(calls, 2);
+        assert!(body.contains("returned an empty response"));
+        assert!(body.contains("\"stop_reason\":\"end_turn\""));
+        assert!(!body.contains("event: error"));
+
+        // Or reported as an error when configured
+        let (body, _) = stream_body(Config { empty_response_error: true, ..Config::default() }).await;
+        assert!(body.contains("event: error"));
+        assert!(!body.contains("message_delta"));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_completions() {
+        let send = |event: serde_json::Value, stream: bool| async move {
+            let (upstream, _) = mock_upstream(event).await;
+            let app = test_router(upstream).await;
+            let payload = serde_json::json!({
+                "model": "antigravity-gemini-3-flash",
+                "stream": stream,
+                "prompt": "Say hi"
+            });
+            let request = axum::http::Request::post("/v1/completions")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(payload.to_string()))
+                .unwrap();
+            let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+            assert_eq!(response.status(), axum::http::StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let body = send(serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi!" }] }, "finishReason": "STOP" }] }
+        }), false).await;
+        let completion: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(completion["object"], "text_completion");
+        assert_eq!(completion["choices"][0]["text"], "Hi!");
+
+        // A stream that fails midway ends on the error, not a normal stop
+        let body = send(serde_json::json!({
+            "response": { "promptFeedback": { "blockReason": "SAFETY" } }
+        }), true).await;
+        assert!(body.contains("PROMPT_BLOCKED"));
+        assert!(!body.contains("\"finish_reason\":\"stop\""));
+        assert!(body.trim_end().ends_with("data: [DONE]"));
+    }
+
+    /// Sends a Claude message to a router whose only account is out of Claude quota
+    async fn claude_limited_request(upstream: String, config: Config) -> axum::response::Response {
+        let manager = oauth::AccountManager::empty();
+        manager.add_account(oauth::TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            email: "dev@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(0, oauth::accounts::ModelFamily::Claude, chrono::Utc::now() + chrono::Duration::hours(1)).await;
+        let config = Config { project_id: Some("test-project".into()), ..config };
+        let app = create_router(AppState::headless(config, manager, Some(upstream)));
+
+        let payload = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 100,
+            "messages": [{ "role": "user", "content": "Hello" }]
+        });
+        let request = axum::http::Request::post("/v1/messages")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(payload.to_string()))
+            .unwrap();
+        tower::ServiceExt::oneshot(app, request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_strategy_header_after_forced_spoof() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }] }
+        })).await;
+        // Claude is limited on the only account, so Strategy 0 spoofs up front
+        let response = claude_limited_request(upstream, Config::default()).await;
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        assert_eq!(response.headers()["x-aether-strategy"], "spoof-same");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["metadata"]["aether_strategy"], "spoof-same");
+        let served = body["metadata"]["aether_actual_model"].as_str().unwrap().to_string();
+        assert!(served.starts_with("gemini"), "{}", served);
+        assert_eq!(seen.lock().unwrap()[0].1["model"], served);
+    }
+
+    #[tokio::test]
+    async fn test_spoofing_disabled_returns_429() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }] }
+        })).await;
+
+        // No Gemini substitute: the hour-long Claude limit is reported instead
+        let response = claude_limited_request(upstream, Config { enable_spoofing: false, ..Config::default() }).await;
+        assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["type"], "rate_limit_error");
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_empty_response_not_cached() {
+        let (upstream, seen) = mock_upstream(serde_json::json!({
+            "response": { "candidates": [{ "content": { "role": "model", "parts": [{ "text": "" }] }, "finishReason": "STOP" }] }
+        })).await;
+        let mut config = Config::default();
+        config.response_cache.enabled = true;
+        let app = test_router_with(upstream, config).await;
+
+        for _ in 0..2 {
+            let payload = serde_json::json!({
+                "model": "antigravity-gemini-3-flash",
+                "max_tokens": 100,
+                "temperature": 0.0,
+                "messages": [{ "role": "user", "content": "Hello?" }]
+            });
+            let request = axum::http::Request::post("/v1/messages")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(payload.to_string()))
+                .unwrap();
+            let response = tower::ServiceExt::oneshot(app.clone(), request).await.unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
 }
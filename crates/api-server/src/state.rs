@@ -1,22 +1,45 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::sync::Mutex;
 use common::config::Config;
 use browser_automator::Automator;
-use oauth::AccountManager;
+use oauth::{AccountManager, ThrottlePolicy};
+use std::time::Duration;
 use browser_automator::fingerprint::Fingerprint;
+use browser_automator::{AntigravityClient, ContextCache, EndpointStats, ResponseCache};
+
+use crate::cancellation::CancelRegistry;
+use crate::client_pool::ClientPool;
+use crate::metrics::Metrics;
 
 /// Shared application state
 #[derive(Clone)]
 pub struct AppState {
-    /// Application configuration
+    /// Application configuration, as of when the request started
     pub config: Arc<Config>,
-    /// Browser automator for legacy protocol driver
-    pub automator: Arc<Mutex<Automator>>,
+    /// Configuration that can be changed at runtime (`PATCH /v1/admin/config`)
+    pub live_config: Arc<RwLock<Arc<Config>>>,
+    /// Browser automator for legacy protocol driver (None when headless)
+    pub automator: Arc<Mutex<Option<Automator>>>,
     /// OAuth account manager for Antigravity authentication
     /// OAuth account manager for Antigravity authentication
     pub account_manager: Arc<AccountManager>,
     /// Session-based device fingerprint
     pub fingerprint: Arc<Fingerprint>,
+    /// In-flight streams that clients can cancel
+    pub cancellations: Arc<CancelRegistry>,
+    /// Latency metrics (`GET /v1/admin/metrics`)
+    pub metrics: Arc<Metrics>,
+    /// Cached content handles for large system prompts
+    pub context_cache: Arc<ContextCache>,
+    /// Completed `temperature: 0` responses for identical requests
+    pub response_cache: Arc<ResponseCache>,
+    /// Antigravity clients reused across requests
+    pub client_pool: Arc<ClientPool>,
+    /// Endpoint latencies shared by every client, so rebuilding one keeps them
+    pub endpoint_stats: Arc<EndpointStats>,
+    /// Base URL clients send to instead of the Antigravity endpoints (a mock upstream)
+    #[cfg(test)]
+    pub upstream_endpoint: Option<String>,
 }
 
 impl AppState {
@@ -24,28 +47,101 @@ impl AppState {
     pub fn new(config: Config, automator: Automator) -> Self {
         // Create a placeholder account manager that will be initialized lazily
         // This maintains backwards compatibility with existing code
+        let config = Arc::new(config);
         Self {
-            config: Arc::new(config),
-            automator: Arc::new(Mutex::new(automator)),
+            live_config: Arc::new(RwLock::new(config.clone())),
+            context_cache: Arc::new(context_cache(&config)),
+            response_cache: Arc::new(response_cache(&config)),
+            endpoint_stats: AntigravityClient::new_endpoint_stats(config.endpoint_probe_interval),
+            config,
+            automator: Arc::new(Mutex::new(Some(automator))),
             account_manager: Arc::new(AccountManager::empty()),
             fingerprint: Arc::new(Fingerprint::generate()),
+            cancellations: Arc::new(CancelRegistry::new()),
+            metrics: Arc::new(Metrics::new()),
+            client_pool: Arc::new(ClientPool::new()),
+            #[cfg(test)]
+            upstream_endpoint: None,
         }
     }
 
     /// Creates a new AppState with OAuth account manager
     pub async fn with_oauth(config: Config, automator: Automator) -> anyhow::Result<Self> {
-        let account_manager = AccountManager::new().await?;
+        let mut account_manager = AccountManager::new().await?;
+        let throttle = &config.throttle;
+        account_manager.set_throttle_policy(ThrottlePolicy {
+            threshold: throttle.threshold,
+            window: Duration::from_secs(throttle.window_secs),
+            base_delay: Duration::from_millis(throttle.base_delay_ms),
+            max_delay: Duration::from_millis(throttle.max_delay_ms),
+        });
+        account_manager.set_cooldown(Duration::from_millis(config.account_cooldown_ms));
+        account_manager.set_max_concurrent_per_account(config.max_concurrent_per_account);
 
+        let config = Arc::new(config);
         Ok(Self {
-            config: Arc::new(config),
-            automator: Arc::new(Mutex::new(automator)),
+            live_config: Arc::new(RwLock::new(config.clone())),
+            context_cache: Arc::new(context_cache(&config)),
+            response_cache: Arc::new(response_cache(&config)),
+            endpoint_stats: AntigravityClient::new_endpoint_stats(config.endpoint_probe_interval),
+            config,
+            automator: Arc::new(Mutex::new(Some(automator))),
             account_manager: Arc::new(account_manager),
             fingerprint: Arc::new(Fingerprint::generate()),
+            cancellations: Arc::new(CancelRegistry::new()),
+            metrics: Arc::new(Metrics::new()),
+            client_pool: Arc::new(ClientPool::new()),
+            #[cfg(test)]
+            upstream_endpoint: None,
         })
     }
 
+    /// Creates an AppState without the legacy automator, which needs a
+    /// desktop session, sending upstream requests to `upstream_endpoint`
+    #[cfg(test)]
+    pub fn headless(config: Config, account_manager: AccountManager, upstream_endpoint: Option<String>) -> Self {
+        let config = Arc::new(config);
+        Self {
+            live_config: Arc::new(RwLock::new(config.clone())),
+            context_cache: Arc::new(context_cache(&config)),
+            response_cache: Arc::new(response_cache(&config)),
+            endpoint_stats: AntigravityClient::new_endpoint_stats(config.endpoint_probe_interval),
+            config,
+            automator: Arc::new(Mutex::new(None)),
+            account_manager: Arc::new(account_manager),
+            fingerprint: Arc::new(Fingerprint::generate()),
+            cancellations: Arc::new(CancelRegistry::new()),
+            metrics: Arc::new(Metrics::new()),
+            client_pool: Arc::new(ClientPool::new()),
+            upstream_endpoint,
+        }
+    }
+
+    /// Returns the state with `config` taken from the live config
+    pub fn current(&self) -> Self {
+        let config = self.live_config.read().unwrap().clone();
+        Self { config, ..self.clone() }
+    }
+
+    /// Replaces the live config; requests that start afterwards use it
+    pub fn replace_config(&self, config: Config) {
+        *self.live_config.write().unwrap() = Arc::new(config);
+    }
+
     /// Sets the account manager
     pub fn set_account_manager(&mut self, manager: AccountManager) {
         self.account_manager = Arc::new(manager);
     }
 }
+
+/// Context cache with the configured threshold and TTL
+fn context_cache(config: &Config) -> ContextCache {
+    let cache = &config.context_cache;
+    ContextCache::new(cache.min_tokens, Duration::from_secs(cache.ttl_secs))
+}
+
+/// Response cache with the configured capacity
+fn response_cache(config: &Config) -> ResponseCache {
+    let cache = &config.response_cache;
+    ResponseCache::new(cache.capacity, cache.replay_streams)
+}
@@ -0,0 +1,174 @@
+//! Tool Call Repair Module
+//!
+//! When the upstream stream is cut off mid-tool-call, the assembled tool_use
+//! JSON can be truncated. Handing that to a client breaks its tool dispatch,
+//! so every tool_use block is validated before it is emitted:
+//!
+//! 1. Valid JSON is passed through unchanged
+//! 2. Truncated JSON is repaired by closing open strings, arrays and objects
+//! 3. Anything that still doesn't parse is dropped and reported as truncated
+
+use serde_json::{json, Value};
+use tracing::warn;
+
+/// A validated tool_use block, ready to be streamed to the client
+#[derive(Debug)]
+pub struct ToolUseBlock {
+    /// The `content_block` for `content_block_start` (with an empty `input`)
+    pub block: Value,
+    /// The tool input, always a JSON object
+    pub input: Value,
+    /// Whether the tool call had to be repaired because it was cut off
+    pub truncated: bool,
+}
+
+/// Parses a tool_use chunk emitted by the Antigravity stream
+///
+/// Returns `None` if the chunk is too damaged to repair; callers should then
+/// signal truncation via `stop_reason` instead of emitting the tool call.
+pub fn parse_tool_use_chunk(delta: &str) -> Option<ToolUseBlock> {
+    let (mut block, mut truncated) = parse_or_repair(delta)?;
+
+    let input = match block.get("input").cloned() {
+        Some(Value::Object(map)) => Value::Object(map),
+        // Some backends send args as an encoded JSON string
+        Some(Value::String(raw)) => {
+            let (parsed, was_truncated) = parse_or_repair(&raw)?;
+            truncated |= was_truncated;
+            if !parsed.is_object() {
+                return None;
+            }
+            parsed
+        }
+        Some(Value::Null) | None => json!({}),
+        Some(_) => return None,
+    };
+
+    let obj = block.as_object_mut()?;
+    if !obj.get("name").is_some_and(|n| n.is_string()) {
+        return None;
+    }
+    obj.insert("input".to_string(), json!({}));
+
+    if truncated {
+        warn!("Repaired truncated tool call: {}", delta);
+    }
+
+    Some(ToolUseBlock { block, input, truncated })
+}
+
+/// Parses JSON, falling back to repairing a truncated document
+///
+/// The returned flag is `true` when repair was needed.
+fn parse_or_repair(raw: &str) -> Option<(Value, bool)> {
+    if let Ok(value) = serde_json::from_str::<Value>(raw) {
+        return Some((value, false));
+    }
+
+    let repaired = repair_truncated_json(raw)?;
+    serde_json::from_str::<Value>(&repaired).ok().map(|v| (v, true))
+}
+
+/// Closes any strings, arrays and objects left open by a truncated document
+///
+/// Returns `None` if the input is not a truncated JSON object or array.
+pub fn repair_truncated_json(raw: &str) -> Option<String> {
+    let trimmed = raw.trim();
+    if !trimmed.starts_with('{') && !trimmed.starts_with('[') {
+        return None;
+    }
+
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in trimmed.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' if closers.pop() != Some(c) => return None,
+            _ => {}
+        }
+    }
+
+    let mut repaired = trimmed.to_string();
+    if in_string {
+        if escaped {
+            repaired.pop();
+        }
+        repaired.push('"');
+    }
+
+    // Drop a dangling separator, and give a dangling key a value
+    let mut repaired = repaired.trim_end().trim_end_matches(',').to_string();
+    if repaired.ends_with(':') {
+        repaired.push_str("null");
+    }
+
+    while let Some(closer) = closers.pop() {
+        repaired.push(closer);
+    }
+
+    Some(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_tool_call_passes_through() {
+        let delta = r#"{"type":"tool_use","id":"call_1","name":"read_file","input":{"path":"src/main.rs"}}"#;
+        let tool = parse_tool_use_chunk(delta).unwrap();
+
+        assert!(!tool.truncated);
+        assert_eq!(tool.input, json!({ "path": "src/main.rs" }));
+        assert_eq!(tool.block["input"], json!({}));
+        assert_eq!(tool.block["name"], "read_file");
+    }
+
+    #[test]
+    fn test_truncated_tool_call_is_repaired() {
+        let delta = r#"{"type":"tool_use","id":"call_1","name":"write_file","input":{"path":"a.txt","content":"hello wor"#;
+        let tool = parse_tool_use_chunk(delta).unwrap();
+
+        assert!(tool.truncated);
+        assert_eq!(tool.input, json!({ "path": "a.txt", "content": "hello wor" }));
+        // The emitted input must round-trip as valid JSON
+        let emitted = serde_json::to_string(&tool.input).unwrap();
+        assert!(serde_json::from_str::<Value>(&emitted).is_ok());
+    }
+
+    #[test]
+    fn test_truncated_string_args_are_repaired() {
+        let delta = json!({
+            "type": "tool_use",
+            "id": "call_1",
+            "name": "search",
+            "input": r#"{"query":"rust", "limit":"#
+        }).to_string();
+        let tool = parse_tool_use_chunk(&delta).unwrap();
+
+        assert!(tool.truncated);
+        assert_eq!(tool.input, json!({ "query": "rust", "limit": null }));
+    }
+
+    #[test]
+    fn test_unrepairable_tool_call_is_rejected() {
+        // Cut off before the tool name arrived
+        assert!(parse_tool_use_chunk(r#"{"type":"tool_use","id":"call_1","#).is_none());
+        assert!(parse_tool_use_chunk("not json").is_none());
+        assert!(repair_truncated_json(r#"{"a":[1,2}"#).is_none());
+    }
+}
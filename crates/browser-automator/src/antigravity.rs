@@ -13,11 +13,18 @@ use oauth::constants::{
     ANTIGRAVITY_API_CLIENT, ANTIGRAVITY_CLIENT_METADATA,
     ANTIGRAVITY_DEFAULT_PROJECT_ID,
 };
+use crate::context_cache::ContextCache;
+use crate::endpoint_stats::EndpointStats;
 use crate::fingerprint::{Fingerprint, HeaderStyle};
+use crate::postprocess::ResponseCleaner;
+use crate::response_cache::ResponseCache;
+use crate::sse::SseParser;
+use common::config::{ClientHeaders, FamilyDefaults, ModelDefaults, SessionIdPolicy};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{debug, warn, error, info};
 use uuid::Uuid;
@@ -54,31 +61,6 @@ fn extract_retry_from_error(error_text: &str) -> Option<u64> {
     None
 }
 
-/// Calculates exponential backoff with jitter
-/// base_seconds: initial retry duration
-/// attempt: retry attempt number (0-indexed)
-/// max_seconds: maximum retry duration
-/// Returns: duration to wait in seconds
-fn exponential_backoff_with_jitter(base_seconds: u64, attempt: u32, max_seconds: u64) -> u64 {
-    use rand::Rng;
-    
-    // Exponential backoff: base * 2^attempt
-    let exponential = base_seconds.saturating_mul(2_u64.saturating_pow(attempt));
-    
-    // Cap at max
-    let capped = exponential.min(max_seconds);
-    
-    // Add jitter (±25% random variation)
-    let jitter_range = capped / 4;
-    let jitter = if jitter_range > 0 {
-        rand::thread_rng().gen_range(0..=jitter_range)
-    } else {
-        0
-    };
-    
-    capped + jitter
-}
-
 // =============================================================================
 // Model Definitions
 // =============================================================================
@@ -145,6 +127,32 @@ impl AntigravityModel {
         matches!(self, Self::Gemini3Pro | Self::Gemini3Flash)
     }
 
+    /// Returns true if the model can return token logprobs (`responseLogprobs`)
+    pub fn supports_logprobs(&self) -> bool {
+        self.is_gemini()
+    }
+
+    /// Returns true if the model honours a sampling `seed`
+    pub fn supports_seed(&self) -> bool {
+        self.is_gemini()
+    }
+
+    /// Maximum number of input + output tokens the model accepts
+    pub fn context_window(&self) -> u32 {
+        match self {
+            Self::Gemini3Pro | Self::Gemini3Flash => 1_048_576,
+            Self::ClaudeSonnet45 | Self::ClaudeSonnet45Thinking | Self::ClaudeOpus45Thinking => 200_000,
+        }
+    }
+
+    /// Most tokens the model will generate in one response, thinking included
+    pub fn max_output_tokens(&self) -> u32 {
+        match self {
+            Self::Gemini3Pro | Self::Gemini3Flash => 65_536,
+            Self::ClaudeSonnet45 | Self::ClaudeSonnet45Thinking | Self::ClaudeOpus45Thinking => 64_000,
+        }
+    }
+
     /// Gets the default thinking budget for this model (if applicable)
     pub fn default_thinking_budget(&self) -> Option<u32> {
         match self {
@@ -155,8 +163,12 @@ impl AntigravityModel {
         }
     }
 
-    /// Parses a model string into an AntigravityModel
-    pub fn from_str(s: &str) -> Option<Self> {
+    /// Guesses a model from a loosely formatted name by keyword
+    ///
+    /// For client-supplied names that don't parse exactly; keyword matching
+    /// can misclassify (e.g. "gemini-sonnet" is taken as Sonnet), so prefer
+    /// [`str::parse`] wherever a name should be exact.
+    pub fn from_loose(s: &str) -> Option<Self> {
         let lower = s.to_lowercase();
 
         // Handle various naming conventions
@@ -187,6 +199,40 @@ impl AntigravityModel {
     }
 }
 
+/// Error for a model name that isn't one of the known models
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseModelError(String);
+
+impl std::fmt::Display for ParseModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let known: Vec<&str> = AntigravityModel::all().iter().map(|m| m.api_id()).collect();
+        write!(f, "Unknown model \"{}\" (expected one of: {})", self.0, known.join(", "))
+    }
+}
+
+impl std::error::Error for ParseModelError {}
+
+impl std::str::FromStr for AntigravityModel {
+    type Err = ParseModelError;
+
+    /// Parses an exact model id, case-insensitively
+    ///
+    /// Accepts the API id with or without the `antigravity-` prefix, with
+    /// `4.5` for `4-5`, and the compact `gemini3pro`/`gemini3flash` forms.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase().replace('.', "-");
+        let id = lower.strip_prefix("antigravity-").unwrap_or(&lower);
+        let compact = match id {
+            "gemini3pro" => Some(Self::Gemini3Pro),
+            "gemini3flash" => Some(Self::Gemini3Flash),
+            _ => None,
+        };
+        compact
+            .or_else(|| Self::all().into_iter().find(|m| m.api_id() == id))
+            .ok_or_else(|| ParseModelError(s.to_string()))
+    }
+}
+
 impl std::fmt::Display for AntigravityModel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.display_name())
@@ -204,6 +250,18 @@ pub struct Message {
     pub role: String,
     /// Message content
     pub content: String,
+    /// Signed thinking blocks from a previous Claude turn (assistant only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thinking: Vec<ThinkingBlock>,
+}
+
+/// A thinking block with the signature Claude issued for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThinkingBlock {
+    /// The thinking text, exactly as returned
+    pub thinking: String,
+    /// Signature validating the thinking text
+    pub signature: String,
 }
 
 impl Message {
@@ -211,6 +269,7 @@ impl Message {
         Self {
             role: "user".to_string(),
             content: content.into(),
+            thinking: Vec::new(),
         }
     }
 
@@ -218,6 +277,7 @@ impl Message {
         Self {
             role: "assistant".to_string(),
             content: content.into(),
+            thinking: Vec::new(),
         }
     }
 
@@ -225,6 +285,7 @@ impl Message {
         Self {
             role: "system".to_string(),
             content: content.into(),
+            thinking: Vec::new(),
         }
     }
 }
@@ -240,6 +301,57 @@ pub struct ThinkingConfig {
     pub include_thoughts: bool,
 }
 
+/// Sampling parameters supplied by the client for one request
+///
+/// Unset values fall back to the per-family defaults from config.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub tool_choice: Option<ToolChoice>,
+    /// Number of alternative responses (`candidateCount`), when more than one
+    pub candidate_count: Option<u32>,
+    /// Return the chosen tokens' log probabilities (`responseLogprobs`)
+    pub logprobs: bool,
+    /// Also return this many most likely alternatives per token (`logprobs`)
+    pub top_logprobs: Option<u32>,
+    /// Sampling seed for reproducible outputs
+    pub seed: Option<i32>,
+    /// The client marked the system prompt with `cache_control`
+    pub cache_system_prompt: bool,
+}
+
+/// Client tool-use requirement, mapped to Gemini `toolConfig.functionCallingConfig`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// Model decides (upstream default)
+    Auto,
+    /// Tools are declared but must not be called
+    None,
+    /// Some tool must be called
+    Required,
+    /// This specific tool must be called
+    Function(String),
+}
+
+impl ToolChoice {
+    /// Parses an OpenAI or Anthropic `tool_choice` value
+    ///
+    /// OpenAI: `"auto"`, `"none"`, `"required"`, `{"type":"function","function":{"name":..}}`
+    /// Anthropic: `{"type":"auto"|"any"|"none"}`, `{"type":"tool","name":..}`
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let kind = value.as_str().or_else(|| value["type"].as_str())?;
+        match kind {
+            "auto" => Some(Self::Auto),
+            "none" => Some(Self::None),
+            "required" | "any" => Some(Self::Required),
+            "tool" => value["name"].as_str().map(|n| Self::Function(n.to_string())),
+            "function" => value["function"]["name"].as_str().map(|n| Self::Function(n.to_string())),
+            _ => None,
+        }
+    }
+}
+
 /// Response from a chat completion request
 #[derive(Debug, Clone)]
 pub struct ChatResponse {
@@ -253,6 +365,40 @@ pub struct ChatResponse {
     pub finish_reason: String,
     /// Token usage (if available)
     pub usage: Option<Usage>,
+    /// Text of candidates after the first, when several were requested
+    pub other_candidates: Vec<String>,
+    /// Log probabilities of the first candidate's tokens, when requested and supported
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+/// Log probability of one generated token
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    /// Most likely alternatives at this position, including the chosen token
+    pub top: Vec<(String, f64)>,
+}
+
+/// Reads a Gemini `logprobsResult`
+fn logprobs_from_result(result: &Value) -> Vec<TokenLogprob> {
+    let entry = |v: &Value| {
+        (v["token"].as_str().unwrap_or_default().to_string(), v["logProbability"].as_f64().unwrap_or_default())
+    };
+    let top = result["topCandidates"].as_array();
+    let Some(chosen) = result["chosenCandidates"].as_array() else {
+        return Vec::new();
+    };
+
+    chosen.iter().enumerate().map(|(i, c)| {
+        let (token, logprob) = entry(c);
+        let top = top
+            .and_then(|t| t.get(i))
+            .and_then(|t| t["candidates"].as_array())
+            .map(|alternatives| alternatives.iter().map(entry).collect())
+            .unwrap_or_default();
+        TokenLogprob { token, logprob, top }
+    }).collect()
 }
 
 /// Token usage information
@@ -261,10 +407,25 @@ pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Tokens spent on thinking (`thoughtsTokenCount`; not part of `completion_tokens`)
+    pub thinking_tokens: u32,
+}
+
+impl Usage {
+    /// Reads a Gemini `usageMetadata` object
+    fn from_metadata(u: &Value) -> Self {
+        let count = |key: &str| u.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        Self {
+            prompt_tokens: count("promptTokenCount"),
+            completion_tokens: count("candidatesTokenCount"),
+            total_tokens: count("totalTokenCount"),
+            thinking_tokens: count("thoughtsTokenCount"),
+        }
+    }
 }
 
 /// A streaming chunk from the API
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct StreamChunk {
     /// Delta text content
     pub delta: String,
@@ -274,6 +435,21 @@ pub struct StreamChunk {
     pub is_tool_use: bool,
     /// Whether this is the final chunk
     pub done: bool,
+    /// Token usage reported by the stream (set on the final chunk)
+    pub usage: Option<Usage>,
+    /// Which candidate the chunk belongs to (0 unless several were requested)
+    pub candidate: usize,
+    /// Log probabilities of the tokens in `delta` (when requested)
+    pub logprobs: Vec<TokenLogprob>,
+    /// Stop sequence that ended the stream (set on the final chunk)
+    pub stop_sequence: Option<String>,
+}
+
+impl StreamChunk {
+    /// Whether the chunk carries any text, thinking or tool call
+    pub fn has_content(&self) -> bool {
+        self.is_tool_use || !self.delta.is_empty()
+    }
 }
 
 /// Error type for rate limiting
@@ -289,6 +465,9 @@ pub struct RateLimitError {
 // Antigravity Client
 // =============================================================================
 
+/// Every this many requests one goes to an endpoint other than the fastest
+const DEFAULT_ENDPOINT_PROBE_INTERVAL: u64 = 20;
+
 /// Client for Google's Cloud Code Assist (Antigravity) API
 pub struct AntigravityClient {
     /// HTTP client (wrapped in RwLock for dynamic header updates)
@@ -297,53 +476,59 @@ pub struct AntigravityClient {
     access_token: Arc<RwLock<String>>,
     /// Project ID for API calls
     project_id: Arc<RwLock<String>>,
-    /// Current endpoint (can fallback)
-    endpoint_index: Arc<RwLock<usize>>,
+    /// Latency and health of each endpoint, deciding which one is used
+    endpoint_stats: Arc<EndpointStats>,
+    /// Base URL used instead of the endpoint list, if set
+    endpoint_override: Option<String>,
     /// If true, we will NOT try to overwrite the project_id via auto-discovery
     force_project_id: bool,
+    /// Whether the user set a project (config or `GOOGLE_CLOUD_PROJECT`)
+    /// rather than relying on the built-in default
+    project_configured: bool,
     /// Device fingerprint for request headers
     fingerprint: Option<Fingerprint>,
+    /// Configured replacements for the identification headers
+    client_headers: ClientHeaders,
     /// Current header style for dual quota support
     header_style: Arc<RwLock<HeaderStyle>>,
     /// Whether dual quota fallback is enabled
     quota_fallback_enabled: bool,
+    /// Gemini 3 Pro tier used when the request specifies no thinking level
+    gemini_pro_default_tier: String,
+    /// Keep signed thinking blocks in history when the target is Claude
+    preserve_thinking_signatures: bool,
+    /// Per-family sampling/thinking defaults
+    model_defaults: ModelDefaults,
+    /// Whether each streaming request gets its own session id
+    session_id_policy: SessionIdPolicy,
+    /// Whether responses go through the artifact cleanup pass
+    clean_responses: bool,
+    /// Delay before the single retry of an `IAM_PERMISSION_DENIED` stream
+    /// request; no retry when unset
+    permission_retry_delay: Option<Duration>,
+    /// Time allowed for a whole generation request, including the streamed body
+    request_timeout: Duration,
+    /// Where large cache-hinted system prompts are cached (Gemini only)
+    context_cache: Option<Arc<ContextCache>>,
+    /// Where completed deterministic responses are kept for identical requests
+    response_cache: Option<Arc<ResponseCache>>,
 }
 
 impl AntigravityClient {
-    /// Creates a new AntigravityClient with the given access token
     /// Creates a new AntigravityClient with the given access token
     pub fn new(access_token: String, project_id: Option<String>, fingerprint: Option<Fingerprint>) -> Result<Self> {
-        let mut headers = HeaderMap::new();
-
-        // Apply fingerprint headers if available, otherwise fallback to static defaults
-        if let Some(ref fp) = fingerprint {
-            let fp_headers = fp.to_headers();
-            for (k, v) in fp_headers {
-                if let Ok(name) = reqwest::header::HeaderName::from_bytes(k.as_bytes()) {
-                    if let Ok(val) = HeaderValue::from_str(&v) {
-                        headers.insert(name, val);
-                    }
-                }
-            }
-        } else {
-            // Fallback to static constants -> but wait, constants.rs has them defined.
-            // Be careful to use the imported constants if fingerprint is missing.
-            use oauth::constants::{ANTIGRAVITY_USER_AGENT, ANTIGRAVITY_API_CLIENT, ANTIGRAVITY_CLIENT_METADATA};
-            headers.insert("User-Agent", HeaderValue::from_static(ANTIGRAVITY_USER_AGENT));
-            headers.insert("X-Goog-Api-Client", HeaderValue::from_static(ANTIGRAVITY_API_CLIENT));
-            headers.insert("Client-Metadata", HeaderValue::from_static(ANTIGRAVITY_CLIENT_METADATA));
-        }
-
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-        // Session Distribution: Randomize session ID to avoid rate limit tracking by client ID
-        let session_id = Self::generate_session_id();
-        if let Ok(val) = HeaderValue::from_str(&session_id) {
-            headers.insert("X-Goog-Session-Id", val);
-        }
+        Self::with_client_headers(access_token, project_id, fingerprint, ClientHeaders::default())
+    }
 
-        // 2026-01-26: Critical Header for thinking models
-        headers.insert("anthropic-beta", HeaderValue::from_static("interleaved-thinking-2025-05-14"));
+    /// Creates a client whose identification headers use `client_headers`
+    /// where set, instead of the fingerprint's values
+    pub fn with_client_headers(
+        access_token: String,
+        project_id: Option<String>,
+        fingerprint: Option<Fingerprint>,
+        client_headers: ClientHeaders,
+    ) -> Result<Self> {
+        let headers = default_headers(fingerprint.as_ref(), HeaderStyle::Antigravity, &client_headers);
 
         let client = reqwest::Client::builder()
             .default_headers(headers)
@@ -351,11 +536,13 @@ impl AntigravityClient {
             .build()?;
 
         // Determine initial project ID(s) and whether to force it
+        let env_project = std::env::var("GOOGLE_CLOUD_PROJECT").ok();
+        let project_configured = project_id.is_some() || env_project.is_some();
         let (raw_project_source, force) = if let Some(p) = project_id {
             // If user explicitly provided a project ID (not from env), respect it
             (p, true)
         } else {
-             (std::env::var("GOOGLE_CLOUD_PROJECT").unwrap_or_else(|_| ANTIGRAVITY_DEFAULT_PROJECT_ID.to_string()), false)
+             (env_project.unwrap_or_else(|| ANTIGRAVITY_DEFAULT_PROJECT_ID.to_string()), false)
         };
 
         // Project ID Rotation: Handle comma-separated list
@@ -379,11 +566,23 @@ impl AntigravityClient {
             client: Arc::new(RwLock::new(client)),
             access_token: Arc::new(RwLock::new(access_token)),
             project_id: Arc::new(RwLock::new(selected_project)),
-            endpoint_index: Arc::new(RwLock::new(0)),
+            endpoint_stats: Self::new_endpoint_stats(DEFAULT_ENDPOINT_PROBE_INTERVAL),
+            endpoint_override: None,
             force_project_id: force,
+            project_configured,
             fingerprint,
+            client_headers,
             header_style: Arc::new(RwLock::new(HeaderStyle::Antigravity)),
             quota_fallback_enabled: false, // Default disabled, can be enabled via config
+            gemini_pro_default_tier: "low".to_string(),
+            preserve_thinking_signatures: false,
+            model_defaults: ModelDefaults::default(),
+            session_id_policy: SessionIdPolicy::default(),
+            clean_responses: true,
+            permission_retry_delay: None,
+            request_timeout: Duration::from_secs(3600),
+            context_cache: None,
+            response_cache: None,
         })
     }
 
@@ -399,6 +598,84 @@ impl AntigravityClient {
         info!("Dual quota fallback {}", if enabled { "enabled" } else { "disabled" });
     }
 
+    /// Sets the Gemini 3 Pro tier ("low"/"high") used when a request gives no level
+    pub fn set_gemini_pro_default_tier(&mut self, tier: &str) {
+        self.gemini_pro_default_tier = tier.to_string();
+    }
+
+    /// Sets the per-family defaults used when a request leaves a setting unspecified
+    pub fn set_model_defaults(&mut self, defaults: ModelDefaults) {
+        self.model_defaults = defaults;
+    }
+
+    /// Sets whether the session id is fixed for this client or fresh per request
+    pub fn set_session_id_policy(&mut self, policy: SessionIdPolicy) {
+        self.session_id_policy = policy;
+    }
+
+    /// Enables or disables stripping Gemini artifacts from responses
+    pub fn set_clean_responses(&mut self, enabled: bool) {
+        self.clean_responses = enabled;
+    }
+
+    /// Retries a permission-denied stream request once after `delay`, giving
+    /// a freshly enabled API time to propagate
+    pub fn set_permission_retry_delay(&mut self, delay: Option<Duration>) {
+        self.permission_retry_delay = delay;
+    }
+
+    /// Sets how long a generation request (including its stream) may take
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Enables context caching of large system prompts the client marked
+    /// with `cache_control`
+    pub fn set_context_cache(&mut self, cache: Arc<ContextCache>) {
+        self.context_cache = Some(cache);
+    }
+
+    /// Enables serving repeated `temperature: 0` requests from a cache
+    pub fn set_response_cache(&mut self, cache: Arc<ResponseCache>) {
+        self.response_cache = Some(cache);
+    }
+
+    /// Defaults for the family the model belongs to
+    fn family_defaults(&self, model: AntigravityModel) -> &FamilyDefaults {
+        if model.is_claude() {
+            &self.model_defaults.claude
+        } else {
+            &self.model_defaults.gemini
+        }
+    }
+
+    /// Sends requests to `url` instead of the Antigravity endpoints
+    pub fn set_endpoint(&mut self, url: impl Into<String>) {
+        self.endpoint_override = Some(url.into().trim_end_matches('/').to_string());
+    }
+
+    /// Latency tracking for the Antigravity endpoints, re-measuring an
+    /// endpoint other than the fastest one every `probe_interval` requests
+    /// (0 never does); share it between clients with `set_endpoint_stats`
+    pub fn new_endpoint_stats(probe_interval: u64) -> Arc<EndpointStats> {
+        Arc::new(EndpointStats::new(ANTIGRAVITY_ENDPOINTS.len(), probe_interval))
+    }
+
+    /// Uses endpoint latencies shared with other clients, so what was
+    /// learned about the endpoints outlives this client
+    pub fn set_endpoint_stats(&mut self, stats: Arc<EndpointStats>) {
+        self.endpoint_stats = stats;
+    }
+
+    /// Enables strict Anthropic passthrough of signed thinking blocks
+    ///
+    /// When enabled and the target model is Claude, thinking blocks carrying a
+    /// signature are sent back verbatim instead of being stripped. Gemini targets
+    /// always get thinking stripped, since they can't validate Claude signatures.
+    pub fn set_preserve_thinking_signatures(&mut self, enabled: bool) {
+        self.preserve_thinking_signatures = enabled;
+    }
+
     /// Switches to Gemini CLI header style for dual quota access
     /// This should be called when Antigravity quota is exhausted
     pub async fn switch_to_gemini_cli_headers(&self) -> Result<()> {
@@ -430,36 +707,7 @@ impl AntigravityClient {
 
     /// Rebuilds the HTTP client with the specified header style
     async fn rebuild_client_with_style(&self, style: HeaderStyle) -> Result<()> {
-        let mut headers = HeaderMap::new();
-        
-        // Apply fingerprint headers with the specified style
-        if let Some(ref fp) = self.fingerprint {
-            let fp_headers = fp.to_headers_with_style(style);
-            for (k, v) in fp_headers {
-                if let Ok(name) = reqwest::header::HeaderName::from_bytes(k.as_bytes()) {
-                    if let Ok(val) = HeaderValue::from_str(&v) {
-                        headers.insert(name, val);
-                    }
-                }
-            }
-        } else {
-            // Fallback to static constants
-            use oauth::constants::{ANTIGRAVITY_USER_AGENT, ANTIGRAVITY_API_CLIENT, ANTIGRAVITY_CLIENT_METADATA};
-            headers.insert("User-Agent", HeaderValue::from_static(ANTIGRAVITY_USER_AGENT));
-            headers.insert("X-Goog-Api-Client", HeaderValue::from_static(ANTIGRAVITY_API_CLIENT));
-            headers.insert("Client-Metadata", HeaderValue::from_static(ANTIGRAVITY_CLIENT_METADATA));
-        }
-
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-        // Generate new session ID
-        let session_id = Self::generate_session_id();
-        if let Ok(val) = HeaderValue::from_str(&session_id) {
-            headers.insert("X-Goog-Session-Id", val);
-        }
-
-        // Critical header for thinking models
-        headers.insert("anthropic-beta", HeaderValue::from_static("interleaved-thinking-2025-05-14"));
+        let headers = default_headers(self.fingerprint.as_ref(), style, &self.client_headers);
 
         // Build new client
         let new_client = reqwest::Client::builder()
@@ -478,10 +726,14 @@ impl AntigravityClient {
         *self.header_style.read().await
     }
 
-    /// Gets the current endpoint URL
-    async fn current_endpoint(&self) -> &'static str {
-        let idx = *self.endpoint_index.read().await;
-        ANTIGRAVITY_ENDPOINTS.get(idx).copied().unwrap_or(ANTIGRAVITY_ENDPOINTS[0])
+    /// Picks the endpoint for a request: its index in `ANTIGRAVITY_ENDPOINTS`
+    /// (None for an override) and its URL
+    fn pick_endpoint(&self) -> (Option<usize>, String) {
+        if let Some(url) = &self.endpoint_override {
+            return (None, url.clone());
+        }
+        let idx = self.endpoint_stats.pick();
+        (Some(idx), ANTIGRAVITY_ENDPOINTS.get(idx).copied().unwrap_or(ANTIGRAVITY_ENDPOINTS[0]).to_string())
     }
 
     /// Helper to generate a dynamic session ID for request anonymity
@@ -489,21 +741,14 @@ impl AntigravityClient {
         Uuid::new_v4().to_string()
     }
 
-    /// Tries the next endpoint in the fallback list
-    async fn try_next_endpoint(&self) -> bool {
-        let mut idx = self.endpoint_index.write().await;
-        if *idx + 1 < ANTIGRAVITY_ENDPOINTS.len() {
-            *idx += 1;
-            warn!("Falling back to endpoint: {}", ANTIGRAVITY_ENDPOINTS[*idx]);
-            true
-        } else {
-            false
-        }
+    /// Project ID requests are currently sent to
+    pub async fn project_id(&self) -> String {
+        self.project_id.read().await.clone()
     }
 
     /// Fetches the provisioned project ID (using loadCodeAssist)
     /// This returns the "Golden Ticket" project ID that has quotas enabled.
-    async fn fetch_provisioned_project_id(&self) {
+    pub async fn fetch_provisioned_project_id(&self) {
         // SKIP discovery if user forced a project ID
         if self.force_project_id {
             return;
@@ -514,11 +759,11 @@ impl AntigravityClient {
         debug!("Attempting to discover provisioned project ID...");
         let token = self.access_token.read().await.clone();
 
-        // Try endpoints in order (Prod -> Daily -> Autopush)
-
-
-        for (idx, endpoint) in ANTIGRAVITY_ENDPOINTS.iter().enumerate() {
+        // Try endpoints fastest first
+        for idx in self.endpoint_stats.order() {
+             let endpoint = ANTIGRAVITY_ENDPOINTS[idx];
              let url = format!("{}/v1internal:loadCodeAssist", endpoint);
+             let sent_at = std::time::Instant::now();
              let body = json!({
                  "metadata": {
                      "ideType": "IDE_UNSPECIFIED",
@@ -536,6 +781,7 @@ impl AntigravityClient {
              {
                  Ok(resp) => {
                      if resp.status().is_success() {
+                         self.endpoint_stats.record_success(idx, sent_at.elapsed());
                          if let Ok(json) = resp.json::<Value>().await {
                              // Check for cloudaicompanionProject (string or object with id)
                              let extracted_id = if let Some(id_str) = json.get("cloudaicompanionProject").and_then(|v| v.as_str()) {
@@ -553,17 +799,21 @@ impl AntigravityClient {
                                  if !id.is_empty() {
                                      info!("Discovered provisioned project ID: {} (via {})", id, endpoint);
                                      *self.project_id.write().await = id;
-                                     // IMPORTANT: Set the endpoint index to the one that worked!
-                                     *self.endpoint_index.write().await = idx;
                                      return;
                                  }
                              }
                          }
                      } else {
+                         if resp.status().is_server_error() {
+                             self.endpoint_stats.record_failure(idx);
+                         }
                          debug!("loadCodeAssist failed at {}: {}", endpoint, resp.status());
                      }
                  },
-                 Err(e) => debug!("Error calling loadCodeAssist at {}: {}", endpoint, e),
+                 Err(e) => {
+                     self.endpoint_stats.record_failure(idx);
+                     debug!("Error calling loadCodeAssist at {}: {}", endpoint, e);
+                 }
              }
         }
 
@@ -578,38 +828,90 @@ impl AntigravityClient {
         messages: &[Message],
         thinking: Option<&ThinkingConfig>,
         tools: Option<&Vec<Value>>,
+        params: &GenerationParams,
     ) -> Value {
         // Separate system messages from chat content
         let (system_messages, chat_messages): (Vec<&Message>, Vec<&Message>) = messages.iter()
             .partition(|m| m.role == "system");
 
         // Convert chat messages to Gemini format (contents array)
-        // CRITICAL: Strip thinking blocks from ALL messages to prevent signature corruption
+        // CRITICAL: Drop signed thinking blocks to prevent signature corruption
         // Thinking blocks contain signatures that become invalid when replayed.
         // See: https://github.com/NoeFabris/opencode-antigravity-auth/blob/main/docs/ARCHITECTURE.md
+        // Exception: in strict passthrough mode, genuine signed Claude thinking is kept
+        // when the target is Claude, so multi-turn reasoning isn't degraded.
+        // Thinking rendered into assistant text is unsigned and always removed.
+        let passthrough_thinking = self.preserve_thinking_signatures && model.is_claude();
         let contents: Vec<Value> = chat_messages.iter().map(|m| {
             let role = if m.role == "assistant" { "model" } else { &m.role };
-            // Strip thinking content from ALL messages (not just assistant)
-            // This prevents "Invalid thinking signature" errors
-            let content = Self::strip_thinking_content(&m.content);
+            let mut parts: Vec<Value> = Vec::new();
+            if passthrough_thinking && m.role == "assistant" {
+                parts.extend(m.thinking.iter()
+                    .filter(|t| !t.thinking.is_empty() && !t.signature.is_empty())
+                    .map(|t| json!({
+                        "text": t.thinking,
+                        "thought": true,
+                        "thoughtSignature": t.signature
+                    })));
+            }
+            // Only assistant turns carry rendered thinking; user text is left alone
+            let content = if m.role == "assistant" {
+                Self::strip_thinking_content(&m.content)
+            } else {
+                m.content.clone()
+            };
+            if !content.is_empty() || parts.is_empty() {
+                // Very large pastes go out as several parts of one content entry
+                parts.extend(split_text_parts(&content, MAX_TEXT_PART_BYTES).into_iter().map(|text| json!({"text": text})));
+            }
             json!({
                 "role": role,
-                "parts": [{"text": content}]
+                "parts": parts
             })
         }).collect();
 
-        // Build generation config
+        // Build generation config (client values win over per-family defaults)
+        let family = self.family_defaults(model);
+        let mut temperature = params.temperature.or(family.temperature).unwrap_or(0.7);
+        if let Some(max) = family.max_temperature.filter(|max| model.supports_thinking() && temperature > *max) {
+            debug!("Clamping temperature {} to {} for thinking model {}", temperature, max, model.display_name());
+            temperature = max;
+        }
         let mut generation_config = json!({
             "maxOutputTokens": 8192,
-            "temperature": 0.7,
+            "temperature": temperature,
         });
+        if let Some(top_p) = params.top_p.or(family.top_p) {
+            generation_config["topP"] = json!(top_p);
+        }
+        if let Some(count) = params.candidate_count.filter(|n| *n > 1) {
+            generation_config["candidateCount"] = json!(count);
+        }
+        if let Some(seed) = params.seed.filter(|_| model.supports_seed()) {
+            generation_config["seed"] = json!(seed);
+        }
+        // Claude rejects the fields, so logprobs are just omitted there
+        if params.logprobs && model.supports_logprobs() {
+            generation_config["responseLogprobs"] = json!(true);
+            if let Some(top) = params.top_logprobs {
+                generation_config["logprobs"] = json!(top);
+            }
+        }
+
+        // Level used when the request doesn't specify one (Gemini 3 Pro tier is configurable)
+        let default_level = if matches!(model, AntigravityModel::Gemini3Pro) {
+            self.gemini_pro_default_tier.as_str()
+        } else {
+            family.thinking_level.as_deref().unwrap_or("low")
+        };
 
         // Add thinking configuration if supported
         if model.supports_thinking() {
             if let Some(thinking) = thinking {
                 if model.is_claude() {
                     // Claude uses thinkingBudget ONLY. Do NOT send thinkingLevel.
-                    if let Some(budget) = thinking.budget.or(model.default_thinking_budget()) {
+                    if let Some(budget) = thinking.budget.or(family.thinking_budget).or(model.default_thinking_budget()) {
+                        let budget = clamp_thinking_budget(model, budget);
                         generation_config["thinkingConfig"] = json!({
                             "thinkingBudget": budget,
                             "includeThoughts": thinking.include_thoughts
@@ -617,14 +919,14 @@ impl AntigravityClient {
                         // Ensure maxOutputTokens > thinkingBudget (spec requirement)
                         if let Some(max_tokens) = generation_config.get_mut("maxOutputTokens").and_then(|v| v.as_u64()) {
                             if max_tokens <= budget as u64 {
-                                generation_config["maxOutputTokens"] = json!(budget + 8192);
+                                generation_config["maxOutputTokens"] = json!(budget + ANSWER_TOKENS);
                             }
                         }
                     }
                 } else {
                     // FIXED: Gemini 3 requires thinkingLevel ONLY
                     // We prioritize level if set, otherwise map from budget/default
-                    let effective_level = thinking.level.as_deref().unwrap_or("low");
+                    let effective_level = thinking.level.as_deref().unwrap_or(default_level);
 
                     generation_config["thinkingConfig"] = json!({
                         "thinkingLevel": match effective_level {
@@ -644,7 +946,7 @@ impl AntigravityClient {
         if matches!(model, AntigravityModel::Gemini3Pro) {
             // Gemini 3 Pro requires the tier in the model name (e.g., gemini-3-pro-low)
             // It does NOT use the bare name like Flash does.
-            let level = thinking.and_then(|t| t.level.as_deref()).unwrap_or("low");
+            let level = thinking.and_then(|t| t.level.as_deref()).unwrap_or(default_level);
             let effective_level = match level {
                 "minimal" => "low",
                 "medium" => "high",
@@ -666,10 +968,11 @@ impl AntigravityClient {
         // Add systemInstruction if system messages exist
         if !system_messages.is_empty() {
             // Merge all system message contents into one block (common practice)
-            // CRITICAL: Also strip thinking blocks from system messages
+            // System prompts often mention <thinking> tags on purpose, so they
+            // are sent as written
             let combined_system_prompt = system_messages.iter()
-                .map(|m| Self::strip_thinking_content(&m.content))
-                .collect::<Vec<String>>()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<&str>>()
                 .join("\n\n");
 
             if let Some(request_obj) = body.get_mut("request").and_then(|r| r.as_object_mut()) {
@@ -687,6 +990,20 @@ impl AntigravityClient {
                     request_obj.insert("tools".to_string(), json!([{
                         "function_declarations": sanitized_tools
                     }]));
+
+                    let calling_config = match &params.tool_choice {
+                        Some(ToolChoice::Auto) | None => None,
+                        Some(ToolChoice::None) => Some(json!({ "mode": "NONE" })),
+                        Some(ToolChoice::Required) => Some(json!({ "mode": "ANY" })),
+                        Some(ToolChoice::Function(name)) => {
+                            // Match the renaming applied to the declarations
+                            let declared = Self::sanitize_tool_definition(&json!({ "name": name }));
+                            Some(json!({ "mode": "ANY", "allowedFunctionNames": [declared["name"]] }))
+                        }
+                    };
+                    if let Some(config) = calling_config {
+                        request_obj.insert("toolConfig".to_string(), json!({ "functionCallingConfig": config }));
+                    }
                 }
             }
         }
@@ -694,19 +1011,22 @@ impl AntigravityClient {
         body
     }
 
-    /// Strips thinking content markers from assistant messages
-    /// This prevents signature corruption errors when thinking blocks are stored
-    /// and re-sent by the client. Claude will generate fresh thinking.
+    /// Strips thinking that was rendered into assistant text
+    ///
+    /// Such text has no signature, so replaying it as thinking can't be
+    /// validated; the model generates fresh thinking instead. Signed thinking
+    /// travels separately in `Message::thinking` and is not touched here.
     fn strip_thinking_content(content: &str) -> String {
         // Remove thinking blocks marked with various formats
         // Format 1: <thinking>...</thinking>
         // Format 2: [Thinking: ...]
-        // Format 3: > *Thinking: ...*
+        // Format 3: the bridge's own `\n> *Thinking: first chunk*` marker,
+        //           followed by the remaining thinking up to the blank line
+        //           inserted before the answer (or the end of the text)
         let patterns = [
             r"<thinking>.*?</thinking>",
             r"\[Thinking:.*?\]",
-            r"> \*Thinking:.*?\*\n\n",
-            r"> \*Thinking:.*?\*",
+            r"\n?> \*Thinking:.*?(?:\n\n|\z)",
         ];
         
         let mut result = content.to_string();
@@ -726,7 +1046,7 @@ impl AntigravityClient {
 
         // Recursively walk and clean the schema
         if let Some(params) = sanitized.get_mut("parameters") {
-            Self::sanitize_schema(params);
+            crate::schema_sanitizer::sanitize_schema(params);
         }
 
         // Sanitize name
@@ -747,38 +1067,6 @@ impl AntigravityClient {
         sanitized
     }
 
-    /// Recursively sanitizes JSON schema for Antigravity
-    fn sanitize_schema(schema: &mut Value) {
-        if let Some(obj) = schema.as_object_mut() {
-            // Remove forbidden keys
-            obj.remove("$schema");
-            obj.remove("$id");
-            obj.remove("$ref");
-            obj.remove("$defs");
-            obj.remove("definitions");
-            obj.remove("default");
-            obj.remove("examples");
-            // const is not supported, ref is not supported
-
-            // Transform strict `const` to `enum` (if present directly)
-            if let Some(const_val) = obj.remove("const") {
-                obj.insert("enum".to_string(), json!([const_val]));
-            }
-
-            // Recurse into properties
-            if let Some(props) = obj.get_mut("properties").and_then(|p| p.as_object_mut()) {
-                for (_, value) in props.iter_mut() {
-                    Self::sanitize_schema(value);
-                }
-            }
-
-            // Recurse into items (array)
-            if let Some(items) = obj.get_mut("items") {
-                Self::sanitize_schema(items);
-            }
-        }
-    }
-
     /// Sends a chat completion request
     /// FIXED: Now uses chat_completion_stream internally to bypass 500 errors on generateContent
     pub async fn chat_completion(
@@ -787,46 +1075,19 @@ impl AntigravityClient {
         messages: Vec<Message>,
         thinking: Option<ThinkingConfig>,
         tools: Option<Vec<Value>>,
+        params: &GenerationParams,
     ) -> Result<ChatResponse> {
         // Use the streaming implementation
-        let stream = self.chat_completion_stream(model.clone(), messages, thinking, tools).await?;
-        let mut stream = Box::pin(stream);
-
-        let mut full_content = String::new();
-        let mut full_thinking = String::new();
-        let mut has_thinking = false;
-
-        // Collect all chunks
-        while let Some(chunk_res) = stream.next().await {
-            let chunk = chunk_res?;
-            if chunk.is_thinking {
-                full_thinking.push_str(&chunk.delta);
-                has_thinking = true;
-            } else {
-                full_content.push_str(&chunk.delta);
-            }
-        }
-
-        // Construct response (usage stats are approximated or missing in stream)
-        Ok(ChatResponse {
-            content: full_content,
-            thinking: if has_thinking { Some(full_thinking) } else { None },
-            model: model.api_id().to_string(),
-            finish_reason: "stop".to_string(),
-            usage: None, // Streaming doesn't always provide final usage
-        })
+        let stream = self.cached_stream(model, messages, thinking, tools, params, true).await?;
+        collect_response(stream, model).await
     }
 
     /// Parses the API response into a ChatResponse
     fn parse_response(&self, raw: Value, model: AntigravityModel) -> Result<ChatResponse> {
-        // Check for "response" wrapper first (sometimes API wraps it)
-        let root = if let Some(inner) = raw.get("response") {
-            inner
-        } else {
-            &raw
-        };
-
-        // Extract from candidates[0].content.parts
+        let root = response_root(&raw);
+        if let Some(error) = prompt_blocked_error(root) {
+            return Err(error);
+        }
         let candidates = root.get("candidates")
             .and_then(|c| c.as_array())
             .ok_or_else(|| anyhow!("No candidates in response"))?;
@@ -836,27 +1097,19 @@ impl AntigravityClient {
         }
 
         let first_candidate = &candidates[0];
-        let parts = first_candidate
-            .get("content")
-            .and_then(|c| c.get("parts"))
-            .and_then(|p| p.as_array())
-            .ok_or_else(|| anyhow!("No content parts in response"))?;
+        if first_candidate.pointer("/content/parts").and_then(|p| p.as_array()).is_none() {
+            return Err(anyhow!("No content parts in response"));
+        }
+        let first_index = candidate_index(first_candidate, 0);
 
         let mut content = String::new();
         let mut thinking = None;
 
-        for part in parts {
-            // Check if this is a thinking part
-            let is_thought = part.get("thought")
-                .and_then(|t| t.as_bool())
-                .unwrap_or(false);
-
-            if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
-                if is_thought {
-                    thinking = Some(text.to_string());
-                } else {
-                    content.push_str(text);
-                }
+        for part in extract_parts(&raw).into_iter().filter(|p| p.candidate == first_index) {
+            match part.kind {
+                PartKind::Text { text, thought: true } => thinking = Some(text),
+                PartKind::Text { text, thought: false } => content.push_str(&text),
+                PartKind::FunctionCall { .. } => {}
             }
         }
 
@@ -867,17 +1120,7 @@ impl AntigravityClient {
             .to_string();
 
         // Extract usage if available
-        let usage = raw.get("usageMetadata").map(|u| Usage {
-            prompt_tokens: u.get("promptTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0) as u32,
-            completion_tokens: u.get("candidatesTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0) as u32,
-            total_tokens: u.get("totalTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0) as u32,
-        });
+        let usage = root.get("usageMetadata").map(Usage::from_metadata);
 
         Ok(ChatResponse {
             content,
@@ -885,9 +1128,79 @@ impl AntigravityClient {
             model: model.api_id().to_string(),
             finish_reason,
             usage,
+            other_candidates: Vec::new(),
+            logprobs: first_candidate.get("logprobsResult").map(logprobs_from_result),
         })
     }
 
+    /// Swaps a large system instruction for a reference to cached content,
+    /// created on `endpoint` (the one the request itself goes to)
+    async fn use_context_cache(&self, cache: &ContextCache, endpoint: &str, project_id: &str, body: &mut Value) {
+        let system = body["request"]["systemInstruction"].clone();
+        let Some(prompt) = system.pointer("/parts/0/text").and_then(|t| t.as_str()) else {
+            return;
+        };
+        if !cache.worth_caching(prompt) {
+            return;
+        }
+
+        let model = body["model"].as_str().unwrap_or_default().to_string();
+        let create = |ttl| self.create_cached_content(endpoint, project_id, &model, &system, ttl);
+        if let Some(name) = cache.handle(project_id, &model, prompt, create).await {
+            use_cached_content(body, &name);
+        }
+    }
+
+    /// Uploads a system instruction as cached content, returning its name
+    async fn create_cached_content(&self, endpoint: &str, project_id: &str, model: &str, system_instruction: &Value, ttl: Duration) -> Result<String> {
+        let url = format!("{}/v1internal:createCachedContent", endpoint);
+        let token = self.access_token.read().await.clone();
+        let body = json!({
+            "project": project_id,
+            "model": model,
+            "cachedContent": {
+                "systemInstruction": system_instruction,
+                "ttl": format!("{}s", ttl.as_secs())
+            }
+        });
+
+        let response = self.client.read().await
+            .post(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("createCachedContent returned {}: {}", status, text));
+        }
+
+        let value: Value = serde_json::from_str(&text)?;
+        response_root(&value).get("name")
+            .and_then(|n| n.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No name in createCachedContent response"))
+    }
+
+    /// Builds the POST for a streaming request
+    ///
+    /// Static headers come from the client's defaults; with the per-request
+    /// session policy a fresh `X-Goog-Session-Id` is set here, which takes
+    /// precedence over the client-wide one.
+    async fn stream_request(&self, url: &str, token: &str, body: &Value) -> reqwest::RequestBuilder {
+        let mut request = self.client.read().await
+            .post(url)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .timeout(self.request_timeout)
+            .json(body);
+
+        if self.session_id_policy == SessionIdPolicy::PerRequest {
+            request = request.header("X-Goog-Session-Id", Self::generate_session_id());
+        }
+        request
+    }
+
     /// Sends a streaming chat completion request
     pub async fn chat_completion_stream(
         &self,
@@ -895,17 +1208,80 @@ impl AntigravityClient {
         messages: Vec<Message>,
         thinking: Option<ThinkingConfig>,
         tools: Option<Vec<Value>>,
+        params: &GenerationParams,
     ) -> Result<impl futures::Stream<Item = Result<StreamChunk>> + Send> {
+        let replay = self.response_cache.as_ref().is_some_and(|cache| cache.replays_streams());
+        self.cached_stream(model, messages, thinking, tools, params, replay).await
+    }
+
+    /// Streams a response, replaying a cached one for a repeated
+    /// `temperature: 0` request (when `use_cache`) and caching it once it
+    /// completes
+    async fn cached_stream(
+        &self,
+        model: AntigravityModel,
+        messages: Vec<Message>,
+        thinking: Option<ThinkingConfig>,
+        tools: Option<Vec<Value>>,
+        params: &GenerationParams,
+        use_cache: bool,
+    ) -> Result<impl futures::Stream<Item = Result<StreamChunk>> + Send + use<>> {
+        let entry = match self.response_cache.as_ref().filter(|_| use_cache && params.temperature == Some(0.0)) {
+            Some(cache) => {
+                let project_id = self.project_id.read().await.clone();
+                let body = self.build_request_body(&project_id, model, &messages, thinking.as_ref(), tools.as_ref(), params);
+                Some((cache.clone(), ResponseCache::key(&body)))
+            }
+            None => None,
+        };
+        if let Some(chunks) = entry.as_ref().and_then(|(cache, key)| cache.get(*key)) {
+            debug!("Serving {} request from the response cache", model.api_id());
+            return Ok(futures::future::Either::Left(futures::stream::iter(chunks.into_iter().map(Ok))));
+        }
+
+        let stream = retry_permission_denied(self.permission_retry_delay, || {
+            self.send_stream_request(model, &messages, thinking.as_ref(), tools.as_ref(), params)
+        }).await?;
+
+        // Keep the chunks of a stream that finishes without an error and with content
+        let mut recording = entry.map(|entry| (entry, Vec::new()));
+        Ok(futures::future::Either::Right(stream.inspect(move |chunk| match chunk {
+            Ok(chunk) => {
+                let Some((_, chunks)) = recording.as_mut() else {
+                    return;
+                };
+                chunks.push(chunk.clone());
+                if chunk.done && let Some(((cache, key), chunks)) = recording.take()
+                    && chunks.iter().any(StreamChunk::has_content) {
+                    cache.insert(key, chunks);
+                }
+            }
+            Err(_) => recording = None,
+        })))
+    }
+
+    /// Sends one streaming request, mapping upstream failures to tagged errors
+    async fn send_stream_request(
+        &self,
+        model: AntigravityModel,
+        messages: &[Message],
+        thinking: Option<&ThinkingConfig>,
+        tools: Option<&Vec<Value>>,
+        params: &GenerationParams,
+    ) -> Result<impl futures::Stream<Item = Result<StreamChunk>> + Send + use<>> {
         // Ensure we have a valid project ID
         self.fetch_provisioned_project_id().await;
 
-        let endpoint = self.current_endpoint().await;
+        let (endpoint_index, endpoint) = self.pick_endpoint();
         // Use streamGenerateContent with alt=sse
         let url = format!("{}/v1internal:streamGenerateContent?alt=sse", endpoint);
         let token = self.access_token.read().await.clone();
         let project_id = self.project_id.read().await.clone();
 
-        let body = self.build_request_body(&project_id, model, &messages, thinking.as_ref(), tools.as_ref());
+        let mut body = self.build_request_body(&project_id, model, messages, thinking, tools, params);
+        if let Some(cache) = self.context_cache.as_ref().filter(|_| params.cache_system_prompt && !model.is_claude()) {
+            self.use_context_cache(cache, &endpoint, &project_id, &mut body).await;
+        }
 
         debug!("Sending streaming request to {}", url);
 
@@ -916,15 +1292,16 @@ impl AntigravityClient {
             tokio::time::sleep(tokio::time::Duration::from_millis(jitter_ms)).await;
         }
 
-        let request = self.client.read().await
-            .post(&url)
-            .header(AUTHORIZATION, format!("Bearer {}", token))
-            .json(&body);
-
-        // Header injection is now handled in new() but we can ensure it here too (redundant but safe)
-        // Also removed redundant header injection logic which is now in `new`.
-
-        let response = request.send().await?;
+        // Time to the response headers counts towards the endpoint's latency
+        let sent_at = std::time::Instant::now();
+        let response = self.stream_request(&url, &token, &body).await.send().await;
+        if let Some(idx) = endpoint_index {
+            match &response {
+                Ok(response) if !response.status().is_server_error() => self.endpoint_stats.record_success(idx, sent_at.elapsed()),
+                _ => self.endpoint_stats.record_failure(idx),
+            }
+        }
+        let response = response?;
 
         let status = response.status();
 
@@ -938,11 +1315,13 @@ impl AntigravityClient {
             let error_text = response.text().await?;
             
             // Handle rate limiting specifically (429)
+            // Without a hint from the header or message the seconds are left
+            // empty, so the caller can fall back to a learned reset time
             if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
-                let retry_seconds = retry_after.unwrap_or_else(|| {
-                    // Try to extract from error message
-                    extract_retry_from_error(&error_text).unwrap_or(60)
-                });
+                let retry_seconds = retry_after
+                    .or_else(|| extract_retry_from_error(&error_text))
+                    .map(|secs| secs.to_string())
+                    .unwrap_or_default();
                 return Err(anyhow!("RATE_LIMITED:{}:{}", retry_seconds, error_text));
             }
             
@@ -955,141 +1334,432 @@ impl AntigravityClient {
             
             // 2026-01-28: Handle "Permission denied" specifically
             if status == reqwest::StatusCode::FORBIDDEN && error_text.contains("generateChat") {
-                 return Err(anyhow!("IAM_PERMISSION_DENIED: The Project ID '{}' likely needs the Gemini API enabled. {}", project_id, error_text));
+                 return Err(permission_denied_error(&project_id, self.project_configured, &error_text));
             }
             return Err(anyhow!("API error {}: {}", status, error_text));
         }
 
-        // Process the byte stream
-        let stream = response.bytes_stream();
+        // Bad tokens can come back as a 200 login page instead of an event stream
+        let content_type = response.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if is_html_response(content_type.as_deref(), "") {
+            let body = response.text().await.unwrap_or_default();
+            return Err(html_auth_error(&body));
+        }
 
-        // Use async-stream to yield parsed chunks
-        let output_stream = async_stream::try_stream! {
-            let mut line_buffer = String::new();
-            let mut byte_stream = Box::pin(stream); // Pin the stream
+        // Process the byte stream, cleaning artifacts unless disabled
+        let mut cleaner = self.clean_responses.then(ResponseCleaner::new);
+        Ok(parse_event_stream(response.bytes_stream()).filter_map(move |chunk| {
+            let chunk = match (chunk, cleaner.as_mut()) {
+                (Ok(chunk), Some(cleaner)) => cleaner.clean(chunk).map(Ok),
+                (chunk, _) => Some(chunk),
+            };
+            futures::future::ready(chunk)
+        }))
+    }
 
-            use futures::StreamExt;
-            while let Some(chunk_result) = byte_stream.next().await {
-                let bytes = chunk_result?;
-                let chunk_str = String::from_utf8_lossy(&bytes);
-                // tracing::debug!("Raw stream chunk: {:?}", chunk_str); // Uncomment for deep debug
-                line_buffer.push_str(&chunk_str);
+    /// Returns the list of available models
+    pub fn available_models() -> Vec<AntigravityModel> {
+        AntigravityModel::all()
+    }
+}
 
-                while let Some(newline_idx) = line_buffer.find('\n') {
-                    let line = line_buffer[..newline_idx].to_string();
-                    line_buffer.drain(..newline_idx + 1);
+// =============================================================================
+// Stream Parsing
+// =============================================================================
 
-                    let trimmed = line.trim();
-                    if trimmed.is_empty() { continue; }
+/// Collects a chunk stream into a complete response
+async fn collect_response<S>(stream: S, model: AntigravityModel) -> Result<ChatResponse>
+where
+    S: futures::Stream<Item = Result<StreamChunk>>,
+{
+    let mut stream = Box::pin(stream);
+
+    let mut full_content = String::new();
+    let mut full_thinking = String::new();
+    let mut has_thinking = false;
+    let mut usage = None;
+    let mut other_candidates: Vec<String> = Vec::new();
+    let mut logprobs: Option<Vec<TokenLogprob>> = None;
+
+    // Collect all chunks
+    while let Some(chunk_res) = stream.next().await {
+        let chunk = chunk_res?;
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+            continue;
+        }
+        if chunk.candidate > 0 {
+            // Alternatives only contribute their visible text
+            if !chunk.is_thinking {
+                if other_candidates.len() < chunk.candidate {
+                    other_candidates.resize(chunk.candidate, String::new());
+                }
+                other_candidates[chunk.candidate - 1].push_str(&chunk.delta);
+            }
+        } else if chunk.is_thinking {
+            full_thinking.push_str(&chunk.delta);
+            has_thinking = true;
+        } else {
+            full_content.push_str(&chunk.delta);
+            if !chunk.logprobs.is_empty() {
+                logprobs.get_or_insert_with(Vec::new).extend(chunk.logprobs);
+            }
+        }
+    }
 
-                    // tracing::info!("DEBUG RAW STREAM: {}", trimmed); // FORCE LOGGING
+    // Construct response (usage stats are approximated or missing in stream)
+    Ok(ChatResponse {
+        content: full_content,
+        thinking: if has_thinking { Some(full_thinking) } else { None },
+        model: model.api_id().to_string(),
+        finish_reason: "stop".to_string(),
+        usage, // Only set if the stream reported usageMetadata
+        other_candidates,
+        logprobs,
+    })
+}
 
-                    if let Some(data) = trimmed.strip_prefix("data: ") {
-                        if data == "[DONE]" {
-                            break;
-                        }
+/// Parses an SSE byte stream from `streamGenerateContent` into stream chunks
+fn parse_event_stream<S, B, E>(stream: S) -> impl futures::Stream<Item = Result<StreamChunk>> + Send
+where
+    S: futures::Stream<Item = std::result::Result<B, E>> + Send,
+    B: AsRef<[u8]> + Send,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    async_stream::try_stream! {
+        // Raw bytes, decoded a line at a time: a chunk boundary can fall inside
+        // a multi-byte character, which per-chunk decoding would corrupt
+        let mut line_buffer: Vec<u8> = Vec::new();
+        let mut parser = SseParser::new();
+        let mut done = false;
+        let mut saw_event = false;
+        let mut usage = None;
+        let mut byte_stream = Box::pin(stream); // Pin the stream
+
+        'outer: while let Some(chunk_result) = byte_stream.next().await {
+            let bytes = chunk_result?;
+            line_buffer.extend_from_slice(bytes.as_ref());
+
+            while let Some(newline_idx) = line_buffer.iter().position(|&b| b == b'\n') {
+                let raw_line: Vec<u8> = line_buffer.drain(..=newline_idx).collect();
+                let line = String::from_utf8_lossy(&raw_line[..newline_idx]).into_owned();
+
+                // Catch an HTML page served without a text/html content type
+                if !saw_event && is_html_response(None, &line) {
+                    Err::<(), _>(html_auth_error(&line))?;
+                }
 
-                        match serde_json::from_str::<Value>(data) {
-                             Ok(value) => {
-                                 // Check for response wrapper in stream chunks too
-                                 let root = if let Some(inner) = value.get("response") { inner } else { &value };
-
-                                 if let Some(candidates) = root.get("candidates").and_then(|c| c.as_array()) {
-                                     if let Some(first) = candidates.first() {
-                                         if let Some(parts) = first.get("content").and_then(|c| c.get("parts")).and_then(|p| p.as_array()) {
-                                             for part in parts {
-                                                 let is_thought = part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false);
-                                                 if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
-                                                      if text.contains("(no content)") { continue; }
-                                                     yield StreamChunk {
-                                                         delta: text.to_string(),
-                                                         is_thinking: is_thought,
-                                                         is_tool_use: false,
-                                                         done: false,
-                                                     };
-                                                 } else if let Some(call) = part.get("functionCall") {
-                                                     // Convert Gemini functionCall back to Anthropic tool_use JSON
-                                                     let tool_use = serde_json::json!({
-                                                         "type": "tool_use",
-                                                         "id": format!("call_{}", &Uuid::new_v4().to_string().replace("-", "")[..12]),
-                                                         "name": call.get("name"),
-                                                         "input": call.get("args")
-                                                     });
-                                                      tracing::info!("DEBUG TOOL USE: {}", tool_use);
-                                                     yield StreamChunk {
-                                                         delta: tool_use.to_string(),
-                                                         is_thinking: false,
-                                                         is_tool_use: true,
-                                                         done: false,
-                                                     };
-                                                 }
-                                             }
-                                         }
-                                     }
-                                 }
-                             },
-                             Err(e) => {
-                                 tracing::warn!("Failed to parse stream JSON: {} | Data: {}", e, data);
-                             }
-                        }
-                    } else {
-                        // Try parsing raw line (maybe no data: prefix?)
-                         match serde_json::from_str::<Value>(trimmed) {
-                             Ok(value) => {
-                                 // Check for response wrapper in stream chunks too
-                                 let root = if let Some(inner) = value.get("response") { inner } else { &value };
-
-                                 if let Some(candidates) = root.get("candidates").and_then(|c| c.as_array()) {
-                                     if let Some(first) = candidates.first() {
-                                         if let Some(parts) = first.get("content").and_then(|c| c.get("parts")).and_then(|p| p.as_array()) {
-                                             for part in parts {
-                                                 let is_thought = part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false);
-                                                 if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
-                                                      if text.contains("(no content)") { continue; }
-                                                     yield StreamChunk {
-                                                         delta: text.to_string(),
-                                                         is_thinking: is_thought,
-                                                         is_tool_use: false,
-                                                         done: false,
-                                                     };
-                                                 } else if let Some(call) = part.get("functionCall") {
-                                                     // Convert Gemini functionCall back to Anthropic tool_use JSON
-                                                     let tool_use = serde_json::json!({
-                                                         "type": "tool_use",
-                                                         "id": format!("call_{}", &Uuid::new_v4().to_string().replace("-", "")[..12]),
-                                                         "name": call.get("name"),
-                                                         "input": call.get("args")
-                                                     });
-                                                      tracing::info!("DEBUG TOOL USE: {}", tool_use);
-                                                     yield StreamChunk {
-                                                         delta: tool_use.to_string(),
-                                                         is_thinking: false,
-                                                         is_tool_use: true,
-                                                         done: false,
-                                                     };
-                                                 }
-                                             }
-                                         }
-                                     }
-                                 }
-                             },
-                             Err(_) => {
-                                 // Just ignore non-json lines that don't start with data:
-                                 tracing::debug!("Ignored non-data line: {}", trimmed);
-                             }
-                        }
-                    }
+                let Some(data) = parser.feed_line(&line) else { continue };
+                saw_event = true;
+                if data.trim() == "[DONE]" {
+                    done = true;
+                    break 'outer;
+                }
+                let (chunks, event_usage) = stream_chunks_from_event(&data)?;
+                usage = event_usage.or(usage);
+                for chunk in chunks {
+                    yield chunk;
+                }
+            }
+        }
+
+        // Flush an event left pending without a trailing blank line
+        if !done {
+            let tail = String::from_utf8_lossy(&std::mem::take(&mut line_buffer)).into_owned();
+            let pending = parser.feed_line(&tail).into_iter().chain(parser.finish());
+            for data in pending.filter(|d| d.trim() != "[DONE]") {
+                let (chunks, event_usage) = stream_chunks_from_event(&data)?;
+                usage = event_usage.or(usage);
+                for chunk in chunks {
+                    yield chunk;
+                }
+            }
+        }
+        yield StreamChunk { done: true, usage, ..Default::default() };
+    }
+}
+
+/// Whether an upstream response is an HTML page (typically a login redirect)
+/// rather than the JSON event stream we asked for
+fn is_html_response(content_type: Option<&str>, body: &str) -> bool {
+    if content_type.is_some_and(|ct| ct.to_ascii_lowercase().contains("text/html")) {
+        return true;
+    }
+    let start = body.trim_start().to_ascii_lowercase();
+    start.starts_with("<!doctype html") || start.starts_with("<html")
+}
+
+/// Error reported when the upstream answers with HTML, so the client sees an
+/// authentication failure instead of an empty stream
+fn html_auth_error(body: &str) -> anyhow::Error {
+    let preview: String = body.chars().take(200).collect();
+    anyhow!("AUTH_ERROR: Upstream returned an HTML page instead of JSON (likely a login redirect). \
+             Re-authenticate with 'aether login'. Response: {}", preview.trim())
+}
+
+/// Default headers for every upstream request in the given style
+///
+/// Identification headers come from the fingerprint (or the static constants
+/// without one), with configured overrides applied to the Antigravity style.
+fn default_headers(fingerprint: Option<&Fingerprint>, style: HeaderStyle, overrides: &ClientHeaders) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    // Apply fingerprint headers if available, otherwise fallback to static defaults
+    if let Some(fp) = fingerprint {
+        for (k, v) in fp.to_headers_with_style(style) {
+            if let Ok(name) = reqwest::header::HeaderName::from_bytes(k.as_bytes())
+                && let Ok(val) = HeaderValue::from_str(&v)
+            {
+                headers.insert(name, val);
+            }
+        }
+    } else {
+        headers.insert("User-Agent", HeaderValue::from_static(ANTIGRAVITY_USER_AGENT));
+        headers.insert("X-Goog-Api-Client", HeaderValue::from_static(ANTIGRAVITY_API_CLIENT));
+        headers.insert("Client-Metadata", HeaderValue::from_static(ANTIGRAVITY_CLIENT_METADATA));
+    }
+
+    if style == HeaderStyle::Antigravity {
+        let configured = [
+            ("User-Agent", &overrides.user_agent),
+            ("X-Goog-Api-Client", &overrides.api_client),
+            ("Client-Metadata", &overrides.client_metadata),
+        ];
+        for (name, value) in configured {
+            let Some(value) = value else { continue };
+            match HeaderValue::from_str(value) {
+                Ok(val) => {
+                    headers.insert(name, val);
                 }
+                Err(_) => warn!("Ignoring invalid {} override: {:?}", name, value),
             }
-            yield StreamChunk { delta: "".into(), is_thinking: false, is_tool_use: false, done: true };
+        }
+    }
+
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    // Session Distribution: Randomize session ID to avoid rate limit tracking by client ID
+    let session_id = AntigravityClient::generate_session_id();
+    if let Ok(val) = HeaderValue::from_str(&session_id) {
+        headers.insert("X-Goog-Session-Id", val);
+    }
+
+    // 2026-01-26: Critical Header for thinking models
+    headers.insert("anthropic-beta", HeaderValue::from_static("interleaved-thinking-2025-05-14"));
+    headers
+}
+
+/// Error for a 403 on the generate call
+///
+/// With no project configured the built-in default was used, so the fix is
+/// to set a project rather than to enable an API on one.
+fn permission_denied_error(project_id: &str, project_configured: bool, error_text: &str) -> anyhow::Error {
+    if !project_configured && project_id == ANTIGRAVITY_DEFAULT_PROJECT_ID {
+        return anyhow!("NO_PROJECT_CONFIGURED: No Google Cloud project is configured and the default project \
+                        '{}' was denied. Run the AetherBridge setup wizard or set GOOGLE_CLOUD_PROJECT to your \
+                        project ID. {}", project_id, error_text);
+    }
+    anyhow!("IAM_PERMISSION_DENIED: The Project ID '{}' likely needs the Gemini API enabled. {}", project_id, error_text)
+}
+
+/// Runs `attempt`, and once more after `delay` if it fails with `IAM_PERMISSION_DENIED`
+///
+/// Enabling the Gemini API on a project takes a while to propagate, so a
+/// request sent right after can be denied and then succeed shortly after.
+async fn retry_permission_denied<T, F, Fut>(delay: Option<Duration>, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    match (attempt().await, delay) {
+        (Err(e), Some(delay)) if e.to_string().starts_with("IAM_PERMISSION_DENIED:") => {
+            warn!("Permission denied; waiting {}ms for API enablement to propagate before retrying", delay.as_millis());
+            tokio::time::sleep(delay).await;
+            attempt().await
+        }
+        (result, _) => result,
+    }
+}
+
+/// Converts one SSE event payload into stream chunks, plus any usage it reports
+///
+/// Fails only when the event says the prompt itself was blocked.
+fn stream_chunks_from_event(data: &str) -> Result<(Vec<StreamChunk>, Option<Usage>)> {
+    let value = match serde_json::from_str::<Value>(data) {
+        Ok(value) => value,
+        Err(e) => {
+            tracing::warn!("Failed to parse stream JSON: {} | Data: {}", e, data);
+            return Ok((Vec::new(), None));
+        }
+    };
+
+    let root = response_root(&value);
+    if let Some(error) = prompt_blocked_error(root) {
+        return Err(error);
+    }
+    let usage = root.get("usageMetadata").map(Usage::from_metadata);
+
+    let mut chunks: Vec<StreamChunk> = extract_parts(&value).into_iter().map(stream_chunk_from_part).collect();
+
+    // Logprobs cover the event's text, so they ride on the candidate's last text chunk
+    let candidates = root.get("candidates").and_then(|c| c.as_array()).map(Vec::as_slice).unwrap_or_default();
+    for (position, candidate_value) in candidates.iter().enumerate() {
+        let candidate = candidate_index(candidate_value, position);
+        if let Some(result) = candidate_value.get("logprobsResult")
+            && let Some(chunk) = chunks.iter_mut().rev().find(|c| c.candidate == candidate && !c.is_thinking && !c.is_tool_use)
+        {
+            chunk.logprobs = logprobs_from_result(result);
+        }
+    }
+    Ok((chunks, usage))
+}
+
+/// Error for a response whose prompt was rejected before generation
+///
+/// Gemini reports this as `promptFeedback.blockReason` with no candidates at
+/// all, unlike a SAFETY finish reason on an answer that was started.
+fn prompt_blocked_error(root: &Value) -> Option<anyhow::Error> {
+    let feedback = root.get("promptFeedback")?;
+    let reason = feedback.get("blockReason").and_then(|r| r.as_str())?;
+    let detail = feedback.get("blockReasonMessage").and_then(|m| m.as_str())
+        .map(|m| format!(": {}", m))
+        .unwrap_or_default();
+    Some(anyhow!("PROMPT_BLOCKED: Gemini rejected the prompt before answering (blockReason {}{}). \
+                  Rephrase or remove the flagged content and try again.", reason, detail))
+}
+
+/// Largest text part sent upstream; longer message text is split across parts
+const MAX_TEXT_PART_BYTES: usize = 512 * 1024;
+
+/// Splits text into pieces of at most `max_bytes`, breaking after a newline
+/// where possible and never inside a character. Always yields one piece.
+fn split_text_parts(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max_bytes {
+        let mut end = max_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let split = match rest[..end].rfind('\n') {
+            Some(newline) if newline > 0 => newline + 1,
+            _ => end,
         };
+        pieces.push(&rest[..split]);
+        rest = &rest[split..];
+    }
+    pieces.push(rest);
+    pieces
+}
+
+/// Output tokens kept for the answer on top of a Claude thinking budget
+const ANSWER_TOKENS: u32 = 8192;
 
-        Ok(output_stream)
+/// Lowers a thinking budget so budget plus answer fits the model's output ceiling
+fn clamp_thinking_budget(model: AntigravityModel, budget: u32) -> u32 {
+    let max_budget = model.max_output_tokens().saturating_sub(ANSWER_TOKENS);
+    if budget <= max_budget {
+        return budget;
     }
+    info!(
+        "Thinking budget {} exceeds what {} can output alongside an answer; clamped to {}",
+        budget, model.display_name(), max_budget
+    );
+    max_budget
+}
 
-    /// Returns the list of available models
-    pub fn available_models() -> Vec<AntigravityModel> {
-        AntigravityModel::all()
+/// Points a request at cached content instead of sending its system instruction
+fn use_cached_content(body: &mut Value, name: &str) {
+    if let Some(request) = body.get_mut("request").and_then(|r| r.as_object_mut()) {
+        request.remove("systemInstruction");
+        request.insert("cachedContent".to_string(), json!(name));
+    }
+}
+
+/// The payload of an API response, which is sometimes wrapped in `"response"`
+fn response_root(value: &Value) -> &Value {
+    value.get("response").unwrap_or(value)
+}
+
+/// A candidate's `index`, falling back to its position in the array
+fn candidate_index(candidate: &Value, position: usize) -> usize {
+    candidate.get("index").and_then(|i| i.as_u64()).map_or(position, |i| i as usize)
+}
+
+/// One text or function-call part of a response candidate
+#[derive(Debug, Clone, PartialEq)]
+struct Part {
+    /// Which candidate the part belongs to
+    candidate: usize,
+    kind: PartKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PartKind {
+    Text { text: String, thought: bool },
+    FunctionCall { name: Value, args: Value },
+}
+
+/// Every text and function-call part of every candidate, in order
+///
+/// Accepts both the bare `{"candidates": ...}` shape and the
+/// `{"response": {"candidates": ...}}` wrapper. Other parts are skipped.
+fn extract_parts(value: &Value) -> Vec<Part> {
+    let Some(candidates) = response_root(value).get("candidates").and_then(|c| c.as_array()) else {
+        return Vec::new();
+    };
+
+    let mut parts = Vec::new();
+    for (position, candidate_value) in candidates.iter().enumerate() {
+        let candidate = candidate_index(candidate_value, position);
+        let Some(raw_parts) = candidate_value.pointer("/content/parts").and_then(|p| p.as_array()) else {
+            continue;
+        };
+        parts.extend(raw_parts.iter().filter_map(|part| {
+            let kind = if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                let thought = part.get("thought").and_then(|t| t.as_bool()).unwrap_or(false);
+                PartKind::Text { text: text.to_string(), thought }
+            } else {
+                let call = part.get("functionCall")?;
+                PartKind::FunctionCall {
+                    name: call.get("name").cloned().unwrap_or(Value::Null),
+                    args: call.get("args").cloned().unwrap_or(Value::Null),
+                }
+            };
+            Some(Part { candidate, kind })
+        }));
+    }
+    parts
+}
+
+/// Converts one response part into a stream chunk
+fn stream_chunk_from_part(part: Part) -> StreamChunk {
+    match part.kind {
+        PartKind::Text { text, thought } => StreamChunk {
+            delta: text,
+            is_thinking: thought,
+            candidate: part.candidate,
+            ..Default::default()
+        },
+        PartKind::FunctionCall { name, args } => {
+            // Convert Gemini functionCall back to Anthropic tool_use JSON
+            let tool_use = serde_json::json!({
+                "type": "tool_use",
+                "id": format!("call_{}", &Uuid::new_v4().to_string().replace("-", "")[..12]),
+                "name": name,
+                "input": args
+            });
+            tracing::info!("DEBUG TOOL USE: {}", tool_use);
+            StreamChunk {
+                delta: tool_use.to_string(),
+                is_tool_use: true,
+                candidate: part.candidate,
+                ..Default::default()
+            }
+        }
     }
 }
 
@@ -1104,17 +1774,33 @@ mod tests {
     #[test]
     fn test_model_from_str() {
         assert_eq!(
-            AntigravityModel::from_str("claude-sonnet-4.5-thinking"),
-            Some(AntigravityModel::ClaudeSonnet45Thinking)
+            "claude-sonnet-4.5-thinking".parse(),
+            Ok(AntigravityModel::ClaudeSonnet45Thinking)
         );
         assert_eq!(
-            AntigravityModel::from_str("gemini-3-pro"),
-            Some(AntigravityModel::Gemini3Pro)
-        );
-        assert_eq!(
-            AntigravityModel::from_str("unknown-model"),
-            None
+            "gemini-3-pro".parse(),
+            Ok(AntigravityModel::Gemini3Pro)
         );
+        assert_eq!("antigravity-gemini-3-flash".parse(), Ok(AntigravityModel::Gemini3Flash));
+        assert_eq!("Claude-Sonnet-4-5".parse(), Ok(AntigravityModel::ClaudeSonnet45));
+        assert_eq!("gemini3pro".parse(), Ok(AntigravityModel::Gemini3Pro));
+        for model in AntigravityModel::all() {
+            assert_eq!(model.api_id().parse(), Ok(model));
+        }
+
+        let err = "unknown-model".parse::<AntigravityModel>().unwrap_err();
+        assert!(err.to_string().contains("gemini-3-flash"), "{}", err);
+    }
+
+    #[test]
+    fn test_strict_parse_rejects_ambiguous_names() {
+        // Keyword matching would call these Sonnet
+        for name in ["gemini-sonnet", "claude-sonnet", "sonnet", "gemini-3-pro-sonnet-thinking"] {
+            assert!(name.parse::<AntigravityModel>().is_err(), "{}", name);
+        }
+        assert_eq!(AntigravityModel::from_loose("gemini-sonnet"), Some(AntigravityModel::ClaudeSonnet45));
+        assert_eq!(AntigravityModel::from_loose("claude-sonnet-4.5-thinking"), Some(AntigravityModel::ClaudeSonnet45Thinking));
+        assert_eq!(AntigravityModel::from_loose("unknown-model"), None);
     }
 
     #[test]
@@ -1135,6 +1821,224 @@ mod tests {
         assert!(AntigravityModel::Gemini3Pro.supports_thinking());
     }
 
+    #[test]
+    fn test_oversized_message_split_into_parts() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let line = "fn main() { println!(\"héllo\"); }\n";
+        let pasted = line.repeat(3 * MAX_TEXT_PART_BYTES / line.len());
+        let messages = vec![Message::user(&pasted)];
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &GenerationParams::default());
+        let parts = body["request"]["contents"][0]["parts"].as_array().unwrap();
+        assert!(parts.len() >= 3);
+        let texts: Vec<&str> = parts.iter().map(|p| p["text"].as_str().unwrap()).collect();
+        assert!(texts.iter().all(|t| t.len() <= MAX_TEXT_PART_BYTES && t.ends_with('\n')));
+        assert_eq!(texts.concat(), pasted);
+
+        // Ordinary messages stay a single part
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &[Message::user("Hi")], None, None, &GenerationParams::default());
+        assert_eq!(body["request"]["contents"][0]["parts"], json!([{ "text": "Hi" }]));
+    }
+
+    #[test]
+    fn test_gemini_pro_default_tier() {
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let no_level = ThinkingConfig { budget: None, level: None, include_thoughts: true };
+
+        // Hardcoded default is "low"
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, Some(&no_level), None, &GenerationParams::default());
+        assert_eq!(body["model"], "gemini-3-pro-low");
+
+        client.set_gemini_pro_default_tier("high");
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, Some(&no_level), None, &GenerationParams::default());
+        assert_eq!(body["model"], "gemini-3-pro-high");
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, None, None, &GenerationParams::default());
+        assert_eq!(body["model"], "gemini-3-pro-high");
+
+        // Per-request level still wins
+        let low = ThinkingConfig { level: Some("low".into()), ..no_level };
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, Some(&low), None, &GenerationParams::default());
+        assert_eq!(body["model"], "gemini-3-pro-low");
+    }
+
+    #[test]
+    fn test_thinking_model_temperature_clamped() {
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        client.set_model_defaults(ModelDefaults {
+            claude: FamilyDefaults { max_temperature: Some(1.0), ..Default::default() },
+            gemini: FamilyDefaults::default(),
+        });
+        let messages = vec![Message::user("Hello")];
+        let hot = GenerationParams { temperature: Some(1.5), ..Default::default() };
+
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45Thinking, &messages, None, None, &hot);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 1.0);
+
+        // Non-thinking models and other families are left alone
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &hot);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 1.5);
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, None, None, &hot);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 1.5);
+    }
+
+    #[test]
+    fn test_family_sampling_defaults() {
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        client.set_model_defaults(ModelDefaults {
+            claude: FamilyDefaults { temperature: Some(1.0), top_p: Some(0.5), ..Default::default() },
+            gemini: FamilyDefaults { temperature: Some(0.25), ..Default::default() },
+        });
+        let messages = vec![Message::user("Hello")];
+        let unset = GenerationParams::default();
+
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &unset);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 1.0);
+        assert_eq!(body["request"]["generationConfig"]["topP"], 0.5);
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &unset);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 0.25);
+        assert!(body["request"]["generationConfig"].get("topP").is_none());
+
+        // Client-supplied values win
+        let explicit = GenerationParams { temperature: Some(0.0), ..Default::default() };
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &explicit);
+        assert_eq!(body["request"]["generationConfig"]["temperature"], 0.0);
+    }
+
+    #[test]
+    fn test_strict_passthrough_preserves_signed_thinking() {
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let mut answer = Message::assistant("The answer is 4.");
+        answer.thinking.push(ThinkingBlock {
+            thinking: "2 + 2 = 4".into(),
+            signature: "sig_abc123".into(),
+        });
+        let messages = vec![Message::user("What is 2 + 2?"), answer, Message::user("And 3 + 3?")];
+
+        // Default mode strips thinking for every target
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45Thinking, &messages, None, None, &GenerationParams::default());
+        assert_eq!(body["request"]["contents"][1]["parts"].as_array().unwrap().len(), 1);
+
+        client.set_preserve_thinking_signatures(true);
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45Thinking, &messages, None, None, &GenerationParams::default());
+        let parts = body["request"]["contents"][1]["parts"].as_array().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0]["thought"], true);
+        assert_eq!(parts[0]["text"], "2 + 2 = 4");
+        assert_eq!(parts[0]["thoughtSignature"], "sig_abc123");
+        assert_eq!(parts[1]["text"], "The answer is 4.");
+
+        // Gemini can't validate Claude signatures, so thinking is still stripped
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &GenerationParams::default());
+        assert_eq!(body["request"]["contents"][1]["parts"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_bridge_thinking_markdown_stripped_for_every_target() {
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        client.set_preserve_thinking_signatures(true);
+        let mut answer = Message::assistant("\n> *Thinking: Let me check.* Both numbers are small.\n\nThe answer is 4.");
+        answer.thinking.push(ThinkingBlock {
+            thinking: "2 + 2 = 4".into(),
+            signature: "sig_abc123".into(),
+        });
+        let messages = vec![Message::user("What is 2 + 2?"), answer];
+
+        // Claude keeps the signed block but never the rendered markdown
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45Thinking, &messages, None, None, &GenerationParams::default());
+        let parts = body["request"]["contents"][1]["parts"].as_array().unwrap();
+        assert_eq!(parts[0]["thoughtSignature"], "sig_abc123");
+        assert_eq!(parts[1]["text"], "The answer is 4.");
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &GenerationParams::default());
+        assert_eq!(body["request"]["contents"][1]["parts"], json!([{ "text": "The answer is 4." }]));
+    }
+
+    #[test]
+    fn test_thinking_tags_kept_outside_assistant_history() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![
+            Message::system("Reason inside <thinking>...</thinking> before answering."),
+            Message::user("Why does [Thinking: x] show up in my log?"),
+            Message::assistant("<thinking>old reasoning</thinking>It is a log marker."),
+        ];
+
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &GenerationParams::default());
+        assert_eq!(body["request"]["systemInstruction"]["parts"][0]["text"], "Reason inside <thinking>...</thinking> before answering.");
+        assert_eq!(body["request"]["contents"][0]["parts"][0]["text"], "Why does [Thinking: x] show up in my log?");
+        assert_eq!(body["request"]["contents"][1]["parts"][0]["text"], "It is a log marker.");
+    }
+
+    #[test]
+    fn test_include_thoughts_false_reaches_thinking_config() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let thinking = ThinkingConfig { budget: Some(4096), level: Some("low".into()), include_thoughts: false };
+
+        for model in [AntigravityModel::Gemini3Flash, AntigravityModel::ClaudeSonnet45Thinking] {
+            let body = client.build_request_body("project", model, &messages, Some(&thinking), None, &GenerationParams::default());
+            assert_eq!(body["request"]["generationConfig"]["thinkingConfig"]["includeThoughts"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_explicit_project_id_used_in_body() {
+        let client = AntigravityClient::new("token".into(), Some("billing-project".into()), None).unwrap();
+
+        // Forced IDs skip discovery, so this must not touch the network
+        client.fetch_provisioned_project_id().await;
+        let project_id = client.project_id().await;
+        assert_eq!(project_id, "billing-project");
+
+        let body = client.build_request_body(&project_id, AntigravityModel::Gemini3Flash, &[Message::user("Hello")], None, None, &GenerationParams::default());
+        assert_eq!(body["project"], "billing-project");
+    }
+
+    #[tokio::test]
+    async fn test_per_request_session_ids_differ() {
+        let session_id = |request: reqwest::RequestBuilder| {
+            let request = request.build().unwrap();
+            request.headers().get("X-Goog-Session-Id").map(|v| v.to_str().unwrap().to_string())
+        };
+        let body = json!({});
+
+        // Per-client: nothing per request, the client-wide default header applies
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        client.set_session_id_policy(SessionIdPolicy::PerClient);
+        assert_eq!(session_id(client.stream_request("http://localhost/", "token", &body).await), None);
+
+        // Per-request is the default
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let first = session_id(client.stream_request("http://localhost/", "token", &body).await).unwrap();
+        let second = session_id(client.stream_request("http://localhost/", "token", &body).await).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_forced_tool_choice_sets_tool_config() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let tool_defs = vec![json!({ "name": "x", "description": "", "parameters": { "type": "object" } })];
+        let tools = || Some(&tool_defs);
+
+        let forced = GenerationParams {
+            tool_choice: ToolChoice::from_value(&json!({ "type": "tool", "name": "x" })),
+            ..Default::default()
+        };
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, tools(), &forced);
+        let config = &body["request"]["toolConfig"]["functionCallingConfig"];
+        assert_eq!(config["mode"], "ANY");
+        assert_eq!(config["allowedFunctionNames"], json!(["x"]));
+
+        let required = GenerationParams { tool_choice: ToolChoice::from_value(&json!("required")), ..Default::default() };
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, tools(), &required);
+        assert_eq!(body["request"]["toolConfig"]["functionCallingConfig"], json!({ "mode": "ANY" }));
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, tools(), &GenerationParams::default());
+        assert!(body["request"].get("toolConfig").is_none());
+    }
+
     #[test]
     fn test_sanitize_tool_definition() {
         let tool = serde_json::json!({
@@ -1173,4 +2077,356 @@ mod tests {
 
         assert!(sanitized["parameters"].get("$schema").is_none());
     }
+
+    fn collect_event_stream(body: &str) -> Vec<Result<StreamChunk>> {
+        let bytes = vec![Ok::<_, std::io::Error>(body.as_bytes().to_vec())];
+        futures::executor::block_on(parse_event_stream(futures::stream::iter(bytes)).collect())
+    }
+
+    #[test]
+    fn test_thoughts_token_count_parsed() {
+        let event = r#"{"response":{"candidates":[{"content":{"parts":[{"text":"hi"}]}}],"usageMetadata":{"promptTokenCount":5,"candidatesTokenCount":2,"thoughtsTokenCount":812,"totalTokenCount":819}}}"#;
+        let (_, usage) = stream_chunks_from_event(event).unwrap();
+        let usage = usage.unwrap();
+        assert_eq!(usage.thinking_tokens, 812);
+        assert_eq!(usage.completion_tokens, 2);
+    }
+
+    #[test]
+    fn test_prompt_block_reason_reported() {
+        let results = collect_event_stream(
+            "data: {\"response\":{\"promptFeedback\":{\"blockReason\":\"SAFETY\"}}}\n\n",
+        );
+        let err = results.into_iter().find_map(|r| r.err()).unwrap().to_string();
+        assert!(err.starts_with("PROMPT_BLOCKED:"));
+        assert!(err.contains("SAFETY"));
+
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let raw = json!({ "promptFeedback": { "blockReason": "OTHER", "blockReasonMessage": "Flagged" } });
+        let err = client.parse_response(raw, AntigravityModel::Gemini3Flash).unwrap_err().to_string();
+        assert!(err.contains("OTHER") && err.contains("Flagged"));
+    }
+
+    /// Sends a request through the client's HTTP client to a local socket and
+    /// returns the raw request head
+    async fn captured_request_head(client: &AntigravityClient) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        client.client.read().await.get(url).send().await.unwrap();
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_configured_user_agent_sent_upstream() {
+        let overrides = ClientHeaders { user_agent: Some("antigravity/9.9.9 linux/x64".into()), ..Default::default() };
+        let client = AntigravityClient::with_client_headers(
+            "token".into(),
+            Some("project".into()),
+            Some(Fingerprint::generate()),
+            overrides,
+        ).unwrap();
+
+        let head = captured_request_head(&client).await.to_lowercase();
+        assert!(head.contains("user-agent: antigravity/9.9.9 linux/x64\r\n"), "{}", head);
+        // Unset fields keep the fingerprint's values
+        assert!(head.contains("x-goog-api-client: "));
+
+        // The override survives a header-style rebuild back to Antigravity
+        client.rebuild_client_with_style(HeaderStyle::Antigravity).await.unwrap();
+        let head = captured_request_head(&client).await.to_lowercase();
+        assert!(head.contains("user-agent: antigravity/9.9.9 linux/x64\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_aborts_slow_upstream() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let mut client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        client.set_request_timeout(Duration::from_millis(300));
+
+        let started = std::time::Instant::now();
+        let err = client.stream_request(&url, "token", &json!({})).await.send().await.unwrap_err();
+        assert!(err.is_timeout());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_permission_denied_retried_once() {
+        let denied = || permission_denied_error("my-project", true, "Permission denied for generateChat");
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let attempt = || {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Err::<(), _>(denied()) }
+        };
+
+        let err = retry_permission_denied(Some(Duration::from_millis(10)), attempt).await.unwrap_err();
+        assert!(err.to_string().starts_with("IAM_PERMISSION_DENIED:"));
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // Disabled by default, and other errors are never retried
+        attempts.store(0, std::sync::atomic::Ordering::SeqCst);
+        retry_permission_denied(None, attempt).await.unwrap_err();
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let other = std::sync::atomic::AtomicUsize::new(0);
+        retry_permission_denied(Some(Duration::from_millis(10)), || {
+            other.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Err::<(), _>(anyhow!("RATE_LIMITED:60:quota")) }
+        }).await.unwrap_err();
+        assert_eq!(other.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_denied_default_project_asks_for_configuration() {
+        let denied = r#"{"error":{"code":403,"message":"Permission denied on resource project for generateChat"}}"#;
+
+        let err = permission_denied_error(ANTIGRAVITY_DEFAULT_PROJECT_ID, false, denied).to_string();
+        assert!(err.starts_with("NO_PROJECT_CONFIGURED:"), "{}", err);
+        assert!(err.contains("GOOGLE_CLOUD_PROJECT"));
+
+        // A project the user chose really is missing the API
+        let err = permission_denied_error("my-project", true, denied).to_string();
+        assert!(err.starts_with("IAM_PERMISSION_DENIED:"));
+        // Auto-discovery swapped the default for a provisioned project
+        let err = permission_denied_error("provisioned-123", false, denied).to_string();
+        assert!(err.starts_with("IAM_PERMISSION_DENIED:"));
+    }
+
+    #[test]
+    fn test_html_body_is_auth_error() {
+        let html = "<!DOCTYPE html>\n<html><head><title>Sign in - Google Accounts</title></head></html>\n";
+        let results = collect_event_stream(html);
+        let err = results.into_iter().next().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("AUTH_ERROR:"));
+
+        assert!(is_html_response(Some("text/html; charset=UTF-8"), ""));
+        assert!(!is_html_response(Some("text/event-stream"), "data: {}"));
+
+        // A normal event stream still parses
+        let sse = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hi\"}]}}]}\n\n";
+        let chunks: Vec<StreamChunk> = collect_event_stream(sse).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks[0].delta, "Hi");
+        assert!(chunks.last().unwrap().done);
+    }
+
+    /// Event stream exercising multi-byte text, thoughts, usage and an unterminated last event
+    const SPLIT_TEST_STREAM: &str = concat!(
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Thinking about caf\\u00e9s\",\"thought\":true}]}}]}\n\n",
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Café ☕ — 日本語 🦀\"}]}}]}\n\n",
+        ": keep-alive comment\n\n",
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"ünïcödé\\nline two\"}]}}]}\n\n",
+        "data: {\"response\":{\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Ωmega 🌍\"}]}}],",
+        "\"usageMetadata\":{\"promptTokenCount\":12,\"candidatesTokenCount\":34,\"totalTokenCount\":46}}}"
+    );
+
+    /// Comparable view of a parsed stream
+    fn parse_split(body: &[u8], splits: &[usize]) -> Vec<(String, bool, bool, Option<u32>)> {
+        let mut bounds: Vec<usize> = splits.iter().map(|s| s % (body.len() + 1)).collect();
+        bounds.extend([0, body.len()]);
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let pieces: Vec<std::io::Result<Vec<u8>>> = bounds.windows(2).map(|w| Ok(body[w[0]..w[1]].to_vec())).collect();
+        let chunks: Vec<Result<StreamChunk>> =
+            futures::executor::block_on(parse_event_stream(futures::stream::iter(pieces)).collect());
+        chunks
+            .into_iter()
+            .map(|c| c.unwrap())
+            .map(|c| (c.delta, c.is_thinking, c.done, c.usage.map(|u| u.completion_tokens)))
+            .collect()
+    }
+
+    #[test]
+    fn test_every_single_split_point_parses_identically() {
+        let body = SPLIT_TEST_STREAM.as_bytes();
+        let expected = parse_split(body, &[]);
+        assert_eq!(expected.len(), 5);
+        assert_eq!(expected[1].0, "Café ☕ — 日本語 🦀");
+        assert_eq!(expected.last().unwrap(), &(String::new(), false, true, Some(34)));
+
+        // Including every point inside a multi-byte character
+        for split in 0..=body.len() {
+            assert_eq!(parse_split(body, &[split]), expected, "split at byte {}", split);
+        }
+    }
+
+    #[test]
+    fn test_emoji_split_across_reads_is_intact() {
+        let body = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"ok 🦀\"}]}}]}\n\n".as_bytes();
+        // Cut between the 2nd and 3rd byte of the 4-byte crab
+        let crab = body.windows(4).position(|w| w == "🦀".as_bytes()).unwrap();
+        let reads = vec![
+            Ok::<_, std::io::Error>(body[..crab + 2].to_vec()),
+            Ok(body[crab + 2..].to_vec()),
+        ];
+
+        let chunks: Vec<StreamChunk> = futures::executor::block_on(parse_event_stream(futures::stream::iter(reads)).collect::<Vec<_>>())
+            .into_iter()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks[0].delta, "ok 🦀");
+        assert!(!chunks[0].delta.contains('\u{FFFD}'));
+    }
+
+    #[test]
+    fn test_extract_parts_from_wrapped_and_bare_responses() {
+        let bare = json!({
+            "candidates": [{ "content": { "parts": [
+                { "text": "Checking the file", "thought": true },
+                { "text": "Reading it now." },
+                { "functionCall": { "name": "read_file", "args": { "path": "a.rs" } } },
+                { "inlineData": { "mimeType": "image/png", "data": "" } }
+            ] } }],
+            "usageMetadata": { "promptTokenCount": 5, "candidatesTokenCount": 7, "totalTokenCount": 12 }
+        });
+        let wrapped = json!({ "response": bare.clone() });
+
+        let parts = extract_parts(&bare);
+        assert_eq!(parts, extract_parts(&wrapped));
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].kind, PartKind::Text { text: "Checking the file".into(), thought: true });
+        assert_eq!(parts[2].kind, PartKind::FunctionCall { name: json!("read_file"), args: json!({ "path": "a.rs" }) });
+
+        for shape in [&bare, &wrapped] {
+            let (chunks, usage) = stream_chunks_from_event(&shape.to_string()).unwrap();
+            assert!(chunks[2].is_tool_use);
+            let tool: Value = serde_json::from_str(&chunks[2].delta).unwrap();
+            assert_eq!(tool["input"]["path"], "a.rs");
+            assert_eq!(usage.unwrap().completion_tokens, 7);
+        }
+
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        for shape in [bare, wrapped] {
+            let response = client.parse_response(shape, AntigravityModel::Gemini3Flash).unwrap();
+            assert_eq!(response.content, "Reading it now.");
+            assert_eq!(response.thinking.as_deref(), Some("Checking the file"));
+            assert_eq!(response.usage.unwrap().total_tokens, 12);
+        }
+    }
+
+    #[test]
+    fn test_multiple_candidates() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let params = GenerationParams { candidate_count: Some(2), ..Default::default() };
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &params);
+        assert_eq!(body["request"]["generationConfig"]["candidateCount"], 2);
+
+        let sse = concat!(
+            "data: {\"candidates\":[{\"index\":0,\"content\":{\"parts\":[{\"text\":\"Hi\"}]}},",
+            "{\"index\":1,\"content\":{\"parts\":[{\"text\":\"Hey\"}]}}]}\n\n",
+            "data: {\"candidates\":[{\"index\":1,\"content\":{\"parts\":[{\"text\":\" there\"}]}}]}\n\n",
+        );
+        let reads = vec![Ok::<_, std::io::Error>(sse.as_bytes().to_vec())];
+        let response = futures::executor::block_on(collect_response(
+            parse_event_stream(futures::stream::iter(reads)),
+            AntigravityModel::Gemini3Flash,
+        ))
+        .unwrap();
+        assert_eq!(response.content, "Hi");
+        assert_eq!(response.other_candidates, vec!["Hey there".to_string()]);
+    }
+
+    #[test]
+    fn test_seed_sent_to_gemini_only() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let params = GenerationParams { seed: Some(42), ..Default::default() };
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Pro, &messages, None, None, &params);
+        assert_eq!(body["request"]["generationConfig"]["seed"], 42);
+
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &params);
+        assert!(body["request"]["generationConfig"].get("seed").is_none());
+    }
+
+    #[test]
+    fn test_oversized_thinking_budget_clamped_to_output_ceiling() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let model = AntigravityModel::ClaudeSonnet45Thinking;
+        let thinking = |budget| ThinkingConfig { budget: Some(budget), level: None, include_thoughts: false };
+
+        let body = client.build_request_body("project", model, &messages, Some(&thinking(100_000)), None, &GenerationParams::default());
+        let config = &body["request"]["generationConfig"];
+        let budget = config["thinkingConfig"]["thinkingBudget"].as_u64().unwrap();
+        let max_output = config["maxOutputTokens"].as_u64().unwrap();
+        assert_eq!(max_output, model.max_output_tokens() as u64);
+        assert_eq!(budget, max_output - ANSWER_TOKENS as u64);
+
+        // Budgets that fit are left alone
+        let body = client.build_request_body("project", model, &messages, Some(&thinking(32_000)), None, &GenerationParams::default());
+        assert_eq!(body["request"]["generationConfig"]["thinkingConfig"]["thinkingBudget"], 32_000);
+        assert_eq!(body["request"]["generationConfig"]["maxOutputTokens"], 40_192);
+    }
+
+    #[test]
+    fn test_cached_content_replaces_system_instruction() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::system("Long agent instructions"), Message::user("Hello")];
+        let mut body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &GenerationParams::default());
+        assert!(body["request"].get("systemInstruction").is_some());
+
+        use_cached_content(&mut body, "cachedContents/abc");
+        assert!(body["request"].get("systemInstruction").is_none());
+        assert_eq!(body["request"]["cachedContent"], "cachedContents/abc");
+        assert_eq!(body["request"]["contents"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_logprobs_requested_on_gemini_only() {
+        let client = AntigravityClient::new("token".into(), Some("project".into()), None).unwrap();
+        let messages = vec![Message::user("Hello")];
+        let params = GenerationParams { logprobs: true, top_logprobs: Some(2), ..Default::default() };
+
+        let body = client.build_request_body("project", AntigravityModel::Gemini3Flash, &messages, None, None, &params);
+        assert_eq!(body["request"]["generationConfig"]["responseLogprobs"], true);
+        assert_eq!(body["request"]["generationConfig"]["logprobs"], 2);
+
+        let body = client.build_request_body("project", AntigravityModel::ClaudeSonnet45, &messages, None, None, &params);
+        assert!(body["request"]["generationConfig"].get("responseLogprobs").is_none());
+
+        let event = json!({ "candidates": [{
+            "content": { "parts": [{ "text": "Hi there" }] },
+            "logprobsResult": {
+                "chosenCandidates": [{ "token": "Hi", "logProbability": -0.1 }, { "token": " there", "logProbability": -0.5 }],
+                "topCandidates": [{ "candidates": [{ "token": "Hi", "logProbability": -0.1 }, { "token": "Hey", "logProbability": -2.5 }] }]
+            }
+        }] });
+        let (chunks, _) = stream_chunks_from_event(&event.to_string()).unwrap();
+        let logprobs = &chunks[0].logprobs;
+        assert_eq!(logprobs.len(), 2);
+        assert_eq!(logprobs[1].token, " there");
+        assert_eq!(logprobs[0].top, vec![("Hi".to_string(), -0.1), ("Hey".to_string(), -2.5)]);
+        assert!(logprobs[1].top.is_empty());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_chunking_does_not_change_parsed_stream(splits in proptest::collection::vec(0usize..4096, 0..40)) {
+            let body = SPLIT_TEST_STREAM.as_bytes();
+            proptest::prop_assert_eq!(parse_split(body, &splits), parse_split(body, &[]));
+        }
+    }
 }
@@ -0,0 +1,139 @@
+//! Context caching for large, repeated system prompts
+//!
+//! Clients such as Claude Code mark their system prompt with `cache_control`
+//! and send the same text every turn. Prompts above a size threshold are
+//! uploaded once as a Gemini cached content resource and referenced by name
+//! afterwards, so the prompt isn't re-sent (and re-billed) on every request.
+//!
+//! Handles are keyed by a hash of the project, model and prompt, and are
+//! forgotten a little before their TTL runs out upstream.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tracing::{debug, warn};
+
+/// Handles are dropped this long before the upstream TTL expires
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// A created cached content resource
+#[derive(Debug, Clone)]
+struct CachedContent {
+    name: String,
+    expires_at: Instant,
+}
+
+/// Cached content handles shared by all clients
+pub struct ContextCache {
+    /// Smallest system prompt worth caching, in estimated tokens
+    min_tokens: u32,
+    /// Lifetime requested for each cached content resource
+    ttl: Duration,
+    /// One cell per key, so concurrent requests create a resource only once
+    entries: Mutex<HashMap<u64, Arc<OnceCell<CachedContent>>>>,
+}
+
+impl ContextCache {
+    pub fn new(min_tokens: u32, ttl: Duration) -> Self {
+        Self {
+            min_tokens,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a system prompt is large enough to cache (chars / 4 estimate)
+    pub fn worth_caching(&self, system_prompt: &str) -> bool {
+        system_prompt.chars().count().div_ceil(4) as u32 >= self.min_tokens
+    }
+
+    /// Name of the cached content for this prompt, creating it with
+    /// `create(ttl)` if there is no live one
+    ///
+    /// Returns None when creation fails, so the caller sends the prompt
+    /// inline instead; the next request tries again.
+    pub async fn handle<F, Fut>(&self, project_id: &str, model: &str, system_prompt: &str, create: F) -> Option<String>
+    where
+        F: FnOnce(Duration) -> Fut,
+        Fut: Future<Output = Result<String>>,
+    {
+        let key = cache_key(project_id, model, system_prompt);
+        let cell = {
+            let mut entries = self.entries.lock().unwrap();
+            let cell = entries.entry(key).or_default();
+            if cell.get().is_some_and(|c| c.expires_at <= Instant::now()) {
+                *cell = Arc::default();
+            }
+            cell.clone()
+        };
+
+        let ttl = self.ttl;
+        let created = cell.get_or_try_init(|| async move {
+            let name = create(ttl).await?;
+            debug!("Created cached content {} for {}", name, model);
+            Ok::<_, anyhow::Error>(CachedContent {
+                name,
+                expires_at: Instant::now() + ttl.saturating_sub(EXPIRY_MARGIN),
+            })
+        }).await;
+
+        match created {
+            Ok(content) => Some(content.name.clone()),
+            Err(e) => {
+                warn!("Context caching failed, sending the system prompt inline: {}", e);
+                None
+            }
+        }
+    }
+}
+
+fn cache_key(project_id: &str, model: &str, system_prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (project_id, model, system_prompt).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_same_prompt_reuses_one_handle() {
+        let cache = ContextCache::new(10, Duration::from_secs(3600));
+        let created = AtomicUsize::new(0);
+        let create = |_ttl| {
+            let n = created.fetch_add(1, Ordering::SeqCst);
+            async move { Ok(format!("cachedContents/{}", n)) }
+        };
+
+        let prompt = "You are a coding agent. ".repeat(20);
+        let first = cache.handle("project", "gemini-3-flash", &prompt, create).await;
+        let second = cache.handle("project", "gemini-3-flash", &prompt, create).await;
+        assert_eq!(first.as_deref(), Some("cachedContents/0"));
+        assert_eq!(first, second);
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        // A different prompt or model gets its own resource
+        let other = cache.handle("project", "gemini-3-flash", "Something else entirely", create).await;
+        assert_eq!(other.as_deref(), Some("cachedContents/1"));
+        cache.handle("project", "gemini-3-pro-low", &prompt, create).await;
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_creation_is_retried() {
+        let cache = ContextCache::new(10, Duration::from_secs(3600));
+        let failed = cache.handle("p", "m", "prompt", |_| async { Err(anyhow::anyhow!("404")) }).await;
+        assert!(failed.is_none());
+
+        let created = cache.handle("p", "m", "prompt", |_| async { Ok("cachedContents/a".to_string()) }).await;
+        assert_eq!(created.as_deref(), Some("cachedContents/a"));
+        assert!(!cache.worth_caching("short"));
+    }
+}
@@ -0,0 +1,142 @@
+//! Latency-based ordering of the Antigravity endpoints
+//!
+//! Each endpoint's response time is tracked as a moving average along with
+//! its consecutive failures. Requests go to the fastest healthy endpoint;
+//! endpoints never measured keep their configured order after the measured
+//! ones, and failing endpoints go last until they answer again.
+//!
+//! So a slow endpoint isn't written off forever, every `probe_interval`th
+//! request is sent to the other endpoint tried least recently instead.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Weight of the newest sample in the moving average, as 1/N
+const LATENCY_SMOOTHING: u32 = 4;
+
+/// What is known about one endpoint
+#[derive(Debug, Clone, Default)]
+struct EndpointStat {
+    /// Moving average of response times (None until measured)
+    latency: Option<Duration>,
+    /// Failures since the last success
+    consecutive_failures: u32,
+    /// Request number this endpoint was last picked for
+    last_picked: u64,
+}
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    endpoints: Vec<EndpointStat>,
+    /// Requests picked so far
+    picks: u64,
+}
+
+/// Per-endpoint health and latency, indexed like the endpoint list
+#[derive(Debug)]
+pub struct EndpointStats {
+    /// Every this many picks go to another endpoint to re-measure it (0 = never)
+    probe_interval: u64,
+    inner: Mutex<StatsInner>,
+}
+
+impl EndpointStats {
+    pub fn new(endpoint_count: usize, probe_interval: u64) -> Self {
+        Self {
+            probe_interval,
+            inner: Mutex::new(StatsInner {
+                endpoints: vec![EndpointStat::default(); endpoint_count],
+                picks: 0,
+            }),
+        }
+    }
+
+    /// Endpoint indices, most preferred first
+    pub fn order(&self) -> Vec<usize> {
+        let inner = self.inner.lock().unwrap();
+        ranked(&inner.endpoints)
+    }
+
+    /// The endpoint to send the next request to
+    pub fn pick(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.picks += 1;
+        let order = ranked(&inner.endpoints);
+        let probing = self.probe_interval > 0 && inner.picks.is_multiple_of(self.probe_interval);
+        let picked = order.iter().skip(1)
+            .filter(|_| probing)
+            .min_by_key(|&&i| inner.endpoints[i].last_picked)
+            .or(order.first())
+            .copied()
+            .unwrap_or(0);
+        let picks = inner.picks;
+        if let Some(stat) = inner.endpoints.get_mut(picked) {
+            stat.last_picked = picks;
+        }
+        picked
+    }
+
+    /// Records a response from an endpoint and how long it took
+    pub fn record_success(&self, index: usize, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(stat) = inner.endpoints.get_mut(index) else {
+            return;
+        };
+        stat.consecutive_failures = 0;
+        stat.latency = Some(match stat.latency {
+            Some(average) => (average * (LATENCY_SMOOTHING - 1) + latency) / LATENCY_SMOOTHING,
+            None => latency,
+        });
+    }
+
+    /// Records that an endpoint could not be reached or failed server-side
+    pub fn record_failure(&self, index: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stat) = inner.endpoints.get_mut(index) {
+            stat.consecutive_failures += 1;
+        }
+    }
+}
+
+/// Healthy endpoints by latency (unmeasured ones after, in list order), then failing ones
+fn ranked(endpoints: &[EndpointStat]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..endpoints.len()).collect();
+    order.sort_by_key(|&i| {
+        let stat = &endpoints[i];
+        (stat.consecutive_failures, stat.latency.is_none(), stat.latency, i)
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faster_endpoint_tried_first() {
+        let stats = EndpointStats::new(3, 0);
+        assert_eq!(stats.order(), [0, 1, 2]);
+
+        // Measured endpoints go ahead of unmeasured ones, fastest first
+        stats.record_success(0, Duration::from_millis(400));
+        stats.record_success(2, Duration::from_millis(80));
+        assert_eq!(stats.order(), [2, 0, 1]);
+        assert_eq!(stats.pick(), 2);
+
+        // A failing endpoint drops to the back until it answers again
+        stats.record_failure(2);
+        assert_eq!(stats.order(), [0, 1, 2]);
+        stats.record_success(2, Duration::from_millis(80));
+        assert_eq!(stats.pick(), 2);
+    }
+
+    #[test]
+    fn test_other_endpoints_reprobed_periodically() {
+        let stats = EndpointStats::new(3, 3);
+        stats.record_success(1, Duration::from_millis(50));
+
+        let picks: Vec<usize> = (0..6).map(|_| stats.pick()).collect();
+        // Every third pick goes to the endpoint tried least recently
+        assert_eq!(picks, [1, 1, 0, 1, 1, 2]);
+    }
+}
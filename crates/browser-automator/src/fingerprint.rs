@@ -61,7 +61,7 @@ const GEMINI_CLI_API_CLIENTS: &[&str] = &[
 // =============================================================================
 
 /// Header style for API requests
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum HeaderStyle {
     /// Antigravity IDE style headers (default)
     Antigravity,
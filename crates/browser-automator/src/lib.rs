@@ -1,8 +1,14 @@
 pub mod antigravity;
 pub mod auth;
+pub mod context_cache;
+pub mod endpoint_stats;
 pub mod fingerprint;
 pub mod google_driver;
+pub mod postprocess;
 pub mod protocol_driver;
+pub mod response_cache;
+pub mod schema_sanitizer;
+pub mod sse;
 pub mod visual_driver;
 
 use anyhow::Result;
@@ -10,9 +16,12 @@ use async_trait::async_trait;
 
 // Re-export key types for external use
 pub use antigravity::{
-    AntigravityClient, AntigravityModel, Message, ChatResponse,
-    ThinkingConfig, Usage, StreamChunk,
+    AntigravityClient, AntigravityModel, Message, ChatResponse, GenerationParams, ParseModelError,
+    ThinkingBlock, ThinkingConfig, TokenLogprob, ToolChoice, Usage, StreamChunk,
 };
+pub use context_cache::ContextCache;
+pub use endpoint_stats::EndpointStats;
+pub use response_cache::ResponseCache;
 pub use fingerprint::{Fingerprint, HeaderStyle};
 
 #[async_trait]
@@ -0,0 +1,120 @@
+//! Response Post-Processing Module
+//!
+//! Gemini sometimes emits artifacts that clients shouldn't see: blank lines
+//! before the first word, `(no content)` placeholder markers, and the same
+//! function call echoed twice in a row. Every response goes through
+//! `chat_completion_stream`, so cleaning its chunks here covers both the
+//! OpenAI and Anthropic endpoints (streaming and not).
+
+use crate::antigravity::StreamChunk;
+use serde_json::Value;
+use tracing::debug;
+
+/// Placeholder Gemini emits for an empty part
+pub const NO_CONTENT_MARKER: &str = "(no content)";
+
+/// Cleans the chunks of one response, in order
+#[derive(Debug, Default)]
+pub struct ResponseCleaner {
+    /// Whether visible text has been emitted yet
+    started: bool,
+    /// Name and input of the previous tool call, to catch echoes
+    last_tool_call: Option<(Value, Value)>,
+}
+
+impl ResponseCleaner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cleans one chunk, returning `None` if nothing is left of it
+    pub fn clean(&mut self, mut chunk: StreamChunk) -> Option<StreamChunk> {
+        if chunk.done {
+            return Some(chunk);
+        }
+        if chunk.is_tool_use {
+            return self.dedupe_tool_call(chunk);
+        }
+
+        if chunk.delta.contains(NO_CONTENT_MARKER) {
+            chunk.delta = chunk.delta.replace(NO_CONTENT_MARKER, "");
+            if chunk.delta.trim().is_empty() {
+                return None;
+            }
+        }
+
+        if !chunk.is_thinking {
+            if !self.started {
+                chunk.delta = trim_leading_blank_lines(&chunk.delta).to_string();
+                if chunk.delta.trim().is_empty() {
+                    return None;
+                }
+                self.started = true;
+            }
+            self.last_tool_call = None;
+        }
+        Some(chunk)
+    }
+
+    /// Drops a tool call identical to the one just before it
+    fn dedupe_tool_call(&mut self, chunk: StreamChunk) -> Option<StreamChunk> {
+        let Ok(call) = serde_json::from_str::<Value>(&chunk.delta) else {
+            return Some(chunk);
+        };
+        let key = (call["name"].clone(), call["input"].clone());
+        if self.last_tool_call.as_ref() == Some(&key) {
+            debug!("Dropping echoed tool call {}", key.0);
+            return None;
+        }
+        self.last_tool_call = Some(key);
+        Some(chunk)
+    }
+}
+
+/// Drops whitespace-only lines at the start, keeping the first line's indent
+fn trim_leading_blank_lines(text: &str) -> &str {
+    let content_start = text.len() - text.trim_start().len();
+    match text[..content_start].rfind('\n') {
+        Some(newline) => &text[newline + 1..],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn text(delta: &str) -> StreamChunk {
+        StreamChunk { delta: delta.to_string(), ..Default::default() }
+    }
+
+    fn tool_call(id: &str, path: &str) -> StreamChunk {
+        let call = json!({ "type": "tool_use", "id": id, "name": "read_file", "input": { "path": path } });
+        StreamChunk { delta: call.to_string(), is_tool_use: true, ..Default::default() }
+    }
+
+    #[test]
+    fn test_leading_blank_lines_and_marker_removed() {
+        let mut cleaner = ResponseCleaner::new();
+        let out: String = ["\n\n", "(no content)", "\n \n  Hello", "\n\nworld (no content)"]
+            .into_iter()
+            .filter_map(|delta| cleaner.clean(text(delta)))
+            .map(|chunk| chunk.delta)
+            .collect();
+
+        assert_eq!(out, "  Hello\n\nworld ");
+    }
+
+    #[test]
+    fn test_echoed_tool_call_dropped() {
+        let mut cleaner = ResponseCleaner::new();
+        assert!(cleaner.clean(tool_call("call_a", "a.rs")).is_some());
+        assert!(cleaner.clean(tool_call("call_b", "a.rs")).is_none());
+        assert!(cleaner.clean(tool_call("call_c", "b.rs")).is_some());
+
+        // The same call again after text is a new call, not an echo
+        assert!(cleaner.clean(text("Now again:")).is_some());
+        assert!(cleaner.clean(tool_call("call_d", "b.rs")).is_some());
+    }
+}
@@ -0,0 +1,121 @@
+//! Response caching for repeated deterministic requests
+//!
+//! CI and eval loops often send the same `temperature: 0` request over and
+//! over. A completed response is kept, as the chunks it streamed, and served
+//! again for an identical request instead of spending quota upstream.
+//!
+//! Entries are keyed by a hash of the model, contents, system instruction,
+//! tools and generation config of the request body (not its session or
+//! project), and the least recently used one is evicted at capacity.
+
+use crate::antigravity::StreamChunk;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Cached responses shared by all clients
+pub struct ResponseCache {
+    /// Most responses kept at once
+    capacity: usize,
+    /// Whether streaming requests are answered from the cache too
+    replay_streams: bool,
+    entries: Mutex<LruEntries>,
+}
+
+#[derive(Default)]
+struct LruEntries {
+    chunks: HashMap<u64, Vec<StreamChunk>>,
+    /// Keys from least to most recently used
+    order: VecDeque<u64>,
+}
+
+impl LruEntries {
+    fn touch(&mut self, key: u64) {
+        self.order.retain(|k| *k != key);
+        self.order.push_back(key);
+    }
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, replay_streams: bool) -> Self {
+        Self {
+            capacity,
+            replay_streams,
+            entries: Mutex::new(LruEntries::default()),
+        }
+    }
+
+    /// Whether streaming requests may be replayed from the cache
+    pub fn replays_streams(&self) -> bool {
+        self.replay_streams
+    }
+
+    /// Key for a Gemini request body
+    pub fn key(body: &Value) -> u64 {
+        let request = &body["request"];
+        let mut hasher = DefaultHasher::new();
+        for part in [
+            &body["model"],
+            &request["contents"],
+            &request["systemInstruction"],
+            &request["tools"],
+            &request["toolConfig"],
+            &request["generationConfig"],
+        ] {
+            part.to_string().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// The chunks of a cached response, marking it recently used
+    pub fn get(&self, key: u64) -> Option<Vec<StreamChunk>> {
+        let mut entries = self.entries.lock().unwrap();
+        let chunks = entries.chunks.get(&key)?.clone();
+        entries.touch(key);
+        Some(chunks)
+    }
+
+    /// Stores a completed response, evicting the least recently used ones
+    pub fn insert(&self, key: u64, chunks: Vec<StreamChunk>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.chunks.insert(key, chunks);
+        entries.touch(key);
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.chunks.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_entry_evicted() {
+        let cache = ResponseCache::new(2, false);
+        let chunks = |text: &str| vec![StreamChunk { delta: text.into(), ..Default::default() }];
+        cache.insert(1, chunks("one"));
+        cache.insert(2, chunks("two"));
+
+        // Reading 1 makes 2 the oldest
+        assert_eq!(cache.get(1).unwrap()[0].delta, "one");
+        cache.insert(3, chunks("three"));
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some() && cache.get(3).is_some());
+
+        // Only the request content counts towards the key
+        let body = |session: &str| serde_json::json!({
+            "model": "gemini-3-flash",
+            "project": "p",
+            "request": { "contents": [{ "parts": [{ "text": "hi" }] }], "sessionId": session }
+        });
+        assert_eq!(ResponseCache::key(&body("a")), ResponseCache::key(&body("b")));
+    }
+}
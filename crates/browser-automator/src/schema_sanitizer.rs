@@ -0,0 +1,149 @@
+//! Tool Schema Sanitizer
+//!
+//! Antigravity rejects function declarations whose parameter schemas use
+//! JSON-schema features outside its OpenAPI subset. Both the route-level
+//! Anthropic tool converter and `AntigravityClient` run schemas through
+//! [`sanitize_schema`] so every path sends the same thing:
+//!
+//! 1. `$ref`/`$defs`/`definitions` are dropped (references aren't resolved upstream)
+//! 2. Validation and metadata keywords (`format`, `minimum`, `title`, ...) are dropped
+//! 3. `const` becomes a single-value `enum`
+//! 4. `properties`, `items` and `allOf`/`anyOf`/`oneOf` are walked recursively
+
+use serde_json::{json, Value};
+
+/// Keywords removed from every schema object
+pub const FORBIDDEN_KEYS: &[&str] = &[
+    "$schema", "$id", "$ref", "$defs", "definitions",
+    "default", "title", "examples",
+    "minLength", "maxLength", "pattern", "format",
+    "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "multipleOf",
+    "minItems", "maxItems", "uniqueItems",
+    "minProperties", "maxProperties", "propertyNames",
+    "contentMediaType", "contentEncoding",
+    "additionalProperties", // Often strict in Gemini
+];
+
+/// Recursively sanitizes a JSON schema in place
+pub fn sanitize_schema(schema: &mut Value) {
+    let Some(obj) = schema.as_object_mut() else {
+        return;
+    };
+
+    for key in FORBIDDEN_KEYS {
+        obj.remove(*key);
+    }
+
+    // Transform strict `const` to `enum`
+    if let Some(const_val) = obj.remove("const") {
+        obj.insert("enum".to_string(), json!([const_val]));
+    }
+
+    if let Some(props) = obj.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        for (_, value) in props.iter_mut() {
+            sanitize_schema(value);
+        }
+    }
+
+    if let Some(items) = obj.get_mut("items") {
+        sanitize_schema(items);
+    }
+
+    for key in ["allOf", "anyOf", "oneOf"] {
+        if let Some(arr) = obj.get_mut(key).and_then(|v| v.as_array_mut()) {
+            for sub_schema in arr {
+                sanitize_schema(sub_schema);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(mut schema: Value) -> Value {
+        sanitize_schema(&mut schema);
+        schema
+    }
+
+    #[test]
+    fn test_every_forbidden_key_removed() {
+        let mut schema = json!({ "type": "string", "description": "kept" });
+        for key in FORBIDDEN_KEYS {
+            schema[*key] = json!("x");
+        }
+
+        assert_eq!(sanitized(schema), json!({ "type": "string", "description": "kept" }));
+    }
+
+    #[test]
+    fn test_const_becomes_enum() {
+        let schema = sanitized(json!({ "type": "string", "const": "fixed" }));
+        assert_eq!(schema, json!({ "type": "string", "enum": ["fixed"] }));
+    }
+
+    #[test]
+    fn test_nested_properties_and_items() {
+        let schema = sanitized(json!({
+            "type": "object",
+            "properties": {
+                "user": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {
+                        "email": { "type": "string", "format": "email", "maxLength": 254 }
+                    }
+                },
+                "ids": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": { "type": "integer", "minimum": 0, "$ref": "#/$defs/Id" }
+                }
+            }
+        }));
+
+        assert_eq!(schema, json!({
+            "type": "object",
+            "properties": {
+                "user": {
+                    "type": "object",
+                    "properties": { "email": { "type": "string" } }
+                },
+                "ids": { "type": "array", "items": { "type": "integer" } }
+            }
+        }));
+    }
+
+    #[test]
+    fn test_combinators_recursed() {
+        let schema = sanitized(json!({
+            "allOf": [{ "type": "object", "title": "Base" }],
+            "anyOf": [{ "type": "string", "pattern": "^a" }, { "const": 1 }],
+            "oneOf": [{ "type": "array", "items": { "default": 0, "type": "number" } }]
+        }));
+
+        assert_eq!(schema, json!({
+            "allOf": [{ "type": "object" }],
+            "anyOf": [{ "type": "string" }, { "enum": [1] }],
+            "oneOf": [{ "type": "array", "items": { "type": "number" } }]
+        }));
+    }
+
+    #[test]
+    fn test_property_named_like_keyword_kept() {
+        // Only schema keywords are stripped, not user properties with the same name
+        let schema = sanitized(json!({
+            "type": "object",
+            "properties": { "format": { "type": "string", "format": "date" } }
+        }));
+
+        assert_eq!(schema["properties"]["format"], json!({ "type": "string" }));
+    }
+
+    #[test]
+    fn test_non_object_schema_untouched() {
+        assert_eq!(sanitized(json!(true)), json!(true));
+        assert_eq!(sanitized(Value::Null), Value::Null);
+    }
+}
@@ -0,0 +1,105 @@
+//! Server-Sent Events Line Parser
+//!
+//! Incremental parser for the `text/event-stream` format used by
+//! `streamGenerateContent?alt=sse`. Follows the WHATWG event-stream rules:
+//!
+//! 1. `data:` with or without a single leading space
+//! 2. Multi-line `data:` fields are joined with `\n` until a blank line
+//! 3. `event:`, `id:`, `retry:` and `:` comment lines are ignored
+//!
+//! Some backends emit bare JSON lines without any `data:` prefix; those are
+//! dispatched immediately as their own event.
+
+/// Accumulates `data:` lines and dispatches complete events
+#[derive(Debug, Default)]
+pub struct SseParser {
+    data: Vec<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one line (without its `\n`) and returns an event payload if the
+    /// line completed one
+    pub fn feed_line(&mut self, line: &str) -> Option<String> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        // Comment line
+        if line.starts_with(':') {
+            return None;
+        }
+
+        // Bare JSON without a data: prefix
+        let trimmed = line.trim();
+        if trimmed.starts_with('{') || trimmed.starts_with('[') {
+            return Some(trimmed.to_string());
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" | "id" | "retry" => {}
+            _ => tracing::debug!("Ignored SSE line: {}", line),
+        }
+
+        None
+    }
+
+    /// Flushes any event left pending when the stream ends without a
+    /// trailing blank line
+    pub fn finish(&mut self) -> Option<String> {
+        self.dispatch()
+    }
+
+    fn dispatch(&mut self) -> Option<String> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let payload = self.data.join("\n");
+        self.data.clear();
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Vec<String> {
+        let mut parser = SseParser::new();
+        let mut events: Vec<String> = input.split('\n').filter_map(|l| parser.feed_line(l)).collect();
+        events.extend(parser.finish());
+        events
+    }
+
+    #[test]
+    fn test_data_without_space() {
+        let events = parse("data:{\"a\":1}\n\ndata: {\"b\":2}\n\n");
+        assert_eq!(events, vec![r#"{"a":1}"#, r#"{"b":2}"#]);
+    }
+
+    #[test]
+    fn test_multi_line_data_frame() {
+        let events = parse("data: {\"text\":\ndata: \"hi\"}\r\n\r\ndata: [DONE]");
+        assert_eq!(events, vec!["{\"text\":\n\"hi\"}", "[DONE]"]);
+    }
+
+    #[test]
+    fn test_event_id_and_comment_lines_ignored() {
+        let events = parse(": keep-alive\nevent: message\nid: 7\nretry: 1000\ndata: {}\n\n");
+        assert_eq!(events, vec!["{}"]);
+
+        // Bare JSON lines are still accepted
+        assert_eq!(parse("{\"c\":3}\n"), vec![r#"{"c":3}"#]);
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
 use directories::ProjectDirs;
 
@@ -11,6 +11,386 @@ pub struct Config {
     pub accounts: HashMap<String, Account>,
     pub providers: HashMap<String, ProviderConfig>,
     pub server: ServerConfig,
+    /// Allow substituting a fallback model (e.g. Claude -> Gemini) when the
+    /// requested one is rate limited. When off, requests queue or return 429.
+    #[serde(default = "default_true")]
+    pub enable_spoofing: bool,
+    /// Allow switching models up front (Strategy 0) when every account is
+    /// already limited for the requested model. Requires `enable_spoofing`.
+    #[serde(default = "default_true")]
+    pub enable_preemptive_spoof: bool,
+    /// Gemini 3 Pro tier ("low" or "high") used when a request sets no thinking level
+    #[serde(default = "default_gemini_pro_tier")]
+    pub gemini_pro_default_tier: String,
+    /// Strict Anthropic passthrough: keep signed thinking blocks in history
+    /// when the request is actually served by Claude (stripped for Gemini)
+    #[serde(default)]
+    pub strict_anthropic_passthrough: bool,
+    /// If set, a streamed request whose model produces no first token within
+    /// this many milliseconds is retried with the faster fallback model
+    #[serde(default)]
+    pub first_token_timeout_ms: Option<u64>,
+    /// Prefer the same account for every request in a conversation, keyed by
+    /// `metadata.conversation_id`/`session_id` or the system prompt
+    #[serde(default)]
+    pub conversation_affinity: bool,
+    /// Sampling/thinking defaults per model family (client values still win)
+    #[serde(default)]
+    pub defaults: ModelDefaults,
+    /// Return thought summaries when thinking is enabled, unless the request
+    /// sets `include_thoughts` itself
+    #[serde(default = "default_true")]
+    pub include_thoughts: bool,
+    /// Whether `X-Goog-Session-Id` is fixed per client or fresh per request
+    #[serde(default)]
+    pub session_id_policy: SessionIdPolicy,
+    /// Strip Gemini artifacts (leading blank lines, `(no content)` markers,
+    /// echoed tool calls) from responses
+    #[serde(default = "default_true")]
+    pub clean_responses: bool,
+    /// Models exposed to clients; all are available when unset
+    #[serde(default)]
+    pub enabled_models: Option<Vec<String>>,
+    /// Spacing of requests on accounts that keep hitting 429s
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    /// Percentage of the context window above which responses carry an
+    /// `x-aether-context-usage` warning
+    #[serde(default = "default_context_warning_percent")]
+    pub context_warning_percent: u32,
+    /// Model used when a request doesn't name one
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// If set, a stream request denied with `IAM_PERMISSION_DENIED` is retried
+    /// once after this many milliseconds, for APIs that were just enabled
+    #[serde(default)]
+    pub permission_retry_delay_ms: Option<u64>,
+    /// Overrides for the client identification headers sent upstream
+    #[serde(default)]
+    pub client_headers: ClientHeaders,
+    /// Background refresh of access tokens before they expire
+    #[serde(default)]
+    pub token_warming: TokenWarmingConfig,
+    /// Longest a request queues for a rate-limited family before getting a 429
+    #[serde(default)]
+    pub max_wait: MaxWaitConfig,
+    /// Budget boundaries used to turn an Anthropic thinking budget into a
+    /// Gemini thinking level
+    #[serde(default)]
+    pub thinking_thresholds: ThinkingThresholds,
+    /// Output quirks for the client in use
+    #[serde(default)]
+    pub client_profile: ClientProfile,
+    /// Open streamed answers with the "AetherBridge System Log" account
+    /// selection status; waits and fallbacks are reported either way
+    #[serde(default)]
+    pub show_system_log: bool,
+    /// Upstream timeout for a generation request, in seconds
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Largest timeout a client may ask for with `x-aether-timeout-secs`
+    #[serde(default = "default_request_timeout_secs")]
+    pub max_request_timeout_secs: u64,
+    /// End a stream that fails after producing content with
+    /// `stop_reason: "error"` instead of an `error` event, so clients keep
+    /// the partial answer
+    #[serde(default)]
+    pub preserve_partial_on_error: bool,
+    /// End a stream that produced no content at all (even after a fallback
+    /// retry) with an `error` event instead of a diagnostic note
+    #[serde(default)]
+    pub empty_response_error: bool,
+    /// Gemini context caching of large system prompts marked `cache_control`
+    #[serde(default)]
+    pub context_cache: ContextCacheConfig,
+    /// Caching of repeated deterministic (`temperature: 0`) responses
+    #[serde(default)]
+    pub response_cache: ResponseCacheConfig,
+    /// Requests go to the fastest healthy Antigravity endpoint; every this
+    /// many requests one re-measures another endpoint instead (0 never does)
+    #[serde(default = "default_endpoint_probe_interval")]
+    pub endpoint_probe_interval: u64,
+    /// After rotation hands out an account, try the others first for this
+    /// many milliseconds (even lower-priority ones); 0 disables it
+    #[serde(default)]
+    pub account_cooldown_ms: u64,
+    /// Most upstream requests one account runs at a time; further requests
+    /// routed to it wait for a free slot (unset = unlimited)
+    #[serde(default)]
+    pub max_concurrent_per_account: Option<usize>,
+    /// Rewrites applied, in order, to every request body before conversion
+    #[serde(default)]
+    pub transformers: Vec<Transformer>,
+    /// Same-family models to step down through when one is rate limited,
+    /// most capable first (e.g. `["claude-opus-4-5-thinking",
+    /// "claude-sonnet-4-5-thinking"]`); tried before switching to Gemini
+    #[serde(default)]
+    pub downgrade_chain: Vec<String>,
+    /// Overrides of the cross-family spoof model, by model id (e.g.
+    /// `{"gemini-3-pro": "claude-opus-4-5-thinking"}`); an empty or unknown
+    /// target disables the fallback for that model
+    #[serde(default)]
+    pub spoof_map: HashMap<String, String>,
+    /// Antigravity model serving an Anthropic model id, by exact id (e.g.
+    /// `{"claude-3-opus-20240229": "claude-sonnet-4-5"}`); takes precedence
+    /// over the built-in table
+    #[serde(default)]
+    pub anthropic_model_map: HashMap<String, String>,
+    /// Key clients must send (`Authorization: Bearer` or `x-api-key`); none
+    /// means the API is open to anyone who can reach the port
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Allow binding beyond loopback without an `api_key` (only warns)
+    #[serde(default)]
+    pub allow_lan: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_context_warning_percent() -> u32 {
+    80
+}
+
+fn default_request_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_endpoint_probe_interval() -> u64 {
+    20
+}
+
+fn default_gemini_pro_tier() -> String {
+    "low".to_string()
+}
+
+/// How often a new `X-Goog-Session-Id` is generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionIdPolicy {
+    /// One session id for the lifetime of each client; clients are pooled
+    /// per account, so every request through an account shares it
+    PerClient,
+    /// A fresh session id on every streaming request
+    #[default]
+    PerRequest,
+}
+
+/// A built-in rewrite of incoming request bodies, e.g.
+/// `{"type": "strip_tool_by_name", "name": "web_search"}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transformer {
+    /// Adds `tool` (in the client's own tool format) unless the request
+    /// already has a tool with the same name
+    ForceTool { tool: serde_json::Value },
+    /// Removes every tool called `name`
+    StripToolByName { name: String },
+    /// Puts `text` ahead of the request's system instruction
+    InjectSystem { text: String },
+}
+
+/// Output adjustments for clients with particular expectations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientProfile {
+    /// Thinking as inline text, status log shown, no extra headers
+    #[default]
+    Generic,
+    /// Thinking as native `thinking` blocks, `x-request-id` on responses
+    ClaudeCode,
+    /// Inline thinking without the status log, `x-request-id` on responses
+    OpenCode,
+    /// Generic, but without the status log
+    NoSystemLog,
+}
+
+impl ClientProfile {
+    /// Stream thinking as `thinking` content blocks instead of marked-up text
+    pub fn thinking_blocks(self) -> bool {
+        matches!(self, Self::ClaudeCode)
+    }
+
+    /// Emit the "AetherBridge System Log" status text in streamed answers
+    pub fn system_log(self) -> bool {
+        !matches!(self, Self::OpenCode | Self::NoSystemLog)
+    }
+
+    /// Add an `x-request-id` header to every response
+    pub fn request_id_header(self) -> bool {
+        matches!(self, Self::ClaudeCode | Self::OpenCode)
+    }
+}
+
+/// Adaptive throttling of accounts that keep hitting rate limits
+///
+/// After `threshold` 429s within `window_secs`, requests on that account are
+/// spaced at least `base_delay_ms` apart, doubling per further 429 up to
+/// `max_delay_ms`. A `threshold` of 0 disables throttling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub threshold: usize,
+    pub window_secs: u64,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            window_secs: 300,
+            base_delay_ms: 2000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+/// Background token refresh (off by default)
+///
+/// From server start and then every `interval_secs`, tokens that would
+/// expire before the next pass are refreshed, `stagger_ms` apart so the
+/// accounts' expiries drift apart instead of all lapsing together after an
+/// idle period.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenWarmingConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub stagger_ms: u64,
+}
+
+impl Default for TokenWarmingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 300,
+            stagger_ms: 2000,
+        }
+    }
+}
+
+/// Context caching of system prompts (off by default)
+///
+/// A system prompt the client marked with `cache_control` that is at least
+/// `min_tokens` long (estimated) is uploaded once as cached content and
+/// referenced by later requests, for up to `ttl_secs`. Gemini models only.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextCacheConfig {
+    pub enabled: bool,
+    pub min_tokens: u32,
+    pub ttl_secs: u64,
+}
+
+impl Default for ContextCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_tokens: 4096,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+/// Response caching of deterministic requests (off by default)
+///
+/// A completed `temperature: 0` response is kept and served again for an
+/// identical request (same model, messages, tools and generation config),
+/// up to `capacity` responses. Streaming requests are only answered from
+/// the cache with `replay_streams`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponseCacheConfig {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub replay_streams: bool,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: 256,
+            replay_streams: false,
+        }
+    }
+}
+
+/// Replacement values for the headers that identify the Antigravity client
+///
+/// Lets a stale built-in client version be bumped without recompiling. Unset
+/// fields keep the fingerprint's value. Only the Antigravity header style is
+/// affected; the Gemini CLI style keeps its own identity.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientHeaders {
+    pub user_agent: Option<String>,
+    pub api_client: Option<String>,
+    pub client_metadata: Option<String>,
+}
+
+/// Longest wait, in seconds, a request will queue for a rate limit to reset
+///
+/// Claude and Gemini quotas reset on different schedules, so each family has
+/// its own cap. A wait longer than the cap returns 429 straight away.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaxWaitConfig {
+    pub claude: u64,
+    pub gemini: u64,
+}
+
+impl Default for MaxWaitConfig {
+    fn default() -> Self {
+        Self {
+            claude: 600,
+            gemini: 600,
+        }
+    }
+}
+
+/// Where an Anthropic `budget_tokens` value switches Gemini thinking level
+///
+/// Budgets below `medium` map to "low", below `high` to "medium", and
+/// anything else to "high".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThinkingThresholds {
+    pub medium: u32,
+    pub high: u32,
+}
+
+impl Default for ThinkingThresholds {
+    fn default() -> Self {
+        Self {
+            medium: 5000,
+            high: 15000,
+        }
+    }
+}
+
+/// Per-family generation defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelDefaults {
+    #[serde(default)]
+    pub claude: FamilyDefaults,
+    #[serde(default)]
+    pub gemini: FamilyDefaults,
+}
+
+/// Defaults applied when a request leaves a setting unspecified
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FamilyDefaults {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Thinking budget in tokens (Claude)
+    pub thinking_budget: Option<u32>,
+    /// Thinking level (Gemini; Gemini 3 Pro uses `gemini_pro_default_tier`)
+    pub thinking_level: Option<String>,
+    /// Ceiling for the temperature sent to thinking models
+    pub max_temperature: Option<f32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,11 +430,52 @@ impl Default for Config {
                 port: 8080,
                 browser_profile_path: None,
             },
+            enable_spoofing: true,
+            enable_preemptive_spoof: true,
+            gemini_pro_default_tier: default_gemini_pro_tier(),
+            strict_anthropic_passthrough: false,
+            first_token_timeout_ms: None,
+            conversation_affinity: false,
+            defaults: ModelDefaults::default(),
+            include_thoughts: true,
+            session_id_policy: SessionIdPolicy::default(),
+            clean_responses: true,
+            enabled_models: None,
+            throttle: ThrottleConfig::default(),
+            context_warning_percent: default_context_warning_percent(),
+            default_model: None,
+            max_wait: MaxWaitConfig::default(),
+            permission_retry_delay_ms: None,
+            client_headers: ClientHeaders::default(),
+            token_warming: TokenWarmingConfig::default(),
+            thinking_thresholds: ThinkingThresholds::default(),
+            client_profile: ClientProfile::default(),
+            show_system_log: false,
+            request_timeout_secs: default_request_timeout_secs(),
+            max_request_timeout_secs: default_request_timeout_secs(),
+            preserve_partial_on_error: false,
+            empty_response_error: false,
+            context_cache: ContextCacheConfig::default(),
+            response_cache: ResponseCacheConfig::default(),
+            endpoint_probe_interval: default_endpoint_probe_interval(),
+            account_cooldown_ms: 0,
+            max_concurrent_per_account: None,
+            transformers: Vec::new(),
+            downgrade_chain: Vec::new(),
+            spoof_map: HashMap::new(),
+            anthropic_model_map: HashMap::new(),
+            api_key: None,
+            allow_lan: false,
         }
     }
 }
 
 impl Config {
+    /// Whether requests may be switched to a fallback model before being tried
+    pub fn preemptive_spoof_enabled(&self) -> bool {
+        self.enable_spoofing && self.enable_preemptive_spoof
+    }
+
     /// Get the configuration directory path
     pub fn get_config_dir() -> PathBuf {
         if let Some(proj_dirs) = ProjectDirs::from("com", "Brian-Zavala", "aether-bridge") {
@@ -71,7 +492,11 @@ impl Config {
 
     /// Load configuration from disk
     pub fn load() -> Result<Self> {
-        let path = Self::get_config_path();
+        Self::load_from(&Self::get_config_path())
+    }
+
+    /// Load configuration from a specific file, or defaults if it doesn't exist
+    pub fn load_from(path: &Path) -> Result<Self> {
         if path.exists() {
             let content = fs::read_to_string(path)?;
             let config: Config = serde_json::from_str(&content)?;
@@ -83,7 +508,11 @@ impl Config {
 
     /// Save configuration to disk
     pub fn save(&self) -> Result<()> {
-        let path = Self::get_config_path();
+        self.save_to(&Self::get_config_path())
+    }
+
+    /// Save configuration to a specific file
+    pub fn save_to(&self, path: &Path) -> Result<()> {
         if let Some(dir) = path.parent() {
             fs::create_dir_all(dir)?;
         }
@@ -91,4 +520,56 @@ impl Config {
         fs::write(path, content)?;
         Ok(())
     }
+
+    /// Loads the config at `path`, detecting the browser profile on first run
+    ///
+    /// `detect` only runs when no profile path is stored yet (or `redetect`
+    /// is set); a newly found path is saved back so later starts skip the scan.
+    pub fn load_with_browser_profile(
+        path: &Path,
+        redetect: bool,
+        detect: impl FnOnce() -> Option<PathBuf>,
+    ) -> Result<Self> {
+        let mut config = Self::load_from(path)?;
+        if config.server.browser_profile_path.is_some() && !redetect {
+            return Ok(config);
+        }
+
+        let detected = detect().map(|p| p.to_string_lossy().to_string());
+        if detected.is_some() && detected != config.server.browser_profile_path {
+            config.server.browser_profile_path = detected;
+            config.save_to(path)?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_detected_browser_profile_is_persisted() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.json");
+        let scans = Cell::new(0);
+        let detect = || {
+            scans.set(scans.get() + 1);
+            Some(PathBuf::from("/profiles/chrome"))
+        };
+
+        let config = Config::load_with_browser_profile(&path, false, detect).unwrap();
+        assert_eq!(config.server.browser_profile_path.as_deref(), Some("/profiles/chrome"));
+        assert_eq!(Config::load_from(&path).unwrap().server.browser_profile_path.as_deref(), Some("/profiles/chrome"));
+
+        // Stored now, so the next start doesn't scan again
+        Config::load_with_browser_profile(&path, false, detect).unwrap();
+        assert_eq!(scans.get(), 1);
+
+        // --redetect forces a scan
+        Config::load_with_browser_profile(&path, true, detect).unwrap();
+        assert_eq!(scans.get(), 2);
+    }
 }
@@ -4,25 +4,147 @@
 //! - Tracks rate limit status per account per model family (Claude vs Gemini)
 //! - Automatically rotates to available accounts when one is rate-limited
 //! - Refreshes access tokens as needed
+//! - Spaces out requests on accounts that keep hitting 429s
 //! - Persists account state to disk
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use chrono::{DateTime, Timelike, Utc};
 use tracing::{info, warn, debug, error};
 use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
 
-use crate::storage::{TokenStorage, StoredAccount, StoredAccounts};
+use crate::storage::{bootstrap_accounts_from_env, AccountStore, TokenStorage, StoredAccount, StoredAccounts};
 use crate::tokens::{TokenPair, refresh_access_token};
 
+/// Number of recent rate-limit events kept for diagnostics
+const RATE_LIMIT_HISTORY_CAPACITY: usize = 50;
+
+/// Maximum number of remembered conversation → account pairings
+const AFFINITY_CAPACITY: usize = 1024;
+
+/// Upper bound for backoff applied to consecutive rate limits (30 minutes)
+const MAX_RATE_LIMIT_BACKOFF_SECS: u64 = 30 * 60;
+
+/// Observed quota resets kept per account for learning its daily window
+const RESET_HISTORY_CAPACITY: usize = 16;
+
+/// How far apart (in time of day) two resets may be and still count as the
+/// same daily window (30 minutes)
+const RESET_MATCH_TOLERANCE_SECS: i64 = 30 * 60;
+
+/// Days a reset must have been seen on, at about the same time of day,
+/// before it is trusted as the account's daily window
+const MIN_RESET_DAYS: usize = 3;
+
+/// Shortest run of rate limits taken for a used-up daily quota (1 hour);
+/// shorter ones are per-minute or capacity limits and say nothing about it
+const MIN_QUOTA_LIMIT_SECS: i64 = 60 * 60;
+
+/// A limit that lapsed this long before the next one (5 minutes) ended its
+/// run, even without a success in between
+const LIMIT_RUN_GAP_SECS: i64 = 5 * 60;
+
+/// Calculates exponential backoff with jitter
+/// base_seconds: initial retry duration
+/// attempt: retry attempt number (0-indexed)
+/// max_seconds: maximum retry duration
+/// Returns: duration to wait in seconds
+pub fn exponential_backoff_with_jitter(base_seconds: u64, attempt: u32, max_seconds: u64) -> u64 {
+    use rand::Rng;
+    
+    // Exponential backoff: base * 2^attempt
+    let exponential = base_seconds.saturating_mul(2_u64.saturating_pow(attempt));
+    
+    // Cap at max
+    let capped = exponential.min(max_seconds);
+    
+    // Add jitter (up to 25% extra, so limited accounts don't all retry at once)
+    let jitter_range = capped / 4;
+    let jitter = if jitter_range > 0 {
+        rand::thread_rng().gen_range(0..=jitter_range)
+    } else {
+        0
+    };
+    
+    capped + jitter
+}
+
+/// Adaptive per-account throttling driven by recent 429s
+///
+/// Once an account collects `threshold` rate limits within `window`, its
+/// requests are spaced at least `base_delay` apart, doubling for each further
+/// 429 in the window (capped at `max_delay`). The delay lapses as the 429s
+/// age out of the window. A `threshold` of 0 disables throttling.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    pub threshold: usize,
+    pub window: Duration,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ThrottlePolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            window: Duration::from_secs(5 * 60),
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ThrottlePolicy {
+    /// Minimum spacing between requests given the 429s in the window
+    fn delay_for(&self, recent_limits: usize) -> Duration {
+        if self.threshold == 0 || recent_limits < self.threshold {
+            return Duration::ZERO;
+        }
+        let doublings = (recent_limits - self.threshold).min(16) as u32;
+        self.base_delay.saturating_mul(1 << doublings).min(self.max_delay)
+    }
+}
+
+/// Throttle state for one account
+#[derive(Debug, Default)]
+struct AccountThrottle {
+    /// When recent rate limits were recorded, oldest first
+    recent_limits: VecDeque<DateTime<Utc>>,
+    /// Earliest time the next request may be sent
+    next_allowed: Option<DateTime<Utc>>,
+}
+
+impl AccountThrottle {
+    /// Drops rate limits that fell out of the window, returning how many remain
+    fn recent_count(&mut self, now: DateTime<Utc>, window: Duration) -> usize {
+        let cutoff = now - chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX);
+        while self.recent_limits.front().is_some_and(|at| *at < cutoff) {
+            self.recent_limits.pop_front();
+        }
+        self.recent_limits.len()
+    }
+}
+
 /// Model family for per-family rate limit tracking
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+///
+/// Written in lowercase, as the admin API takes it; the capitalised names
+/// older account files were saved with are still read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ModelFamily {
     /// Claude models (Sonnet, Opus)
+    #[serde(alias = "Claude")]
     Claude,
     /// Gemini models (Pro, Flash)
+    #[serde(alias = "Gemini")]
     Gemini,
+    /// Anything else (aliases, other providers); limited as its own pool
+    #[serde(alias = "Unknown")]
+    Unknown,
 }
 
 impl ModelFamily {
@@ -31,9 +153,19 @@ impl ModelFamily {
         let lower = model_id.to_lowercase();
         if lower.contains("claude") {
             ModelFamily::Claude
-        } else {
-            // Default to Gemini for any non-Claude model
+        } else if lower.contains("gemini") {
             ModelFamily::Gemini
+        } else {
+            ModelFamily::Unknown
+        }
+    }
+
+    /// Parses a family name (`claude` or `gemini`, case-insensitive)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "claude" => Some(ModelFamily::Claude),
+            "gemini" => Some(ModelFamily::Gemini),
+            _ => None,
         }
     }
 }
@@ -55,6 +187,18 @@ pub struct Account {
 
     /// Refresh token for obtaining new access tokens
     pub refresh_token: String,
+
+    /// Taken out of rotation by the user (persisted)
+    pub disabled: bool,
+
+    /// Selection priority; lower numbers are preferred (persisted)
+    pub priority: i32,
+
+    /// The last token refresh failed (e.g. revoked); the user needs to log in again
+    pub refresh_failed: bool,
+
+    /// Model families this account is dedicated to; empty serves all (persisted)
+    pub model_families: Vec<ModelFamily>,
 }
 
 impl Account {
@@ -62,6 +206,78 @@ impl Account {
     pub fn needs_refresh(&self) -> bool {
         Utc::now() + chrono::Duration::minutes(5) >= self.expires_at
     }
+
+    /// Whether the access token expires within `window`
+    pub fn expires_within(&self, window: Duration) -> bool {
+        let window = chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX);
+        Utc::now() + window >= self.expires_at
+    }
+
+    /// Whether the account may be used for a model family
+    pub fn serves(&self, family: ModelFamily) -> bool {
+        self.model_families.is_empty() || self.model_families.contains(&family)
+    }
+}
+
+/// Why no account could be selected for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionFailure {
+    /// No accounts are configured at all
+    NoAccounts,
+    /// Every account that could serve the model is disabled (or dedicated to
+    /// the other family)
+    AllDisabled,
+    /// Every usable account failed its last token refresh; the user has to
+    /// log in again
+    AllRefreshFailed,
+    /// Some account is usable in principle but not right now (rate limited)
+    Unavailable,
+}
+
+/// A recorded `mark_rate_limited` call, kept for debugging quota issues
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitEvent {
+    /// Index of the limited account
+    pub account_index: usize,
+    /// Email of the limited account (empty if the index is unknown)
+    pub email: String,
+    /// Model family that was limited
+    pub family: ModelFamily,
+    /// When the limit was recorded
+    pub at: DateTime<Utc>,
+    /// When the limit expires
+    pub until: DateTime<Utc>,
+    /// Seconds between `at` and `until`
+    pub retry_after_secs: i64,
+    /// Consecutive limits for this account and family, including this one
+    pub consecutive_count: u32,
+}
+
+/// Snapshot of an account's rate-limit state for status reporting
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountStatus {
+    /// Index in the accounts list
+    pub index: usize,
+    /// Email address
+    pub email: String,
+    /// Whether the account is taken out of rotation
+    pub disabled: bool,
+    /// Whether the last token refresh failed (dead until re-login)
+    pub refresh_failed: bool,
+    /// Selection priority (lower is preferred)
+    pub priority: i32,
+    /// Model families the account is dedicated to (empty serves all)
+    pub model_families: Vec<ModelFamily>,
+    /// When the Claude limit expires, if currently limited
+    pub claude_limited_until: Option<DateTime<Utc>>,
+    /// When the Gemini limit expires, if currently limited
+    pub gemini_limited_until: Option<DateTime<Utc>>,
+    /// When the limit on models of no known family expires, if currently limited
+    pub unknown_limited_until: Option<DateTime<Utc>>,
+    /// Rate limits within the throttle window
+    pub recent_rate_limits: usize,
+    /// Minimum spacing currently imposed between requests (0 if not throttled)
+    pub throttle_delay_ms: u64,
 }
 
 /// Rate limit tracking for an account per model family
@@ -72,6 +288,9 @@ struct RateLimitInfo {
 
     /// Number of consecutive rate limits
     consecutive_count: u32,
+
+    /// When this run of back-to-back limits started
+    since: DateTime<Utc>,
 }
 
 /// Per-model-family rate limit tracking for an account
@@ -81,6 +300,8 @@ struct AccountRateLimits {
     claude: Option<RateLimitInfo>,
     /// Rate limit info for Gemini models
     gemini: Option<RateLimitInfo>,
+    /// Rate limit info for models of no known family
+    unknown: Option<RateLimitInfo>,
 }
 
 impl AccountRateLimits {
@@ -88,6 +309,7 @@ impl AccountRateLimits {
         Self {
             claude: None,
             gemini: None,
+            unknown: None,
         }
     }
 
@@ -96,6 +318,7 @@ impl AccountRateLimits {
         match family {
             ModelFamily::Claude => &self.claude,
             ModelFamily::Gemini => &self.gemini,
+            ModelFamily::Unknown => &self.unknown,
         }
     }
 
@@ -104,6 +327,7 @@ impl AccountRateLimits {
         match family {
             ModelFamily::Claude => self.claude = Some(info),
             ModelFamily::Gemini => self.gemini = Some(info),
+            ModelFamily::Unknown => self.unknown = Some(info),
         }
     }
 
@@ -112,6 +336,7 @@ impl AccountRateLimits {
         match family {
             ModelFamily::Claude => self.claude = None,
             ModelFamily::Gemini => self.gemini = None,
+            ModelFamily::Unknown => self.unknown = None,
         }
     }
 
@@ -137,15 +362,46 @@ impl AccountRateLimits {
                 earliest = Some(gemini_info.until);
             }
         }
+
+        if let Some(ref unknown_info) = self.unknown {
+            if earliest.map(|e| unknown_info.until < e).unwrap_or(true) {
+                earliest = Some(unknown_info.until);
+            }
+        }
         
         earliest
     }
 }
 
+/// Order in which accounts are tried for selection
+///
+/// Accounts still `cooling` down from their last request go last; otherwise
+/// lower priority numbers come first, and accounts sharing a priority keep
+/// round-robin order starting after `last_used`.
+fn selection_order(accounts: &[Account], last_used: usize, cooling: &HashSet<usize>) -> Vec<usize> {
+    let count = accounts.len();
+    let mut order: Vec<usize> = (0..count).map(|offset| (last_used + offset + 1) % count).collect();
+    order.sort_by_key(|&idx| (cooling.contains(&idx), accounts[idx].priority));
+    order
+}
+
+/// A place at the head of a family's wait queue; dropping it lets the
+/// next waiter in
+pub type QueueTicket = OwnedSemaphorePermit;
+
+/// One of an account's concurrent request slots; dropping it frees the slot
+pub type AccountSlot = OwnedSemaphorePermit;
+
+/// Exchanges a refresh token for new tokens
+type Refresher = Arc<dyn Fn(String) -> BoxFuture<'static, Result<TokenPair>> + Send + Sync>;
+
 /// Manages multiple OAuth accounts with intelligent rotation
-pub struct AccountManager {
+///
+/// Generic over the persistence backend; the JSON file + keyring
+/// `TokenStorage` is used unless another `AccountStore` is supplied.
+pub struct AccountManager<S: AccountStore = TokenStorage> {
     /// Persistent storage (None for empty/uninitialized state)
-    storage: Option<TokenStorage>,
+    storage: Option<S>,
 
     /// Loaded accounts with runtime state
     accounts: Arc<RwLock<Vec<Account>>>,
@@ -156,6 +412,42 @@ pub struct AccountManager {
 
     /// Index of the last used account (for round-robin)
     last_used_index: Arc<RwLock<usize>>,
+
+    /// Most recent rate-limit events, oldest first (bounded)
+    rate_limit_history: Arc<RwLock<VecDeque<RateLimitEvent>>>,
+
+    /// Account index last used for each conversation key
+    affinity: Arc<RwLock<HashMap<String, usize>>>,
+
+    /// When adaptive throttling kicks in
+    throttle_policy: ThrottlePolicy,
+
+    /// Adaptive throttle state per account index
+    throttles: Arc<RwLock<HashMap<usize, AccountThrottle>>>,
+
+    /// Token refresh call, replaceable in tests
+    refresher: Refresher,
+
+    /// Per-family line for requests waiting out a rate limit; the semaphore
+    /// hands out its single permit in arrival order
+    wait_queues: HashMap<ModelFamily, Arc<Semaphore>>,
+
+    /// How long an account is tried after the others once rotation has
+    /// handed it out (zero disables the cooldown)
+    cooldown: Duration,
+
+    /// When rotation last handed out each account index
+    served_at: Arc<RwLock<HashMap<usize, DateTime<Utc>>>>,
+
+    /// When each account index was seen working again after a rate limit,
+    /// oldest first (bounded)
+    observed_resets: Arc<RwLock<HashMap<usize, VecDeque<DateTime<Utc>>>>>,
+
+    /// Most upstream requests one account runs at a time (None = unlimited)
+    max_concurrent: Option<usize>,
+
+    /// Concurrent request slots per account index, created on first use
+    slots: Arc<RwLock<HashMap<usize, Arc<Semaphore>>>>,
 }
 
 impl AccountManager {
@@ -164,11 +456,151 @@ impl AccountManager {
     /// This creates an uninitialized manager that has no accounts and
     /// cannot persist tokens. Use `new()` for full functionality.
     pub fn empty() -> Self {
+        Self::from_parts(None, 0)
+    }
+
+    /// Creates a new AccountManager and loads accounts from the default storage
+    ///
+    /// Accounts given in `AETHER_ACCOUNTS_JSON`/`AETHER_ACCOUNTS_FILE` are
+    /// imported into the storage first, so containers start without an
+    /// interactive login. Like any stored account, each is refreshed on load.
+    pub async fn new() -> Result<Self> {
+        let store = TokenStorage::new()?;
+        if let Some(bootstrap) = bootstrap_accounts_from_env()? {
+            store.import_accounts(&bootstrap)?;
+            info!("Imported {} account(s) from the environment", bootstrap.len());
+        }
+        Self::with_store(store).await
+    }
+}
+
+impl<S: AccountStore> AccountManager<S> {
+    /// Creates an AccountManager backed by `store` and loads its accounts
+    pub async fn with_store(store: S) -> Result<Self> {
+        let stored = store.load_accounts()?;
+        let manager = Self::from_parts(Some(store), stored.active_index);
+
+        // Load and refresh accounts
+        manager.load_accounts_from_storage(&stored).await?;
+
+        Ok(manager)
+    }
+
+    fn from_parts(storage: Option<S>, last_used_index: usize) -> Self {
         Self {
-            storage: None,
+            storage,
             accounts: Arc::new(RwLock::new(vec![])),
             rate_limits: Arc::new(RwLock::new(HashMap::new())),
-            last_used_index: Arc::new(RwLock::new(0)),
+            last_used_index: Arc::new(RwLock::new(last_used_index)),
+            rate_limit_history: Arc::new(RwLock::new(VecDeque::new())),
+            affinity: Arc::new(RwLock::new(HashMap::new())),
+            throttle_policy: ThrottlePolicy::default(),
+            throttles: Arc::new(RwLock::new(HashMap::new())),
+            refresher: Arc::new(|token| Box::pin(async move { refresh_access_token(&token).await })),
+            wait_queues: [ModelFamily::Claude, ModelFamily::Gemini, ModelFamily::Unknown]
+                .into_iter()
+                .map(|family| (family, Arc::new(Semaphore::new(1))))
+                .collect(),
+            cooldown: Duration::ZERO,
+            served_at: Arc::new(RwLock::new(HashMap::new())),
+            observed_resets: Arc::new(RwLock::new(HashMap::new())),
+            max_concurrent: None,
+            slots: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Sets when accounts that keep hitting 429s get throttled
+    pub fn set_throttle_policy(&mut self, policy: ThrottlePolicy) {
+        self.throttle_policy = policy;
+    }
+
+    /// Sets how long a just-used account is deprioritized in rotation
+    pub fn set_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown;
+    }
+
+    /// Caps how many requests each account runs at once (None = unlimited)
+    pub fn set_max_concurrent_per_account(&mut self, max: Option<usize>) {
+        self.max_concurrent = max.filter(|&max| max > 0);
+    }
+
+    /// Waits for a free request slot on an account
+    ///
+    /// Call after selecting an account and hold the slot until the upstream
+    /// request is done. Returns None when concurrency is unlimited.
+    pub async fn acquire_slot(&self, index: usize) -> Option<AccountSlot> {
+        let max = self.max_concurrent?;
+        let slots = self.slots_for(index, max).await;
+        if slots.available_permits() == 0 {
+            debug!("Account {} is at its limit of {} concurrent request(s); waiting", index, max);
+        }
+        Some(slots.acquire_owned().await.expect("account slots are never closed"))
+    }
+
+    /// Takes a free request slot on an account without waiting
+    ///
+    /// `Ok(None)` when concurrency is unlimited, `Err(())` when the account
+    /// is at its limit.
+    async fn try_acquire_slot(&self, index: usize) -> Result<Option<AccountSlot>, ()> {
+        let Some(max) = self.max_concurrent else {
+            return Ok(None);
+        };
+        self.slots_for(index, max).await.try_acquire_owned().map(Some).map_err(|_| ())
+    }
+
+    /// The slot semaphore of an account, created on first use
+    async fn slots_for(&self, index: usize, max: usize) -> Arc<Semaphore> {
+        self.slots.write().await
+            .entry(index)
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone()
+    }
+
+    /// Accounts running as many requests as they may at once
+    async fn busy_accounts(&self) -> HashSet<usize> {
+        if self.max_concurrent.is_none() {
+            return HashSet::new();
+        }
+        self.slots.read().await.iter()
+            .filter(|(_, slots)| slots.available_permits() == 0)
+            .map(|(&idx, _)| idx)
+            .collect()
+    }
+
+    /// Minimum spacing currently imposed between requests on an account
+    pub async fn throttle_delay(&self, index: usize) -> Duration {
+        let mut throttles = self.throttles.write().await;
+        let recent = throttles
+            .get_mut(&index)
+            .map_or(0, |t| t.recent_count(Utc::now(), self.throttle_policy.window));
+        self.throttle_policy.delay_for(recent)
+    }
+
+    /// Waits until a throttled account may send its next request
+    ///
+    /// Call after selecting an account. The slot is reserved before waiting,
+    /// so concurrent requests on the same account queue up behind each other.
+    pub async fn pace(&self, index: usize) {
+        let now = Utc::now();
+        let wait = {
+            let mut throttles = self.throttles.write().await;
+            let Some(throttle) = throttles.get_mut(&index) else {
+                return;
+            };
+            let delay = self.throttle_policy.delay_for(throttle.recent_count(now, self.throttle_policy.window));
+            if delay.is_zero() {
+                throttle.next_allowed = None;
+                return;
+            }
+
+            let send_at = throttle.next_allowed.filter(|at| *at > now).unwrap_or(now);
+            throttle.next_allowed = Some(send_at + chrono::Duration::from_std(delay).unwrap_or_default());
+            (send_at - now).to_std().unwrap_or_default()
+        };
+
+        if !wait.is_zero() {
+            debug!("Throttling account {} for {}ms after frequent rate limits", index, wait.as_millis());
+            tokio::time::sleep(wait).await;
         }
     }
 
@@ -179,58 +611,95 @@ impl AccountManager {
     pub async fn get_available_account_for_model(&self, model_id: &str) -> Option<Account> {
         let family = ModelFamily::from_model_id(model_id);
         let now = Utc::now();
-        let mut accounts = self.accounts.write().await;
-        let rate_limits = self.rate_limits.read().await;
-        let last_used = *self.last_used_index.read().await;
+        let candidates: Vec<usize> = {
+            let accounts = self.accounts.read().await;
+            let rate_limits = self.rate_limits.read().await;
+            let last_used = *self.last_used_index.read().await;
+            let cooling = self.cooling_down(now).await;
+
+            // Lowest priority number first, round-robin within a priority
+            selection_order(&accounts, last_used, &cooling)
+                .into_iter()
+                .filter(|&idx| !accounts[idx].disabled && accounts[idx].serves(family))
+                .filter(|&idx| {
+                    // Check rate limit for this specific model family
+                    let limits = rate_limits.get(&idx).filter(|l| l.is_rate_limited(family, now));
+                    if let Some(account_limits) = limits {
+                        debug!("Account {} is rate-limited for {:?} until {:?}",
+                               accounts[idx].email, family, account_limits.get(family).as_ref().map(|i| i.until));
+                    }
+                    limits.is_none()
+                })
+                .collect()
+        };
 
-        if accounts.is_empty() {
-            return None;
+        self.first_fresh_account(candidates).await
+    }
+
+    /// Accounts handed out by rotation within the last `cooldown`
+    async fn cooling_down(&self, now: DateTime<Utc>) -> HashSet<usize> {
+        if self.cooldown.is_zero() {
+            return HashSet::new();
         }
+        let cutoff = now - chrono::Duration::from_std(self.cooldown).unwrap_or_default();
+        self.served_at.read().await.iter().filter(|(_, at)| **at > cutoff).map(|(idx, _)| *idx).collect()
+    }
 
-        // Start from the account after last used (round-robin)
-        let account_count = accounts.len();
-        for offset in 0..account_count {
-            let idx = (last_used + offset + 1) % account_count;
+    /// Returns the first of `candidates` with a usable access token and
+    /// records it as last used. Expired tokens are refreshed on the way.
+    async fn first_fresh_account(&self, candidates: Vec<usize>) -> Option<Account> {
+        for idx in candidates {
+            let Some(account) = self.fresh_account(idx).await else {
+                continue; // Try next account
+            };
 
-            // Check rate limit for this specific model family
-            if let Some(account_limits) = rate_limits.get(&idx) {
-                if account_limits.is_rate_limited(family, now) {
-                    if let Some(account) = accounts.get(idx) {
-                        debug!("Account {} is rate-limited for {:?} until {:?}", 
-                               account.email, family, account_limits.get(family).as_ref().map(|i| i.until));
-                    }
-                    continue;
-                }
+            // Update last used index
+            *self.last_used_index.write().await = idx;
+            if !self.cooldown.is_zero() {
+                self.served_at.write().await.insert(idx, Utc::now());
             }
+            return Some(account);
+        }
+        None
+    }
 
-            let account = &mut accounts[idx];
-
-            // Refresh if needed
-            if account.needs_refresh() {
-                debug!("Refreshing token for account {}", account.email);
-                match refresh_access_token(&account.refresh_token).await {
-                    Ok(new_tokens) => {
-                        account.access_token = new_tokens.access_token;
-                        account.expires_at = new_tokens.expires_at;
-                        if new_tokens.refresh_token != account.refresh_token {
-                            account.refresh_token = new_tokens.refresh_token;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to refresh token for {}: {}", account.email, e);
-                        continue; // Try next account
-                    }
-                }
+    /// Gets account `idx`, refreshing its access token first if needed
+    ///
+    /// The refresh runs without holding the accounts lock, so a slow token
+    /// endpoint only delays the request that needs this account. Returns
+    /// None if the refresh fails.
+    async fn fresh_account(&self, idx: usize) -> Option<Account> {
+        let refresh_token = {
+            let accounts = self.accounts.read().await;
+            let account = accounts.get(idx)?;
+            if !account.needs_refresh() {
+                return Some(account.clone());
             }
+            debug!("Refreshing token for account {}", account.email);
+            account.refresh_token.clone()
+        };
 
-            // Update last used index
-            drop(rate_limits);
-            *self.last_used_index.write().await = idx;
-
-            return Some(account.clone());
+        let result = (self.refresher)(refresh_token.clone()).await;
+        let mut accounts = self.accounts.write().await;
+        let account = accounts.get_mut(idx)?;
+        if account.refresh_token != refresh_token {
+            // Refreshed by another request or re-logged in meanwhile
+            return (!account.needs_refresh()).then(|| account.clone());
+        }
+        match result {
+            Ok(new_tokens) => {
+                account.access_token = new_tokens.access_token;
+                account.expires_at = new_tokens.expires_at;
+                account.refresh_failed = false;
+                account.refresh_token = new_tokens.refresh_token;
+                Some(account.clone())
+            }
+            Err(e) => {
+                error!("Failed to refresh token for {}: {}", account.email, e);
+                account.refresh_failed = true;
+                None
+            }
         }
-
-        None
     }
 
     /// Checks if this manager is properly initialized
@@ -238,24 +707,6 @@ impl AccountManager {
         self.storage.is_some()
     }
 
-    /// Creates a new AccountManager and loads accounts from storage
-    pub async fn new() -> Result<Self> {
-        let storage = TokenStorage::new()?;
-        let stored = storage.load_accounts()?;
-
-        let manager = Self {
-            storage: Some(storage),
-            accounts: Arc::new(RwLock::new(vec![])),
-            rate_limits: Arc::new(RwLock::new(HashMap::new())),
-            last_used_index: Arc::new(RwLock::new(stored.active_index)),
-        };
-
-        // Load and refresh accounts
-        manager.load_accounts_from_storage(&stored).await?;
-
-        Ok(manager)
-    }
-
     /// Loads accounts from storage and refreshes access tokens
     async fn load_accounts_from_storage(&self, stored: &StoredAccounts) -> Result<()> {
         let mut accounts = self.accounts.write().await;
@@ -270,6 +721,10 @@ impl AccountManager {
                         access_token: token_pair.access_token,
                         expires_at: token_pair.expires_at,
                         refresh_token: token_pair.refresh_token,
+                        disabled: stored_account.disabled,
+                        priority: stored_account.priority,
+                        refresh_failed: false,
+                        model_families: stored_account.model_families.clone(),
                     });
                     info!("Loaded account: {}", stored_account.email);
                 }
@@ -283,6 +738,10 @@ impl AccountManager {
                         access_token: String::new(),
                         expires_at: Utc::now() - chrono::Duration::hours(1), // Expired
                         refresh_token: stored_account.refresh_token.clone(),
+                        disabled: stored_account.disabled,
+                        priority: stored_account.priority,
+                        refresh_failed: true,
+                        model_families: stored_account.model_families.clone(),
                     });
                 }
             }
@@ -294,7 +753,7 @@ impl AccountManager {
 
     /// Refreshes the access token for a stored account
     async fn refresh_token_for_account(&self, stored: &StoredAccount) -> Result<TokenPair> {
-        refresh_access_token(&stored.refresh_token).await
+        (self.refresher)(stored.refresh_token.clone()).await
     }
 
     /// Returns the number of configured accounts
@@ -322,6 +781,7 @@ impl AccountManager {
             existing.access_token = token_pair.access_token;
             existing.expires_at = token_pair.expires_at;
             existing.refresh_token = token_pair.refresh_token;
+            existing.refresh_failed = false;
             info!("Updated existing account: {}", token_pair.email);
         } else {
             let index = accounts.len();
@@ -331,6 +791,10 @@ impl AccountManager {
                 access_token: token_pair.access_token,
                 expires_at: token_pair.expires_at,
                 refresh_token: token_pair.refresh_token,
+                disabled: false,
+                priority: 0,
+                refresh_failed: false,
+                model_families: Vec::new(),
             });
             info!("Added new account: {}", token_pair.email);
         }
@@ -338,6 +802,89 @@ impl AccountManager {
         Ok(())
     }
 
+    /// Takes an account out of rotation (or puts it back) without removing it
+    ///
+    /// The flag is persisted so it survives restarts. Returns `false` if no
+    /// account with that email exists.
+    pub async fn set_account_disabled(&self, email: &str, disabled: bool) -> Result<bool> {
+        if let Some(storage) = &self.storage {
+            if !storage.set_account_disabled(email, disabled)? {
+                return Ok(false);
+            }
+        }
+
+        let mut accounts = self.accounts.write().await;
+        let Some(account) = accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        account.disabled = disabled;
+        info!("Account {} {}", email, if disabled { "disabled" } else { "enabled" });
+        Ok(true)
+    }
+
+    /// Sets an account's selection priority (lower numbers are tried first)
+    ///
+    /// Persisted like the disabled flag. Returns `false` if no account with
+    /// that email exists.
+    pub async fn set_account_priority(&self, email: &str, priority: i32) -> Result<bool> {
+        if let Some(storage) = &self.storage {
+            if !storage.set_account_priority(email, priority)? {
+                return Ok(false);
+            }
+        }
+
+        let mut accounts = self.accounts.write().await;
+        let Some(account) = accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        account.priority = priority;
+        info!("Account {} priority set to {}", email, priority);
+        Ok(true)
+    }
+
+    /// Dedicates an account to some model families (empty serves all)
+    ///
+    /// Persisted like the priority. Returns `false` if no account with that
+    /// email exists.
+    pub async fn set_account_families(&self, email: &str, families: Vec<ModelFamily>) -> Result<bool> {
+        if let Some(storage) = &self.storage {
+            if !storage.set_account_families(email, &families)? {
+                return Ok(false);
+            }
+        }
+
+        let mut accounts = self.accounts.write().await;
+        let Some(account) = accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        info!("Account {} model families set to {:?}", email, families);
+        account.model_families = families;
+        Ok(true)
+    }
+
+    /// Writes runtime state back to storage
+    ///
+    /// Refresh tokens rotated by Google during refreshes and the rotation
+    /// position only live in memory until this is called (e.g. on shutdown).
+    pub async fn persist(&self) -> Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+
+        let accounts = self.accounts.read().await;
+        let mut stored = storage.load_accounts()?;
+        for stored_account in stored.accounts.iter_mut() {
+            if let Some(account) = accounts.iter().find(|a| a.email == stored_account.email) {
+                stored_account.refresh_token = account.refresh_token.clone();
+            }
+        }
+        stored.active_index = (*self.last_used_index.read().await).min(stored.accounts.len().saturating_sub(1));
+        storage.save_accounts(&stored)?;
+
+        debug!("Persisted {} accounts", stored.accounts.len());
+        Ok(())
+    }
+
     /// Removes an account by email
     pub async fn remove_account(&self, email: &str) -> Result<bool> {
         let removed = if let Some(storage) = &self.storage {
@@ -357,6 +904,9 @@ impl AccountManager {
                 account.index = i;
             }
 
+            // Indices shifted, so existing pairings would point at the wrong account
+            self.affinity.write().await.clear();
+
             info!("Removed account: {}", email);
         }
 
@@ -365,101 +915,183 @@ impl AccountManager {
 
     /// Gets the next available account (not rate-limited) with fresh access token
     pub async fn get_available_account(&self) -> Option<Account> {
+        self.next_available_account(None, &HashSet::new()).await
+    }
+
+    /// Round-robin selection, limited to accounts serving `family` if given
+    /// and skipping those in `busy`
+    async fn next_available_account(&self, family: Option<ModelFamily>, busy: &HashSet<usize>) -> Option<Account> {
+        let candidates = self.selectable_accounts(family, busy).await;
+        self.first_fresh_account(candidates).await
+    }
+
+    /// Accounts `next_available_account` would try, in the order it would
+    /// try them, without selecting one (nothing is recorded or refreshed)
+    async fn selectable_accounts(&self, family: Option<ModelFamily>, busy: &HashSet<usize>) -> Vec<usize> {
         let now = Utc::now();
-        let mut accounts = self.accounts.write().await;
+        let accounts = self.accounts.read().await;
         let rate_limits = self.rate_limits.read().await;
         let last_used = *self.last_used_index.read().await;
+        let cooling = self.cooling_down(now).await;
+
+        // Lowest priority number first, round-robin within a priority
+        selection_order(&accounts, last_used, &cooling)
+            .into_iter()
+            .filter(|&idx| !accounts[idx].disabled && !busy.contains(&idx) && family.is_none_or(|f| accounts[idx].serves(f)))
+            .filter(|&idx| {
+                // The requested family's limit, or any family's without one
+                let limited = rate_limits.get(&idx).is_some_and(|l| match family {
+                    Some(f) => l.is_rate_limited(f, now),
+                    None => [ModelFamily::Claude, ModelFamily::Gemini, ModelFamily::Unknown]
+                        .into_iter()
+                        .any(|f| l.is_rate_limited(f, now)),
+                });
+                if limited {
+                    debug!("Account {} is rate-limited", idx);
+                }
+                !limited
+            })
+            .collect()
+    }
 
-        if accounts.is_empty() {
-            return None;
-        }
+    /// Gets an account for a conversation, preferring the one it used last
+    ///
+    /// Sticking to one account keeps agentic sessions consistent and reuses
+    /// the project discovered for that account. Falls back to normal rotation
+    /// when there is no key, or when the paired account is disabled or
+    /// rate-limited. Only accounts serving `model_id`'s family are considered.
+    pub async fn get_available_account_for_conversation(&self, key: Option<&str>, model_id: &str) -> Option<Account> {
+        self.conversation_account(key, model_id, &HashSet::new()).await
+    }
 
-        // Start from the account after last used (round-robin)
-        let account_count = accounts.len();
-        for offset in 0..account_count {
-            let idx = (last_used + offset + 1) % account_count;
+    /// `get_available_account_for_conversation`, skipping accounts in `busy`
+    async fn conversation_account(&self, key: Option<&str>, model_id: &str, busy: &HashSet<usize>) -> Option<Account> {
+        let family = ModelFamily::from_model_id(model_id);
+        let Some(key) = key else {
+            return self.next_available_account(Some(family), busy).await;
+        };
 
-            // Check rate limit for any model family
-            if let Some(account_limits) = rate_limits.get(&idx) {
-                if account_limits.is_rate_limited(ModelFamily::Claude, now) ||
-                   account_limits.is_rate_limited(ModelFamily::Gemini, now) {
-                    debug!("Account {} is rate-limited", idx);
-                    continue;
-                }
+        let preferred = self.affinity.read().await.get(key).copied().filter(|idx| !busy.contains(idx));
+        if let Some(idx) = preferred {
+            if let Some(account) = self.get_account_if_available(idx, family).await {
+                debug!("Conversation affinity: reusing account {}", account.email);
+                return Some(account);
             }
+            debug!("Conversation affinity: account {} unavailable, rotating", idx);
+        }
 
-            let account = &mut accounts[idx];
-
-            // Refresh if needed
-            if account.needs_refresh() {
-                debug!("Refreshing token for account {}", account.email);
-                match refresh_access_token(&account.refresh_token).await {
-                    Ok(new_tokens) => {
-                        account.access_token = new_tokens.access_token;
-                        account.expires_at = new_tokens.expires_at;
-                        if new_tokens.refresh_token != account.refresh_token {
-                            account.refresh_token = new_tokens.refresh_token;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to refresh token for {}: {}", account.email, e);
-                        continue; // Try next account
-                    }
-                }
-            }
+        let account = self.next_available_account(Some(family), busy).await?;
+        let mut affinity = self.affinity.write().await;
+        if affinity.len() >= AFFINITY_CAPACITY && !affinity.contains_key(key) {
+            affinity.clear();
+        }
+        affinity.insert(key.to_string(), account.index);
 
-            // Update last used index
-            drop(rate_limits);
-            *self.last_used_index.write().await = idx;
+        Some(account)
+    }
 
-            return Some(account.clone());
+    /// Selects an account like `get_available_account_for_conversation`, but
+    /// behind any request already waiting for the same model family, and
+    /// takes one of its request slots
+    ///
+    /// Accounts at their concurrency limit are skipped; only when every
+    /// candidate is busy does this wait, until any of them frees a slot.
+    ///
+    /// Use with `wait_turn` in the rate-limit wait loop and keep `turn` until
+    /// the loop ends, so blocked requests are served in arrival order instead
+    /// of racing for the first freed account. Hold the slot until the
+    /// upstream request is done.
+    pub async fn next_in_line(&self, turn: &mut Option<QueueTicket>, key: Option<&str>, model_id: &str) -> Option<(Account, Option<AccountSlot>)> {
+        let family = ModelFamily::from_model_id(model_id);
+        let queue = &self.wait_queues[&family];
+        if turn.is_none() && queue.available_permits() == 0 {
+            *turn = Some(queue.clone().acquire_owned().await.expect("wait queue is never closed"));
         }
 
-        None
-    }
+        loop {
+            let busy = self.busy_accounts().await;
+            if let Some(account) = self.conversation_account(key, model_id, &busy).await {
+                // Another request can take the last slot in between
+                if let Ok(slot) = self.try_acquire_slot(account.index).await {
+                    return Some((account, slot));
+                }
+                continue;
+            }
 
-    /// Gets an account ignoring rate limits (used for fallback retry with different model)
-    pub async fn get_available_account_ignoring_rate_limit(&self) -> Option<Account> {
-        let mut accounts = self.accounts.write().await;
-        // let rate_limits = self.rate_limits.read().await; // Ignored
-        let last_used = *self.last_used_index.read().await;
+            // Only worth waiting if a busy account could serve the request once free
+            let waiting_on: Vec<usize> = self.selectable_accounts(Some(family), &HashSet::new()).await
+                .into_iter()
+                .filter(|idx| busy.contains(idx))
+                .collect();
+            if waiting_on.is_empty() {
+                return None;
+            }
 
-        if accounts.is_empty() {
-            return None;
+            debug!("Every account for {} is at its concurrency limit; waiting", model_id);
+            let slots: Vec<Arc<Semaphore>> = {
+                let slots = self.slots.read().await;
+                waiting_on.iter().filter_map(|idx| slots.get(idx).cloned()).collect()
+            };
+            let (freed, _, _) = futures::future::select_all(slots.into_iter().map(|slots| Box::pin(slots.acquire_owned()))).await;
+            drop(freed);
         }
+    }
 
-        let account_count = accounts.len();
+    /// Waits after `next_in_line` found no account
+    ///
+    /// A request not yet in line joins the queue and returns once everyone
+    /// ahead of it has been served; only the head of the queue sleeps out
+    /// `wait_time` before trying again.
+    pub async fn wait_turn(&self, turn: &mut Option<QueueTicket>, model_id: &str, wait_time: Duration) {
+        if turn.is_none() {
+            let queue = &self.wait_queues[&ModelFamily::from_model_id(model_id)];
+            *turn = Some(queue.clone().acquire_owned().await.expect("wait queue is never closed"));
+            return;
+        }
+        tokio::time::sleep(wait_time).await;
+    }
 
-        // Try all accounts starting from next in rotation
-        for i in 0..account_count {
-            let idx = (last_used + 1 + i) % account_count;
-            let account = accounts.get_mut(idx).expect("Account should exist");
+    /// Gets a specific account if it is enabled, serves `family` and is not rate-limited
+    async fn get_account_if_available(&self, idx: usize, family: ModelFamily) -> Option<Account> {
+        let now = Utc::now();
+        {
+            let accounts = self.accounts.read().await;
+            let rate_limits = self.rate_limits.read().await;
 
-            // Refresh if needed
-            if account.needs_refresh() {
-                debug!("Refreshing token for account {} (fallback)", account.email);
-                 match refresh_access_token(&account.refresh_token).await {
-                    Ok(new_tokens) => {
-                        account.access_token = new_tokens.access_token;
-                        account.expires_at = new_tokens.expires_at;
-                        if new_tokens.refresh_token != account.refresh_token {
-                             account.refresh_token = new_tokens.refresh_token;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to refresh token for {}: {} (skipping in fallback)", account.email, e);
-                        continue; // Try next account
-                    }
+            accounts.get(idx).filter(|a| !a.disabled && a.serves(family))?;
+            if let Some(account_limits) = rate_limits.get(&idx) {
+                if account_limits.is_rate_limited(family, now) {
+                    return None;
                 }
             }
+        }
 
-            // Found a usable account
-            *self.last_used_index.write().await = idx;
-            return Some(account.clone());
+        self.fresh_account(idx).await
+    }
+
+    /// Gets an account serving `family`, ignoring rate limits (used for
+    /// fallback retry with a different model)
+    pub async fn get_available_account_ignoring_rate_limit(&self, family: ModelFamily) -> Option<Account> {
+        let candidates: Vec<usize> = {
+            let accounts = self.accounts.read().await;
+            let last_used = *self.last_used_index.read().await;
+            let cooling = self.cooling_down(Utc::now()).await;
+
+            // Try all accounts starting from next in rotation
+            selection_order(&accounts, last_used, &cooling)
+                .into_iter()
+                .filter(|&idx| !accounts[idx].disabled && accounts[idx].serves(family))
+                .collect()
+        };
+        if candidates.is_empty() {
+            return None;
         }
 
-        error!("All accounts failed refresh in fallback selection");
-        None
+        let account = self.first_fresh_account(candidates).await;
+        if account.is_none() {
+            error!("All accounts failed refresh in fallback selection");
+        }
+        account
     }
 
     /// Marks an account as rate-limited for a specific model family
@@ -471,31 +1103,254 @@ impl AccountManager {
 
         let account_limits = rate_limits.entry(index).or_insert_with(AccountRateLimits::new);
 
-        let current_count = account_limits.get(family).as_ref().map(|i| i.consecutive_count).unwrap_or(0);
-        
+        let previous = account_limits.get(family).as_ref();
+        let current_count = previous.map(|i| i.consecutive_count).unwrap_or(0);
+
+        // Back off progressively on consecutive limits: the 1st uses the server's
+        // retry-after as-is, each further one doubles it (with jitter, capped)
+        let now = Utc::now();
+        let since = previous
+            .filter(|i| now - i.until < chrono::Duration::seconds(LIMIT_RUN_GAP_SECS))
+            .map_or(now, |i| i.since);
+        let retry_after = ((until - now).num_milliseconds() + 999).div_euclid(1000).max(1) as u64;
+        let backoff = exponential_backoff_with_jitter(retry_after, current_count, MAX_RATE_LIMIT_BACKOFF_SECS);
+        let until = if current_count > 0 && backoff > retry_after {
+            now + chrono::Duration::seconds(backoff as i64)
+        } else {
+            until
+        };
+
         account_limits.set(family, RateLimitInfo {
             until,
             consecutive_count: current_count + 1,
+            since,
         });
 
-        if let Some(account) = self.accounts.read().await.get(index) {
+        drop(rate_limits);
+
+        let mut throttles = self.throttles.write().await;
+        let throttle = throttles.entry(index).or_default();
+        throttle.recent_limits.push_back(now);
+        let recent = throttle.recent_count(now, self.throttle_policy.window);
+        drop(throttles);
+        if recent == self.throttle_policy.threshold {
+            warn!("Account {} hit {} rate limits within {}s; throttling its requests", index, recent, self.throttle_policy.window.as_secs());
+        }
+
+        let email = self.accounts.read().await.get(index).map(|a| a.email.clone());
+        if let Some(ref email) = email {
             warn!(
                 "Account {} rate-limited for {:?} until {} (consecutive: {})",
-                account.email, family, until, current_count + 1
+                email, family, until, current_count + 1
             );
         }
+
+        let mut history = self.rate_limit_history.write().await;
+        if history.len() == RATE_LIMIT_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(RateLimitEvent {
+            account_index: index,
+            email: email.unwrap_or_default(),
+            family,
+            at: now,
+            until,
+            retry_after_secs: (until - now).num_seconds().max(0),
+            consecutive_count: current_count + 1,
+        });
+    }
+
+    /// Returns recent rate-limit events, newest first
+    pub async fn rate_limit_history(&self) -> Vec<RateLimitEvent> {
+        self.rate_limit_history.read().await.iter().rev().cloned().collect()
+    }
+
+    /// Returns the current rate-limit state of every account
+    pub async fn account_statuses(&self) -> Vec<AccountStatus> {
+        let accounts = self.accounts.read().await;
+        let rate_limits = self.rate_limits.read().await;
+        let mut throttles = self.throttles.write().await;
+        let now = Utc::now();
+        let mut recent_limits = |index: usize| {
+            throttles.get_mut(&index).map_or(0, |t| t.recent_count(now, self.throttle_policy.window))
+        };
+
+        let limited_until = |index: usize, family: ModelFamily| {
+            rate_limits
+                .get(&index)
+                .and_then(|limits| limits.get(family).as_ref().map(|info| info.until))
+                .filter(|until| *until > now)
+        };
+
+        accounts.iter().map(|a| AccountStatus {
+            index: a.index,
+            email: a.email.clone(),
+            disabled: a.disabled,
+            refresh_failed: a.refresh_failed,
+            priority: a.priority,
+            model_families: a.model_families.clone(),
+            claude_limited_until: limited_until(a.index, ModelFamily::Claude),
+            gemini_limited_until: limited_until(a.index, ModelFamily::Gemini),
+            unknown_limited_until: limited_until(a.index, ModelFamily::Unknown),
+            recent_rate_limits: recent_limits(a.index),
+            throttle_delay_ms: self.throttle_policy.delay_for(recent_limits(a.index)).as_millis() as u64,
+        }).collect()
     }
 
     /// Clears the rate limit for an account and model family (on successful request)
+    ///
+    /// A success after the family was limited for an hour or more is recorded
+    /// as a quota reset.
     pub async fn clear_rate_limit(&self, index: usize, family: ModelFamily) {
+        let now = Utc::now();
         let mut rate_limits = self.rate_limits.write().await;
+        let mut quota_reset = false;
         if let Some(account_limits) = rate_limits.get_mut(&index) {
+            quota_reset = account_limits.get(family).as_ref()
+                .is_some_and(|info| now - info.since >= chrono::Duration::seconds(MIN_QUOTA_LIMIT_SECS));
             account_limits.clear(family);
-            // If both families are clear, remove the entry entirely
-            if account_limits.claude.is_none() && account_limits.gemini.is_none() {
+            // If every family is clear, remove the entry entirely
+            if account_limits.claude.is_none() && account_limits.gemini.is_none() && account_limits.unknown.is_none() {
                 rate_limits.remove(&index);
             }
         }
+        drop(rate_limits);
+
+        if quota_reset {
+            self.record_quota_reset(index, now).await;
+        }
+    }
+
+    /// Records that an account's quota was seen to have reset at `at`
+    pub async fn record_quota_reset(&self, index: usize, at: DateTime<Utc>) {
+        let mut observed = self.observed_resets.write().await;
+        let resets = observed.entry(index).or_default();
+        if resets.len() == RESET_HISTORY_CAPACITY {
+            resets.pop_front();
+        }
+        resets.push_back(at);
+    }
+
+    /// Next time after `now` the account's quota is expected to reset, once
+    /// resets have been observed at about the same time of day on at least
+    /// `MIN_RESET_DAYS` different days
+    ///
+    /// The prediction is the average time of day of the resets matching the
+    /// most recent one. Returns `None` until such a daily window shows up.
+    pub async fn predicted_reset(&self, index: usize, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        const DAY_SECS: i64 = 24 * 60 * 60;
+
+        let observed = self.observed_resets.read().await;
+        let resets = observed.get(&index)?;
+        let latest = *resets.back()?;
+        let latest_second = latest.num_seconds_from_midnight() as i64;
+
+        // Time-of-day offset from the latest reset, wrapped to ±12 hours
+        let matching: Vec<(DateTime<Utc>, i64)> = resets.iter().filter_map(|at| {
+            let mut offset = (at.num_seconds_from_midnight() as i64 - latest_second).rem_euclid(DAY_SECS);
+            if offset > DAY_SECS / 2 {
+                offset -= DAY_SECS;
+            }
+            (offset.abs() <= RESET_MATCH_TOLERANCE_SECS).then_some((*at, offset))
+        }).collect();
+
+        // Resets hours apart on the same day are a short window, not a daily one
+        let days: HashSet<chrono::NaiveDate> = matching.iter().map(|(at, _)| at.date_naive()).collect();
+        if days.len() < MIN_RESET_DAYS {
+            return None;
+        }
+
+        let mean_offset = matching.iter().map(|(_, offset)| offset).sum::<i64>() / matching.len() as i64;
+        let reset_second = (latest_second + mean_offset).rem_euclid(DAY_SECS);
+        let midnight = now.date_naive().and_hms_opt(0, 0, 0)?.and_utc();
+        let mut next = midnight + chrono::Duration::seconds(reset_second);
+        if next <= now {
+            next += chrono::Duration::days(1);
+        }
+        Some(next)
+    }
+
+    /// Refreshes enabled accounts whose token expires within `lookahead`,
+    /// one at a time with `stagger` between refreshes
+    ///
+    /// Spacing the refreshes spreads the accounts' expiry times apart, so a
+    /// burst of requests after an idle period doesn't queue behind a refresh
+    /// for every account. Returns how many accounts were refreshed.
+    pub async fn warm_tokens(&self, lookahead: Duration, stagger: Duration) -> usize {
+        self.warm_tokens_with(lookahead, stagger, |token| (self.refresher)(token)).await
+    }
+
+    async fn warm_tokens_with<F, Fut>(&self, lookahead: Duration, stagger: Duration, refresh: F) -> usize
+    where
+        F: Fn(String) -> Fut,
+        Fut: std::future::Future<Output = Result<TokenPair>>,
+    {
+        // Refresh needs a 5 minute margin on top of the lookahead
+        let window = lookahead + Duration::from_secs(5 * 60);
+        let due: Vec<(usize, String)> = self.accounts.read().await
+            .iter()
+            .filter(|a| !a.disabled && a.expires_within(window))
+            .map(|a| (a.index, a.refresh_token.clone()))
+            .collect();
+
+        let mut refreshed = 0;
+        for (position, (idx, refresh_token)) in due.into_iter().enumerate() {
+            if position > 0 {
+                tokio::time::sleep(stagger).await;
+            }
+
+            // No lock is held across the network call
+            let result = refresh(refresh_token.clone()).await;
+            let mut accounts = self.accounts.write().await;
+            let Some(account) = accounts.get_mut(idx).filter(|a| a.refresh_token == refresh_token) else {
+                continue; // Removed or re-logged in meanwhile
+            };
+            match result {
+                Ok(new_tokens) => {
+                    account.access_token = new_tokens.access_token;
+                    account.expires_at = new_tokens.expires_at;
+                    account.refresh_failed = false;
+                    account.refresh_token = new_tokens.refresh_token;
+                    debug!("Pre-warmed token for {}", account.email);
+                    refreshed += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to pre-warm token for {}: {}", account.email, e);
+                    account.refresh_failed = true;
+                }
+            }
+        }
+        refreshed
+    }
+
+    /// Keeps tokens warm: every `interval`, refreshes the ones that would
+    /// expire before the next pass. Runs until the task is aborted.
+    pub async fn run_token_warmer(&self, interval: Duration, stagger: Duration) {
+        loop {
+            let refreshed = self.warm_tokens(interval, stagger).await;
+            if refreshed > 0 {
+                info!("Pre-warmed {} account token(s)", refreshed);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Explains why selection for `model_id` returned no account
+    pub async fn selection_failure(&self, model_id: &str) -> SelectionFailure {
+        let family = ModelFamily::from_model_id(model_id);
+        let accounts = self.accounts.read().await;
+
+        if accounts.is_empty() {
+            return SelectionFailure::NoAccounts;
+        }
+        let mut usable = accounts.iter().filter(|a| !a.disabled && a.serves(family)).peekable();
+        if usable.peek().is_none() {
+            return SelectionFailure::AllDisabled;
+        }
+        if usable.all(|a| a.refresh_failed) {
+            return SelectionFailure::AllRefreshFailed;
+        }
+        SelectionFailure::Unavailable
     }
 
     /// Gets the minimum wait time until any account becomes available for a model family
@@ -506,7 +1361,7 @@ impl AccountManager {
         let now = Utc::now();
 
         // Check if any account is available for this model family
-        let any_available = accounts.iter().any(|a| {
+        let any_available = accounts.iter().filter(|a| !a.disabled && a.serves(family)).any(|a| {
             if let Some(account_limits) = rate_limits.get(&a.index) {
                 !account_limits.is_rate_limited(family, now)
             } else {
@@ -518,9 +1373,11 @@ impl AccountManager {
             return None;
         }
 
-        // Find the earliest expiration across all accounts for this family
-        rate_limits
-            .values()
+        // Find the earliest expiration across all enabled accounts for this family
+        accounts
+            .iter()
+            .filter(|a| !a.disabled && a.serves(family))
+            .filter_map(|a| rate_limits.get(&a.index))
             .filter_map(|account_limits| account_limits.get(family).as_ref())
             .filter(|info| info.until > now)
             .map(|info| (info.until - now).to_std().unwrap_or_default())
@@ -534,7 +1391,7 @@ impl AccountManager {
         let now = Utc::now();
 
         // Check if any account has no rate limits at all
-        let any_available = accounts.iter().any(|a| {
+        let any_available = accounts.iter().filter(|a| !a.disabled).any(|a| {
             if let Some(account_limits) = rate_limits.get(&a.index) {
                 // Account is available if neither family is rate-limited
                 !account_limits.is_rate_limited(ModelFamily::Claude, now) &&
@@ -548,9 +1405,11 @@ impl AccountManager {
             return None;
         }
 
-        // Find the earliest expiration across all accounts and families
-        rate_limits
-            .values()
+        // Find the earliest expiration across all enabled accounts and families
+        accounts
+            .iter()
+            .filter(|a| !a.disabled)
+            .filter_map(|a| rate_limits.get(&a.index))
             .filter_map(|account_limits| account_limits.earliest_expiration())
             .filter(|until| *until > now)
             .map(|until| (until - now).to_std().unwrap_or_default())
@@ -620,6 +1479,10 @@ mod tests {
             access_token: "token".into(),
             expires_at: Utc::now() + chrono::Duration::hours(1),
             refresh_token: "refresh".into(),
+            disabled: false,
+            priority: 0,
+            refresh_failed: false,
+            model_families: Vec::new(),
         };
         assert!(!account.needs_refresh());
 
@@ -629,6 +1492,10 @@ mod tests {
             access_token: "token".into(),
             expires_at: Utc::now() - chrono::Duration::hours(1),
             refresh_token: "refresh".into(),
+            disabled: false,
+            priority: 0,
+            refresh_failed: false,
+            model_families: Vec::new(),
         };
         assert!(expired_account.needs_refresh());
     }
@@ -647,14 +1514,672 @@ mod tests {
         manager.add_account(token_pair).await.unwrap();
 
         // Mark it as rate limited
-        manager.mark_rate_limited(0, Utc::now() + chrono::Duration::hours(1)).await;
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::hours(1)).await;
 
         // Should be None normally
         assert!(manager.get_available_account().await.is_none());
 
         // Should be Some ignoring limit
-        let account = manager.get_available_account_ignoring_rate_limit().await;
+        let account = manager.get_available_account_ignoring_rate_limit(ModelFamily::Gemini).await;
         assert!(account.is_some());
         assert_eq!(account.unwrap().email, "test@example.com");
+
+        // ...but only if it serves the fallback model's family
+        manager.set_account_families("test@example.com", vec![ModelFamily::Claude]).await.unwrap();
+        assert!(manager.get_available_account_ignoring_rate_limit(ModelFamily::Gemini).await.is_none());
+        assert!(manager.get_available_account_ignoring_rate_limit(ModelFamily::Claude).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_history_newest_first() {
+        let manager = AccountManager::empty();
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "test@example.com".into(),
+        }).await.unwrap();
+
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::seconds(30)).await;
+        manager.mark_rate_limited(0, ModelFamily::Gemini, Utc::now() + chrono::Duration::seconds(60)).await;
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::seconds(120)).await;
+
+        let history = manager.rate_limit_history().await;
+        assert_eq!(history.len(), 3);
+
+        assert_eq!(history[0].family, ModelFamily::Claude);
+        // Second consecutive Claude limit is backed off (120s doubled, plus jitter)
+        assert!((239..=300).contains(&history[0].retry_after_secs));
+        assert_eq!(history[0].consecutive_count, 2);
+        assert_eq!(history[0].email, "test@example.com");
+
+        assert_eq!(history[1].family, ModelFamily::Gemini);
+        assert!((58..=60).contains(&history[1].retry_after_secs));
+        assert_eq!(history[1].consecutive_count, 1);
+
+        assert_eq!(history[2].family, ModelFamily::Claude);
+        assert!((28..=30).contains(&history[2].retry_after_secs));
+        assert_eq!(history[2].consecutive_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_account_skipped_by_selection() {
+        let manager = AccountManager::empty();
+        for email in ["a@example.com", "b@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: "refresh".into(),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+
+        assert!(manager.set_account_disabled("a@example.com", true).await.unwrap());
+        assert!(!manager.set_account_disabled("missing@example.com", true).await.unwrap());
+
+        for _ in 0..4 {
+            assert_eq!(manager.get_available_account().await.unwrap().email, "b@example.com");
+            assert_eq!(manager.get_available_account_for_model("gemini-3-flash").await.unwrap().email, "b@example.com");
+            assert_eq!(manager.get_available_account_ignoring_rate_limit(ModelFamily::Gemini).await.unwrap().email, "b@example.com");
+        }
+
+        manager.set_account_disabled("a@example.com", false).await.unwrap();
+        let mut chosen = Vec::new();
+        for _ in 0..2 {
+            chosen.push(manager.get_available_account().await.unwrap().email);
+        }
+        assert!(chosen.contains(&"a@example.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_consecutive_rate_limits_back_off() {
+        let manager = AccountManager::empty();
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "test@example.com".into(),
+        }).await.unwrap();
+
+        let retry_after = chrono::Duration::seconds(60);
+        for _ in 0..3 {
+            manager.mark_rate_limited(0, ModelFamily::Gemini, Utc::now() + retry_after).await;
+        }
+
+        let history = manager.rate_limit_history().await;
+        let (third, first) = (&history[0], &history[2]);
+        assert_eq!(third.consecutive_count, 3);
+        assert_eq!(first.consecutive_count, 1);
+        assert!(third.until > first.until);
+        // 3rd limit: 60s * 2^2 = 240s, plus up to 25% jitter
+        assert!((239..=300).contains(&third.retry_after_secs));
+        assert!((59..=60).contains(&first.retry_after_secs));
+
+        // Never beyond the cap (plus jitter)
+        assert!(exponential_backoff_with_jitter(60, 20, MAX_RATE_LIMIT_BACKOFF_SECS) <= MAX_RATE_LIMIT_BACKOFF_SECS * 5 / 4);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_affinity_prefers_same_account() {
+        let manager = AccountManager::empty();
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: "refresh".into(),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+
+        let first = manager.get_available_account_for_conversation(Some("conv-1"), "claude-sonnet-4-5").await.unwrap();
+        // Unrelated traffic advances the rotation in between
+        manager.get_available_account().await.unwrap();
+        let second = manager.get_available_account_for_conversation(Some("conv-1"), "claude-sonnet-4-5").await.unwrap();
+        assert_eq!(first.email, second.email);
+
+        // Rotates away once the paired account is rate-limited, and sticks to the new one
+        manager.mark_rate_limited(first.index, ModelFamily::Claude, Utc::now() + chrono::Duration::hours(1)).await;
+        let third = manager.get_available_account_for_conversation(Some("conv-1"), "claude-sonnet-4-5").await.unwrap();
+        assert_ne!(third.email, first.email);
+        let fourth = manager.get_available_account_for_conversation(Some("conv-1"), "claude-sonnet-4-5").await.unwrap();
+        assert_eq!(third.email, fourth.email);
+
+        // A limit on the other family doesn't break the pairing
+        manager.mark_rate_limited(third.index, ModelFamily::Gemini, Utc::now() + chrono::Duration::hours(1)).await;
+        let fifth = manager.get_available_account_for_conversation(Some("conv-1"), "claude-sonnet-4-5").await.unwrap();
+        assert_eq!(third.email, fifth.email);
+    }
+
+    #[tokio::test]
+    async fn test_family_dedicated_account_skipped_for_other_family() {
+        use crate::storage::MemoryStore;
+
+        let store = MemoryStore::default();
+        let manager = AccountManager::with_store(store.clone()).await.unwrap();
+        for email in ["claude@example.com", "any@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: "refresh".into(),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        assert!(manager.set_account_families("claude@example.com", vec![ModelFamily::Claude]).await.unwrap());
+        assert_eq!(store.load_accounts().unwrap().accounts[0].model_families, vec![ModelFamily::Claude]);
+
+        for _ in 0..4 {
+            let account = manager.get_available_account_for_model("gemini-3-flash").await.unwrap();
+            assert_eq!(account.email, "any@example.com");
+            let account = manager.get_available_account_for_conversation(None, "gemini-3-flash").await.unwrap();
+            assert_eq!(account.email, "any@example.com");
+        }
+
+        // The untagged account keeps serving every family
+        let mut claude_emails: Vec<String> = Vec::new();
+        for _ in 0..2 {
+            claude_emails.push(manager.get_available_account_for_model("claude-sonnet-4-5").await.unwrap().email);
+        }
+        claude_emails.sort();
+        assert_eq!(claude_emails, ["any@example.com", "claude@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_persists_changes() {
+        use crate::storage::MemoryStore;
+
+        let store = MemoryStore::default();
+        let manager = AccountManager::with_store(store.clone()).await.unwrap();
+        assert!(manager.is_initialized());
+
+        for email in ["a@example.com", "b@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        assert!(manager.set_account_disabled("b@example.com", true).await.unwrap());
+        assert!(manager.remove_account("a@example.com").await.unwrap());
+
+        let stored = store.load_accounts().unwrap();
+        assert_eq!(stored.accounts.len(), 1);
+        assert_eq!(stored.accounts[0].email, "b@example.com");
+        assert_eq!(stored.accounts[0].refresh_token, "refresh-b@example.com");
+        assert!(stored.accounts[0].disabled);
+        assert_eq!(manager.get_account_emails().await, vec!["b@example.com".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_frequent_rate_limits_raise_throttle_delay() {
+        let mut manager = AccountManager::empty();
+        manager.set_throttle_policy(ThrottlePolicy {
+            threshold: 2,
+            window: Duration::from_secs(60),
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(3000),
+        });
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "a@example.com".into(),
+        }).await.unwrap();
+
+        let until = Utc::now() + chrono::Duration::seconds(1);
+        let mut delays = vec![manager.throttle_delay(0).await];
+        for _ in 0..4 {
+            manager.mark_rate_limited(0, ModelFamily::Claude, until).await;
+            delays.push(manager.throttle_delay(0).await);
+        }
+        let ms: Vec<u128> = delays.iter().map(|d| d.as_millis()).collect();
+        assert_eq!(ms, [0, 0, 1000, 2000, 3000]);
+
+        let status = &manager.account_statuses().await[0];
+        assert_eq!(status.recent_rate_limits, 4);
+        assert_eq!(status.throttle_delay_ms, 3000);
+
+        // Once the 429s age out of the window the throttle lifts
+        manager.throttles.write().await.get_mut(&0).unwrap().recent_limits.iter_mut()
+            .for_each(|at| *at -= chrono::Duration::minutes(2));
+        assert_eq!(manager.throttle_delay(0).await, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_persist_writes_rotated_refresh_token() {
+        use crate::storage::MemoryStore;
+
+        let store = MemoryStore::default();
+        let manager = AccountManager::with_store(store.clone()).await.unwrap();
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "original".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "a@example.com".into(),
+        }).await.unwrap();
+
+        // As a refresh that rotated the token would leave it
+        manager.accounts.write().await[0].refresh_token = "rotated".into();
+        assert_eq!(store.load_accounts().unwrap().accounts[0].refresh_token, "original");
+
+        manager.persist().await.unwrap();
+        assert_eq!(store.load_accounts().unwrap().accounts[0].refresh_token, "rotated");
+    }
+
+    /// Manager loaded from `store` whose token refreshes are all rejected,
+    /// the way Google answers a revoked refresh token
+    async fn with_revoked_tokens(store: crate::storage::MemoryStore) -> AccountManager<crate::storage::MemoryStore> {
+        let mut manager = AccountManager::from_parts(Some(store), 0);
+        manager.refresher = Arc::new(|_| Box::pin(async { Err(anyhow::anyhow!("Token refresh failed: invalid_grant")) }));
+        manager.reload().await.unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_account_statuses_flag_dead_accounts() {
+        use crate::storage::MemoryStore;
+
+        // A stored account whose refresh token is rejected
+        let store = MemoryStore::with_accounts(StoredAccounts {
+            accounts: vec![StoredAccount {
+                email: "dead@example.com".into(),
+                refresh_token: "revoked".into(),
+                added_at: 0,
+                last_used: 0,
+                disabled: false,
+                priority: 0,
+                token_encrypted: false,
+                model_families: Vec::new(),
+            }],
+            ..Default::default()
+        });
+        let manager = with_revoked_tokens(store).await;
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "live@example.com".into(),
+        }).await.unwrap();
+
+        let statuses = manager.account_statuses().await;
+        let status = |email: &str| statuses.iter().find(|s| s.email == email).unwrap().clone();
+        assert!(status("dead@example.com").refresh_failed);
+        assert!(!status("live@example.com").refresh_failed);
+    }
+
+    #[tokio::test]
+    async fn test_warm_tokens_leaves_no_account_needing_refresh() {
+        let manager = AccountManager::empty();
+        for (email, minutes) in [("stale@example.com", -10), ("soon@example.com", 3), ("fresh@example.com", 50)] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: Utc::now() + chrono::Duration::minutes(minutes),
+                email: email.into(),
+            }).await.unwrap();
+        }
+
+        let calls = std::sync::Mutex::new(Vec::new());
+        let refreshed = manager.warm_tokens_with(Duration::from_secs(60), Duration::from_millis(5), |token| {
+            calls.lock().unwrap().push(token.clone());
+            async move {
+                Ok(TokenPair {
+                    access_token: "warm".into(),
+                    refresh_token: token,
+                    expires_at: Utc::now() + chrono::Duration::hours(1),
+                    email: String::new(),
+                })
+            }
+        }).await;
+
+        // Only tokens expiring within the lookahead (plus margin) are refreshed
+        assert_eq!(refreshed, 2);
+        assert!(!calls.lock().unwrap().contains(&"refresh-fresh@example.com".to_string()));
+        assert!(manager.accounts.read().await.iter().all(|a| !a.needs_refresh()));
+    }
+
+    #[tokio::test]
+    async fn test_slow_refresh_does_not_block_other_selection() {
+        let mut manager = AccountManager::empty();
+        for (email, minutes) in [("slow@example.com", -10), ("ready@example.com", 50)] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: Utc::now() + chrono::Duration::minutes(minutes),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        manager.set_account_families("slow@example.com", vec![ModelFamily::Claude]).await.unwrap();
+        manager.set_account_families("ready@example.com", vec![ModelFamily::Gemini]).await.unwrap();
+        manager.refresher = Arc::new(|token| Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Ok(TokenPair {
+                access_token: "refreshed".into(),
+                refresh_token: token,
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: String::new(),
+            })
+        }));
+
+        // The Claude selection starts first and sits in the slow refresh
+        let (claude, gemini) = tokio::join!(
+            manager.get_available_account_for_model("claude-sonnet-4-5"),
+            tokio::time::timeout(
+                Duration::from_millis(200),
+                manager.get_available_account_for_model("gemini-3-pro"),
+            ),
+        );
+
+        let gemini = gemini.expect("selection blocked behind another account's refresh");
+        assert_eq!(gemini.unwrap().email, "ready@example.com");
+        let claude = claude.unwrap();
+        assert_eq!(claude.email, "slow@example.com");
+        assert_eq!(claude.access_token, "refreshed");
+    }
+
+    #[tokio::test]
+    async fn test_all_dead_tokens_reported_as_refresh_failure() {
+        use crate::storage::MemoryStore;
+
+        assert_eq!(AccountManager::empty().selection_failure("gemini-3-flash").await, SelectionFailure::NoAccounts);
+
+        let dead = |email: &str| StoredAccount {
+            email: email.into(),
+            refresh_token: "revoked".into(),
+            added_at: 0,
+            last_used: 0,
+            disabled: false,
+            priority: 0,
+            token_encrypted: false,
+            model_families: Vec::new(),
+        };
+        let store = MemoryStore::with_accounts(StoredAccounts {
+            accounts: vec![dead("a@example.com"), dead("b@example.com")],
+            ..Default::default()
+        });
+        let manager = with_revoked_tokens(store).await;
+
+        assert!(manager.get_available_account_for_conversation(None, "gemini-3-flash").await.is_none());
+        assert_eq!(manager.selection_failure("gemini-3-flash").await, SelectionFailure::AllRefreshFailed);
+
+        // Disabling the dead accounts is a different problem
+        manager.set_account_disabled("a@example.com", true).await.unwrap();
+        manager.set_account_disabled("b@example.com", true).await.unwrap();
+        assert_eq!(manager.selection_failure("gemini-3-flash").await, SelectionFailure::AllDisabled);
+    }
+
+    #[tokio::test]
+    async fn test_lower_priority_number_preferred() {
+        let manager = AccountManager::empty();
+        for email in ["free@example.com", "paid@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: format!("access-{}", email),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        assert!(manager.set_account_priority("free@example.com", 10).await.unwrap());
+        assert!(!manager.set_account_priority("missing@example.com", 1).await.unwrap());
+
+        // Round-robin would alternate; priority keeps picking the paid account
+        for _ in 0..3 {
+            assert_eq!(manager.get_available_account().await.unwrap().email, "paid@example.com");
+        }
+
+        // Falls back to the lower-priority account once the preferred one is limited
+        let paid = manager.get_available_account().await.unwrap();
+        manager.mark_rate_limited(paid.index, ModelFamily::Claude, Utc::now() + chrono::Duration::minutes(5)).await;
+        assert_eq!(manager.get_available_account().await.unwrap().email, "free@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_model_gets_its_own_family() {
+        assert_eq!(ModelFamily::from_model_id("claude-sonnet-4-5"), ModelFamily::Claude);
+        assert_eq!(ModelFamily::from_model_id("gemini-3-flash"), ModelFamily::Gemini);
+        assert_eq!(ModelFamily::from_model_id("gpt-oss-120b"), ModelFamily::Unknown);
+        assert_eq!(ModelFamily::from_model_id("aether-echo"), ModelFamily::Unknown);
+
+        // Limiting the unknown pool leaves Gemini alone
+        let manager = AccountManager::empty();
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "test@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(0, ModelFamily::Unknown, Utc::now() + chrono::Duration::minutes(5)).await;
+        assert!(manager.all_rate_limited_for_model("gpt-oss-120b").await);
+        assert!(!manager.all_rate_limited_for_model("gemini-3-flash").await);
+        assert!(manager.get_min_wait_time_for_model("gemini-3-flash").await.is_none());
+
+
+        // Selection agrees: the account is out for unknown models only
+        assert!(manager.get_available_account_for_conversation(None, "gpt-oss-120b").await.is_none());
+        assert!(manager.next_in_line(&mut None, None, "gpt-oss-120b").await.is_none());
+        assert!(manager.get_available_account_for_conversation(None, "gemini-3-flash").await.is_some());
+        assert!(manager.get_available_account_for_conversation(None, "claude-sonnet-4-5").await.is_some());
+        let status = manager.account_statuses().await.remove(0);
+        assert!(status.unknown_limited_until.is_some());
+        assert!(status.gemini_limited_until.is_none());
+
+        // Nor does a Gemini limit hold up Claude requests
+        manager.mark_rate_limited(0, ModelFamily::Gemini, Utc::now() + chrono::Duration::minutes(5)).await;
+        assert!(manager.get_available_account_for_conversation(None, "claude-sonnet-4-5").await.is_some());
+        assert!(manager.get_available_account_for_conversation(None, "gemini-3-flash").await.is_none());
+
+        manager.clear_rate_limit(0, ModelFamily::Unknown).await;
+        assert!(!manager.all_rate_limited_for_model("gpt-oss-120b").await);
+    }
+
+    #[tokio::test]
+    async fn test_queued_requests_served_in_arrival_order() {
+        let manager = Arc::new(AccountManager::empty());
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "only@example.com".into(),
+        }).await.unwrap();
+        manager.mark_rate_limited(0, ModelFamily::Gemini, Utc::now() + chrono::Duration::milliseconds(100)).await;
+
+        let served = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for request in 0..3 {
+            let (manager, served) = (manager.clone(), served.clone());
+            tasks.push(tokio::spawn(async move {
+                let mut turn = None;
+                let account = loop {
+                    if let Some((account, _)) = manager.next_in_line(&mut turn, None, "gemini-3-flash").await {
+                        break account;
+                    }
+                    let wait = manager.get_min_wait_time_for_model("gemini-3-flash").await.unwrap_or_default();
+                    manager.wait_turn(&mut turn, "gemini-3-flash", wait + Duration::from_millis(5)).await;
+                };
+                // Each request uses up the account again before leaving the line
+                served.lock().unwrap().push(request);
+                manager.clear_rate_limit(account.index, ModelFamily::Gemini).await;
+                manager.mark_rate_limited(account.index, ModelFamily::Gemini, Utc::now() + chrono::Duration::milliseconds(50)).await;
+                drop(turn);
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*served.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_daily_reset_learned_from_observed_resets() {
+        let manager = AccountManager::empty();
+        let at = |day: u32, h: u32, m: u32| chrono::NaiveDate::from_ymd_opt(2026, 3, day).unwrap().and_hms_opt(h, m, 0).unwrap().and_utc();
+
+        // One reset, or several within a day, isn't a daily window yet
+        manager.record_quota_reset(0, at(1, 7, 59)).await;
+        manager.record_quota_reset(0, at(1, 8, 1)).await;
+        assert!(manager.predicted_reset(0, at(1, 12, 0)).await.is_none());
+
+        // Nor are two days: seen on a third at about the same time of day
+        manager.record_quota_reset(0, at(2, 8, 0)).await;
+        assert!(manager.predicted_reset(0, at(2, 12, 0)).await.is_none());
+        manager.record_quota_reset(0, at(3, 8, 0)).await;
+        assert_eq!(manager.predicted_reset(0, at(3, 12, 0)).await, Some(at(4, 8, 0)));
+        assert_eq!(manager.predicted_reset(0, at(4, 6, 0)).await, Some(at(4, 8, 0)));
+
+        // Other accounts have learned nothing
+        assert!(manager.predicted_reset(1, at(3, 12, 0)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_only_long_limits_learned_as_quota_resets() {
+        let manager = AccountManager::empty();
+        let resets = || async { manager.observed_resets.read().await.get(&0).map_or(0, |r| r.len()) };
+
+        // A per-minute limit cleared by the next success isn't a quota reset
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::seconds(60)).await;
+        manager.clear_rate_limit(0, ModelFamily::Claude).await;
+        assert_eq!(resets().await, 0);
+
+        // A run of limits that held the account for hours is
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::seconds(60)).await;
+        if let Some(info) = manager.rate_limits.write().await.get_mut(&0).and_then(|l| l.claude.as_mut()) {
+            info.since -= chrono::Duration::hours(3);
+        }
+        manager.mark_rate_limited(0, ModelFamily::Claude, Utc::now() + chrono::Duration::seconds(60)).await;
+        manager.clear_rate_limit(0, ModelFamily::Claude).await;
+        assert_eq!(resets().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_prefers_other_account_after_serving() {
+        let mut manager = AccountManager::empty();
+        manager.set_cooldown(Duration::from_millis(200));
+        for email in ["a@example.com", "b@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: format!("access-{}", email),
+                refresh_token: format!("refresh-{}", email),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        // Without the cooldown priority would keep picking A
+        manager.set_account_priority("b@example.com", 5).await.unwrap();
+
+        assert_eq!(manager.get_available_account().await.unwrap().email, "a@example.com");
+        assert_eq!(manager.get_available_account().await.unwrap().email, "b@example.com");
+
+        // Both cooling down: back to priority order, not excluded
+        assert_eq!(manager.get_available_account().await.unwrap().email, "a@example.com");
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(manager.get_available_account_for_model("gemini-3-flash").await.unwrap().email, "a@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_account_concurrency_limit_queues_second_request() {
+        let mut manager = AccountManager::empty();
+        manager.set_max_concurrent_per_account(Some(1));
+        manager.add_account(TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            email: "only@example.com".into(),
+        }).await.unwrap();
+        let manager = Arc::new(manager);
+
+        let first = manager.get_available_account().await.unwrap();
+        let first_slot = manager.acquire_slot(first.index).await;
+        assert!(first_slot.is_some());
+
+        // The second request on the same account waits for the first to finish
+        let second = {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let account = manager.get_available_account().await.unwrap();
+                manager.acquire_slot(account.index).await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+
+        drop(first_slot);
+        let second_slot = tokio::time::timeout(Duration::from_secs(5), second).await.unwrap().unwrap();
+        assert!(second_slot.is_some());
+
+        // Unlimited by default
+        assert!(AccountManager::empty().acquire_slot(0).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_busy_account_skipped_until_all_are_busy() {
+        let mut manager = AccountManager::empty();
+        manager.set_max_concurrent_per_account(Some(1));
+        for email in ["a@example.com", "b@example.com"] {
+            manager.add_account(TokenPair {
+                access_token: "access".into(),
+                refresh_token: "refresh".into(),
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: email.into(),
+            }).await.unwrap();
+        }
+        let manager = Arc::new(manager);
+
+        // The same conversation would stay on a, but a is busy, so b serves it
+        let (first, first_slot) = manager.next_in_line(&mut None, Some("conversation"), "gemini-3-flash").await.unwrap();
+        let (second, second_slot) = manager.next_in_line(&mut None, Some("conversation"), "gemini-3-flash").await.unwrap();
+        assert_ne!(first.index, second.index);
+        assert!(first_slot.is_some() && second_slot.is_some());
+
+        // With both busy, the next request waits for a slot to free up
+        let last_used = *manager.last_used_index.read().await;
+        let third = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.next_in_line(&mut None, Some("other"), "gemini-3-flash").await.unwrap().0 })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!third.is_finished());
+
+        // Waiting doesn't count as a selection
+        assert_eq!(*manager.last_used_index.read().await, last_used);
+        assert!(!manager.affinity.read().await.contains_key("other"));
+
+        drop(second_slot);
+        let third = tokio::time::timeout(Duration::from_secs(5), third).await.unwrap().unwrap();
+        assert_eq!(third.index, second.index);
+        drop(first_slot);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_accounts_loaded_and_usable() {
+        let json = r#"{"accounts": [
+            {"email": "ci@example.com", "refresh_token": "refresh-ci"},
+            {"email": "batch@example.com", "refresh_token": "refresh-batch", "priority": 5, "model_families": ["gemini"]}
+        ]}"#;
+        let store = crate::storage::MemoryStore::with_accounts(StoredAccounts::default());
+        store.import_accounts(&crate::storage::parse_bootstrap_accounts(json).unwrap()).unwrap();
+
+        let mut manager = AccountManager::from_parts(Some(store), 0);
+        manager.refresher = Arc::new(|token| Box::pin(async move {
+            Ok(TokenPair {
+                access_token: format!("access-for-{}", token),
+                refresh_token: token,
+                expires_at: Utc::now() + chrono::Duration::hours(1),
+                email: String::new(),
+            })
+        }));
+        manager.reload().await.unwrap();
+
+        assert_eq!(manager.account_count().await, 2);
+        let account = manager.get_available_account_for_model("claude-sonnet-4-5").await.unwrap();
+        assert_eq!(account.email, "ci@example.com");
+        assert_eq!(account.access_token, "access-for-refresh-ci");
+        let statuses = manager.account_statuses().await;
+        assert_eq!(statuses[1].priority, 5);
+        assert_eq!(statuses[1].model_families, vec![ModelFamily::Gemini]);
+
+        // Entries without a refresh token are rejected up front
+        assert!(crate::storage::parse_bootstrap_accounts(r#"[{"email": "x@example.com", "refresh_token": ""}]"#).is_err());
     }
 }
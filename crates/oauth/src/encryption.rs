@@ -0,0 +1,116 @@
+//! File-level encryption for refresh tokens
+//!
+//! Used when the system keyring is present but rejects a write (locked
+//! collection, flaky DBus), so the token doesn't end up in plaintext in
+//! `accounts.json`. The key is generated on first use and kept next to the
+//! accounts file (`accounts.key`, owner-only on Unix). This guards against the
+//! accounts file being copied or shared on its own; it is not a substitute for
+//! the keyring.
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::path::Path;
+
+/// Nonce length for ChaCha20-Poly1305
+const NONCE_LEN: usize = 12;
+
+/// Encrypts and decrypts refresh tokens with a per-install key
+pub struct FileCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl FileCipher {
+    /// Loads the key at `key_path`, generating it if it doesn't exist yet
+    pub fn load_or_create(key_path: &Path) -> Result<Self> {
+        let key = if key_path.exists() {
+            let encoded = std::fs::read_to_string(key_path)
+                .with_context(|| format!("Failed to read {}", key_path.display()))?;
+            let bytes = STANDARD
+                .decode(encoded.trim())
+                .map_err(|e| anyhow!("Corrupt key file {}: {}", key_path.display(), e))?;
+            if bytes.len() != 32 {
+                return Err(anyhow!("Corrupt key file {}: expected 32 bytes", key_path.display()));
+            }
+            *Key::from_slice(&bytes)
+        } else {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+            write_private(key_path, &STANDARD.encode(key))?;
+            key
+        };
+
+        Ok(Self { cipher: ChaCha20Poly1305::new(&key) })
+    }
+
+    /// Encrypts a token, returning base64 of nonce + ciphertext
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt refresh token"))?;
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&ciphertext);
+        Ok(STANDARD.encode(out))
+    }
+
+    /// Decrypts a value produced by [`FileCipher::encrypt`]
+    pub fn decrypt(&self, encoded: &str) -> Result<String> {
+        let bytes = STANDARD
+            .decode(encoded)
+            .map_err(|e| anyhow!("Encrypted refresh token is not valid base64: {}", e))?;
+        if bytes.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted refresh token is truncated"));
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt refresh token (wrong or replaced key file?)"))?;
+        String::from_utf8(plaintext).map_err(|e| anyhow!("Decrypted refresh token is not UTF-8: {}", e))
+    }
+}
+
+/// Writes a file readable only by the current user
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        file.write_all(contents.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    std::fs::write(path, contents).with_context(|| format!("Failed to create {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_round_trip_with_persisted_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_path = temp_dir.path().join("accounts.key");
+
+        let encrypted = FileCipher::load_or_create(&key_path).unwrap().encrypt("1//refresh").unwrap();
+        assert!(!encrypted.contains("refresh"));
+
+        // A fresh cipher reads the same key back
+        let cipher = FileCipher::load_or_create(&key_path).unwrap();
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "1//refresh");
+        assert!(cipher.decrypt("AAAA").is_err());
+    }
+}
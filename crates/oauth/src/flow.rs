@@ -2,7 +2,7 @@
 //!
 //! Implements the secure OAuth flow for desktop applications:
 //! 1. Generate PKCE code verifier/challenge
-//! 2. Open browser for user authorization
+//! 2. Open browser for user authorization (or print the URL when headless)
 //! 3. Listen for OAuth callback on localhost
 //! 4. Exchange authorization code for tokens
 
@@ -45,6 +45,15 @@ fn generate_pkce() -> (String, String) {
     (verifier_str, challenge)
 }
 
+/// How the authorization URL reaches the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginMode {
+    /// Open the system browser
+    Browser,
+    /// Only hand back the URL, for SSH sessions and servers without a display
+    Headless,
+}
+
 /// Manages the OAuth 2.0 authorization flow
 pub struct OAuthFlow {
     state: String,
@@ -77,6 +86,59 @@ impl OAuthFlow {
         )
     }
 
+    /// Returns the authorization URL, opening it with `open_browser` in `Browser` mode
+    ///
+    /// `open_browser` is never called in `Headless` mode. A failure to open is
+    /// logged and the URL is still returned so the caller can print it.
+    pub fn present_authorization_url<F>(&self, mode: LoginMode, open_browser: F) -> String
+    where
+        F: FnOnce(&str) -> std::io::Result<()>,
+    {
+        let url = self.authorization_url();
+        if mode == LoginMode::Browser {
+            if let Err(e) = open_browser(&url) {
+                warn!("Failed to open browser: {}", e);
+            }
+        }
+        url
+    }
+
+    /// Extracts the authorization code from a pasted redirect URL or bare code
+    ///
+    /// Used by the manual-copy flow when the browser runs on another machine and
+    /// its redirect to localhost never reaches the callback server.
+    pub fn code_from_redirect(&self, input: &str) -> Result<String> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(anyhow!("No authorization code entered"));
+        }
+
+        let Some((_, query)) = input.split_once('?') else {
+            // Bare code
+            return Ok(input.to_string());
+        };
+
+        let mut code = None;
+        let mut state = None;
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = urlencoding::decode(value)
+                .map(|v| v.into_owned())
+                .unwrap_or_else(|_| value.to_string());
+            match key {
+                "code" => code = Some(value),
+                "state" => state = Some(value),
+                "error" => return Err(anyhow!("OAuth error: {}", value)),
+                _ => {}
+            }
+        }
+
+        if state.as_deref() != Some(self.state.as_str()) {
+            return Err(anyhow!("Invalid OAuth state - possible CSRF attack"));
+        }
+        code.ok_or_else(|| anyhow!("No authorization code in pasted URL"))
+    }
+
     /// Starts the local callback server and waits for the OAuth redirect
     ///
     /// This spawns a temporary HTTP server on the callback port that waits
@@ -195,6 +257,7 @@ impl OAuthFlow {
         }
 
         let token_response: TokenResponse = response.json().await?;
+        verify_scopes(token_response.scope.as_deref())?;
 
         // Fetch user email
         let email = Self::fetch_user_email(&token_response.access_token).await?;
@@ -247,6 +310,35 @@ struct TokenResponse {
     access_token: String,
     refresh_token: String,
     expires_in: i64,
+    /// Space-separated scopes actually granted
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+/// Checks that the user granted every scope in `ANTIGRAVITY_SCOPES`
+///
+/// The consent screen lets users untick scopes; without them the token only
+/// fails later, on the first API call. A response without `scope` is taken
+/// to grant what was requested.
+fn verify_scopes(granted: Option<&str>) -> Result<()> {
+    let Some(granted) = granted else {
+        return Ok(());
+    };
+    let granted: Vec<&str> = granted.split_whitespace().collect();
+    let missing: Vec<&str> = ANTIGRAVITY_SCOPES
+        .iter()
+        .copied()
+        .filter(|scope| !granted.contains(scope))
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Login did not grant all required permissions. Missing scopes: {}. \
+         Log in again and leave every permission checked on the consent screen.",
+        missing.join(", ")
+    ))
 }
 
 /// Userinfo endpoint response
@@ -334,3 +426,51 @@ const ERROR_HTML: &str = r#"<!DOCTYPE html>
     </div>
 </body>
 </html>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headless_returns_url_without_opening_browser() {
+        let flow = OAuthFlow::new();
+        let url = flow.present_authorization_url(LoginMode::Headless, |_| {
+            panic!("browser must not be opened in headless mode")
+        });
+
+        assert_eq!(url, flow.authorization_url());
+        assert!(url.starts_with(GOOGLE_AUTH_URL));
+
+        let mut opened = None;
+        flow.present_authorization_url(LoginMode::Browser, |u| {
+            opened = Some(u.to_string());
+            Ok(())
+        });
+        assert_eq!(opened.as_deref(), Some(url.as_str()));
+    }
+
+    #[test]
+    fn test_code_from_pasted_redirect() {
+        let flow = OAuthFlow::new();
+        let redirect = format!("http://localhost:51121/oauth-callback?state={}&code=4%2F0Abc&scope=email", flow.state);
+
+        assert_eq!(flow.code_from_redirect(&redirect).unwrap(), "4/0Abc");
+        assert_eq!(flow.code_from_redirect(" 4/0Abc \n").unwrap(), "4/0Abc");
+        assert!(flow.code_from_redirect("http://localhost/oauth-callback?state=other&code=x").is_err());
+        assert!(flow.code_from_redirect("").is_err());
+    }
+
+    #[test]
+    fn test_missing_scope_is_reported() {
+        let all = ANTIGRAVITY_SCOPES.join(" ");
+        assert!(verify_scopes(Some(&format!("openid {}", all))).is_ok());
+        assert!(verify_scopes(None).is_ok());
+
+        let partial: Vec<&str> = ANTIGRAVITY_SCOPES.iter().copied()
+            .filter(|s| !s.ends_with("/cclog"))
+            .collect();
+        let err = verify_scopes(Some(&partial.join(" "))).unwrap_err().to_string();
+        assert!(err.contains("Missing scopes: https://www.googleapis.com/auth/cclog."), "{}", err);
+        assert!(!err.contains("cloud-platform"));
+    }
+}
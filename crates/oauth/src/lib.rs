@@ -4,12 +4,14 @@
 //! Assist API (Antigravity), enabling access to models like Gemini 3 and Claude 4.5.
 
 pub mod constants;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod flow;
 pub mod storage;
 pub mod tokens;
 pub mod accounts;
 
-pub use flow::OAuthFlow;
-pub use storage::TokenStorage;
+pub use flow::{LoginMode, OAuthFlow};
+pub use storage::{AccountStore, MemoryStore, TokenStorage};
 pub use tokens::{TokenPair, refresh_access_token};
-pub use accounts::AccountManager;
+pub use accounts::{AccountManager, ThrottlePolicy};
@@ -6,12 +6,25 @@
 //! - Windows: %APPDATA%\aether-bridge\accounts.json
 //!
 //! Refresh tokens are additionally stored in the system keyring when available.
+//! If the keyring rejects a write, the token is encrypted in the accounts file
+//! instead (with the `encryption` feature) rather than left in plaintext.
+//! When both hold a token and they disagree (e.g. after a partial write), the
+//! keyring wins on load.
+//!
+//! Persistence is abstracted behind [`AccountStore`] so accounts can also be
+//! kept elsewhere (e.g. in memory for tests).
+//!
+//! For containers, where nobody can log in interactively, accounts can be
+//! supplied as refresh tokens in `AETHER_ACCOUNTS_JSON` (or a file named by
+//! `AETHER_ACCOUNTS_FILE`) and are imported into the store at startup.
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tracing::{info, warn, debug};
 
+use crate::accounts::ModelFamily;
 use crate::tokens::TokenPair;
 
 /// Storage format version (for future migrations)
@@ -20,6 +33,12 @@ const STORAGE_VERSION: u32 = 1;
 /// Service name for system keyring
 const KEYRING_SERVICE: &str = "aether-bridge";
 
+/// Environment variable holding accounts to import at startup
+pub const ACCOUNTS_JSON_ENV: &str = "AETHER_ACCOUNTS_JSON";
+
+/// Environment variable naming a file with the same JSON (e.g. a mounted secret)
+pub const ACCOUNTS_FILE_ENV: &str = "AETHER_ACCOUNTS_FILE";
+
 /// Container for all stored accounts
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredAccounts {
@@ -57,6 +76,265 @@ pub struct StoredAccount {
 
     /// Unix timestamp of last successful use
     pub last_used: i64,
+
+    /// Whether the account is taken out of rotation (kept, but never selected)
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Selection priority; lower numbers are tried first (default 0)
+    #[serde(default)]
+    pub priority: i32,
+
+    /// Whether `refresh_token` is encrypted in the accounts file (the keyring
+    /// rejected it). Tokens are decrypted on load, so in memory it's plaintext.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub token_encrypted: bool,
+
+    /// Model families this account may serve; empty means all of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub model_families: Vec<ModelFamily>,
+}
+
+/// An account supplied as a refresh token instead of by interactive login
+#[derive(Debug, Clone, Deserialize)]
+pub struct BootstrapAccount {
+    pub email: String,
+    pub refresh_token: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub model_families: Vec<ModelFamily>,
+}
+
+/// Parses bootstrap accounts: a JSON array of
+/// `{"email", "refresh_token", "priority"?, "model_families"?}` objects, or
+/// an object with that array under `accounts`
+pub fn parse_bootstrap_accounts(json: &str) -> Result<Vec<BootstrapAccount>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Input {
+        List(Vec<BootstrapAccount>),
+        Wrapped { accounts: Vec<BootstrapAccount> },
+    }
+
+    let accounts = match serde_json::from_str(json).map_err(|e| anyhow!("Invalid bootstrap accounts JSON: {}", e))? {
+        Input::List(accounts) | Input::Wrapped { accounts } => accounts,
+    };
+    for (i, account) in accounts.iter().enumerate() {
+        if account.email.trim().is_empty() || account.refresh_token.trim().is_empty() {
+            return Err(anyhow!("Bootstrap account {} needs a non-empty email and refresh_token", i));
+        }
+    }
+    Ok(accounts)
+}
+
+/// Reads bootstrap accounts from `AETHER_ACCOUNTS_JSON`, or else the file
+/// named by `AETHER_ACCOUNTS_FILE`; None when neither is set
+pub fn bootstrap_accounts_from_env() -> Result<Option<Vec<BootstrapAccount>>> {
+    if let Ok(json) = std::env::var(ACCOUNTS_JSON_ENV) {
+        return parse_bootstrap_accounts(&json).map(Some);
+    }
+    if let Ok(path) = std::env::var(ACCOUNTS_FILE_ENV) {
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read {} ({}): {}", ACCOUNTS_FILE_ENV, path, e))?;
+        return parse_bootstrap_accounts(&json).map(Some);
+    }
+    Ok(None)
+}
+
+/// Persistence backend for accounts
+///
+/// `load_accounts` and `save_accounts` are the only required methods; the
+/// rest default to load-modify-save.
+pub trait AccountStore: Send + Sync {
+    /// Loads all stored accounts
+    fn load_accounts(&self) -> Result<StoredAccounts>;
+
+    /// Replaces all stored accounts
+    fn save_accounts(&self, accounts: &StoredAccounts) -> Result<()>;
+
+    /// Adds a new account or updates an existing one (by email)
+    fn add_account(&self, token_pair: &TokenPair) -> Result<()> {
+        let mut accounts = self.load_accounts()?;
+        upsert_account(&mut accounts, token_pair);
+        self.save_accounts(&accounts)
+    }
+
+    /// Removes an account by email, returning `false` if it doesn't exist
+    fn remove_account(&self, email: &str) -> Result<bool> {
+        let mut accounts = self.load_accounts()?;
+        if !remove_from(&mut accounts, email) {
+            return Ok(false);
+        }
+        self.save_accounts(&accounts)?;
+        Ok(true)
+    }
+
+    /// Sets whether an account is disabled, returning `false` if it doesn't exist
+    fn set_account_disabled(&self, email: &str, disabled: bool) -> Result<bool> {
+        let mut accounts = self.load_accounts()?;
+
+        let Some(account) = accounts.accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        account.disabled = disabled;
+        self.save_accounts(&accounts)?;
+        Ok(true)
+    }
+
+    /// Sets an account's selection priority, returning `false` if it doesn't exist
+    fn set_account_priority(&self, email: &str, priority: i32) -> Result<bool> {
+        let mut accounts = self.load_accounts()?;
+
+        let Some(account) = accounts.accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        account.priority = priority;
+        self.save_accounts(&accounts)?;
+        Ok(true)
+    }
+
+    /// Sets the model families an account may serve, returning `false` if it doesn't exist
+    fn set_account_families(&self, email: &str, families: &[ModelFamily]) -> Result<bool> {
+        let mut accounts = self.load_accounts()?;
+
+        let Some(account) = accounts.accounts.iter_mut().find(|a| a.email == email) else {
+            return Ok(false);
+        };
+        account.model_families = families.to_vec();
+        self.save_accounts(&accounts)?;
+        Ok(true)
+    }
+
+    /// Adds or updates bootstrap accounts (by email), including their
+    /// priority and families
+    fn import_accounts(&self, bootstrap: &[BootstrapAccount]) -> Result<()> {
+        let mut accounts = self.load_accounts()?;
+        for account in bootstrap {
+            upsert_account(&mut accounts, &TokenPair {
+                access_token: String::new(),
+                refresh_token: account.refresh_token.clone(),
+                expires_at: chrono::Utc::now(),
+                email: account.email.clone(),
+            });
+            if let Some(stored) = accounts.accounts.iter_mut().find(|a| a.email == account.email) {
+                stored.priority = account.priority;
+                stored.model_families = account.model_families.clone();
+            }
+        }
+        self.save_accounts(&accounts)
+    }
+}
+
+/// Inserts or updates an account (by email)
+fn upsert_account(accounts: &mut StoredAccounts, token_pair: &TokenPair) {
+    let now = chrono::Utc::now().timestamp();
+
+    // Check if account already exists
+    if let Some(existing) = accounts.accounts.iter_mut().find(|a| a.email == token_pair.email) {
+        info!("Updating existing account: {}", token_pair.email);
+        existing.refresh_token = token_pair.refresh_token.clone();
+        existing.last_used = now;
+    } else {
+        info!("Adding new account: {}", token_pair.email);
+        accounts.accounts.push(StoredAccount {
+            email: token_pair.email.clone(),
+            refresh_token: token_pair.refresh_token.clone(),
+            added_at: now,
+            last_used: now,
+            disabled: false,
+            priority: 0,
+            token_encrypted: false,
+            model_families: Vec::new(),
+        });
+    }
+}
+
+/// Removes an account (by email), keeping the active index in range
+fn remove_from(accounts: &mut StoredAccounts, email: &str) -> bool {
+    let original_len = accounts.accounts.len();
+    accounts.accounts.retain(|a| a.email != email);
+
+    if accounts.accounts.len() == original_len {
+        return false;
+    }
+
+    // Adjust active index if needed
+    if accounts.active_index >= accounts.accounts.len() && !accounts.accounts.is_empty() {
+        accounts.active_index = accounts.accounts.len() - 1;
+    }
+    true
+}
+
+/// Keeps accounts in memory only (tests, ephemeral deployments)
+///
+/// Clones share the same underlying accounts.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    accounts: Arc<Mutex<StoredAccounts>>,
+}
+
+impl MemoryStore {
+    /// Creates a store pre-populated with accounts
+    pub fn with_accounts(accounts: StoredAccounts) -> Self {
+        Self { accounts: Arc::new(Mutex::new(accounts)) }
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn load_accounts(&self) -> Result<StoredAccounts> {
+        Ok(self.accounts.lock().map_err(|_| anyhow!("Account store lock poisoned"))?.clone())
+    }
+
+    fn save_accounts(&self, accounts: &StoredAccounts) -> Result<()> {
+        *self.accounts.lock().map_err(|_| anyhow!("Account store lock poisoned"))? = accounts.clone();
+        Ok(())
+    }
+}
+
+/// Secure secret store used for refresh tokens
+///
+/// Abstracts the system keyring so runtime failures (e.g. a keyring that was
+/// unlocked at startup but locked later) can be handled and tested.
+pub trait KeyringBackend: Send + Sync {
+    /// Stores the secret for an account
+    fn set_password(&self, email: &str, secret: &str) -> keyring::Result<()>;
+
+    /// Retrieves the secret for an account
+    fn get_password(&self, email: &str) -> keyring::Result<String>;
+
+    /// Deletes the secret for an account
+    fn delete_password(&self, email: &str) -> keyring::Result<()>;
+}
+
+/// The platform keyring (Secret Service, Keychain, Credential Manager)
+pub struct SystemKeyring;
+
+impl KeyringBackend for SystemKeyring {
+    fn set_password(&self, email: &str, secret: &str) -> keyring::Result<()> {
+        keyring::Entry::new(KEYRING_SERVICE, email)?.set_password(secret)
+    }
+
+    fn get_password(&self, email: &str) -> keyring::Result<String> {
+        keyring::Entry::new(KEYRING_SERVICE, email)?.get_password()
+    }
+
+    fn delete_password(&self, email: &str) -> keyring::Result<()> {
+        keyring::Entry::new(KEYRING_SERVICE, email)?.delete_credential()
+    }
+}
+
+/// Describes a keyring error in terms the user can act on
+fn describe_keyring_error(err: &keyring::Error) -> String {
+    match err {
+        keyring::Error::NoStorageAccess(_) => {
+            format!("system keyring is locked or access was denied ({})", err)
+        }
+        keyring::Error::PlatformFailure(_) => {
+            format!("system keyring is unavailable ({})", err)
+        }
+        _ => err.to_string(),
+    }
 }
 
 /// Handles persistent storage of OAuth tokens
@@ -64,8 +342,8 @@ pub struct TokenStorage {
     /// Path to the accounts JSON file
     config_path: PathBuf,
 
-    /// Whether keyring storage is available
-    keyring_available: bool,
+    /// Keyring backend, if one was available at startup
+    keyring: Option<Box<dyn KeyringBackend>>,
 }
 
 impl TokenStorage {
@@ -82,26 +360,29 @@ impl TokenStorage {
         let config_path = config_dir.join("accounts.json");
 
         // Check if keyring is available
-        let keyring_available = Self::check_keyring_available();
-        if keyring_available {
+        let keyring: Option<Box<dyn KeyringBackend>> = if Self::check_keyring_available() {
             debug!("System keyring is available for secure token storage");
+            Some(Box::new(SystemKeyring))
         } else {
             warn!("System keyring not available; tokens will be stored in plaintext");
-        }
+            None
+        };
+
+        Ok(Self::with_path(config_path, keyring))
+    }
 
-        Ok(Self {
+    /// Creates a TokenStorage for an explicit accounts file and keyring
+    pub fn with_path(config_path: PathBuf, keyring: Option<Box<dyn KeyringBackend>>) -> Self {
+        Self {
             config_path,
-            keyring_available,
-        })
+            keyring,
+        }
     }
 
     /// Checks if the system keyring is functional
     fn check_keyring_available() -> bool {
         // Try to access keyring with a test entry
-        match keyring::Entry::new(KEYRING_SERVICE, "test-availability") {
-            Ok(_) => true,
-            Err(_) => false,
-        }
+        keyring::Entry::new(KEYRING_SERVICE, "test-availability").is_ok()
     }
 
     /// Returns the path to the config file
@@ -109,94 +390,118 @@ impl TokenStorage {
         &self.config_path
     }
 
-    /// Loads all stored accounts from disk
-    pub fn load_accounts(&self) -> Result<StoredAccounts> {
-        if !self.config_path.exists() {
-            debug!("No accounts file found, returning empty");
-            return Ok(StoredAccounts::default());
-        }
-
-        let content = std::fs::read_to_string(&self.config_path)?;
-        let accounts: StoredAccounts = serde_json::from_str(&content)
-            .map_err(|e| anyhow!("Failed to parse accounts file: {}", e))?;
-
-        debug!("Loaded {} accounts from storage", accounts.accounts.len());
-        Ok(accounts)
-    }
-
-    /// Saves accounts to disk
-    pub fn save_accounts(&self, accounts: &StoredAccounts) -> Result<()> {
-        let content = serde_json::to_string_pretty(accounts)?;
-        std::fs::write(&self.config_path, content)?;
-        debug!("Saved {} accounts to storage", accounts.accounts.len());
-        Ok(())
+    /// Key for tokens encrypted in the accounts file, kept beside it
+    #[cfg(feature = "encryption")]
+    fn file_cipher(&self) -> Result<crate::encryption::FileCipher> {
+        crate::encryption::FileCipher::load_or_create(&self.config_path.with_extension("key"))
     }
 
-    /// Adds a new account or updates an existing one (by email)
-    pub fn add_account(&self, token_pair: &TokenPair) -> Result<()> {
-        let mut accounts = self.load_accounts()?;
-        let now = chrono::Utc::now().timestamp();
+    /// Decrypts the encrypted refresh tokens of freshly loaded accounts
+    fn decrypt_tokens(&self, accounts: &mut StoredAccounts) -> Result<()> {
+        if !accounts.accounts.iter().any(|a| a.token_encrypted) {
+            return Ok(());
+        }
 
-        // Check if account already exists
-        if let Some(existing) = accounts.accounts.iter_mut().find(|a| a.email == token_pair.email) {
-            info!("Updating existing account: {}", token_pair.email);
-            existing.refresh_token = token_pair.refresh_token.clone();
-            existing.last_used = now;
-        } else {
-            info!("Adding new account: {}", token_pair.email);
-            accounts.accounts.push(StoredAccount {
-                email: token_pair.email.clone(),
-                refresh_token: token_pair.refresh_token.clone(),
-                added_at: now,
-                last_used: now,
-            });
+        #[cfg(feature = "encryption")]
+        {
+            let cipher = self.file_cipher()?;
+            for account in accounts.accounts.iter_mut().filter(|a| a.token_encrypted) {
+                account.refresh_token = cipher
+                    .decrypt(&account.refresh_token)
+                    .map_err(|e| anyhow!("Could not read refresh token for {}: {}", account.email, e))?;
+            }
+            Ok(())
         }
+        #[cfg(not(feature = "encryption"))]
+        Err(anyhow!(
+            "Accounts file contains encrypted refresh tokens, but this build lacks the `encryption` feature"
+        ))
+    }
 
-        self.save_accounts(&accounts)?;
+    /// Replaces file tokens that disagree with the keyring by the keyring's
+    ///
+    /// Skips accounts whose token is encrypted in the file: the keyring
+    /// rejected their last write, so its copy is the stale one.
+    fn reconcile_with_keyring(&self, accounts: &mut StoredAccounts) {
+        let Some(keyring) = &self.keyring else {
+            return;
+        };
 
-        // Also store in system keyring for extra security
-        if self.keyring_available {
-            if let Err(e) = self.store_in_keyring(&token_pair.email, &token_pair.refresh_token) {
-                warn!("Failed to store token in keyring: {}", e);
+        for account in accounts.accounts.iter_mut().filter(|a| !a.token_encrypted) {
+            match keyring.get_password(&account.email) {
+                Ok(token) if token != account.refresh_token => {
+                    warn!(
+                        "Refresh token for {} differs between keyring and accounts file; using the keyring token",
+                        account.email
+                    );
+                    account.refresh_token = token;
+                }
+                Ok(_) | Err(keyring::Error::NoEntry) => {}
+                Err(e) => debug!(
+                    "Could not read keyring entry for {}: {}",
+                    account.email,
+                    describe_keyring_error(&e)
+                ),
             }
         }
-
-        Ok(())
     }
 
-    /// Removes an account by email
-    pub fn remove_account(&self, email: &str) -> Result<bool> {
-        let mut accounts = self.load_accounts()?;
-        let original_len = accounts.accounts.len();
-
-        accounts.accounts.retain(|a| a.email != email);
+    /// Mirrors refresh tokens into the keyring so it stays authoritative
+    ///
+    /// Accounts the keyring rejects are marked `token_encrypted`, so the
+    /// stale keyring copy is ignored on the next load.
+    fn sync_keyring(&self, accounts: &mut StoredAccounts) {
+        let Some(keyring) = &self.keyring else {
+            return;
+        };
 
-        if accounts.accounts.len() < original_len {
-            // Adjust active index if needed
-            if accounts.active_index >= accounts.accounts.len() && !accounts.accounts.is_empty() {
-                accounts.active_index = accounts.accounts.len() - 1;
+        for account in accounts.accounts.iter_mut().filter(|a| !a.token_encrypted) {
+            if keyring.get_password(&account.email).is_ok_and(|token| token == account.refresh_token) {
+                continue;
+            }
+            if let Err(e) = keyring.set_password(&account.email, &account.refresh_token) {
+                warn!(
+                    "Could not update refresh token for {} in keyring: {}",
+                    account.email,
+                    describe_keyring_error(&e)
+                );
+                account.token_encrypted = cfg!(feature = "encryption");
             }
+        }
+    }
 
-            self.save_accounts(&accounts)?;
+    /// Encrypts the refresh tokens of accounts marked `token_encrypted`
+    fn encrypt_tokens(&self, accounts: &mut StoredAccounts) -> Result<()> {
+        if !accounts.accounts.iter().any(|a| a.token_encrypted) {
+            return Ok(());
+        }
 
-            // Remove from keyring
-            if self.keyring_available {
-                let _ = self.remove_from_keyring(email);
+        #[cfg(feature = "encryption")]
+        {
+            let cipher = self.file_cipher()?;
+            for account in accounts.accounts.iter_mut().filter(|a| a.token_encrypted) {
+                account.refresh_token = cipher.encrypt(&account.refresh_token)?;
             }
-
-            info!("Removed account: {}", email);
-            Ok(true)
-        } else {
-            Ok(false)
+            Ok(())
         }
+        #[cfg(not(feature = "encryption"))]
+        Err(anyhow!("Cannot encrypt refresh tokens: this build lacks the `encryption` feature"))
     }
 
     /// Gets the refresh token for an account, preferring keyring storage
     pub fn get_refresh_token(&self, email: &str) -> Result<String> {
         // Try keyring first (more secure)
-        if self.keyring_available {
-            if let Ok(token) = self.get_from_keyring(email) {
-                return Ok(token);
+        if let Some(keyring) = &self.keyring {
+            match keyring.get_password(email) {
+                Ok(token) => return Ok(token),
+                Err(keyring::Error::NoEntry) => {
+                    debug!("No keyring entry for {}, using file storage", email);
+                }
+                Err(e) => warn!(
+                    "Could not read refresh token for {} from keyring: {}. Falling back to file storage.",
+                    email,
+                    describe_keyring_error(&e)
+                ),
             }
         }
 
@@ -235,32 +540,90 @@ impl TokenStorage {
         self.save_accounts(&accounts)?;
         Ok(())
     }
+}
 
-    // =========================================================================
-    // Keyring operations
-    // =========================================================================
+impl AccountStore for TokenStorage {
+    /// Loads all stored accounts from disk
+    fn load_accounts(&self) -> Result<StoredAccounts> {
+        if !self.config_path.exists() {
+            debug!("No accounts file found, returning empty");
+            return Ok(StoredAccounts::default());
+        }
+
+        let content = std::fs::read_to_string(&self.config_path)?;
+        let mut accounts: StoredAccounts = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Failed to parse accounts file: {}", e))?;
+        self.decrypt_tokens(&mut accounts)?;
+        self.reconcile_with_keyring(&mut accounts);
+
+        debug!("Loaded {} accounts from storage", accounts.accounts.len());
+        Ok(accounts)
+    }
 
-    fn store_in_keyring(&self, email: &str, refresh_token: &str) -> Result<()> {
-        let entry = keyring::Entry::new(KEYRING_SERVICE, email)
-            .map_err(|e| anyhow!("Failed to create keyring entry: {}", e))?;
-        entry.set_password(refresh_token)
-            .map_err(|e| anyhow!("Failed to store in keyring: {}", e))?;
+    /// Saves accounts to disk
+    fn save_accounts(&self, accounts: &StoredAccounts) -> Result<()> {
+        let mut on_disk = accounts.clone();
+        self.sync_keyring(&mut on_disk);
+        self.encrypt_tokens(&mut on_disk)?;
+        let content = serde_json::to_string_pretty(&on_disk)?;
+        std::fs::write(&self.config_path, content)?;
+        debug!("Saved {} accounts to storage", accounts.accounts.len());
         Ok(())
     }
 
-    fn get_from_keyring(&self, email: &str) -> Result<String> {
-        let entry = keyring::Entry::new(KEYRING_SERVICE, email)
-            .map_err(|e| anyhow!("Failed to create keyring entry: {}", e))?;
-        entry.get_password()
-            .map_err(|e| anyhow!("Failed to get from keyring: {}", e))
+    /// Adds a new account or updates an existing one (by email)
+    fn add_account(&self, token_pair: &TokenPair) -> Result<()> {
+        // Prefer the system keyring; if it rejects the write, encrypt the file copy
+        let mut keyring_failed = false;
+        if let Some(keyring) = &self.keyring {
+            if let Err(e) = keyring.set_password(&token_pair.email, &token_pair.refresh_token) {
+                let fallback = if cfg!(feature = "encryption") {
+                    "The token is encrypted in file storage instead."
+                } else {
+                    "The token is kept in file storage only."
+                };
+                warn!(
+                    "Could not store refresh token for {} in keyring: {}. {}",
+                    token_pair.email,
+                    describe_keyring_error(&e),
+                    fallback
+                );
+                keyring_failed = true;
+            }
+        }
+
+        let mut accounts = self.load_accounts()?;
+        upsert_account(&mut accounts, token_pair);
+        if let Some(account) = accounts.accounts.iter_mut().find(|a| a.email == token_pair.email) {
+            account.token_encrypted = keyring_failed && cfg!(feature = "encryption");
+        }
+        self.save_accounts(&accounts)
     }
 
-    fn remove_from_keyring(&self, email: &str) -> Result<()> {
-        let entry = keyring::Entry::new(KEYRING_SERVICE, email)
-            .map_err(|e| anyhow!("Failed to create keyring entry: {}", e))?;
-        entry.delete_credential()
-            .map_err(|e| anyhow!("Failed to remove from keyring: {}", e))?;
-        Ok(())
+    /// Removes an account by email
+    fn remove_account(&self, email: &str) -> Result<bool> {
+        let mut accounts = self.load_accounts()?;
+
+        if remove_from(&mut accounts, email) {
+            self.save_accounts(&accounts)?;
+
+            // Remove from keyring
+            if let Some(keyring) = &self.keyring {
+                match keyring.delete_password(email) {
+                    Ok(()) | Err(keyring::Error::NoEntry) => {}
+                    Err(e) => warn!(
+                        "Could not remove refresh token for {} from keyring: {}",
+                        email,
+                        describe_keyring_error(&e)
+                    ),
+                }
+            }
+
+            info!("Removed account: {}", email);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
     }
 }
 
@@ -271,13 +634,47 @@ mod tests {
 
     fn create_test_storage() -> (TokenStorage, TempDir) {
         let temp_dir = TempDir::new().unwrap();
-        let storage = TokenStorage {
-            config_path: temp_dir.path().join("accounts.json"),
-            keyring_available: false, // Don't use keyring in tests
-        };
+        // Don't use keyring in tests
+        let storage = TokenStorage::with_path(temp_dir.path().join("accounts.json"), None);
         (storage, temp_dir)
     }
 
+    /// Keyring that fails every operation as if it had been locked after startup
+    struct LockedKeyring;
+
+    impl KeyringBackend for LockedKeyring {
+        fn set_password(&self, _email: &str, _secret: &str) -> keyring::Result<()> {
+            Err(keyring::Error::NoStorageAccess("collection is locked".into()))
+        }
+
+        fn get_password(&self, _email: &str) -> keyring::Result<String> {
+            Err(keyring::Error::NoStorageAccess("collection is locked".into()))
+        }
+
+        fn delete_password(&self, _email: &str) -> keyring::Result<()> {
+            Err(keyring::Error::NoStorageAccess("collection is locked".into()))
+        }
+    }
+
+    /// In-memory keyring
+    #[derive(Clone, Default)]
+    struct MockKeyring(Arc<Mutex<std::collections::HashMap<String, String>>>);
+
+    impl KeyringBackend for MockKeyring {
+        fn set_password(&self, email: &str, secret: &str) -> keyring::Result<()> {
+            self.0.lock().unwrap().insert(email.to_string(), secret.to_string());
+            Ok(())
+        }
+
+        fn get_password(&self, email: &str) -> keyring::Result<String> {
+            self.0.lock().unwrap().get(email).cloned().ok_or(keyring::Error::NoEntry)
+        }
+
+        fn delete_password(&self, email: &str) -> keyring::Result<()> {
+            self.0.lock().unwrap().remove(email).map(|_| ()).ok_or(keyring::Error::NoEntry)
+        }
+    }
+
     #[test]
     fn test_add_and_load_account() {
         let (storage, _temp) = create_test_storage();
@@ -296,6 +693,29 @@ mod tests {
         assert_eq!(accounts.accounts[0].email, "test@example.com");
     }
 
+    #[test]
+    fn test_bootstrap_families_in_lowercase() {
+        let accounts = parse_bootstrap_accounts(r#"[
+            {"email": "ci@example.com", "refresh_token": "refresh-ci", "model_families": ["claude"]},
+            {"email": "old@example.com", "refresh_token": "refresh-old", "model_families": ["Gemini"]}
+        ]"#).unwrap();
+        assert_eq!(accounts[0].model_families, vec![ModelFamily::Claude]);
+        assert_eq!(accounts[1].model_families, vec![ModelFamily::Gemini]);
+
+        // Saved the way the admin API spells them
+        let stored = StoredAccount {
+            email: "ci@example.com".into(),
+            refresh_token: "refresh-ci".into(),
+            added_at: 0,
+            last_used: 0,
+            disabled: false,
+            priority: 0,
+            token_encrypted: false,
+            model_families: accounts[0].model_families.clone(),
+        };
+        assert_eq!(serde_json::to_value(&stored).unwrap()["model_families"], serde_json::json!(["claude"]));
+    }
+
     #[test]
     fn test_update_existing_account() {
         let (storage, _temp) = create_test_storage();
@@ -339,4 +759,108 @@ mod tests {
         let accounts = storage.load_accounts().unwrap();
         assert!(accounts.accounts.is_empty());
     }
+
+    #[test]
+    fn test_disabled_flag_persists() {
+        let (storage, _temp) = create_test_storage();
+
+        let token = TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now(),
+            email: "test@example.com".into(),
+        };
+
+        storage.add_account(&token).unwrap();
+        assert!(!storage.load_accounts().unwrap().accounts[0].disabled);
+
+        assert!(storage.set_account_disabled("test@example.com", true).unwrap());
+        assert!(storage.load_accounts().unwrap().accounts[0].disabled);
+
+        // Re-login keeps the flag
+        storage.add_account(&token).unwrap();
+        assert!(storage.load_accounts().unwrap().accounts[0].disabled);
+
+        assert!(!storage.set_account_disabled("missing@example.com", true).unwrap());
+    }
+
+    #[test]
+    fn test_keyring_token_wins_over_stale_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("accounts.json");
+        let keyring = MockKeyring::default();
+        let storage = TokenStorage::with_path(path.clone(), Some(Box::new(keyring.clone())));
+
+        let token = TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh-old".into(),
+            expires_at: chrono::Utc::now(),
+            email: "test@example.com".into(),
+        };
+        storage.add_account(&token).unwrap();
+
+        // A partial write updated the keyring but not the file
+        keyring.set_password("test@example.com", "refresh-new").unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("refresh-old"));
+
+        let loaded = storage.load_accounts().unwrap();
+        assert_eq!(loaded.accounts[0].refresh_token, "refresh-new");
+
+        // Saving a rotated token updates the keyring too, so it isn't undone on reload
+        let mut rotated = loaded.clone();
+        rotated.accounts[0].refresh_token = "refresh-rotated".into();
+        storage.save_accounts(&rotated).unwrap();
+        assert_eq!(keyring.get_password("test@example.com").unwrap(), "refresh-rotated");
+        assert_eq!(storage.load_accounts().unwrap().accounts[0].refresh_token, "refresh-rotated");
+    }
+
+    #[test]
+    fn test_locked_keyring_falls_back_to_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = TokenStorage::with_path(temp_dir.path().join("accounts.json"), Some(Box::new(LockedKeyring)));
+
+        let token = TokenPair {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::Utc::now(),
+            email: "test@example.com".into(),
+        };
+
+        // Keyring write failure must not fail the add
+        storage.add_account(&token).unwrap();
+
+        // Keyring read failure falls back to the file store
+        assert_eq!(storage.get_refresh_token("test@example.com").unwrap(), "refresh");
+
+        // Keyring delete failure must not fail the removal
+        assert!(storage.remove_account("test@example.com").unwrap());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_keyring_write_failure_encrypts_file_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("accounts.json");
+        let storage = TokenStorage::with_path(path.clone(), Some(Box::new(LockedKeyring)));
+
+        let token = TokenPair {
+            access_token: "access".into(),
+            refresh_token: "1//secret-refresh".into(),
+            expires_at: chrono::Utc::now(),
+            email: "test@example.com".into(),
+        };
+        storage.add_account(&token).unwrap();
+
+        // The file holds ciphertext, not the token
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("secret-refresh"), "{}", raw);
+        assert!(raw.contains("\"token_encrypted\": true"));
+
+        // Loading decrypts transparently, and rewrites stay encrypted
+        let loaded = storage.load_accounts().unwrap();
+        assert_eq!(loaded.accounts[0].refresh_token, "1//secret-refresh");
+        storage.mark_account_used("test@example.com").unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("secret-refresh"));
+        assert_eq!(storage.get_refresh_token("test@example.com").unwrap(), "1//secret-refresh");
+    }
 }
@@ -11,6 +11,7 @@ use std::time::{Duration, Instant};
 use std::sync::Arc;
 use oauth::{OAuthFlow, AccountManager};
 
+use crate::log_layer::LogQueue;
 use crate::ui;
 
 /// Server running state
@@ -102,10 +103,14 @@ pub struct App {
     pub account_manager: Option<Arc<AccountManager>>,
     /// Connected account emails
     pub connected_accounts: Vec<String>,
+    /// Accounts whose token refresh failed (revoked); shown in red until re-login
+    pub dead_accounts: Vec<String>,
     /// Is OAuth login in progress?
     pub login_in_progress: bool,
     /// Persistent configuration
     pub config: Config,
+    /// Server events forwarded by the tracing layer, drained each tick
+    server_logs: Option<LogQueue>,
 }
 
 impl App {
@@ -140,8 +145,10 @@ impl App {
             server_handle: None,
             account_manager: None,
             connected_accounts: Vec::new(),
+            dead_accounts: Vec::new(),
             login_in_progress: false,
             config,
+            server_logs: None,
         };
 
         if matches!(app.input_mode, InputMode::Wizard(_)) {
@@ -155,16 +162,27 @@ impl App {
         app
     }
 
+    /// Shows server log events in the log panel
+    pub fn attach_server_logs(&mut self, queue: LogQueue) {
+        self.server_logs = Some(queue);
+    }
+
     /// Initialize the account manager and load existing accounts
     pub async fn init_account_manager(&mut self) {
         match AccountManager::new().await {
             Ok(manager) => {
                 let count = manager.account_count().await;
-                self.connected_accounts = manager.get_account_emails().await;
+                self.refresh_account_list(&manager).await;
                 self.account_manager = Some(Arc::new(manager));
 
                 if count > 0 {
                     self.log_success(format!("Loaded {} Google account(s)", count));
+                    if !self.dead_accounts.is_empty() {
+                        self.log_warning(format!(
+                            "Token refresh failed for {}. Press [L] to re-authenticate.",
+                            self.dead_accounts.join(", ")
+                        ));
+                    }
                 } else {
                     if !matches!(self.input_mode, InputMode::Wizard(_)) {
                          self.log_info("No accounts configured. Press [L] to login.");
@@ -177,6 +195,16 @@ impl App {
         }
     }
 
+    /// Reloads account emails and refresh health from the manager
+    async fn refresh_account_list(&mut self, manager: &AccountManager) {
+        let statuses = manager.account_statuses().await;
+        self.dead_accounts = statuses.iter()
+            .filter(|s| s.refresh_failed)
+            .map(|s| s.email.clone())
+            .collect();
+        self.connected_accounts = statuses.into_iter().map(|s| s.email).collect();
+    }
+
     /// Detect available browsers
     fn detect_browsers() -> Vec<BrowserInfo> {
         Browser::all()
@@ -616,15 +644,20 @@ impl App {
                 self.log_info(format!("Starting server on port {}...", self.port));
                 self.server_state = ServerState::Starting;
 
-                // Create config with auto-detected browser profile
-                let mut config = Config::default();
+                // Detect the browser profile once and keep it in the saved config
+                if self.config.server.browser_profile_path.is_none()
+                    && let Some(path) = platform::detect_browser_profile()
+                {
+                    self.config.server.browser_profile_path = Some(path.to_string_lossy().to_string());
+                    if let Err(e) = self.config.save() {
+                        self.log_error(format!("Failed to save config: {}", e));
+                    }
+                }
+
+                // Start from the saved config
+                let mut config = self.config.clone();
                 config.server.port = self.port;
                 config.server.host = self.host.clone();
-                // Prefer config path if set, otherwise detect
-                config.server.browser_profile_path = self.config.server.browser_profile_path.clone()
-                    .or_else(|| platform::detect_browser_profile().map(|p| p.to_string_lossy().to_string()));
-                config.project_id = self.config.project_id.clone();
-
 
                 // Actually start the server
                 match api_server::start_server(config, &self.host, self.port).await {
@@ -708,7 +741,7 @@ impl App {
                             if let Err(e) = manager.add_account(token_pair.clone()).await {
                                 self.log_warning(format!("Failed to save account: {}", e));
                             }
-                            self.connected_accounts = manager.get_account_emails().await;
+                            self.refresh_account_list(&manager).await;
                         } else {
                             // Initialize account manager if not already done
                             match AccountManager::new().await {
@@ -716,7 +749,7 @@ impl App {
                                     if let Err(e) = manager.add_account(token_pair.clone()).await {
                                         self.log_warning(format!("Failed to save account: {}", e));
                                     }
-                                    self.connected_accounts = manager.get_account_emails().await;
+                                    self.refresh_account_list(&manager).await;
                                     self.account_manager = Some(Arc::new(manager));
                                 }
                                 Err(e) => {
@@ -743,7 +776,19 @@ impl App {
 
     /// Periodic tick updates
     fn tick(&mut self) {
-        // Future: update server stats, check health, etc.
+        let entries = self.server_logs.as_ref().map(|q| q.drain()).unwrap_or_default();
+        for entry in entries {
+            self.log_with_level(entry.message, entry.level);
+        }
+
+        // Catch a server task that died on its own (panic, listener error)
+        if matches!(self.server_state, ServerState::Running { .. })
+            && self.server_handle.as_ref().is_some_and(|h| !h.is_running())
+        {
+            self.server_handle = None;
+            self.server_state = ServerState::Error("Server stopped unexpectedly".into());
+            self.log_error("Server stopped unexpectedly");
+        }
     }
 }
 
@@ -0,0 +1,140 @@
+//! Server log tail for the TUI
+//!
+//! A `tracing` layer that copies events from the embedded server into a
+//! shared queue. The app drains the queue every tick, so server errors and
+//! rate limits show up in the log panel instead of only in the log file.
+//! The layer is installed once at startup, so it keeps working across
+//! server restarts.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::app::{LogEntry, LogLevel};
+
+/// Events older than this many entries are dropped if the TUI falls behind
+const QUEUE_CAPACITY: usize = 500;
+
+/// Targets forwarded to the TUI (dependency crates are too noisy)
+const SERVER_TARGETS: &[&str] = &["api_server", "browser_automator", "oauth", "common"];
+
+/// Queue shared between the tracing layer and the app
+#[derive(Debug, Clone, Default)]
+pub struct LogQueue {
+    entries: Arc<Mutex<VecDeque<LogEntry>>>,
+}
+
+impl LogQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, entry: LogEntry) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() >= QUEUE_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Takes all queued entries, oldest first
+    pub fn drain(&self) -> Vec<LogEntry> {
+        self.entries
+            .lock()
+            .map(|mut entries| entries.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Forwards INFO and above from the server crates into a [`LogQueue`]
+pub struct TuiLogLayer {
+    queue: LogQueue,
+}
+
+impl TuiLogLayer {
+    pub fn new(queue: LogQueue) -> Self {
+        Self { queue }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TuiLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::INFO {
+            return;
+        }
+        let target = metadata.target();
+        let crate_name = target.split("::").next().unwrap_or(target);
+        if !SERVER_TARGETS.contains(&crate_name) {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let level = match *metadata.level() {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warning,
+            _ => LogLevel::Info,
+        };
+        self.queue.push(LogEntry {
+            timestamp: chrono::Local::now().format("%H:%M:%S").to_string(),
+            message: visitor.message,
+            level,
+        });
+    }
+}
+
+/// Collects the `message` field, appending any other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.message, " {}={}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_server_event_lands_in_queue() {
+        let queue = LogQueue::new();
+        let subscriber = tracing_subscriber::registry().with(TuiLogLayer::new(queue.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(target: "api_server::routes", "Rate limited on account {}", 2);
+            tracing::debug!(target: "api_server::routes", "too verbose");
+            tracing::error!(target: "hyper::proto", "not ours");
+        });
+
+        let entries = queue.drain();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].message, "Rate limited on account 2");
+        assert_eq!(entries[0].level, LogLevel::Warning);
+        assert!(queue.drain().is_empty());
+    }
+}
@@ -4,6 +4,7 @@
 //! It initializes the terminal, sets up the event loop, and runs the app.
 
 mod app;
+mod log_layer;
 mod ui;
 
 use anyhow::Result;
@@ -14,21 +15,29 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::prelude::*;
+use log_layer::{LogQueue, TuiLogLayer};
 use std::io;
-use tracing::Level;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging to file (not stdout, since we're using the terminal)
-    tracing_subscriber::fmt()
-        .with_max_level(Level::DEBUG)
-        .with_writer(|| {
-            std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open("/tmp/aether-bridge.log")
-                .unwrap_or_else(|_| std::fs::File::create("/dev/null").unwrap())
-        })
+    // Initialize logging to file (not stdout, since we're using the terminal),
+    // and mirror server events into the TUI log panel
+    let server_logs = LogQueue::new();
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(|| {
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open("/tmp/aether-bridge.log")
+                        .unwrap_or_else(|_| std::fs::File::create("/dev/null").unwrap())
+                })
+                .with_filter(LevelFilter::DEBUG),
+        )
+        .with(TuiLogLayer::new(server_logs.clone()))
         .init();
 
     // Setup terminal
@@ -40,6 +49,7 @@ async fn main() -> Result<()> {
 
     // Create app and run
     let mut app = App::new();
+    app.attach_server_logs(server_logs);
 
     // Initialize OAuth account manager (loads existing accounts)
     app.init_account_manager().await;
@@ -28,12 +28,16 @@ pub fn render(frame: &mut Frame, app: &App) {
         return;
     }
 
+    // One line per account (at least one for the empty hint), plus borders
+    let accounts_height = app.connected_accounts.len().clamp(1, 6) as u16 + 2;
+
     // Main layout: Header, Content, Footer
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Length(5),  // Header with status
             Constraint::Length(6),  // Browser panel
+            Constraint::Length(accounts_height), // Accounts panel
             Constraint::Min(5),     // Logs
             Constraint::Length(3),  // Help footer
         ])
@@ -41,8 +45,9 @@ pub fn render(frame: &mut Frame, app: &App) {
 
     render_header(frame, app, chunks[0]);
     render_browser_panel(frame, app, chunks[1]);
-    render_logs(frame, app, chunks[2]);
-    render_footer(frame, app, chunks[3]);
+    render_accounts_panel(frame, app, chunks[2]);
+    render_logs(frame, app, chunks[3]);
+    render_footer(frame, app, chunks[4]);
 
     // Render overlays
     if app.input_mode == InputMode::Help {
@@ -302,6 +307,44 @@ fn render_browser_panel(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(panel, area);
 }
 
+/// Render connected accounts, flagging dead ones (failed refresh) in red
+fn render_accounts_panel(frame: &mut Frame, app: &App, area: Rect) {
+    let account_items: Vec<Line> = if app.connected_accounts.is_empty() {
+        vec![Line::from(Span::styled("  No accounts. Press [L] to login.", Style::default().fg(MUTED_COLOR)))]
+    } else {
+        app.connected_accounts
+            .iter()
+            .map(|email| {
+                if app.dead_accounts.contains(email) {
+                    Line::from(vec![
+                        Span::styled("  ✗ ", Style::default().fg(ERROR_COLOR)),
+                        Span::styled(email.as_str(), Style::default().fg(ERROR_COLOR)),
+                        Span::styled("  refresh failed, press [L] to re-authenticate", Style::default().fg(MUTED_COLOR)),
+                    ])
+                } else {
+                    Line::from(vec![
+                        Span::styled("  ✓ ", Style::default().fg(SUCCESS_COLOR)),
+                        Span::styled(email.as_str(), Style::default().fg(Color::White)),
+                    ])
+                }
+            })
+            .collect()
+    };
+
+    let border_color = if app.dead_accounts.is_empty() { MUTED_COLOR } else { ERROR_COLOR };
+    let panel = Paragraph::new(account_items)
+        .block(
+            Block::default()
+                .title(" Accounts ")
+                .title_style(Style::default().fg(Color::White))
+                .borders(Borders::ALL)
+                .border_set(border::ROUNDED)
+                .border_style(Style::default().fg(border_color)),
+        );
+
+    frame.render_widget(panel, area);
+}
+
 /// Render the log viewer with colored levels
 fn render_logs(frame: &mut Frame, app: &App, area: Rect) {
     let visible_height = area.height.saturating_sub(2) as usize;